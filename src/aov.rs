@@ -0,0 +1,177 @@
+use crate::colorspace::ColorMatrix;
+use crate::sensor::Color;
+
+/// Arbitrary output variables an integrator can write next to the beauty pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aov {
+    /// Albedo of the first surface hit by the camera ray.
+    Albedo,
+    /// Shading normal at the first hit, in world space.
+    Normal,
+    /// Ray distance `SurfaceInteraction::t` of the first hit.
+    Depth,
+    /// World space position of the first hit.
+    Position,
+    /// Index of the first hit shape in `Scene::shapes`, -1 for misses.
+    ShapeId,
+    /// Index of the material of the first hit, -1 for misses.
+    MaterialId,
+    /// Emission seen by the camera plus light arriving at the first hit straight from a light.
+    Direct,
+    /// Everything in the beauty pass that is not in `Direct`.
+    Indirect,
+    /// Light reflected by the diffuse lobes of the first hit.
+    Diffuse,
+    /// Light reflected by the glossy and specular lobes of the first hit.
+    Specular,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 10] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::ShapeId,
+        Aov::MaterialId,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Diffuse,
+        Aov::Specular,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::ShapeId => "shape_id",
+            Aov::MaterialId => "material_id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Diffuse => "diffuse",
+            Aov::Specular => "specular",
+        }
+    }
+
+    /// Whether samples of this AOV can be averaged over a pixel.
+    ///
+    /// Ids are categorical, so averaging them would produce ids that do not
+    /// exist. For those the first sample of a pixel is kept.
+    pub fn is_filtered(&self) -> bool {
+        !matches!(self, Aov::ShapeId | Aov::MaterialId)
+    }
+
+    /// Whether this AOV holds a color in the working space of the scene.
+    ///
+    /// Those are converted into the color space of the sensor like the beauty pass.
+    pub fn is_color(&self) -> bool {
+        matches!(
+            self,
+            Aov::Albedo | Aov::Direct | Aov::Indirect | Aov::Diffuse | Aov::Specular
+        )
+    }
+}
+
+/// Values of all AOVs for a single camera sample.
+#[derive(Clone, Copy, Debug)]
+pub struct AovRecord {
+    values: [Color; Aov::ALL.len()],
+}
+
+impl AovRecord {
+    pub fn new() -> AovRecord {
+        let mut record = AovRecord {
+            values: [Color::new(0.0, 0.0, 0.0); Aov::ALL.len()],
+        };
+        record.set(Aov::ShapeId, Color::new(-1.0, -1.0, -1.0));
+        record.set(Aov::MaterialId, Color::new(-1.0, -1.0, -1.0));
+        record
+    }
+
+    pub fn get(&self, aov: Aov) -> Color {
+        self.values[aov as usize]
+    }
+
+    pub fn set(&mut self, aov: Aov, value: Color) {
+        self.values[aov as usize] = value;
+    }
+
+    pub fn add(&mut self, aov: Aov, value: Color) {
+        self.values[aov as usize] = self.values[aov as usize] + value;
+    }
+
+    /// Adds the filtered AOVs of `sample` to this record.
+    ///
+    /// Unfiltered AOVs are only taken over if `first` is set.
+    pub fn accumulate(&mut self, sample: &AovRecord, first: bool) {
+        for aov in Aov::ALL {
+            if aov.is_filtered() {
+                self.add(aov, sample.get(aov));
+            } else if first {
+                self.set(aov, sample.get(aov));
+            }
+        }
+    }
+
+    /// Multiplies the filtered AOVs by `f`, e.g. to turn a sum into an average.
+    pub fn scale_filtered(&mut self, f: f32) {
+        for aov in Aov::ALL.into_iter().filter(Aov::is_filtered) {
+            self.set(aov, f * self.get(aov));
+        }
+    }
+
+    /// Applies `matrix` to the color AOVs, e.g. to move them into the space of a sensor.
+    pub fn convert_colors(&mut self, matrix: &ColorMatrix) {
+        for aov in Aov::ALL.into_iter().filter(Aov::is_color) {
+            self.set(aov, matrix.apply(self.get(aov)));
+        }
+    }
+}
+
+impl Default for AovRecord {
+    fn default() -> Self {
+        AovRecord::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colorspace::ColorSpace;
+
+    #[test]
+    fn keeps_first_id() {
+        let mut accum = AovRecord::new();
+
+        let mut a = AovRecord::new();
+        a.set(Aov::ShapeId, Color::new(3.0, 3.0, 3.0));
+        a.set(Aov::Depth, Color::new(1.0, 1.0, 1.0));
+        let mut b = AovRecord::new();
+        b.set(Aov::ShapeId, Color::new(5.0, 5.0, 5.0));
+        b.set(Aov::Depth, Color::new(2.0, 2.0, 2.0));
+
+        accum.accumulate(&a, true);
+        accum.accumulate(&b, false);
+        accum.scale_filtered(0.5);
+
+        assert_eq!(accum.get(Aov::ShapeId).r, 3.0);
+        assert_eq!(accum.get(Aov::Depth).r, 1.5);
+    }
+
+    #[test]
+    fn converts_only_colors() {
+        let mut record = AovRecord::new();
+        record.set(Aov::Albedo, Color::new(1.0, 0.0, 0.0));
+        record.set(Aov::Normal, Color::new(1.0, 0.0, 0.0));
+
+        let matrix = ColorSpace::Srgb.matrix_to(ColorSpace::AcesCg);
+        record.convert_colors(&matrix);
+
+        let red = matrix.apply(Color::new(1.0, 0.0, 0.0));
+        assert_eq!(record.get(Aov::Albedo).g, red.g);
+        assert_eq!(record.get(Aov::Normal).g, 0.0);
+        assert_eq!(record.get(Aov::ShapeId).r, -1.0);
+    }
+}
@@ -0,0 +1,699 @@
+use crate::aov::AovRecord;
+use crate::emitter::Emitter;
+use crate::integrator::{record_first_hit, render_pixels, Integrator, Splat};
+use crate::material::*;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::{Camera, Color};
+
+use rand::Rng;
+
+/// Bidirectional path tracer.
+///
+/// Every camera sample traces a path from the camera and one from a light
+/// chosen uniformly, then joins every prefix of the one with every prefix of
+/// the other. The strategies are weighted by multiple importance sampling
+/// with the balance heuristic. Light paths that connect straight to the camera
+/// land on other pixels and are splatted onto the sensor, which lets caustics
+/// through glass show up that a path tracer rarely finds.
+///
+/// Media are ignored, surfaces with a `NullMaterial` are passed through.
+pub struct BidirectionalPathIntegrator {
+    max_depth: usize,
+}
+
+/// Scene data shared by all vertices of a sample.
+struct Context<'a> {
+    scene: &'a Scene,
+    /// Camera to splat light paths onto, `None` leaves out light tracing.
+    camera: Option<&'a dyn Camera>,
+    /// Bounds that lights at infinity shine onto.
+    bounds: Bounds3,
+    /// Probability of choosing any one light.
+    light_pdf: f32,
+}
+
+enum VertexKind<'a> {
+    Camera,
+    Light(&'a dyn Emitter),
+    Surface(SurfaceInteraction<'a>),
+}
+
+/// Vertex of a camera or light subpath.
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    position: Point,
+    normal: Option<Vector>,
+    /// Throughput of the subpath up to and including this vertex.
+    beta: Color,
+    /// Whether the vertex scattered through a delta lobe, so that the path
+    /// could not have been sampled from the other end.
+    delta: bool,
+    /// Area density of sampling the vertex from its subpath.
+    pdf_fwd: f32,
+    /// Area density of sampling the vertex from the other end of the path.
+    pdf_rev: f32,
+}
+
+fn remap0(pdf: f32) -> f32 {
+    match pdf != 0.0 {
+        true => pdf.abs(),
+        false => 1.0,
+    }
+}
+
+/// Whether nothing blocks the segment between two points.
+fn visible(scene: &Scene, from: Point, to: Point) -> bool {
+    let dist = norm(to - from);
+    let direction = (to - from).normalize();
+    match scene.closest_hit(&Ray {
+        origin: from + 1e-3 * direction,
+        direction,
+    }) {
+        // the surface at `to` itself is hit at about dist - 1e-3
+        Some(si) => si.t >= dist - 2e-3,
+        None => true,
+    }
+}
+
+impl<'a> Vertex<'a> {
+    fn surface(si: SurfaceInteraction<'a>, beta: Color) -> Vertex<'a> {
+        Vertex {
+            position: si.position,
+            normal: Some(si.normal),
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            kind: VertexKind::Surface(si),
+        }
+    }
+
+    fn emitter(&self) -> Option<&'a dyn Emitter> {
+        match &self.kind {
+            VertexKind::Light(light) => Some(*light),
+            VertexKind::Surface(si) => si.emitter,
+            VertexKind::Camera => None,
+        }
+    }
+
+    /// Whether subpaths can be joined at the vertex, which needs a lobe that is not delta.
+    fn is_connectible(&self) -> bool {
+        match &self.kind {
+            VertexKind::Surface(si) => !si.material.is_delta_reflector(),
+            _ => !self.delta,
+        }
+    }
+
+    fn is_infinite_light(&self) -> bool {
+        match self.kind {
+            VertexKind::Light(light) => light.is_infinite(),
+            _ => false,
+        }
+    }
+
+    /// Scattered radiance towards `next` times the cosine at this vertex.
+    fn f(&self, next: &Vertex) -> Color {
+        match &self.kind {
+            VertexKind::Surface(si) => {
+                let wo = (next.position - self.position).normalize();
+                si.material.bsdf_eval(si, wo).radiance
+            }
+            _ => Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Converts a solid angle density at this vertex into an area density at `next`.
+    fn convert_density(&self, pdf: f32, next: &Vertex) -> f32 {
+        if next.is_infinite_light() {
+            return pdf;
+        }
+        let w = next.position - self.position;
+        let dist2 = norm2(w);
+        let cos = next.normal.map_or(1.0, |n| dot(n, w.normalize()).abs());
+        pdf * cos / dist2
+    }
+
+    /// Area density of sampling `next` from this vertex when it was reached from `prev`.
+    fn pdf(&self, ctx: &Context, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let wn = (next.position - self.position).normalize();
+        let pdf = match &self.kind {
+            VertexKind::Light(_) => return self.pdf_light(ctx, next),
+            VertexKind::Camera => ctx.camera.map_or(0.0, |camera| camera.pdf_direction(wn)),
+            VertexKind::Surface(si) => {
+                let Some(prev) = prev else {
+                    return 0.0;
+                };
+                let mut si = si.clone();
+                si.wi = (prev.position - self.position).normalize();
+                si.material.bsdf_pdf(&si, wn)
+            }
+        };
+        self.convert_density(pdf, next)
+    }
+
+    /// Area density at `next` of light leaving this vertex on a light.
+    fn pdf_light(&self, ctx: &Context, next: &Vertex) -> f32 {
+        let Some(light) = self.emitter() else {
+            return 0.0;
+        };
+        let w = next.position - self.position;
+        let dist2 = norm2(w);
+        let w = w.normalize();
+        let (pdf_position, pdf_direction) =
+            light.pdf_emission(self.position, self.normal, w, &ctx.bounds);
+        let pdf = match light.is_infinite() {
+            true => pdf_position,
+            false => pdf_direction / dist2,
+        };
+        pdf * next.normal.map_or(1.0, |n| dot(n, w).abs())
+    }
+
+    /// Area density of this vertex as the origin of a light subpath towards `next`.
+    fn pdf_light_origin(&self, ctx: &Context, next: &Vertex) -> f32 {
+        let Some(light) = self.emitter() else {
+            return 0.0;
+        };
+        // lights at infinity here only shine from a single direction
+        if light.is_infinite() {
+            return 0.0;
+        }
+        let w = (next.position - self.position).normalize();
+        let (pdf_position, _) = light.pdf_emission(self.position, self.normal, w, &ctx.bounds);
+        ctx.light_pdf * pdf_position
+    }
+}
+
+impl BidirectionalPathIntegrator {
+    /// Paths have at most `max_depth` bounces, as in `PathIntegrator`.
+    pub fn new(max_depth: usize) -> BidirectionalPathIntegrator {
+        BidirectionalPathIntegrator { max_depth }
+    }
+
+    /// Extends `path` by scattering until it holds `max_vertices` vertices.
+    ///
+    /// Returns the background radiance times the throughput if the path left the scene.
+    fn random_walk<'a>(
+        scene: &'a Scene,
+        mut ray: Ray,
+        mut beta: Color,
+        mut pdf_fwd: f32,
+        sampler: &mut Sampler,
+        max_vertices: usize,
+        path: &mut Vec<Vertex<'a>>,
+    ) -> Option<Color> {
+        while path.len() < max_vertices {
+            let Some(si) = scene.closest_surface(&ray) else {
+                return Some(beta * scene.background(ray.direction));
+            };
+            let mut vertex = Vertex::surface(si, beta);
+            vertex.pdf_fwd = path.last().unwrap().convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+
+            let last = path.len() - 1;
+            let (prev, current) = path.split_at_mut(last);
+            let (prev, current) = (prev.last_mut().unwrap(), &mut current[0]);
+            let VertexKind::Surface(si) = &current.kind else {
+                unreachable!()
+            };
+
+            let ScatterSample {
+                wo,
+                radiance,
+                pdf,
+                delta,
+            } = si.material.bsdf_scatter(si, sampler);
+            if pdf <= 0.0 || radiance.is_black() {
+                break;
+            }
+            let mut reversed = si.clone();
+            reversed.wi = wo;
+            let mut pdf_rev = si.material.bsdf_pdf(&reversed, si.wi);
+
+            beta = (1.0 / pdf) * beta * radiance;
+            pdf_fwd = pdf;
+            if delta {
+                current.delta = true;
+                pdf_fwd = 0.0;
+                pdf_rev = 0.0;
+            }
+            prev.pdf_rev = current.convert_density(pdf_rev, prev);
+
+            ray = Ray {
+                origin: si.position + 1e-3 * wo,
+                direction: wo,
+            };
+        }
+        None
+    }
+
+    /// Traces the camera subpath, returning the radiance of rays that left the scene.
+    fn camera_subpath<'a>(
+        &self,
+        ctx: &Context<'a>,
+        ray: &Ray,
+        sampler: &mut Sampler,
+        path: &mut Vec<Vertex<'a>>,
+    ) -> Color {
+        path.push(Vertex {
+            kind: VertexKind::Camera,
+            position: ray.origin,
+            normal: None,
+            beta: Color::new(1.0, 1.0, 1.0),
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        });
+        let pdf_direction = ctx
+            .camera
+            .map_or(0.0, |camera| camera.pdf_direction(ray.direction));
+        let ray = Ray {
+            origin: ray.origin,
+            direction: ray.direction,
+        };
+        let beta = Color::new(1.0, 1.0, 1.0);
+        Self::random_walk(
+            ctx.scene,
+            ray,
+            beta,
+            pdf_direction,
+            sampler,
+            self.max_depth + 2,
+            path,
+        )
+        .unwrap_or(Color::new(0.0, 0.0, 0.0))
+    }
+
+    /// Traces the light subpath from a uniformly chosen light.
+    fn light_subpath<'a>(
+        &self,
+        ctx: &Context<'a>,
+        sampler: &mut Sampler,
+        path: &mut Vec<Vertex<'a>>,
+    ) {
+        let lights = &ctx.scene.lights;
+        if lights.is_empty() {
+            return;
+        }
+        let index = usize::min(
+            (sampler.gen::<f32>() * lights.len() as f32) as usize,
+            lights.len() - 1,
+        );
+        let light = lights[index].as_ref();
+        let Some(sample) = light.sample_emission(&ctx.bounds, sampler) else {
+            return;
+        };
+        if sample.pdf_position <= 0.0 || sample.pdf_direction <= 0.0 {
+            return;
+        }
+
+        let cos = sample
+            .normal
+            .map_or(1.0, |n| dot(n, sample.direction).abs());
+        let beta =
+            (cos / (ctx.light_pdf * sample.pdf_position * sample.pdf_direction)) * sample.emitted;
+        path.push(Vertex {
+            kind: VertexKind::Light(light),
+            position: sample.position,
+            normal: sample.normal,
+            beta: sample.emitted,
+            delta: false,
+            pdf_fwd: match light.is_infinite() {
+                true => 0.0,
+                false => ctx.light_pdf * sample.pdf_position,
+            },
+            pdf_rev: 0.0,
+        });
+        let ray = Ray {
+            origin: sample.position + 1e-3 * sample.direction,
+            direction: sample.direction,
+        };
+        Self::random_walk(
+            ctx.scene,
+            ray,
+            beta,
+            sample.pdf_direction,
+            sampler,
+            self.max_depth + 1,
+            path,
+        );
+
+        // rays from infinity start on a disk, their first hit has the density of that disk
+        if light.is_infinite() && path.len() > 1 {
+            let cos = path[1]
+                .normal
+                .map_or(1.0, |n| dot(n, sample.direction).abs());
+            path[1].pdf_fwd = sample.pdf_position * cos;
+        }
+    }
+
+    /// Balance heuristic weight of joining `s` light and `t` camera vertices.
+    ///
+    /// `sampled` replaces the last light vertex for `s == 1`, where the light
+    /// is sampled anew from the camera vertex.
+    fn mis_weight(
+        ctx: &Context,
+        light: &[Vertex],
+        camera: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f32 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let densities = |v: &Vertex| (v.pdf_fwd, v.pdf_rev, v.delta);
+        let mut light_pdfs: Vec<_> = light[..s].iter().map(densities).collect();
+        let mut camera_pdfs: Vec<_> = camera[..t].iter().map(densities).collect();
+
+        let qs = match s {
+            0 => None,
+            1 => sampled,
+            _ => Some(&light[s - 1]),
+        };
+        let pt = &camera[t - 1];
+        let qs_minus = (s > 1).then(|| &light[s - 2]);
+        let pt_minus = (t > 1).then(|| &camera[t - 2]);
+
+        if let (1, Some(sampled)) = (s, sampled) {
+            light_pdfs[0] = densities(sampled);
+        }
+        // the connected vertices scatter through their non-delta lobes
+        camera_pdfs[t - 1].2 = false;
+        if s > 0 {
+            light_pdfs[s - 1].2 = false;
+        }
+
+        camera_pdfs[t - 1].1 = match qs {
+            Some(qs) => qs.pdf(ctx, qs_minus, pt),
+            None => pt_minus.map_or(0.0, |pt_minus| pt.pdf_light_origin(ctx, pt_minus)),
+        };
+        if let Some(pt_minus) = pt_minus {
+            camera_pdfs[t - 2].1 = match qs {
+                Some(qs) => pt.pdf(ctx, Some(qs), pt_minus),
+                None => pt.pdf_light(ctx, pt_minus),
+            };
+        }
+        if let Some(qs) = qs {
+            light_pdfs[s - 1].1 = pt.pdf(ctx, pt_minus, qs);
+            if let Some(qs_minus) = qs_minus {
+                light_pdfs[s - 2].1 = qs.pdf(ctx, Some(pt), qs_minus);
+            }
+        }
+
+        let mut sum = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap0(camera_pdfs[i].1) / remap0(camera_pdfs[i].0);
+            // i == 1 is light tracing, which needs a camera to splat onto
+            let connectible = i > 1 || ctx.camera.is_some();
+            if connectible && !camera_pdfs[i].2 && !camera_pdfs[i - 1].2 {
+                sum += ri;
+            }
+        }
+
+        let origin = match s {
+            1 => sampled,
+            _ => light.first(),
+        };
+        let delta_light = origin
+            .and_then(|v| v.emitter())
+            .is_some_and(|light| light.is_delta());
+        let mut ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap0(light_pdfs[i].1) / remap0(light_pdfs[i].0);
+            let delta_prev = match i {
+                0 => delta_light,
+                _ => light_pdfs[i - 1].2,
+            };
+            if !light_pdfs[i].2 && !delta_prev {
+                sum += ri;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+
+    /// Contribution of joining `s` light and `t` camera vertices, and the pixel it lands on
+    /// if it is a light tracing splat.
+    fn connect(
+        ctx: &Context,
+        light: &[Vertex],
+        camera: &[Vertex],
+        s: usize,
+        t: usize,
+        sampler: &mut Sampler,
+    ) -> Option<(Color, Option<usize>)> {
+        let pt = &camera[t - 1];
+        let mut sampled = None;
+        let mut pixel = None;
+
+        let radiance = match (s, t) {
+            (0, _) => {
+                let VertexKind::Surface(si) = &pt.kind else {
+                    return None;
+                };
+                pt.beta * si.emitter?.emitted(si)
+            }
+            (_, 1) => {
+                let qs = &light[s - 1];
+                if !qs.is_connectible() {
+                    return None;
+                }
+                let camera = ctx.camera?;
+                let (i, j) = camera.pixel_of(qs.position)?;
+                let importance = camera.importance(qs.position);
+                if importance <= 0.0 || !visible(ctx.scene, qs.position, pt.position) {
+                    return None;
+                }
+                pixel = Some(j * camera.get_sensor().width() + i);
+                importance * qs.beta * qs.f(pt)
+            }
+            (1, _) => {
+                if !pt.is_connectible() {
+                    return None;
+                }
+                let lights = &ctx.scene.lights;
+                let index = usize::min(
+                    (sampler.gen::<f32>() * lights.len() as f32) as usize,
+                    lights.len() - 1,
+                );
+                let light = lights[index].as_ref();
+                let sample = light.sample_position(pt.position, &ctx.bounds, sampler)?;
+                if sample.pdf_position <= 0.0 {
+                    return None;
+                }
+                let weight = match light.is_infinite() {
+                    true => 1.0 / ctx.light_pdf,
+                    false => {
+                        let w = pt.position - sample.position;
+                        let cos = sample.normal.map_or(1.0, |n| dot(n, w.normalize()).abs());
+                        cos / (ctx.light_pdf * sample.pdf_position * norm2(w))
+                    }
+                };
+                let mut vertex = Vertex {
+                    kind: VertexKind::Light(light),
+                    position: sample.position,
+                    normal: sample.normal,
+                    beta: weight * sample.emitted,
+                    delta: false,
+                    pdf_fwd: 0.0,
+                    pdf_rev: 0.0,
+                };
+                vertex.pdf_fwd = vertex.pdf_light_origin(ctx, pt);
+                let radiance = pt.beta * pt.f(&vertex) * vertex.beta;
+                if radiance.is_black() || !visible(ctx.scene, pt.position, vertex.position) {
+                    return None;
+                }
+                sampled = Some(vertex);
+                radiance
+            }
+            _ => {
+                let qs = &light[s - 1];
+                if !qs.is_connectible() || !pt.is_connectible() {
+                    return None;
+                }
+                let g = 1.0 / norm2(qs.position - pt.position);
+                let radiance = g * qs.beta * qs.f(pt) * pt.f(qs) * pt.beta;
+                if radiance.is_black() || !visible(ctx.scene, pt.position, qs.position) {
+                    return None;
+                }
+                radiance
+            }
+        };
+        if radiance.is_black() {
+            return None;
+        }
+
+        let weight = Self::mis_weight(ctx, light, camera, sampled.as_ref(), s, t);
+        Some((weight * radiance, pixel))
+    }
+}
+
+impl BidirectionalPathIntegrator {
+    #[allow(clippy::too_many_arguments)]
+    fn trace(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        bounds: Bounds3,
+        sampler: &mut Sampler,
+        camera: Option<&dyn Camera>,
+        aovs: Option<&mut AovRecord>,
+        splats: &mut Vec<Splat>,
+    ) -> Color {
+        let ctx = Context {
+            scene,
+            camera,
+            bounds,
+            light_pdf: 1.0 / scene.lights.len().max(1) as f32,
+        };
+        let mut camera_path = Vec::with_capacity(self.max_depth + 2);
+        let mut radiance = self.camera_subpath(&ctx, ray, sampler, &mut camera_path);
+        if let (Some(aovs), Some(vertex)) = (aovs, camera_path.get(1)) {
+            if let VertexKind::Surface(si) = &vertex.kind {
+                record_first_hit(aovs, si);
+            }
+        }
+        let mut light_path = Vec::with_capacity(self.max_depth + 1);
+        self.light_subpath(&ctx, sampler, &mut light_path);
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                if (s == 1 && t == 1) || s + t < 2 || s + t - 2 > self.max_depth {
+                    continue;
+                }
+                let Some((contribution, pixel)) =
+                    Self::connect(&ctx, &light_path, &camera_path, s, t, sampler)
+                else {
+                    continue;
+                };
+                match pixel {
+                    Some(pixel) => splats.push(Splat {
+                        pixel,
+                        radiance: contribution,
+                    }),
+                    None => radiance = radiance + contribution,
+                }
+            }
+        }
+        radiance
+    }
+}
+
+impl Integrator for BidirectionalPathIntegrator {
+    /// Without a camera to splat onto, light tracing is left out of the estimate.
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        self.trace(
+            ray,
+            scene,
+            scene.bounds(),
+            sampler,
+            None,
+            None,
+            &mut Vec::new(),
+        )
+    }
+
+    fn sample_radiance_aovs(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        sampler: &mut Sampler,
+        aovs: &mut AovRecord,
+    ) -> Color {
+        let bounds = scene.bounds();
+        self.trace(
+            ray,
+            scene,
+            bounds,
+            sampler,
+            None,
+            Some(aovs),
+            &mut Vec::new(),
+        )
+    }
+
+    fn sample_camera_ray(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        sampler: &mut Sampler,
+        camera: &dyn Camera,
+        aovs: Option<&mut AovRecord>,
+        splats: &mut Vec<Splat>,
+    ) -> Color {
+        let bounds = scene.bounds();
+        self.trace(ray, scene, bounds, sampler, Some(camera), aovs, splats)
+    }
+
+    /// Computes the bounds of the scene once instead of for every sample.
+    fn render(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        spp: usize,
+        seed: u64,
+        num_threads: usize,
+    ) {
+        let bounds = scene.bounds();
+        render_pixels(
+            camera,
+            scene,
+            spp,
+            seed,
+            num_threads,
+            |ray, sampler, aovs, splats| {
+                self.trace(ray, scene, bounds, sampler, Some(camera), aovs, splats)
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::tests::{lamp_scene, render};
+    use crate::integrator::PathIntegrator;
+    use crate::sensor::{PinholeCamera, Sensor};
+
+    #[test]
+    fn matches_path_tracer() {
+        let mean = |pixels: Vec<Color>| {
+            let n = pixels.len() as f32;
+            pixels
+                .into_iter()
+                .fold(Color::new(0.0, 0.0, 0.0), |sum, c| sum + (1.0 / n) * c)
+        };
+        let scene = lamp_scene();
+        let path = mean(render(&PathIntegrator::new(4, 4), &scene, 256, 3, 4));
+        let bdpt = mean(render(
+            &BidirectionalPathIntegrator::new(4),
+            &scene,
+            256,
+            3,
+            4,
+        ));
+        assert!(path.g > 0.01);
+        assert!((path.g - bdpt.g).abs() < 0.03 * path.g, "{path:?} {bdpt:?}");
+    }
+
+    #[test]
+    fn splats_deterministically() {
+        // large enough to span several tiles of splats
+        let pixels = |num_threads| {
+            let camera = PinholeCamera::new(Sensor::zero(96, 64), 60.0);
+            let integrator = BidirectionalPathIntegrator::new(3);
+            integrator.render(&camera, &lamp_scene(), 1, 3, num_threads);
+            camera
+                .get_pixels()
+                .iter()
+                .map(|pixel| {
+                    let c = *pixel.color.read().unwrap();
+                    (c.r, c.g, c.b)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pixels(1), pixels(5));
+    }
+}
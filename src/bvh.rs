@@ -0,0 +1,279 @@
+use crate::math::*;
+
+/// Bounding volume hierarchy over items known only by their bounds.
+///
+/// Nodes are split with the surface area heuristic evaluated over a few
+/// centroid bins, which is cheap to build and good enough for static meshes.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+struct BvhNode {
+    bounds: Bounds3,
+    /// Leaves hold `count` items from `start`, interior nodes have `count == 0`.
+    start: usize,
+    count: usize,
+    /// The first child directly follows its parent, this is the second one.
+    second: usize,
+    axis: usize,
+}
+
+const BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+
+fn component(p: Point, axis: usize) -> f32 {
+    match axis {
+        0 => p.x,
+        1 => p.y,
+        _ => p.z,
+    }
+}
+
+fn vector_component(v: Vector, axis: usize) -> f32 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+impl Bvh {
+    pub fn new(bounds: &[Bounds3]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            indices: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            bvh.build(bounds, 0, bounds.len());
+        }
+        bvh
+    }
+
+    /// Builds the subtree over `indices[start..end]` and returns its node index.
+    fn build(&mut self, bounds: &[Bounds3], start: usize, end: usize) -> usize {
+        let node_bounds = self.indices[start..end]
+            .iter()
+            .fold(Bounds3::empty(), |b, &k| b.union(&bounds[k]));
+        let centroids = self.indices[start..end]
+            .iter()
+            .fold(Bounds3::empty(), |b, &k| {
+                b.union_point(bounds[k].centroid())
+            });
+
+        let node = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            start,
+            count: end - start,
+            second: 0,
+            axis: 0,
+        });
+
+        let Some((axis, split)) = self.split(bounds, &node_bounds, &centroids, start, end) else {
+            return node;
+        };
+
+        let mid = start
+            + partition(&mut self.indices[start..end], |&k| {
+                component(bounds[k].centroid(), axis) < split
+            });
+        if mid == start || mid == end {
+            return node;
+        }
+
+        self.build(bounds, start, mid);
+        let second = self.build(bounds, mid, end);
+        self.nodes[node] = BvhNode {
+            bounds: node_bounds,
+            start,
+            count: 0,
+            second,
+            axis,
+        };
+        node
+    }
+
+    /// Axis and centroid coordinate of the cheapest split, `None` when a leaf is cheaper.
+    fn split(
+        &self,
+        bounds: &[Bounds3],
+        node_bounds: &Bounds3,
+        centroids: &Bounds3,
+        start: usize,
+        end: usize,
+    ) -> Option<(usize, f32)> {
+        let count = end - start;
+        if count <= MAX_LEAF_SIZE {
+            return None;
+        }
+
+        let extent = centroids.diagonal();
+        let axis = (0..3)
+            .max_by(|&a, &b| vector_component(extent, a).total_cmp(&vector_component(extent, b)))
+            .unwrap();
+        let lo = component(centroids.min, axis);
+        let width = vector_component(extent, axis);
+        if width <= 0.0 {
+            return None;
+        }
+
+        let bin_of = |k: usize| {
+            let c = component(bounds[k].centroid(), axis);
+            usize::min(BINS - 1, ((c - lo) / width * BINS as f32) as usize)
+        };
+        let mut bins = [(Bounds3::empty(), 0usize); BINS];
+        for &k in self.indices[start..end].iter() {
+            let bin = &mut bins[bin_of(k)];
+            *bin = (bin.0.union(&bounds[k]), bin.1 + 1);
+        }
+
+        // cost of splitting after each bin, up to the common factor of 1 / parent area
+        let cost = |(b, n): (Bounds3, usize)| match n {
+            0 => 0.0,
+            _ => b.surface_area() * n as f32,
+        };
+        let (best, best_cost) = (1..BINS)
+            .map(|split| {
+                let merge = |acc: (Bounds3, usize), bin: &(Bounds3, usize)| {
+                    (acc.0.union(&bin.0), acc.1 + bin.1)
+                };
+                let left = bins[..split].iter().fold((Bounds3::empty(), 0), merge);
+                let right = bins[split..].iter().fold((Bounds3::empty(), 0), merge);
+                (split, cost(left) + cost(right))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        // large leaves are split even when the heuristic disagrees
+        let leaf_cost = node_bounds.surface_area() * count as f32;
+        match best_cost < leaf_cost || count > 4 * MAX_LEAF_SIZE {
+            true => Some((axis, lo + width * best as f32 / BINS as f32)),
+            false => None,
+        }
+    }
+
+    /// Bounds of all items.
+    pub fn bounds(&self) -> Bounds3 {
+        self.nodes
+            .first()
+            .map_or(Bounds3::empty(), |node| node.bounds)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Visits the items whose bounds `ray` overlaps before the closest hit so far.
+    ///
+    /// `hit` tests the item with the given index and returns its hit distance,
+    /// which then prunes the rest of the traversal. Near children are visited
+    /// first. Returns the number of nodes visited.
+    pub fn traverse(
+        &self,
+        ray: &Ray,
+        t_max: f32,
+        mut hit: impl FnMut(usize) -> Option<f32>,
+    ) -> usize {
+        let mut t_max = t_max;
+        let mut visited = 0;
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            visited += 1;
+            if node.bounds.intersect(ray, t_max).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &k in self.indices[node.start..node.start + node.count].iter() {
+                    if let Some(t) = hit(k) {
+                        t_max = f32::min(t_max, t);
+                    }
+                }
+                continue;
+            }
+
+            // push the far child first so the near one is popped next
+            match vector_component(ray.direction, node.axis) < 0.0 {
+                true => stack.extend([index + 1, node.second]),
+                false => stack.extend([node.second, index + 1]),
+            }
+        }
+
+        visited
+    }
+}
+
+/// Moves the items matching `pred` to the front and returns how many there are.
+fn partition<T>(items: &mut [T], pred: impl Fn(&T) -> bool) -> usize {
+    let mut first = 0;
+    for k in 0..items.len() {
+        if pred(&items[k]) {
+            items.swap(first, k);
+            first += 1;
+        }
+    }
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Sampler;
+
+    use rand::Rng;
+
+    #[test]
+    fn finds_closest_box() {
+        let mut sampler = Sampler::new(5, 0);
+        let boxes: Vec<Bounds3> = (0..500)
+            .map(|_| {
+                let p = Point {
+                    x: 10.0 * sampler.gen::<f32>(),
+                    y: 10.0 * sampler.gen::<f32>(),
+                    z: 10.0 * sampler.gen::<f32>(),
+                };
+                let size = 0.2 * sampler.gen::<f32>();
+                Bounds3::new(
+                    p,
+                    p + Vector {
+                        x: size,
+                        y: size,
+                        z: size,
+                    },
+                )
+            })
+            .collect();
+        let bvh = Bvh::new(&boxes);
+
+        for _ in 0..100 {
+            let ray = Ray {
+                origin: Point {
+                    x: 10.0 * sampler.gen::<f32>(),
+                    y: 10.0 * sampler.gen::<f32>(),
+                    z: -1.0,
+                },
+                direction: Vector {
+                    x: sampler.gen::<f32>() - 0.5,
+                    y: sampler.gen::<f32>() - 0.5,
+                    z: 1.0,
+                }
+                .normalize(),
+            };
+            let entry = |b: &Bounds3| b.intersect(&ray, f32::INFINITY).map(|(t0, _)| t0);
+
+            let expected = boxes.iter().filter_map(entry).reduce(f32::min);
+            let mut closest: Option<f32> = None;
+            bvh.traverse(&ray, f32::INFINITY, |k| {
+                let t = entry(&boxes[k])?;
+                closest = Some(closest.map_or(t, |c| f32::min(c, t)));
+                Some(t)
+            });
+            assert_eq!(closest, expected);
+        }
+    }
+}
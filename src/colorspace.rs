@@ -0,0 +1,239 @@
+use crate::sensor::Color;
+
+type Matrix = [[f64; 3]; 3];
+
+/// RGB primaries and white point of linear color data.
+///
+/// `Color` values carry no tag, a color space says how to read them. All
+/// conversions go through CIE XYZ relative to D65, spaces with another white
+/// point are adapted with the Bradford transform.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// Rec. 709 primaries with a D65 white, the space of sRGB images.
+    #[default]
+    Srgb,
+    /// ACES AP1 primaries with the ACES white, the usual ACES rendering space.
+    AcesCg,
+    /// Wide gamut Rec. 2020 primaries with a D65 white.
+    Rec2020,
+}
+
+/// Nonlinear encoding applied to color values for storage or display.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferFunction {
+    Linear,
+    /// Piecewise sRGB curve.
+    Srgb,
+    /// Pure power law, e.g. 2.4 for Rec. 1886 displays.
+    Gamma(f32),
+}
+
+/// Linear map between two color spaces.
+#[derive(Clone, Copy, Debug)]
+pub struct ColorMatrix([[f32; 3]; 3]);
+
+const D65: (f64, f64) = (0.3127, 0.3290);
+const ACES_WHITE: (f64, f64) = (0.32168, 0.33767);
+const EQUAL_ENERGY: (f64, f64) = (1.0 / 3.0, 1.0 / 3.0);
+
+const BRADFORD: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    std::array::from_fn(|r| std::array::from_fn(|c| (0..3).map(|k| a[r][k] * b[k][c]).sum()))
+}
+
+fn apply(m: &Matrix, v: [f64; 3]) -> [f64; 3] {
+    std::array::from_fn(|r| (0..3).map(|k| m[r][k] * v[k]).sum())
+}
+
+fn invert(m: &Matrix) -> Matrix {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    std::array::from_fn(|r| std::array::from_fn(|c| cofactor(c, r) / det))
+}
+
+fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+/// Maps XYZ under white `from` to XYZ under white `to`.
+fn bradford(from: (f64, f64), to: (f64, f64)) -> Matrix {
+    let source = apply(&BRADFORD, xy_to_xyz(from));
+    let target = apply(&BRADFORD, xy_to_xyz(to));
+    let scale: Matrix = std::array::from_fn(|r| {
+        std::array::from_fn(|c| if r == c { target[r] / source[r] } else { 0.0 })
+    });
+    multiply(&invert(&BRADFORD), &multiply(&scale, &BRADFORD))
+}
+
+impl ColorSpace {
+    pub const ALL: [ColorSpace; 3] = [ColorSpace::Srgb, ColorSpace::AcesCg, ColorSpace::Rec2020];
+
+    /// Chromaticities of the red, green and blue primaries and of the white point.
+    pub fn chromaticities(self) -> [(f64, f64); 4] {
+        match self {
+            ColorSpace::Srgb => [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06), D65],
+            ColorSpace::AcesCg => [(0.713, 0.293), (0.165, 0.830), (0.128, 0.044), ACES_WHITE],
+            ColorSpace::Rec2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046), D65],
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorSpace::Srgb => "srgb",
+            ColorSpace::AcesCg => "acescg",
+            ColorSpace::Rec2020 => "rec2020",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ColorSpace> {
+        ColorSpace::ALL
+            .into_iter()
+            .find(|space| space.name() == name)
+    }
+
+    /// RGB to XYZ relative to D65.
+    fn to_xyz(self) -> Matrix {
+        let [r, g, b, white] = self.chromaticities();
+        let primaries = [xy_to_xyz(r), xy_to_xyz(g), xy_to_xyz(b)];
+        let columns: Matrix = std::array::from_fn(|row| std::array::from_fn(|c| primaries[c][row]));
+        // scale the primaries so that RGB (1, 1, 1) is the white point
+        let s = apply(&invert(&columns), xy_to_xyz(white));
+        let native: Matrix = std::array::from_fn(|r| std::array::from_fn(|c| columns[r][c] * s[c]));
+        match white == D65 {
+            true => native,
+            false => multiply(&bradford(white, D65), &native),
+        }
+    }
+
+    /// Matrix taking linear colors in `self` to `to`.
+    pub fn matrix_to(self, to: ColorSpace) -> ColorMatrix {
+        let m = multiply(&invert(&to.to_xyz()), &self.to_xyz());
+        ColorMatrix(m.map(|row| row.map(|v| v as f32)))
+    }
+
+    /// Matrix taking linear colors in `self` to CIE XYZ relative to D65.
+    pub fn matrix_to_xyz(self) -> ColorMatrix {
+        ColorMatrix(self.to_xyz().map(|row| row.map(|v| v as f32)))
+    }
+
+    /// Matrix taking CIE XYZ relative to D65 to linear colors in `self`.
+    pub fn matrix_from_xyz(self) -> ColorMatrix {
+        ColorMatrix(invert(&self.to_xyz()).map(|row| row.map(|v| v as f32)))
+    }
+
+    /// Matrix taking XYZ integrated from spectra, where an equal-energy
+    /// spectrum is white, to linear colors in `self`.
+    pub fn matrix_from_spectral_xyz(self) -> ColorMatrix {
+        let m = multiply(&invert(&self.to_xyz()), &bradford(EQUAL_ENERGY, D65));
+        ColorMatrix(m.map(|row| row.map(|v| v as f32)))
+    }
+
+    /// Converts a single color, prefer `matrix_to` for many.
+    pub fn convert(self, color: Color, to: ColorSpace) -> Color {
+        match self == to {
+            true => color,
+            false => self.matrix_to(to).apply(color),
+        }
+    }
+}
+
+impl ColorMatrix {
+    pub fn apply(&self, color: Color) -> Color {
+        let m = &self.0;
+        let row = |r: usize| m[r][0] * color.r + m[r][1] * color.g + m[r][2] * color.b;
+        Color::new(row(0), row(1), row(2))
+    }
+}
+
+/// Inverse of the sRGB transfer function.
+pub fn srgb_to_linear(v: f32) -> f32 {
+    match v <= 0.04045 {
+        true => v / 12.92,
+        false => f32::powf((v + 0.055) / 1.055, 2.4),
+    }
+}
+
+/// The sRGB transfer function.
+pub fn linear_to_srgb(v: f32) -> f32 {
+    match v <= 0.003_130_8 {
+        true => 12.92 * v,
+        false => 1.055 * f32::powf(v, 1.0 / 2.4) - 0.055,
+    }
+}
+
+impl TransferFunction {
+    /// Linear value to its encoding.
+    pub fn encode(self, v: f32) -> f32 {
+        match self {
+            TransferFunction::Linear => v,
+            TransferFunction::Srgb => linear_to_srgb(f32::max(v, 0.0)),
+            TransferFunction::Gamma(gamma) => f32::powf(f32::max(v, 0.0), 1.0 / gamma),
+        }
+    }
+
+    /// Encoded value back to linear.
+    pub fn decode(self, v: f32) -> f32 {
+        match self {
+            TransferFunction::Linear => v,
+            TransferFunction::Srgb => srgb_to_linear(f32::max(v, 0.0)),
+            TransferFunction::Gamma(gamma) => f32::powf(f32::max(v, 0.0), gamma),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Color, b: Color, tolerance: f32) {
+        let error = f32::max(
+            (a.r - b.r).abs(),
+            f32::max((a.g - b.g).abs(), (a.b - b.b).abs()),
+        );
+        assert!(error < tolerance, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn matches_published_matrices() {
+        let xyz = ColorSpace::Srgb
+            .matrix_to_xyz()
+            .apply(Color::new(1.0, 0.0, 0.0));
+        assert_close(xyz, Color::new(0.4124, 0.2126, 0.0193), 1e-4);
+
+        // sRGB to ACEScg and Rec. 2020 as listed by OCIO and ITU-R BT.2087
+        let red = Color::new(1.0, 0.0, 0.0);
+        let aces = ColorSpace::Srgb.convert(red, ColorSpace::AcesCg);
+        assert_close(aces, Color::new(0.6131, 0.0702, 0.0206), 1e-3);
+        let rec2020 = ColorSpace::Srgb.convert(red, ColorSpace::Rec2020);
+        assert_close(rec2020, Color::new(0.6274, 0.0691, 0.0164), 1e-3);
+    }
+
+    #[test]
+    fn round_trips_and_keeps_white() {
+        let color = Color::new(0.2, 0.5, 0.9);
+        for from in ColorSpace::ALL {
+            for to in ColorSpace::ALL {
+                let white = from.convert(Color::new(1.0, 1.0, 1.0), to);
+                assert_close(white, Color::new(1.0, 1.0, 1.0), 1e-4);
+                let back = to.convert(from.convert(color, to), from);
+                assert_close(back, color, 1e-5);
+            }
+            assert_eq!(ColorSpace::from_name(from.name()), Some(from));
+        }
+
+        for transfer in [TransferFunction::Srgb, TransferFunction::Gamma(2.2)] {
+            let v = transfer.decode(transfer.encode(0.18));
+            assert!((v - 0.18).abs() < 1e-6);
+        }
+        assert!((linear_to_srgb(0.18) - 0.4613).abs() < 1e-3);
+    }
+}
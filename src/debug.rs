@@ -0,0 +1,354 @@
+use crate::integrator::{render_pixels, Integrator};
+use crate::material::*;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::{Camera, Color};
+
+use rand::Rng;
+use std::f32::consts::PI;
+
+/// Ambient occlusion, the share of the hemisphere above the first hit that is
+/// open up to `radius`.
+///
+/// Directions are sampled by cosine, so the result is the irradiance from a
+/// white sky that only nearby geometry shadows. Misses are black.
+pub struct AmbientOcclusionIntegrator {
+    radius: f32,
+    samples: usize,
+}
+
+/// Emitted light plus light arriving straight from the lights, after
+/// following delta bounces up to `max_depth`.
+///
+/// Lights are sampled as in `PathIntegrator`, so the two agree on the first bounce.
+pub struct DirectLightingIntegrator {
+    max_depth: usize,
+}
+
+/// Surface property shown by a `DebugIntegrator`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    /// Shading normal mapped from [-1, 1] to [0, 1].
+    Normal,
+    /// Texture coordinates in red and green.
+    Uv,
+    /// `SurfaceInteraction::t`, white up close and black at the far end of the scene bounds.
+    Depth,
+    /// Shapes plus BVH nodes visited by the camera ray as a heat map, red at 100 and above.
+    TraversalCost,
+    /// Material id in false color.
+    MaterialId,
+    /// Shape id in false color.
+    ShapeId,
+}
+
+/// Shows a surface property of the first hit instead of light.
+pub struct DebugIntegrator {
+    view: DebugView,
+}
+
+impl AmbientOcclusionIntegrator {
+    pub fn new(radius: f32) -> AmbientOcclusionIntegrator {
+        AmbientOcclusionIntegrator { radius, samples: 1 }
+    }
+
+    /// Occlusion rays per camera sample.
+    pub fn with_samples(mut self, samples: usize) -> AmbientOcclusionIntegrator {
+        self.samples = samples.max(1);
+        self
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        let Some(si) = scene.closest_hit(ray) else {
+            return Color::new(0.0, 0.0, 0.0);
+        };
+        // occlusion is measured on the side the ray came from
+        let n = match dot(si.normal, si.wi) < 0.0 {
+            true => -si.normal,
+            false => si.normal,
+        };
+        let (u, v, w) = coordinate_system(n);
+
+        let open = (0..self.samples)
+            .filter(|_| {
+                let r = f32::sqrt(sampler.gen::<f32>());
+                let phi = 2.0 * PI * sampler.gen::<f32>();
+                let cos = f32::sqrt(f32::max(0.0, 1.0 - r * r));
+                let direction = r * f32::cos(phi) * u + r * f32::sin(phi) * v + cos * w;
+                let hit = scene.closest_hit(&Ray {
+                    origin: si.position + 1e-3 * direction,
+                    direction,
+                });
+                hit.is_none_or(|hit| hit.t > self.radius)
+            })
+            .count();
+        let visibility = open as f32 / self.samples as f32;
+        Color::new(visibility, visibility, visibility)
+    }
+}
+
+impl DirectLightingIntegrator {
+    pub fn new(max_depth: usize) -> DirectLightingIntegrator {
+        DirectLightingIntegrator { max_depth }
+    }
+}
+
+impl Integrator for DirectLightingIntegrator {
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut color = Color::new(0.0, 0.0, 0.0);
+        let mut ray = Ray {
+            origin: ray.origin,
+            direction: ray.direction,
+        };
+
+        for _ in 0..self.max_depth {
+            let Some(si) = scene.closest_hit(&ray) else {
+                return color + throughput * scene.background(ray.direction);
+            };
+            if let Some(light) = si.emitter {
+                color = color + throughput * light.emitted(&si);
+            }
+
+            if !si.material.is_delta_reflector() {
+                for light in scene.lights.iter() {
+                    let light_sample = light.sample(si.position, sampler);
+                    let wo = (light_sample.position - si.position).normalize();
+                    let dist = norm(light_sample.position - si.position);
+                    let shadow_si = scene.closest_hit(&Ray {
+                        origin: si.position + 1e-3 * wo,
+                        direction: wo,
+                    });
+                    if let Some(si) = shadow_si {
+                        if si.t < dist - 2e-3 {
+                            continue;
+                        }
+                    }
+                    let f = si.material.bsdf_eval(&si, wo).radiance;
+                    color = color + throughput * f * light_sample.radiance;
+                }
+                break;
+            }
+
+            let wo = si.material.bsdf_sample(&si, sampler);
+            let BsdfSample { radiance, pdf } = si.material.bsdf_eval(&si, wo);
+            if pdf <= 0.0 {
+                break;
+            }
+            throughput = (1.0 / pdf) * throughput * radiance;
+            ray = Ray {
+                origin: si.position + 1e-3 * wo,
+                direction: wo,
+            };
+        }
+        color
+    }
+}
+
+impl DebugView {
+    pub const ALL: [DebugView; 6] = [
+        DebugView::Normal,
+        DebugView::Uv,
+        DebugView::Depth,
+        DebugView::TraversalCost,
+        DebugView::MaterialId,
+        DebugView::ShapeId,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugView::Normal => "normal",
+            DebugView::Uv => "uv",
+            DebugView::Depth => "depth",
+            DebugView::TraversalCost => "bvh",
+            DebugView::MaterialId => "material_id",
+            DebugView::ShapeId => "shape_id",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DebugView> {
+        DebugView::ALL.into_iter().find(|view| view.name() == name)
+    }
+}
+
+/// Fully saturated color of `hue` in [0, 1), red at 0 going over green to blue.
+fn hue_color(hue: f32, saturation: f32, value: f32) -> Color {
+    let h = 6.0 * (hue - f32::floor(hue));
+    let channel = |offset: f32| {
+        let k = (offset + h) % 6.0;
+        value * (1.0 - saturation * f32::clamp(f32::min(k, 4.0 - k), 0.0, 1.0))
+    };
+    Color::new(channel(5.0), channel(3.0), channel(1.0))
+}
+
+/// Color of `id` that stands out from those of neighboring ids.
+pub fn false_color(id: usize) -> Color {
+    // golden ratio steps spread consecutive ids over the hues
+    let hue = id as f32 * 0.618_034;
+    hue_color(hue, 0.8, 0.9)
+}
+
+impl DebugIntegrator {
+    pub fn new(view: DebugView) -> DebugIntegrator {
+        DebugIntegrator { view }
+    }
+
+    /// The view of the first hit of `ray`, depth is scaled to `bounds` or to
+    /// the bounds of `scene` if not given.
+    fn shade(&self, ray: &Ray, scene: &Scene, bounds: Option<&Bounds3>) -> Color {
+        if self.view == DebugView::TraversalCost {
+            let cost = f32::min(scene.traversal_cost(ray) as f32 / 100.0, 1.0);
+            return hue_color(2.0 / 3.0 * (1.0 - cost), 1.0, 1.0);
+        }
+        let Some(si) = scene.closest_hit(ray) else {
+            return Color::new(0.0, 0.0, 0.0);
+        };
+        match self.view {
+            DebugView::Normal => {
+                let n = si.normal;
+                Color::new(0.5 * (n.x + 1.0), 0.5 * (n.y + 1.0), 0.5 * (n.z + 1.0))
+            }
+            DebugView::Uv => Color::new(si.uv.0, si.uv.1, 0.0),
+            DebugView::Depth => {
+                let bounds = bounds.copied().unwrap_or_else(|| scene.bounds());
+                let far = match bounds.is_empty() {
+                    true => 1.0,
+                    false => norm(bounds.diagonal()) + norm(bounds.centroid() - ray.origin),
+                };
+                let gray = f32::clamp(1.0 - si.t / far, 0.0, 1.0);
+                Color::new(gray, gray, gray)
+            }
+            DebugView::MaterialId => false_color(si.material_id),
+            DebugView::ShapeId => false_color(si.shape_id),
+            DebugView::TraversalCost => unreachable!(),
+        }
+    }
+}
+
+impl Integrator for DebugIntegrator {
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, _sampler: &mut Sampler) -> Color {
+        self.shade(ray, scene, None)
+    }
+
+    /// Scales all depths of the image to the same bounds.
+    fn render(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        spp: usize,
+        seed: u64,
+        num_threads: usize,
+    ) {
+        let bounds = scene.bounds();
+        render_pixels(camera, scene, spp, seed, num_threads, |ray, _, _, _| {
+            self.shade(ray, scene, Some(&bounds))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emitter::PointLight;
+    use crate::integrator::tests::diffuse;
+    use crate::integrator::PathIntegrator;
+
+    fn floor() -> InfinitePlane {
+        InfinitePlane::new(
+            Point {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            diffuse(0.5),
+        )
+    }
+
+    fn down_ray() -> Ray {
+        Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+        }
+    }
+
+    #[test]
+    fn occludes_within_radius() {
+        let mut scene = Scene::new();
+        scene.add_shape(Box::new(floor()));
+        // a ball resting on the floor right next to the hit point
+        scene.add_shape(Box::new(Sphere::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: -1.2,
+            },
+            1.0,
+            diffuse(0.5),
+        )));
+        let mut sampler = Sampler::new(0, 0);
+        let ray = down_ray();
+
+        let open = AmbientOcclusionIntegrator::new(1e-3).with_samples(64);
+        assert_eq!(open.sample_radiance(&ray, &scene, &mut sampler).g, 1.0);
+        let near = AmbientOcclusionIntegrator::new(10.0).with_samples(4096);
+        let visibility = near.sample_radiance(&ray, &scene, &mut sampler).g;
+        assert!(visibility > 0.5 && visibility < 0.95, "{visibility}");
+    }
+
+    #[test]
+    fn direct_matches_first_bounce() {
+        let mut scene = Scene::new();
+        scene.add_shape(Box::new(floor()));
+        scene.add_light(Box::new(PointLight::new(
+            Point {
+                x: 0.5,
+                y: 1.0,
+                z: 0.0,
+            },
+            2.0,
+        )));
+        let direct = DirectLightingIntegrator::new(4);
+        let path = PathIntegrator::new(1, 1);
+        let ray = down_ray();
+        let a = direct.sample_radiance(&ray, &scene, &mut Sampler::new(0, 0));
+        let b = path.sample_radiance(&ray, &scene, &mut Sampler::new(0, 0));
+        assert!(a.g > 0.0);
+        assert_eq!((a.r, a.g, a.b), (b.r, b.g, b.b));
+    }
+
+    #[test]
+    fn shows_surface_properties() {
+        let mut scene = Scene::new();
+        scene.add_shape(Box::new(floor()));
+        let mut sampler = Sampler::new(0, 0);
+        let ray = down_ray();
+        let view = |view: DebugView, sampler: &mut Sampler| {
+            DebugIntegrator::new(view).sample_radiance(&ray, &scene, sampler)
+        };
+
+        let normal = view(DebugView::Normal, &mut sampler);
+        assert_eq!((normal.r, normal.g, normal.b), (0.5, 1.0, 0.5));
+        assert_eq!(view(DebugView::TraversalCost, &mut sampler).b, 1.0);
+        let (a, b) = (false_color(0), false_color(1));
+        assert!((a.r - b.r).abs() + (a.g - b.g).abs() + (a.b - b.b).abs() > 0.3);
+        for view in DebugView::ALL {
+            assert_eq!(DebugView::from_name(view.name()), Some(view));
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use crate::aov::Aov;
+use crate::sensor::{Color, Sensor};
+
+/// Edge-avoiding à-trous wavelet filter in the spirit of SVGF.
+///
+/// The beauty pass is demodulated by the albedo AOV, filtered with a 5x5
+/// B3-spline kernel of growing footprint and remodulated afterwards. Edges
+/// are preserved by the normal and depth AOVs and by a luminance term whose
+/// tolerance follows the locally estimated variance. AOVs that the sensor
+/// does not carry are simply not used as guides.
+pub struct Denoiser {
+    pub iterations: usize,
+    pub sigma_luminance: f32,
+    pub sigma_normal: f32,
+    pub sigma_depth: f32,
+    pub sigma_albedo: f32,
+}
+
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+struct Features {
+    albedo: Option<Vec<Color>>,
+    normal: Option<Vec<Color>>,
+    depth: Option<Vec<f32>>,
+}
+
+impl Denoiser {
+    pub fn new(iterations: usize) -> Denoiser {
+        Denoiser {
+            iterations,
+            sigma_luminance: 4.0,
+            sigma_normal: 128.0,
+            sigma_depth: 0.05,
+            sigma_albedo: 0.1,
+        }
+    }
+
+    /// Filters the beauty pass of `sensor` in place.
+    pub fn denoise(&self, sensor: &Sensor) {
+        let (width, height) = (sensor.width(), sensor.height());
+        let read = |aov: Aov| {
+            sensor.aov(aov).map(|buffer| {
+                buffer
+                    .pixels
+                    .iter()
+                    .map(|color| *color.read().unwrap())
+                    .collect::<Vec<Color>>()
+            })
+        };
+        let features = Features {
+            albedo: read(Aov::Albedo),
+            normal: read(Aov::Normal),
+            depth: read(Aov::Depth).map(|depth| depth.iter().map(|d| d.r).collect()),
+        };
+
+        let beauty: Vec<Color> = (0..height)
+            .flat_map(|j| (0..width).map(move |i| (i, j)))
+            .map(|(i, j)| *sensor.get(i, j).unwrap().color.read().unwrap())
+            .collect();
+
+        let mut irradiance = match &features.albedo {
+            Some(albedo) => beauty
+                .iter()
+                .zip(albedo)
+                .map(|(&c, &a)| c / demodulation(a))
+                .collect(),
+            None => beauty,
+        };
+        let mut variance = spatial_variance(&irradiance, width, height);
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            (irradiance, variance) =
+                self.atrous_step(&irradiance, &variance, &features, width, height, step);
+        }
+
+        for (index, color) in irradiance.into_iter().enumerate() {
+            let color = match &features.albedo {
+                Some(albedo) => color * demodulation(albedo[index]),
+                None => color,
+            };
+            let pixel = sensor.get(index % width, index / width).unwrap();
+            *pixel.color.write().unwrap() = color;
+        }
+    }
+
+    fn atrous_step(
+        &self,
+        color: &[Color],
+        variance: &[f32],
+        features: &Features,
+        width: usize,
+        height: usize,
+        step: usize,
+    ) -> (Vec<Color>, Vec<f32>) {
+        let blurred_variance = gaussian3x3(variance, width, height);
+        let mut out_color = Vec::with_capacity(color.len());
+        let mut out_variance = Vec::with_capacity(color.len());
+
+        for j in 0..height {
+            for i in 0..width {
+                let p = j * width + i;
+                let luminance_p = color[p].luminance();
+                let luminance_tolerance =
+                    self.sigma_luminance * f32::sqrt(f32::max(blurred_variance[p], 0.0)) + 1e-4;
+
+                let mut sum = Color::new(0.0, 0.0, 0.0);
+                let mut sum_variance = 0.0;
+                let mut sum_weight = 0.0;
+
+                for (dy, ky) in KERNEL.iter().enumerate() {
+                    for (dx, kx) in KERNEL.iter().enumerate() {
+                        let x = i as isize + (dx as isize - 2) * step as isize;
+                        let y = j as isize + (dy as isize - 2) * step as isize;
+                        if x < 0 || y < 0 || x >= width as isize || y >= height as isize {
+                            continue;
+                        }
+                        let q = y as usize * width + x as usize;
+
+                        let w_luminance = f32::exp(
+                            -f32::abs(luminance_p - color[q].luminance()) / luminance_tolerance,
+                        );
+                        let weight = kx * ky * w_luminance * self.feature_weight(features, p, q);
+
+                        sum = sum + weight * color[q];
+                        sum_variance += weight * weight * variance[q];
+                        sum_weight += weight;
+                    }
+                }
+
+                // background pixels have no normal, so no tap gets a weight
+                if sum_weight <= 0.0 {
+                    out_color.push(color[p]);
+                    out_variance.push(variance[p]);
+                } else {
+                    out_color.push(sum / sum_weight);
+                    out_variance.push(sum_variance / (sum_weight * sum_weight));
+                }
+            }
+        }
+
+        (out_color, out_variance)
+    }
+
+    fn feature_weight(&self, features: &Features, p: usize, q: usize) -> f32 {
+        let mut weight = 1.0;
+
+        if let Some(normal) = &features.normal {
+            let (a, b) = (normal[p], normal[q]);
+            let cos = a.r * b.r + a.g * b.g + a.b * b.b;
+            weight *= f32::powf(f32::max(cos, 0.0), self.sigma_normal);
+        }
+
+        if let Some(depth) = &features.depth {
+            let scale = self.sigma_depth * f32::max(depth[p], 1e-3);
+            weight *= f32::exp(-f32::abs(depth[p] - depth[q]) / scale);
+        }
+
+        if let Some(albedo) = &features.albedo {
+            let d = albedo[p] - albedo[q];
+            let dist2 = d.r * d.r + d.g * d.g + d.b * d.b;
+            weight *= f32::exp(-dist2 / (self.sigma_albedo * self.sigma_albedo));
+        }
+
+        weight
+    }
+}
+
+/// Albedo used to demodulate the beauty pass, kept away from zero so black surfaces survive.
+fn demodulation(albedo: Color) -> Color {
+    Color::new(
+        f32::max(albedo.r, 1e-2),
+        f32::max(albedo.g, 1e-2),
+        f32::max(albedo.b, 1e-2),
+    )
+}
+
+/// Luminance variance in a 3x3 window around every pixel.
+fn spatial_variance(color: &[Color], width: usize, height: usize) -> Vec<f32> {
+    let mut variance = Vec::with_capacity(color.len());
+    for j in 0..height {
+        for i in 0..width {
+            let (mut sum, mut sum2, mut n) = (0.0, 0.0, 0.0);
+            for y in j.saturating_sub(1)..usize::min(j + 2, height) {
+                for x in i.saturating_sub(1)..usize::min(i + 2, width) {
+                    let l = color[y * width + x].luminance();
+                    sum += l;
+                    sum2 += l * l;
+                    n += 1.0;
+                }
+            }
+            let mean = sum / n;
+            variance.push(f32::max(sum2 / n - mean * mean, 0.0));
+        }
+    }
+    variance
+}
+
+fn gaussian3x3(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    const WEIGHTS: [f32; 3] = [0.25, 0.5, 0.25];
+    let mut out = Vec::with_capacity(values.len());
+    for j in 0..height {
+        for i in 0..width {
+            let (mut sum, mut sum_weight) = (0.0, 0.0);
+            for (dy, wy) in WEIGHTS.iter().enumerate() {
+                for (dx, wx) in WEIGHTS.iter().enumerate() {
+                    let (x, y) = (i + dx, j + dy);
+                    if x == 0 || y == 0 || x > width || y > height {
+                        continue;
+                    }
+                    sum += wx * wy * values[(y - 1) * width + x - 1];
+                    sum_weight += wx * wy;
+                }
+            }
+            out.push(sum / sum_weight);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Sampler;
+    use rand::Rng;
+
+    fn noisy_sensor() -> Sensor {
+        let sensor = Sensor::zero(32, 32).with_aovs(&[Aov::Normal, Aov::Albedo]);
+        let mut sampler = Sampler::new(1, 0);
+        for j in 0..32 {
+            for i in 0..32 {
+                let index = j * 32 + i;
+                // left half faces +x, right half faces +y
+                let (normal, base) = match i < 16 {
+                    true => (Color::new(1.0, 0.0, 0.0), 0.2),
+                    false => (Color::new(0.0, 1.0, 0.0), 0.8),
+                };
+                let noise = base * (0.5 + sampler.gen::<f32>());
+                *sensor.get(i, j).unwrap().color.write().unwrap() = Color::new(noise, noise, noise);
+                *sensor.aov(Aov::Normal).unwrap().pixels[index]
+                    .write()
+                    .unwrap() = normal;
+                *sensor.aov(Aov::Albedo).unwrap().pixels[index]
+                    .write()
+                    .unwrap() = Color::new(1.0, 1.0, 1.0);
+            }
+        }
+        sensor
+    }
+
+    fn region_stats(sensor: &Sensor, columns: std::ops::Range<usize>) -> (f32, f32) {
+        let values: Vec<f32> = (0..32)
+            .flat_map(|j| columns.clone().map(move |i| (i, j)))
+            .map(|(i, j)| sensor.get(i, j).unwrap().color.read().unwrap().r)
+            .collect();
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        let variance =
+            values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / values.len() as f32;
+        (mean, variance)
+    }
+
+    #[test]
+    fn reduces_noise_and_keeps_edges() {
+        let sensor = noisy_sensor();
+        let (_, noisy_variance) = region_stats(&sensor, 0..16);
+
+        Denoiser::new(4).denoise(&sensor);
+
+        let (left_mean, left_variance) = region_stats(&sensor, 0..16);
+        let (right_mean, _) = region_stats(&sensor, 16..32);
+        assert!(left_variance < 0.25 * noisy_variance);
+        assert!((left_mean - 0.2).abs() < 0.03);
+        assert!((right_mean - 0.8).abs() < 0.1);
+    }
+
+    #[test]
+    fn keeps_background_finite() {
+        let sensor = noisy_sensor();
+        // a miss leaves the normal AOV at zero
+        for j in 0..32 {
+            for i in 0..4 {
+                *sensor.aov(Aov::Normal).unwrap().pixels[j * 32 + i]
+                    .write()
+                    .unwrap() = Color::new(0.0, 0.0, 0.0);
+            }
+        }
+
+        Denoiser::new(4).denoise(&sensor);
+
+        for j in 0..32 {
+            for i in 0..32 {
+                let color = *sensor.get(i, j).unwrap().color.read().unwrap();
+                assert!(color.r.is_finite() && color.g.is_finite() && color.b.is_finite());
+            }
+        }
+    }
+}
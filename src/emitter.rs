@@ -1,29 +1,212 @@
 use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::{uniform_sphere_sample, Shape, SurfaceInteraction};
 use crate::sensor::Color;
 
+use rand::Rng;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Light source of a scene.
+///
+/// Every light states what it emits, its power and whether rays can hit it.
+/// Tracing paths from the light and picking it by importance are optional,
+/// by default the light emits no light paths and has no bounds.
 pub trait Emitter: Sync + Send {
-    fn sample(&self) -> EmitterSample;
+    /// Samples a point on the light as seen from `reference`.
+    ///
+    /// Point and spot lights fall off with the squared distance to `reference`.
+    fn sample(&self, reference: Point, sampler: &mut Sampler) -> EmitterSample;
+
+    /// Radiance leaving the light at `si` towards `si.wi`.
+    fn emitted(&self, si: &SurfaceInteraction) -> Color;
+
+    /// Samples a ray leaving the light, `bounds` enclose the scene for lights at infinity.
+    fn sample_emission(&self, _bounds: &Bounds3, _sampler: &mut Sampler) -> Option<EmissionSample> {
+        None
+    }
+
+    /// Samples a point on the light like `sample_emission` does, emitting towards `reference`.
+    fn sample_position(
+        &self,
+        _reference: Point,
+        _bounds: &Bounds3,
+        _sampler: &mut Sampler,
+    ) -> Option<EmissionSample> {
+        None
+    }
+
+    /// Position and direction densities of `sample_emission` for light leaving
+    /// `position` with surface `normal` along `direction`.
+    fn pdf_emission(
+        &self,
+        _position: Point,
+        _normal: Option<Vector>,
+        _direction: Vector,
+        _bounds: &Bounds3,
+    ) -> (f32, f32) {
+        (0.0, 0.0)
+    }
+
+    /// Radiance, intensity or irradiance leaving a point with `normal` along `direction`.
+    fn emission(&self, normal: Option<Vector>, direction: Vector) -> Color;
+
+    /// Whether the light is a point or a direction that no ray can hit.
+    fn is_delta(&self) -> bool;
+
+    /// Total emitted power, `bounds` enclose the scene for lights at infinity.
+    fn power(&self, bounds: &Bounds3) -> Color;
+
+    /// Region and directions of emission, `None` for lights at infinity.
+    fn light_bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    /// Whether the light is infinitely far away, its position density is then per area
+    /// perpendicular to the light.
+    fn is_infinite(&self) -> bool {
+        false
+    }
 }
 
 pub struct EmitterSample {
+    /// Incident radiance at the reference point divided by the sampling density.
     pub radiance: Color,
     pub position: Point,
     pub weight: f32,
 }
 
+/// Ray leaving a light, as used to trace paths from the lights.
+pub struct EmissionSample {
+    pub position: Point,
+    /// Surface normal at `position`, `None` for point and directional lights.
+    pub normal: Option<Vector>,
+    pub direction: Vector,
+    /// Radiance of area lights, intensity of point lights or irradiance of directional lights.
+    pub emitted: Color,
+    /// Density with respect to the area of the light, one for lights at a single point.
+    pub pdf_position: f32,
+    /// Density with respect to solid angle, one for lights emitting in a single direction.
+    pub pdf_direction: f32,
+}
+
+/// Conservative bounds on where and in which directions a light emits, as used by `LightTree`.
+///
+/// Emitting surfaces face at most `theta_o` away from `direction` and emit at
+/// most `theta_e` away from their normal. Angles are in radians.
+#[derive(Clone, Copy, Debug)]
+pub struct LightBounds {
+    pub bounds: Bounds3,
+    /// Scalar emitted power.
+    pub power: f32,
+    pub direction: Vector,
+    pub theta_o: f32,
+    pub theta_e: f32,
+}
+
+/// Light from a single point, emitting the same intensity in every direction.
+///
+/// The light arriving at a surface falls off with the squared distance to the
+/// point, so `intensity` is the irradiance at a distance of one.
 pub struct PointLight {
     position: Point,
     intensity: Color,
 }
 
+/// Point light restricted to a cone, fading out between the inner and outer angle.
+///
+/// Falls off with the squared distance like `PointLight`.
+pub struct SpotLight {
+    position: Point,
+    direction: Vector,
+    intensity: Color,
+    cos_inner: f32,
+    cos_outer: f32,
+}
+
+/// Parallel light from infinitely far away, e.g. the sun.
+pub struct DirectionalLight {
+    /// Direction the light travels in.
+    direction: Vector,
+    irradiance: Color,
+}
+
+/// Light emitted uniformly from the front side of a shape.
+pub struct AreaLight {
+    shape: Arc<dyn Shape>,
+    radiance: Color,
+}
+
 impl Emitter for PointLight {
-    fn sample(&self) -> EmitterSample {
+    fn sample(&self, reference: Point, _sampler: &mut Sampler) -> EmitterSample {
         EmitterSample {
-            radiance: self.intensity,
+            radiance: (1.0 / norm2(reference - self.position)) * self.intensity,
             position: self.position,
             weight: 1.0,
         }
     }
+
+    fn emitted(&self, _si: &SurfaceInteraction) -> Color {
+        // a point cannot be hit by a ray
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    fn sample_emission(&self, _bounds: &Bounds3, sampler: &mut Sampler) -> Option<EmissionSample> {
+        Some(EmissionSample {
+            position: self.position,
+            normal: None,
+            direction: uniform_sphere_sample(sampler),
+            emitted: self.intensity,
+            pdf_position: 1.0,
+            pdf_direction: 1.0 / (4.0 * PI),
+        })
+    }
+
+    fn sample_position(
+        &self,
+        reference: Point,
+        _bounds: &Bounds3,
+        _sampler: &mut Sampler,
+    ) -> Option<EmissionSample> {
+        Some(EmissionSample {
+            position: self.position,
+            normal: None,
+            direction: (reference - self.position).normalize(),
+            emitted: self.intensity,
+            pdf_position: 1.0,
+            pdf_direction: 1.0 / (4.0 * PI),
+        })
+    }
+
+    fn pdf_emission(&self, _: Point, _: Option<Vector>, _: Vector, _: &Bounds3) -> (f32, f32) {
+        (1.0, 1.0 / (4.0 * PI))
+    }
+
+    fn emission(&self, _normal: Option<Vector>, _direction: Vector) -> Color {
+        self.intensity
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn power(&self, _bounds: &Bounds3) -> Color {
+        4.0 * PI * self.intensity
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            bounds: Bounds3::new(self.position, self.position),
+            power: self.power(&Bounds3::empty()).average(),
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            theta_o: PI,
+            theta_e: 0.5 * PI,
+        })
+    }
 }
 
 impl PointLight {
@@ -41,3 +224,391 @@ impl PointLight {
         }
     }
 }
+
+impl SpotLight {
+    /// Spot at `position` shining along `direction` with cone angles in degrees.
+    pub fn new(
+        position: Point,
+        direction: Vector,
+        intensity: Color,
+        inner_angle: f32,
+        outer_angle: f32,
+    ) -> SpotLight {
+        SpotLight {
+            position,
+            direction: direction.normalize(),
+            intensity,
+            cos_inner: f32::cos(inner_angle.to_radians()),
+            cos_outer: f32::cos(outer_angle.to_radians()),
+        }
+    }
+}
+
+impl SpotLight {
+    /// Smooth falloff between the inner and outer cone for light leaving along `direction`.
+    fn falloff(&self, direction: Vector) -> f32 {
+        let cos = dot(direction.normalize(), self.direction);
+        let x = f32::clamp(
+            (cos - self.cos_outer) / f32::max(self.cos_inner - self.cos_outer, 1e-6),
+            0.0,
+            1.0,
+        );
+        x * x * (3.0 - 2.0 * x)
+    }
+
+    /// Density of directions sampled uniformly within the outer cone.
+    fn pdf_cone(&self) -> f32 {
+        1.0 / (2.0 * PI * (1.0 - self.cos_outer))
+    }
+}
+
+impl Emitter for SpotLight {
+    fn sample(&self, reference: Point, _sampler: &mut Sampler) -> EmitterSample {
+        EmitterSample {
+            radiance: (self.falloff(reference - self.position) / norm2(reference - self.position))
+                * self.intensity,
+            position: self.position,
+            weight: 1.0,
+        }
+    }
+
+    fn emitted(&self, _si: &SurfaceInteraction) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    fn sample_emission(&self, _bounds: &Bounds3, sampler: &mut Sampler) -> Option<EmissionSample> {
+        let cos = 1.0 - sampler.gen::<f32>() * (1.0 - self.cos_outer);
+        let sin = f32::sqrt(f32::max(0.0, 1.0 - cos * cos));
+        let phi = 2.0 * PI * sampler.gen::<f32>();
+        let (u, v, w) = coordinate_system(self.direction);
+        let direction = f32::cos(phi) * sin * u + f32::sin(phi) * sin * v + cos * w;
+        Some(EmissionSample {
+            position: self.position,
+            normal: None,
+            direction,
+            emitted: self.emission(None, direction),
+            pdf_position: 1.0,
+            pdf_direction: self.pdf_cone(),
+        })
+    }
+
+    fn sample_position(
+        &self,
+        reference: Point,
+        bounds: &Bounds3,
+        _sampler: &mut Sampler,
+    ) -> Option<EmissionSample> {
+        let direction = (reference - self.position).normalize();
+        let (pdf_position, pdf_direction) =
+            self.pdf_emission(self.position, None, direction, bounds);
+        Some(EmissionSample {
+            position: self.position,
+            normal: None,
+            direction,
+            emitted: self.emission(None, direction),
+            pdf_position,
+            pdf_direction,
+        })
+    }
+
+    fn pdf_emission(
+        &self,
+        _: Point,
+        _: Option<Vector>,
+        direction: Vector,
+        _: &Bounds3,
+    ) -> (f32, f32) {
+        match dot(direction.normalize(), self.direction) >= self.cos_outer {
+            true => (1.0, self.pdf_cone()),
+            false => (1.0, 0.0),
+        }
+    }
+
+    fn emission(&self, _normal: Option<Vector>, direction: Vector) -> Color {
+        self.falloff(direction) * self.intensity
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    /// Exact for a hard cone, the smooth falloff is approximated by its midpoint.
+    fn power(&self, _bounds: &Bounds3) -> Color {
+        let solid_angle = 2.0 * PI * (1.0 - 0.5 * (self.cos_inner + self.cos_outer));
+        solid_angle * self.intensity
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            bounds: Bounds3::new(self.position, self.position),
+            power: self.power(&Bounds3::empty()).average(),
+            direction: self.direction,
+            theta_o: 0.0,
+            theta_e: f32::acos(f32::clamp(self.cos_outer, -1.0, 1.0)),
+        })
+    }
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vector, irradiance: Color) -> DirectionalLight {
+        DirectionalLight {
+            direction: direction.normalize(),
+            irradiance,
+        }
+    }
+}
+
+/// Distance at which directional lights are placed for shadow rays.
+const DISTANT: f32 = 1e5;
+
+/// Center and radius of a sphere around `bounds`, `None` for scenes without finite shapes.
+fn bounding_sphere(bounds: &Bounds3) -> Option<(Point, f32)> {
+    match bounds.is_empty() || bounds.is_unbounded() {
+        true => None,
+        false => Some((
+            bounds.centroid(),
+            f32::max(0.5 * norm(bounds.diagonal()), 1e-3),
+        )),
+    }
+}
+
+impl Emitter for DirectionalLight {
+    fn sample(&self, reference: Point, _sampler: &mut Sampler) -> EmitterSample {
+        EmitterSample {
+            radiance: self.irradiance,
+            position: reference + -DISTANT * self.direction,
+            weight: 1.0,
+        }
+    }
+
+    fn emitted(&self, _si: &SurfaceInteraction) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    /// Rays start on a disk facing the light that covers the whole scene.
+    fn sample_emission(&self, bounds: &Bounds3, sampler: &mut Sampler) -> Option<EmissionSample> {
+        let (center, radius) = bounding_sphere(bounds)?;
+        let (u, v, _) = coordinate_system(self.direction);
+        let r = radius * f32::sqrt(sampler.gen::<f32>());
+        let phi = 2.0 * PI * sampler.gen::<f32>();
+        let offset = r * f32::cos(phi) * u + r * f32::sin(phi) * v;
+        Some(EmissionSample {
+            position: center + offset + -radius * self.direction,
+            normal: None,
+            direction: self.direction,
+            emitted: self.irradiance,
+            pdf_position: 1.0 / (PI * radius * radius),
+            pdf_direction: 1.0,
+        })
+    }
+
+    fn sample_position(
+        &self,
+        reference: Point,
+        bounds: &Bounds3,
+        _sampler: &mut Sampler,
+    ) -> Option<EmissionSample> {
+        let (_, radius) = bounding_sphere(bounds)?;
+        Some(EmissionSample {
+            position: reference + -DISTANT * self.direction,
+            normal: None,
+            direction: self.direction,
+            emitted: self.irradiance,
+            pdf_position: 1.0 / (PI * radius * radius),
+            pdf_direction: 1.0,
+        })
+    }
+
+    fn pdf_emission(&self, _: Point, _: Option<Vector>, _: Vector, bounds: &Bounds3) -> (f32, f32) {
+        match bounding_sphere(bounds) {
+            Some((_, radius)) => (1.0 / (PI * radius * radius), 0.0),
+            None => (0.0, 0.0),
+        }
+    }
+
+    fn emission(&self, _normal: Option<Vector>, _direction: Vector) -> Color {
+        self.irradiance
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn power(&self, bounds: &Bounds3) -> Color {
+        let radius = bounding_sphere(bounds).map_or(0.0, |(_, radius)| radius);
+        PI * radius * radius * self.irradiance
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+}
+
+impl AreaLight {
+    pub fn new(shape: Arc<dyn Shape>, radiance: Color) -> AreaLight {
+        AreaLight { shape, radiance }
+    }
+}
+
+impl Emitter for AreaLight {
+    fn sample(&self, reference: Point, sampler: &mut Sampler) -> EmitterSample {
+        let none = EmitterSample {
+            radiance: Color::new(0.0, 0.0, 0.0),
+            position: reference,
+            weight: 1.0,
+        };
+        let Some(sample) = self.shape.sample_area(sampler) else {
+            return none;
+        };
+
+        let to_light = sample.position - reference;
+        let dist2 = norm2(to_light);
+        let cos_light = -dot(sample.normal, to_light.normalize());
+        if cos_light <= 0.0 || dist2 <= 0.0 {
+            return EmitterSample {
+                position: sample.position,
+                ..none
+            };
+        }
+
+        // convert the area density into a solid angle density at the reference point
+        let pdf = sample.pdf * dist2 / cos_light;
+        EmitterSample {
+            radiance: (1.0 / pdf) * self.radiance,
+            position: sample.position,
+            weight: 1.0,
+        }
+    }
+
+    fn emitted(&self, si: &SurfaceInteraction) -> Color {
+        self.emission(Some(si.normal), si.wi)
+    }
+
+    fn sample_emission(&self, _bounds: &Bounds3, sampler: &mut Sampler) -> Option<EmissionSample> {
+        let sample = self.shape.sample_area(sampler)?;
+        // cosine weighted about the normal
+        let (u, v, w) = coordinate_system(sample.normal);
+        let r = f32::sqrt(sampler.gen::<f32>());
+        let phi = 2.0 * PI * sampler.gen::<f32>();
+        let cos = f32::sqrt(f32::max(0.0, 1.0 - r * r));
+        let direction = r * f32::cos(phi) * u + r * f32::sin(phi) * v + cos * w;
+        Some(EmissionSample {
+            position: sample.position,
+            normal: Some(sample.normal),
+            direction,
+            emitted: self.radiance,
+            pdf_position: sample.pdf,
+            pdf_direction: cos / PI,
+        })
+    }
+
+    fn sample_position(
+        &self,
+        reference: Point,
+        _bounds: &Bounds3,
+        sampler: &mut Sampler,
+    ) -> Option<EmissionSample> {
+        let sample = self.shape.sample_area(sampler)?;
+        let direction = (reference - sample.position).normalize();
+        Some(EmissionSample {
+            position: sample.position,
+            normal: Some(sample.normal),
+            direction,
+            emitted: self.emission(Some(sample.normal), direction),
+            pdf_position: sample.pdf,
+            pdf_direction: f32::max(0.0, dot(sample.normal, direction)) / PI,
+        })
+    }
+
+    fn pdf_emission(
+        &self,
+        _position: Point,
+        normal: Option<Vector>,
+        direction: Vector,
+        _bounds: &Bounds3,
+    ) -> (f32, f32) {
+        let cos = normal.map_or(0.0, |n| dot(n, direction.normalize()));
+        (1.0 / self.shape.area(), f32::max(0.0, cos) / PI)
+    }
+
+    fn emission(&self, normal: Option<Vector>, direction: Vector) -> Color {
+        match normal.is_some_and(|n| dot(n, direction) > 0.0) {
+            true => self.radiance,
+            false => Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn power(&self, _bounds: &Bounds3) -> Color {
+        PI * self.shape.area() * self.radiance
+    }
+
+    /// The shape may face any way, so all directions are assumed.
+    fn light_bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            bounds: self.shape.bounds(),
+            power: self.power(&Bounds3::empty()).average(),
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            theta_o: PI,
+            theta_e: 0.5 * PI,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::BlackBody;
+    use crate::primitives::Disk;
+
+    #[test]
+    fn area_light_irradiance() {
+        let (radius, distance) = (1.0, 2.0);
+        let disk = Disk::new(
+            Point {
+                x: 0.0,
+                y: distance,
+                z: 0.0,
+            },
+            Vector {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            radius,
+            Box::new(BlackBody {}),
+        );
+        let light = AreaLight::new(Arc::new(disk), Color::new(1.0, 1.0, 1.0));
+        let reference = Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+
+        let mut sampler = Sampler::new(3, 0);
+        let n = 20000;
+        let irradiance = (0..n)
+            .map(|_| {
+                let sample = light.sample(reference, &mut sampler);
+                let cos = (sample.position - reference).normalize().y;
+                cos * sample.radiance.r
+            })
+            .sum::<f32>()
+            / n as f32;
+
+        let expected =
+            std::f32::consts::PI * radius * radius / (distance * distance + radius * radius);
+        assert!((irradiance - expected).abs() < 0.01 * expected);
+    }
+}
@@ -0,0 +1,717 @@
+use crate::emitter::*;
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::TriangleMesh;
+use crate::principled::PrincipledMaterial;
+use crate::scene::*;
+use crate::sensor::*;
+use crate::texture::*;
+
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A parsed glTF file with its buffers loaded.
+struct Document {
+    json: Value,
+    buffers: Vec<Vec<u8>>,
+    base: PathBuf,
+    images: Vec<Option<Arc<ImageTexture>>>,
+}
+
+/// Mesh primitive ready to be instanced, with the radiance it emits.
+struct Primitive {
+    mesh: Arc<TriangleMesh>,
+    emission: Color,
+    /// Index of the glTF material, shared by every primitive that uses it.
+    material: Option<usize>,
+}
+
+const GLB_MAGIC: &[u8] = b"glTF";
+const CHUNK_JSON: u32 = 0x4E4F534A;
+const CHUNK_BIN: u32 = 0x004E4942;
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value[key].as_array().map_or(&[], |a| a.as_slice())
+}
+
+fn number(value: &Value, key: &str, default: f32) -> f32 {
+    value[key].as_f64().map_or(default, |v| v as f32)
+}
+
+/// Reads an optional array of exactly `len` numbers.
+fn numbers(value: &Value, key: &str, len: usize) -> std::io::Result<Option<Vec<f32>>> {
+    if value[key].is_null() {
+        return Ok(None);
+    }
+    value[key]
+        .as_array()
+        .filter(|a| a.len() == len)
+        .and_then(|a| a.iter().map(|v| v.as_f64().map(|v| v as f32)).collect())
+        .map(Some)
+        .ok_or_else(|| invalid(format!("`{key}` is not an array of {len} numbers")))
+}
+
+fn index(value: &Value, key: &str) -> Option<usize> {
+    value[key].as_u64().map(|v| v as usize)
+}
+
+fn color(v: &[f32]) -> Color {
+    Color::new(v[0], v[1], v[2])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> std::io::Result<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("GLB file is truncated"))
+}
+
+fn decode_base64(text: &str) -> std::io::Result<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' | b'-' => Some(62),
+        b'/' | b'_' => Some(63),
+        _ => None,
+    };
+    let digits = text
+        .bytes()
+        .filter(|&c| c != b'=' && !c.is_ascii_whitespace())
+        .map(|c| value(c).ok_or_else(|| invalid("malformed base64 data")))
+        .collect::<std::io::Result<Vec<u8>>>()?;
+
+    Ok(digits
+        .chunks(4)
+        .flat_map(|chunk| {
+            let bits = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |acc, (k, &d)| acc | (d as u32) << (18 - 6 * k));
+            let bytes = [(bits >> 16) as u8, (bits >> 8) as u8, bits as u8];
+            bytes.into_iter().take(chunk.len().saturating_sub(1))
+        })
+        .collect())
+}
+
+/// Row-major matrix of a node, from `matrix` or translation, rotation and scale.
+fn node_matrix(node: &Value) -> std::io::Result<[[f32; 4]; 4]> {
+    if let Some(m) = numbers(node, "matrix", 16)? {
+        // glTF stores matrices column by column
+        return Ok([0, 1, 2, 3].map(|row| [0, 1, 2, 3].map(|col| m[4 * col + row])));
+    }
+
+    let t = numbers(node, "translation", 3)?.unwrap_or(vec![0.0; 3]);
+    let q = numbers(node, "rotation", 4)?.unwrap_or(vec![0.0, 0.0, 0.0, 1.0]);
+    let s = numbers(node, "scale", 3)?.unwrap_or(vec![1.0; 3]);
+    let (x, y, z, w) = (q[0], q[1], q[2], q[3]);
+    let r = [
+        [
+            1.0 - 2.0 * (y * y + z * z),
+            2.0 * (x * y - z * w),
+            2.0 * (x * z + y * w),
+        ],
+        [
+            2.0 * (x * y + z * w),
+            1.0 - 2.0 * (x * x + z * z),
+            2.0 * (y * z - x * w),
+        ],
+        [
+            2.0 * (x * z - y * w),
+            2.0 * (y * z + x * w),
+            1.0 - 2.0 * (x * x + y * y),
+        ],
+    ];
+
+    let mut m = [[0.0; 4]; 4];
+    for row in 0..3 {
+        for col in 0..3 {
+            m[row][col] = r[row][col] * s[col];
+        }
+        m[row][3] = t[row];
+    }
+    m[3][3] = 1.0;
+    Ok(m)
+}
+
+impl Document {
+    fn open(path: &Path) -> std::io::Result<Document> {
+        let bytes = fs::read(path)?;
+        let base = path.parent().map_or(PathBuf::new(), Path::to_path_buf);
+
+        let (json, binary) = match bytes.starts_with(GLB_MAGIC) {
+            true => {
+                let mut offset = 12;
+                let (mut json, mut binary) = (None, None);
+                while offset + 8 <= bytes.len() {
+                    let length = u32_at(&bytes, offset)? as usize;
+                    let kind = u32_at(&bytes, offset + 4)?;
+                    let chunk = bytes
+                        .get(offset + 8..offset + 8 + length)
+                        .ok_or_else(|| invalid("GLB chunk is truncated"))?;
+                    match kind {
+                        CHUNK_JSON => json = Some(chunk),
+                        CHUNK_BIN => binary = Some(chunk.to_vec()),
+                        _ => {}
+                    }
+                    offset += 8 + length;
+                }
+                (
+                    json.ok_or_else(|| invalid("GLB file has no JSON chunk"))?,
+                    binary,
+                )
+            }
+            false => (bytes.as_slice(), None),
+        };
+        let json: Value = serde_json::from_slice(json).map_err(|e| invalid(e.to_string()))?;
+
+        let mut binary = binary;
+        let buffers = array(&json, "buffers")
+            .iter()
+            .map(|buffer| match buffer["uri"].as_str() {
+                Some(uri) if uri.starts_with("data:") => {
+                    let data = uri
+                        .split_once("base64,")
+                        .ok_or_else(|| invalid("only base64 data URIs are supported"))?;
+                    decode_base64(data.1)
+                }
+                Some(uri) => fs::read(base.join(uri)),
+                // the GLB binary chunk belongs to the first buffer without a URI
+                None => binary.take().ok_or_else(|| invalid("buffer without data")),
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let mut document = Document {
+            json,
+            buffers,
+            base,
+            images: Vec::new(),
+        };
+        // unreadable images fall back to the plain base color factor
+        document.images = (0..array(&document.json, "images").len())
+            .map(|k| document.image(k).ok().map(Arc::new))
+            .collect();
+        Ok(document)
+    }
+
+    fn buffer_view(&self, k: usize) -> std::io::Result<(&[u8], Option<usize>)> {
+        let view = &self.json["bufferViews"][k];
+        let buffer = index(view, "buffer").and_then(|k| self.buffers.get(k));
+        let buffer = buffer.ok_or_else(|| invalid("buffer view without buffer"))?;
+        let offset = index(view, "byteOffset").unwrap_or(0);
+        let length = index(view, "byteLength").unwrap_or(0);
+        let bytes = buffer
+            .get(offset..offset + length)
+            .ok_or_else(|| invalid("buffer view exceeds its buffer"))?;
+        Ok((bytes, index(view, "byteStride")))
+    }
+
+    /// Values of an accessor and the number of components per element.
+    fn accessor(&self, k: usize) -> std::io::Result<(Vec<f64>, usize)> {
+        let accessor = &self.json["accessors"][k];
+        let count = index(accessor, "count").unwrap_or(0);
+        let components = match accessor["type"].as_str() {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            Some("MAT4") => 16,
+            _ => return Err(invalid("unsupported accessor type")),
+        };
+        let Some(view) = index(accessor, "bufferView") else {
+            return Ok((vec![0.0; count * components], components));
+        };
+
+        let component_type = index(accessor, "componentType").unwrap_or(0);
+        let size = match component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            _ => return Err(invalid("unsupported component type")),
+        };
+        let normalized = accessor["normalized"].as_bool().unwrap_or(false);
+        let (bytes, stride) = self.buffer_view(view)?;
+        let stride = stride.unwrap_or(size * components);
+        let offset = index(accessor, "byteOffset").unwrap_or(0);
+
+        let mut values = Vec::with_capacity(count * components);
+        for element in 0..count {
+            for c in 0..components {
+                let at = offset + element * stride + c * size;
+                let b = bytes
+                    .get(at..at + size)
+                    .ok_or_else(|| invalid("accessor exceeds its buffer view"))?;
+                let (value, max) = match component_type {
+                    5120 => (b[0] as i8 as f64, 127.0),
+                    5121 => (b[0] as f64, 255.0),
+                    5122 => (i16::from_le_bytes([b[0], b[1]]) as f64, 32767.0),
+                    5123 => (u16::from_le_bytes([b[0], b[1]]) as f64, 65535.0),
+                    5125 => (u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+                    _ => (f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+                };
+                values.push(match normalized {
+                    true => f64::max(value / max, -1.0),
+                    false => value,
+                });
+            }
+        }
+        Ok((values, components))
+    }
+
+    fn image(&self, k: usize) -> std::io::Result<ImageTexture> {
+        let image = &self.json["images"][k];
+        let decoded = match (image["uri"].as_str(), index(image, "bufferView")) {
+            (Some(uri), _) if uri.starts_with("data:") => {
+                let data = uri.split_once("base64,").map_or("", |d| d.1);
+                image::load_from_memory(&decode_base64(data)?)
+            }
+            (Some(uri), _) => image::open(self.base.join(uri)),
+            (None, Some(view)) => image::load_from_memory(self.buffer_view(view)?.0),
+            (None, None) => return Err(invalid("image without data")),
+        };
+        decoded
+            .map(ImageTexture::from_image)
+            .map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Maps a metallic-roughness material onto a `PrincipledMaterial`.
+    ///
+    /// The transmission, ior and clearcoat extensions are read as well, the ior
+    /// also setting the reflectance of opaque dielectrics. Vertex colors
+    /// modulate the base color unless a base color texture is present.
+    fn material(
+        &self,
+        k: Option<usize>,
+        vertex_colors: bool,
+    ) -> std::io::Result<(Box<dyn Material>, Color)> {
+        let Some(material) = k.map(|k| &self.json["materials"][k]) else {
+            let albedo = Color::new(0.8, 0.8, 0.8);
+            return Ok((
+                Box::new(DiffuseMaterial {
+                    albedo: Box::new(albedo),
+                }),
+                Color::new(0.0, 0.0, 0.0),
+            ));
+        };
+        let pbr = &material["pbrMetallicRoughness"];
+        let base =
+            numbers(pbr, "baseColorFactor", 4)?.map_or(Color::new(1.0, 1.0, 1.0), |f| color(&f));
+        let extensions = &material["extensions"];
+
+        let strength = number(
+            &extensions["KHR_materials_emissive_strength"],
+            "emissiveStrength",
+            1.0,
+        );
+        let emission = numbers(material, "emissiveFactor", 3)?
+            .map_or(Color::new(0.0, 0.0, 0.0), |f| strength * color(&f));
+
+        let image = index(&pbr["baseColorTexture"], "index")
+            .and_then(|k| index(&self.json["textures"][k], "source"))
+            .and_then(|k| self.images.get(k).cloned().flatten());
+        let texture: Box<dyn Texture> = match (image, vertex_colors) {
+            (Some(image), _) => Box::new(image),
+            (None, true) => Box::new(VertexColor::new(Color::new(1.0, 1.0, 1.0))),
+            (None, false) => Box::new(Color::new(1.0, 1.0, 1.0)),
+        };
+        let albedo = ScaledTexture {
+            scale: base,
+            texture,
+        };
+        let ior = number(&extensions["KHR_materials_ior"], "ior", 1.5);
+        let clearcoat = &extensions["KHR_materials_clearcoat"];
+        let material = PrincipledMaterial {
+            metallic: number(pbr, "metallicFactor", 1.0),
+            roughness: number(pbr, "roughnessFactor", 1.0),
+            specular: PrincipledMaterial::specular_from_ior(ior),
+            transmission: number(
+                &extensions["KHR_materials_transmission"],
+                "transmissionFactor",
+                0.0,
+            ),
+            ior,
+            clearcoat: number(clearcoat, "clearcoatFactor", 0.0),
+            clearcoat_gloss: PrincipledMaterial::gloss_from_roughness(number(
+                clearcoat,
+                "clearcoatRoughnessFactor",
+                0.0,
+            )),
+            ..PrincipledMaterial::new(Box::new(albedo))
+        };
+        Ok((Box::new(material), emission))
+    }
+
+    /// Builds one triangle mesh per triangle-list primitive of a mesh.
+    fn primitives(&self, mesh: &Value) -> std::io::Result<Vec<Primitive>> {
+        let mut primitives = Vec::new();
+        for primitive in array(mesh, "primitives") {
+            // only triangle lists, points and lines have no area
+            if index(primitive, "mode").unwrap_or(4) != 4 {
+                continue;
+            }
+            let attributes = &primitive["attributes"];
+            let attribute = |name: &str, types: &[usize]| {
+                let Some(k) = index(attributes, name) else {
+                    return Ok(None);
+                };
+                let (values, components) = self.accessor(k)?;
+                match types.contains(&components) {
+                    true => Ok(Some((values, components))),
+                    false => Err(invalid(format!("{name} accessor has the wrong type"))),
+                }
+            };
+
+            let Some((positions, _)) = attribute("POSITION", &[3])? else {
+                continue;
+            };
+            let positions: Vec<Point> = positions
+                .chunks(3)
+                .map(|p| Point {
+                    x: p[0] as f32,
+                    y: p[1] as f32,
+                    z: p[2] as f32,
+                })
+                .collect();
+            let indices: Vec<usize> = match index(primitive, "indices") {
+                Some(k) => self.accessor(k)?.0.iter().map(|&i| i as usize).collect(),
+                None => (0..positions.len()).collect(),
+            };
+            if indices.iter().any(|&i| i >= positions.len()) {
+                return Err(invalid("primitive index refers to a missing vertex"));
+            }
+            let indices = indices
+                .chunks_exact(3)
+                .map(|t| [t[0], t[1], t[2]])
+                .collect();
+
+            let normals = attribute("NORMAL", &[3])?.map_or(Vec::new(), |(n, _)| {
+                n.chunks(3)
+                    .map(|n| Vector {
+                        x: n[0] as f32,
+                        y: n[1] as f32,
+                        z: n[2] as f32,
+                    })
+                    .collect()
+            });
+            let uvs = attribute("TEXCOORD_0", &[2])?.map_or(Vec::new(), |(uv, _)| {
+                uv.chunks(2)
+                    .map(|uv| (uv[0] as f32, uv[1] as f32))
+                    .collect()
+            });
+            let colors: Vec<Color> =
+                attribute("COLOR_0", &[3, 4])?.map_or(Vec::new(), |(c, components)| {
+                    c.chunks(components)
+                        .map(|c| Color::new(c[0] as f32, c[1] as f32, c[2] as f32))
+                        .collect()
+                });
+
+            let material_index = index(primitive, "material");
+            let (material, emission) = self.material(material_index, !colors.is_empty())?;
+            let mesh = TriangleMesh::new(positions, indices, material)?
+                .with_normals(normals)?
+                .with_uvs(uvs)?
+                .with_colors(colors)?;
+            primitives.push(Primitive {
+                mesh: Arc::new(mesh),
+                emission,
+                material: material_index,
+            });
+        }
+        Ok(primitives)
+    }
+}
+
+/// Imports a glTF 2.0 scene, either `.gltf` with its buffers or a binary `.glb`.
+///
+/// Mesh primitives are instanced wherever nodes refer to them, emissive
+/// materials turn them into area lights. The first perspective camera of the
+/// scene renders into `sensor`, without one the camera sits at the origin.
+/// Punctual light intensities are taken over as they are.
+pub fn load_gltf(
+    path: impl AsRef<Path>,
+    sensor: Sensor,
+) -> std::io::Result<(Scene, PinholeCamera)> {
+    let document = Document::open(path.as_ref())?;
+    let json = &document.json;
+
+    let meshes = array(json, "meshes")
+        .iter()
+        .map(|mesh| document.primitives(mesh))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let lights = array(&json["extensions"]["KHR_lights_punctual"], "lights");
+
+    let mut scene = Scene::new();
+    let mut camera: Option<(f32, Transform)> = None;
+
+    let root = index(json, "scene").unwrap_or(0);
+    let roots = match json["scenes"][root]["nodes"].as_array() {
+        Some(nodes) => nodes
+            .iter()
+            .filter_map(|n| n.as_u64())
+            .map(|n| n as usize)
+            .collect(),
+        // files without scenes list every node at the root
+        None => (0..array(json, "nodes").len()).collect::<Vec<_>>(),
+    };
+    let mut stack: Vec<(usize, Transform)> = roots
+        .into_iter()
+        .rev()
+        .map(|n| (n, Transform::identity()))
+        .collect();
+
+    let mut material_ids = HashMap::new();
+    // every node appears at most once in the hierarchy
+    let mut visited = 0;
+    while let Some((k, parent)) = stack.pop() {
+        let node = &json["nodes"][k];
+        visited += 1;
+        if node.is_null() || visited > array(json, "nodes").len() {
+            return Err(invalid("malformed node hierarchy"));
+        }
+        // a node scaled to zero hides its whole subtree
+        let Some(local) = Transform::from_matrix(node_matrix(node)?) else {
+            continue;
+        };
+        let transform = parent * local;
+        for child in array(node, "children").iter().rev() {
+            if let Some(child) = child.as_u64() {
+                stack.push((child as usize, transform));
+            }
+        }
+
+        if let Some(mesh) = index(node, "mesh").and_then(|m| meshes.get(m)) {
+            for primitive in mesh.iter() {
+                let shape: Arc<dyn Shape> = primitive.mesh.clone();
+                let instance = Instance::new(shape, transform);
+                let emissive =
+                    primitive.emission.r + primitive.emission.g + primitive.emission.b > 0.0;
+                if emissive && transform.uniform_scale().is_none() {
+                    return Err(invalid("emissive meshes need a uniform scale"));
+                }
+                match emissive {
+                    true => scene.add_area_light(Arc::new(instance), primitive.emission),
+                    false => {
+                        let material_id = *material_ids
+                            .entry(primitive.material)
+                            .or_insert_with(|| scene.new_material_id());
+                        scene.add_shape_with_material_id(Box::new(instance), material_id);
+                    }
+                }
+            }
+        }
+
+        let perspective = index(node, "camera").map(|c| &json["cameras"][c]["perspective"]);
+        if let Some(perspective) = perspective.filter(|p| p.is_object()) {
+            if camera.is_none() {
+                let yfov = number(perspective, "yfov", 0.8).to_degrees();
+                camera = Some((yfov, transform));
+            }
+        }
+
+        let light =
+            index(&node["extensions"]["KHR_lights_punctual"], "light").and_then(|l| lights.get(l));
+        if let Some(light) = light {
+            let position = transform.point(Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            });
+            // lights shine down their local -z axis
+            let direction = transform.vector(Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            });
+            let intensity = number(light, "intensity", 1.0)
+                * numbers(light, "color", 3)?.map_or(Color::new(1.0, 1.0, 1.0), |c| color(&c));
+            match light["type"].as_str() {
+                Some("point") => {
+                    scene.add_light(Box::new(PointLight::new_colored(position, intensity)))
+                }
+                Some("spot") => {
+                    let spot = &light["spot"];
+                    let inner = number(spot, "innerConeAngle", 0.0).to_degrees();
+                    let outer =
+                        number(spot, "outerConeAngle", std::f32::consts::FRAC_PI_4).to_degrees();
+                    scene.add_light(Box::new(SpotLight::new(
+                        position, direction, intensity, inner, outer,
+                    )));
+                }
+                Some("directional") => {
+                    scene.add_light(Box::new(DirectionalLight::new(direction, intensity)))
+                }
+                _ => return Err(invalid("unknown light type")),
+            }
+        }
+    }
+
+    let camera = match camera {
+        Some((yfov, transform)) => PinholeCamera::new(sensor, yfov).with_transform(transform),
+        None => PinholeCamera::new(sensor, 45.0),
+    };
+    Ok((scene, camera))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_path;
+
+    const JSON: &str = r#"{
+        "asset": {"version": "2.0"},
+        "scene": 0,
+        "scenes": [{"nodes": [0, 1, 2]}],
+        "nodes": [
+            {"mesh": 0, "translation": [0, 0, -5], "children": [3]},
+            {"camera": 0, "translation": [0, 0, 1]},
+            {"extensions": {"KHR_lights_punctual": {"light": 0}}, "translation": [0, 3, 0]},
+            {"mesh": 0, "translation": [10, 0, 0]}
+        ],
+        "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1, "material": 0}]}],
+        "materials": [{"pbrMetallicRoughness": {"baseColorFactor": [1, 0, 0, 1], "metallicFactor": 0}}],
+        "cameras": [{"type": "perspective", "perspective": {"yfov": 0.5, "znear": 0.1}}],
+        "extensions": {"KHR_lights_punctual": {"lights": [{"type": "point", "intensity": 2}]}},
+        "buffers": [{URI"byteLength": 44}],
+        "bufferViews": [
+            {"buffer": 0, "byteOffset": 0, "byteLength": 36},
+            {"buffer": 0, "byteOffset": 36, "byteLength": 6}
+        ],
+        "accessors": [
+            {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"},
+            {"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}
+        ]
+    }"#;
+
+    fn buffer() -> Vec<u8> {
+        let positions = [-1.0f32, -1.0, 0.0, 1.0, -1.0, 0.0, 0.0, 1.0, 0.0];
+        let mut bytes: Vec<u8> = positions.iter().flat_map(|f| f.to_le_bytes()).collect();
+        bytes.extend([0u16, 1, 2].iter().flat_map(|i| i.to_le_bytes()));
+        bytes.extend([0, 0]);
+        bytes
+    }
+
+    fn check(scene: &Scene, camera: &PinholeCamera) {
+        assert_eq!(scene.shapes().len(), 2);
+        assert_eq!(scene.lights.len(), 1);
+        assert!(
+            norm(
+                camera.position()
+                    - Point {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 1.0
+                    }
+            ) < 1e-5
+        );
+
+        let forward = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        let si = scene
+            .closest_hit(&Ray {
+                origin: camera.position(),
+                direction: forward,
+            })
+            .unwrap();
+        assert!((si.t - 6.0).abs() < 1e-4);
+        let albedo = si.material.albedo(&si);
+        assert!((albedo.r - 1.0).abs() < 1e-5 && albedo.g.abs() < 1e-5);
+
+        // the child node inherits the translation of its parent
+        let child = Ray {
+            origin: Point {
+                x: 10.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: forward,
+        };
+        assert!((scene.closest_hit(&child).unwrap().t - 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn imports_gltf_with_external_buffer() {
+        let (bin, gltf) = (temp_path("triangle.bin"), temp_path("triangle.gltf"));
+        fs::write(&bin, buffer()).unwrap();
+        let uri = bin.file_name().unwrap().to_str().unwrap();
+        let json = JSON.replace("{URI", &format!("{{\"uri\": \"{uri}\", "));
+        fs::write(&gltf, json).unwrap();
+
+        let loaded = load_gltf(&gltf, Sensor::zero(4, 4));
+        fs::remove_file(bin).unwrap();
+        fs::remove_file(gltf).unwrap();
+        let (scene, camera) = loaded.unwrap();
+        check(&scene, &camera);
+    }
+
+    #[test]
+    fn imports_glb() {
+        let mut json = JSON.replace("{URI", "{").into_bytes();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let bin = buffer();
+
+        let mut glb = GLB_MAGIC.to_vec();
+        glb.extend(2u32.to_le_bytes());
+        glb.extend(((12 + 8 + json.len() + 8 + bin.len()) as u32).to_le_bytes());
+        glb.extend((json.len() as u32).to_le_bytes());
+        glb.extend(CHUNK_JSON.to_le_bytes());
+        glb.extend(json);
+        glb.extend((bin.len() as u32).to_le_bytes());
+        glb.extend(CHUNK_BIN.to_le_bytes());
+        glb.extend(bin);
+
+        let path = temp_path("triangle.glb");
+        fs::write(&path, glb).unwrap();
+        let loaded = load_gltf(&path, Sensor::zero(4, 4));
+        fs::remove_file(path).unwrap();
+        let (scene, camera) = loaded.unwrap();
+        check(&scene, &camera);
+    }
+
+    #[test]
+    fn rejects_malformed_arrays() {
+        let bin = temp_path("malformed.bin");
+        fs::write(&bin, buffer()).unwrap();
+        let uri = bin.file_name().unwrap().to_str().unwrap();
+        let json = JSON.replace("{URI", &format!("{{\"uri\": \"{uri}\", "));
+        let broken = [
+            ("[0, 0, -5]", "[0, 0]"),
+            ("[1, 0, 0, 1]", "[1, 0]"),
+            (
+                "\"count\": 3, \"type\": \"VEC3\"",
+                "\"count\": 3, \"type\": \"VEC2\"",
+            ),
+        ];
+        let loaded: Vec<_> = broken
+            .iter()
+            .map(|(from, to)| {
+                let gltf = temp_path("malformed.gltf");
+                fs::write(&gltf, json.replacen(from, to, 1)).unwrap();
+                let loaded = load_gltf(&gltf, Sensor::zero(4, 4)).map(|_| ());
+                fs::remove_file(gltf).unwrap();
+                loaded
+            })
+            .collect();
+        fs::remove_file(bin).unwrap();
+        for result in loaded {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVsbG8h").unwrap(), b"hello!");
+    }
+}
@@ -0,0 +1,379 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Dense 3D grid of scalar values covering the unit cube.
+///
+/// Values sit at voxel centers and are interpolated trilinearly in between.
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    data: Vec<f32>,
+}
+
+/// Coarse grid storing an upper bound of a `VoxelGrid` per cell.
+pub struct MajorantGrid {
+    resolution: [usize; 3],
+    data: Vec<f32>,
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl VoxelGrid {
+    /// Grid with `data` in x-fastest order.
+    pub fn new(resolution: [usize; 3], data: Vec<f32>) -> VoxelGrid {
+        assert_eq!(resolution[0] * resolution[1] * resolution[2], data.len());
+        VoxelGrid { resolution, data }
+    }
+
+    /// Loads a headerless file of little-endian `f32` values in x-fastest order.
+    pub fn from_raw<P: AsRef<Path>>(path: P, resolution: [usize; 3]) -> Result<VoxelGrid> {
+        let bytes = fs::read(path)?;
+        let data = decode(&bytes, "float", Endian::Little)?;
+        if data.len() != resolution[0] * resolution[1] * resolution[2] {
+            return Err(invalid_data(format!(
+                "expected {}x{}x{} voxels, found {}",
+                resolution[0],
+                resolution[1],
+                resolution[2],
+                data.len()
+            )));
+        }
+        Ok(VoxelGrid::new(resolution, data))
+    }
+
+    /// Loads a 3D NRRD volume with raw encoding, either attached or in a detached data file.
+    ///
+    /// Supported types are `uchar` and `ushort`, which get normalized to [0, 1], and `float`.
+    pub fn from_nrrd<P: AsRef<Path>>(path: P) -> Result<VoxelGrid> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        if !bytes.starts_with(b"NRRD") {
+            return Err(invalid_data("missing NRRD magic".to_string()));
+        }
+
+        let mut offset = 0;
+        let mut fields = Vec::new();
+        for line in bytes.split(|&b| b == b'\n') {
+            offset += line.len() + 1;
+            let line = String::from_utf8_lossy(line).trim().to_string();
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with('#') || line.starts_with("NRRD") {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                let value = value.trim_start_matches('=').trim();
+                fields.push((key.trim().to_lowercase(), value.to_string()));
+            }
+        }
+        let field = |key: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+
+        if field("dimension") != Some("3") {
+            return Err(invalid_data("only 3D volumes are supported".to_string()));
+        }
+        if let Some(encoding) = field("encoding") {
+            if encoding != "raw" {
+                return Err(invalid_data(format!("unsupported encoding {encoding}")));
+            }
+        }
+        let sizes: Vec<usize> = field("sizes")
+            .ok_or_else(|| invalid_data("missing sizes".to_string()))?
+            .split_whitespace()
+            .map(|s| s.parse().map_err(|_| invalid_data(format!("bad size {s}"))))
+            .collect::<Result<_>>()?;
+        if sizes.len() != 3 {
+            return Err(invalid_data("expected three sizes".to_string()));
+        }
+        let endian = match field("endian") {
+            Some("big") => Endian::Big,
+            _ => Endian::Little,
+        };
+        let kind = match field("type") {
+            Some("uchar" | "unsigned char" | "uint8" | "uint8_t") => "uchar",
+            Some("ushort" | "unsigned short" | "uint16" | "uint16_t") => "ushort",
+            Some("float") => "float",
+            other => return Err(invalid_data(format!("unsupported type {other:?}"))),
+        };
+
+        let payload = match field("data file").or(field("datafile")) {
+            Some(file) => fs::read(path.with_file_name(file))?,
+            None => bytes[usize::min(offset, bytes.len())..].to_vec(),
+        };
+        let mut data = decode(&payload, kind, endian)?;
+        let count = sizes[0] * sizes[1] * sizes[2];
+        if data.len() < count {
+            return Err(invalid_data(format!(
+                "expected {count} voxels, found {}",
+                data.len()
+            )));
+        }
+        data.truncate(count);
+
+        Ok(VoxelGrid::new([sizes[0], sizes[1], sizes[2]], data))
+    }
+
+    pub fn resolution(&self) -> [usize; 3] {
+        self.resolution
+    }
+
+    pub fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        let [nx, ny, _] = self.resolution;
+        self.data[(z * ny + y) * nx + x]
+    }
+
+    pub fn max_value(&self) -> f32 {
+        self.data.iter().copied().fold(0.0, f32::max)
+    }
+
+    /// Trilinearly interpolated value at `p` in [0, 1]^3, zero outside.
+    pub fn lookup(&self, p: [f32; 3]) -> f32 {
+        if p.iter().any(|&x| !(0.0..=1.0).contains(&x)) {
+            return 0.0;
+        }
+
+        let mut base = [0usize; 3];
+        let mut frac = [0.0f32; 3];
+        for axis in 0..3 {
+            let n = self.resolution[axis];
+            let x = f32::clamp(p[axis] * n as f32 - 0.5, 0.0, (n - 1) as f32);
+            base[axis] = usize::min(x as usize, n.saturating_sub(2));
+            frac[axis] = x - base[axis] as f32;
+        }
+
+        let at = |dx: usize, dy: usize, dz: usize| {
+            let [nx, ny, nz] = self.resolution;
+            self.voxel(
+                usize::min(base[0] + dx, nx - 1),
+                usize::min(base[1] + dy, ny - 1),
+                usize::min(base[2] + dz, nz - 1),
+            )
+        };
+        let lerp = |a: f32, b: f32, t: f32| (1.0 - t) * a + t * b;
+
+        let [fx, fy, fz] = frac;
+        let c00 = lerp(at(0, 0, 0), at(1, 0, 0), fx);
+        let c10 = lerp(at(0, 1, 0), at(1, 1, 0), fx);
+        let c01 = lerp(at(0, 0, 1), at(1, 0, 1), fx);
+        let c11 = lerp(at(0, 1, 1), at(1, 1, 1), fx);
+        lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+    }
+}
+
+impl MajorantGrid {
+    /// Builds a `resolution` sized bound of `grid`, conservative under trilinear interpolation.
+    pub fn new(grid: &VoxelGrid, resolution: [usize; 3]) -> MajorantGrid {
+        let [mx, my, mz] = resolution;
+        let mut data = Vec::with_capacity(mx * my * mz);
+
+        // voxels whose interpolation support overlaps cell k of n along an axis
+        let range = |k: usize, n: usize, voxels: usize| {
+            let lo = k as f32 / n as f32 * voxels as f32 - 0.5;
+            let hi = (k + 1) as f32 / n as f32 * voxels as f32 - 0.5;
+            let lo = f32::max(f32::floor(lo), 0.0) as usize;
+            let hi = usize::min(f32::ceil(hi).max(0.0) as usize, voxels - 1);
+            lo..=hi
+        };
+
+        let [nx, ny, nz] = grid.resolution();
+        for z in 0..mz {
+            for y in 0..my {
+                for x in 0..mx {
+                    let mut max = 0.0f32;
+                    for vz in range(z, mz, nz) {
+                        for vy in range(y, my, ny) {
+                            for vx in range(x, mx, nx) {
+                                max = f32::max(max, grid.voxel(vx, vy, vz));
+                            }
+                        }
+                    }
+                    data.push(max);
+                }
+            }
+        }
+
+        MajorantGrid { resolution, data }
+    }
+
+    pub fn resolution(&self) -> [usize; 3] {
+        self.resolution
+    }
+
+    pub fn cell(&self, x: usize, y: usize, z: usize) -> f32 {
+        let [mx, my, _] = self.resolution;
+        self.data[(z * my + y) * mx + x]
+    }
+
+    /// Walks the cells pierced by the segment `origin + t * direction`, `t` in [`t_min`, `t_max`].
+    ///
+    /// Coordinates are in the unit cube. Calls `visit(majorant, t0, t1)` per cell
+    /// until it returns `false`.
+    pub fn traverse(
+        &self,
+        origin: [f32; 3],
+        direction: [f32; 3],
+        t_min: f32,
+        t_max: f32,
+        mut visit: impl FnMut(f32, f32, f32) -> bool,
+    ) {
+        if t_min >= t_max {
+            return;
+        }
+
+        let mut cell = [0usize; 3];
+        let mut step = [0isize; 3];
+        let mut t_next = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+
+        for axis in 0..3 {
+            let n = self.resolution[axis];
+            let p = (origin[axis] + t_min * direction[axis]) * n as f32;
+            cell[axis] = f32::clamp(p, 0.0, (n - 1) as f32) as usize;
+
+            let d = direction[axis] * n as f32;
+            if d > 0.0 {
+                step[axis] = 1;
+                t_delta[axis] = 1.0 / d;
+                t_next[axis] = t_min + ((cell[axis] + 1) as f32 - p) / d;
+            } else if d < 0.0 {
+                step[axis] = -1;
+                t_delta[axis] = -1.0 / d;
+                t_next[axis] = t_min + (cell[axis] as f32 - p) / d;
+            }
+        }
+
+        let mut t = t_min;
+        loop {
+            let axis = match (
+                t_next[0] < t_next[1],
+                t_next[0] < t_next[2],
+                t_next[1] < t_next[2],
+            ) {
+                (true, true, _) => 0,
+                (false, _, true) => 1,
+                _ => 2,
+            };
+            let t_exit = f32::min(t_next[axis], t_max);
+            let majorant = self.cell(cell[0], cell[1], cell[2]);
+
+            if !visit(majorant, t, t_exit) || t_exit >= t_max {
+                return;
+            }
+
+            let next = cell[axis] as isize + step[axis];
+            if next < 0 || next >= self.resolution[axis] as isize {
+                return;
+            }
+            cell[axis] = next as usize;
+            t = t_exit;
+            t_next[axis] += t_delta[axis];
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Endian {
+    Little,
+    Big,
+}
+
+fn decode(bytes: &[u8], kind: &str, endian: Endian) -> Result<Vec<f32>> {
+    let data = match kind {
+        "uchar" => bytes.iter().map(|&b| b as f32 / 255.0).collect(),
+        "ushort" => bytes
+            .chunks_exact(2)
+            .map(|c| {
+                let raw = [c[0], c[1]];
+                let v = match endian {
+                    Endian::Little => u16::from_le_bytes(raw),
+                    Endian::Big => u16::from_be_bytes(raw),
+                };
+                v as f32 / 65535.0
+            })
+            .collect(),
+        "float" => bytes
+            .chunks_exact(4)
+            .map(|c| {
+                let raw = [c[0], c[1], c[2], c[3]];
+                match endian {
+                    Endian::Little => f32::from_le_bytes(raw),
+                    Endian::Big => f32::from_be_bytes(raw),
+                }
+            })
+            .collect(),
+        _ => return Err(invalid_data(format!("unsupported type {kind}"))),
+    };
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_path;
+
+    #[test]
+    fn interpolates_trilinearly() {
+        // x-gradient from 0 to 1 over two voxels
+        let grid = VoxelGrid::new([2, 1, 1], vec![0.0, 1.0]);
+        assert_eq!(grid.lookup([0.25, 0.5, 0.5]), 0.0);
+        assert_eq!(grid.lookup([0.75, 0.5, 0.5]), 1.0);
+        assert!((grid.lookup([0.5, 0.5, 0.5]) - 0.5).abs() < 1e-6);
+        assert_eq!(grid.lookup([1.5, 0.5, 0.5]), 0.0);
+    }
+
+    #[test]
+    fn majorants_bound_the_grid() {
+        let data = (0..64).map(|k| ((k * 37) % 11) as f32).collect();
+        let grid = VoxelGrid::new([4, 4, 4], data);
+        let majorants = MajorantGrid::new(&grid, [2, 2, 2]);
+
+        for k in 0..1000 {
+            let p = [
+                (k % 10) as f32 / 9.0,
+                (k / 10 % 10) as f32 / 9.0,
+                (k / 100) as f32 / 9.0,
+            ];
+            let cell = p.map(|x| usize::min((x * 2.0) as usize, 1));
+            assert!(grid.lookup(p) <= majorants.cell(cell[0], cell[1], cell[2]) + 1e-6);
+        }
+    }
+
+    #[test]
+    fn traverses_cells_in_order() {
+        let grid = VoxelGrid::new([4, 1, 1], vec![1.0, 2.0, 3.0, 4.0]);
+        let majorants = MajorantGrid::new(&grid, [4, 1, 1]);
+        let mut segments = Vec::new();
+        majorants.traverse([0.0, 0.5, 0.5], [1.0, 0.0, 0.0], 0.0, 1.0, |m, t0, t1| {
+            segments.push((m, t0, t1));
+            true
+        });
+
+        assert_eq!(segments.len(), 4);
+        assert!((segments[0].2 - 0.25).abs() < 1e-6);
+        assert!((segments[3].2 - 1.0).abs() < 1e-6);
+        // trilinear support reaches into the neighbouring voxel
+        assert_eq!(segments[0].0, 2.0);
+    }
+
+    #[test]
+    fn reads_nrrd() {
+        let mut bytes =
+            b"NRRD0004\ntype: uchar\ndimension: 3\nsizes: 2 1 1\nencoding: raw\n\n".to_vec();
+        bytes.extend_from_slice(&[0, 255]);
+        let path = temp_path("grid.nrrd");
+        fs::write(&path, bytes).unwrap();
+
+        let grid = VoxelGrid::from_nrrd(&path);
+        fs::remove_file(path).unwrap();
+        let grid = grid.unwrap();
+        assert_eq!(grid.resolution(), [2, 1, 1]);
+        assert_eq!(grid.voxel(1, 0, 0), 1.0);
+    }
+}
@@ -0,0 +1,499 @@
+use crate::math::*;
+use crate::sampler::Sampler;
+
+use rand::Rng;
+use std::f32::consts::PI;
+
+/// Quadtree cells holding more than this share of the energy are split.
+const SPLIT_THRESHOLD: f32 = 0.01;
+const MAX_DEPTH: usize = 20;
+
+/// Radiance arriving at a path vertex, as used to train an `SdTree`.
+pub struct GuideRecord {
+    pub position: Point,
+    /// Direction the radiance arrives from, pointing away from `position`.
+    pub direction: Vector,
+    /// Incident radiance divided by the density `direction` was sampled with.
+    pub weight: f32,
+}
+
+/// Distribution of incident radiance over the sphere of directions.
+///
+/// Directions are mapped to the unit square by the area preserving
+/// cylindrical mapping, which is covered by a quadtree whose nodes store the
+/// energy recorded in their quadrants. Sampling descends the tree by energy.
+#[derive(Clone)]
+pub struct DirectionTree {
+    nodes: Vec<QuadNode>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct QuadNode {
+    sums: [f32; 4],
+    /// Index of the node refining each quadrant, zero for leaf quadrants.
+    children: [usize; 4],
+}
+
+/// Spatio-directional tree of practical path guiding (Müller et al. 2017).
+///
+/// A binary tree splits the scene bounds in halves along alternating axes,
+/// and each leaf holds a `DirectionTree` for sampling as well as one that
+/// collects records for the next iteration. `refine` splits leaves that saw
+/// many records and rebuilds the quadtrees where the energy is.
+pub struct SdTree {
+    bounds: Bounds3,
+    nodes: Vec<SpatialNode>,
+}
+
+enum SpatialNode {
+    /// Children at `first` and `first + 1` cover the lower and upper half along `axis`.
+    Interior {
+        axis: usize,
+        first: usize,
+    },
+    Leaf(GuideLeaf),
+}
+
+#[derive(Clone)]
+struct GuideLeaf {
+    /// Axis the leaf is split along when it gets too many records.
+    axis: usize,
+    sampling: DirectionTree,
+    building: DirectionTree,
+    records: usize,
+}
+
+/// Point of the unit square that `direction` maps to.
+fn to_square(direction: Vector) -> (f32, f32) {
+    let d = direction.normalize();
+    let u = 0.5 * (f32::clamp(d.z, -1.0, 1.0) + 1.0);
+    let phi = f32::atan2(d.y, d.x);
+    let v = match phi < 0.0 {
+        true => phi / (2.0 * PI) + 1.0,
+        false => phi / (2.0 * PI),
+    };
+    (
+        f32::min(u, 1.0 - f32::EPSILON),
+        f32::min(v, 1.0 - f32::EPSILON),
+    )
+}
+
+fn from_square(u: f32, v: f32) -> Vector {
+    let cos = 2.0 * u - 1.0;
+    let sin = f32::sqrt(f32::max(0.0, 1.0 - cos * cos));
+    let phi = 2.0 * PI * v;
+    Vector {
+        x: sin * f32::cos(phi),
+        y: sin * f32::sin(phi),
+        z: cos,
+    }
+}
+
+/// Quadrant of `(u, v)` in the unit square and the point in its own unit square.
+fn quadrant(u: f32, v: f32) -> (usize, f32, f32) {
+    let (right, top) = (u >= 0.5, v >= 0.5);
+    let i = right as usize + 2 * top as usize;
+    let u = 2.0 * u - right as usize as f32;
+    let v = 2.0 * v - top as usize as f32;
+    (
+        i,
+        f32::min(u, 1.0 - f32::EPSILON),
+        f32::min(v, 1.0 - f32::EPSILON),
+    )
+}
+
+impl DirectionTree {
+    /// Single node without any energy.
+    pub fn new() -> DirectionTree {
+        DirectionTree {
+            nodes: vec![QuadNode::default()],
+        }
+    }
+
+    /// Energy recorded over all directions.
+    pub fn total(&self) -> f32 {
+        self.nodes[0].sums.iter().sum()
+    }
+
+    pub fn record(&mut self, direction: Vector, weight: f32) {
+        let (mut u, mut v) = to_square(direction);
+        let mut node = 0;
+        loop {
+            let (i, child_u, child_v) = quadrant(u, v);
+            self.nodes[node].sums[i] += weight;
+            match self.nodes[node].children[i] {
+                0 => break,
+                child => (node, u, v) = (child, child_u, child_v),
+            }
+        }
+    }
+
+    /// Solid angle density of `sample`, uniform over the sphere without any energy.
+    pub fn pdf(&self, direction: Vector) -> f32 {
+        if self.total() <= 0.0 {
+            return 1.0 / (4.0 * PI);
+        }
+        let (mut u, mut v) = to_square(direction);
+        let mut node = 0;
+        let mut pdf = 1.0;
+        loop {
+            let sums = self.nodes[node].sums;
+            let total: f32 = sums.iter().sum();
+            if total <= 0.0 {
+                return 0.0;
+            }
+            let (i, child_u, child_v) = quadrant(u, v);
+            pdf *= 4.0 * sums[i] / total;
+            match self.nodes[node].children[i] {
+                0 => break,
+                child => (node, u, v) = (child, child_u, child_v),
+            }
+        }
+        // the mapping stretches the unit square over the 4π of the sphere
+        pdf / (4.0 * PI)
+    }
+
+    /// Direction picked proportionally to the recorded energy.
+    pub fn sample(&self, sampler: &mut Sampler) -> Vector {
+        if self.total() <= 0.0 {
+            return from_square(sampler.gen(), sampler.gen());
+        }
+        let (mut x, mut y, mut size) = (0.0, 0.0, 1.0);
+        let mut node = 0;
+        loop {
+            let sums = self.nodes[node].sums;
+            let total: f32 = sums.iter().sum();
+            let mut target = sampler.gen::<f32>() * total;
+            let i = (0..3)
+                .find(|&i| {
+                    target -= sums[i];
+                    target < 0.0
+                })
+                .unwrap_or(3);
+            size *= 0.5;
+            x += size * (i % 2) as f32;
+            y += size * (i / 2) as f32;
+            match self.nodes[node].children[i] {
+                0 => break,
+                child => node = child,
+            }
+        }
+        from_square(
+            x + size * sampler.gen::<f32>(),
+            y + size * sampler.gen::<f32>(),
+        )
+    }
+
+    /// Empty tree with cells split where this one holds much energy.
+    ///
+    /// Energy below a leaf quadrant is assumed to be uniform, so busy leaves
+    /// are split until no cell holds more than `SPLIT_THRESHOLD` of the total.
+    pub fn refined(&self) -> DirectionTree {
+        let mut tree = DirectionTree::new();
+        let total = self.total();
+        if total > 0.0 {
+            tree.subdivide(self, 0, Some(0), self.nodes[0].sums, total, 1);
+        }
+        tree
+    }
+
+    fn subdivide(
+        &mut self,
+        old: &DirectionTree,
+        node: usize,
+        source: Option<usize>,
+        energies: [f32; 4],
+        total: f32,
+        depth: usize,
+    ) {
+        for (i, energy) in energies.into_iter().enumerate() {
+            if energy <= SPLIT_THRESHOLD * total || depth >= MAX_DEPTH {
+                continue;
+            }
+            let source = source
+                .map(|source| old.nodes[source].children[i])
+                .filter(|&child| child != 0);
+            let energies = match source {
+                Some(source) => old.nodes[source].sums,
+                None => [0.25 * energy; 4],
+            };
+            let child = self.nodes.len();
+            self.nodes.push(QuadNode::default());
+            self.nodes[node].children[i] = child;
+            self.subdivide(old, child, source, energies, total, depth + 1);
+        }
+    }
+}
+
+impl Default for DirectionTree {
+    fn default() -> Self {
+        DirectionTree::new()
+    }
+}
+
+fn component(p: Point, axis: usize) -> f32 {
+    match axis {
+        0 => p.x,
+        1 => p.y,
+        _ => p.z,
+    }
+}
+
+impl SdTree {
+    /// Untrained tree over a cube around `bounds`.
+    pub fn new(bounds: Bounds3) -> SdTree {
+        let bounds = match bounds.is_empty() || bounds.is_unbounded() {
+            true => Bounds3::new(
+                Point {
+                    x: -1.0,
+                    y: -1.0,
+                    z: -1.0,
+                },
+                Point {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                },
+            ),
+            false => {
+                let d = bounds.diagonal();
+                let half = 0.5 * f32::max(d.x, f32::max(d.y, d.z));
+                let c = bounds.centroid();
+                let extent = Vector {
+                    x: half,
+                    y: half,
+                    z: half,
+                };
+                Bounds3::new(c + -1.0 * extent, c + extent)
+            }
+        };
+        SdTree {
+            bounds,
+            nodes: vec![SpatialNode::Leaf(GuideLeaf {
+                axis: 0,
+                sampling: DirectionTree::new(),
+                building: DirectionTree::new(),
+                records: 0,
+            })],
+        }
+    }
+
+    /// Index of the leaf containing `position`, points outside go to the closest leaf.
+    fn leaf(&self, position: Point) -> usize {
+        let (mut min, mut max) = (self.bounds.min, self.bounds.max);
+        let mut node = 0;
+        while let SpatialNode::Interior { axis, first } = self.nodes[node] {
+            let mid = 0.5 * (component(min, axis) + component(max, axis));
+            let upper = component(position, axis) >= mid;
+            let bound = match upper {
+                true => &mut min,
+                false => &mut max,
+            };
+            match axis {
+                0 => bound.x = mid,
+                1 => bound.y = mid,
+                _ => bound.z = mid,
+            }
+            node = first + upper as usize;
+        }
+        node
+    }
+
+    /// Learned distribution of directions at `position`, `None` before anything was learned there.
+    pub fn distribution(&self, position: Point) -> Option<&DirectionTree> {
+        match &self.nodes[self.leaf(position)] {
+            SpatialNode::Leaf(leaf) if leaf.sampling.total() > 0.0 => Some(&leaf.sampling),
+            _ => None,
+        }
+    }
+
+    pub fn record(&mut self, record: &GuideRecord) {
+        let node = self.leaf(record.position);
+        if let SpatialNode::Leaf(leaf) = &mut self.nodes[node] {
+            leaf.building.record(record.direction, record.weight);
+            leaf.records += 1;
+        }
+    }
+
+    /// Ends an iteration, leaves with more than `max_records` are split in halves
+    /// and the records collected so far become the distributions to sample.
+    pub fn refine(&mut self, max_records: usize) {
+        let mut node = 0;
+        while node < self.nodes.len() {
+            if let SpatialNode::Leaf(leaf) = &self.nodes[node] {
+                if leaf.records > max_records {
+                    let child = GuideLeaf {
+                        axis: (leaf.axis + 1) % 3,
+                        records: leaf.records / 2,
+                        ..leaf.clone()
+                    };
+                    let axis = leaf.axis;
+                    let first = self.nodes.len();
+                    self.nodes.push(SpatialNode::Leaf(child.clone()));
+                    self.nodes.push(SpatialNode::Leaf(child));
+                    self.nodes[node] = SpatialNode::Interior { axis, first };
+                }
+            }
+            node += 1;
+        }
+
+        for node in self.nodes.iter_mut() {
+            if let SpatialNode::Leaf(leaf) = node {
+                leaf.sampling = leaf.building.clone();
+                leaf.building = leaf.building.refined();
+                leaf.records = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::tests::{lamp_scene, render};
+    use crate::integrator::{Integrator, PathIntegrator};
+    use crate::sensor::Color;
+
+    #[test]
+    fn learns_directions() {
+        let up = Vector {
+            x: 0.1,
+            y: 0.2,
+            z: 1.0,
+        }
+        .normalize();
+        let mut tree = DirectionTree::new();
+        let mut sampler = Sampler::new(0, 0);
+        // a bright lobe around `up` over a dim uniform background
+        for _ in 0..3 {
+            let mut next = tree.refined();
+            for _ in 0..20_000 {
+                let d = from_square(sampler.gen(), sampler.gen());
+                let weight = match dot(d, up) > 0.95 {
+                    true => 10.0,
+                    false => 0.1,
+                };
+                next.record(d, weight);
+            }
+            tree = next;
+        }
+        assert!(tree.nodes.len() > 10);
+        assert!(tree.pdf(up) > 10.0 / (4.0 * PI));
+
+        // the density integrates to one over the sphere
+        let n = 256;
+        let integral: f32 = (0..n * n)
+            .map(|k| {
+                let (i, j) = (k % n, k / n);
+                let d = from_square((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+                4.0 * PI * tree.pdf(d) / (n * n) as f32
+            })
+            .sum();
+        assert!((integral - 1.0).abs() < 1e-3, "{integral}");
+
+        // samples follow the density
+        let m = 20_000;
+        let inside = (0..m)
+            .filter(|_| dot(tree.sample(&mut sampler), up) > 0.95)
+            .count();
+        assert!(inside as f32 / m as f32 > 0.5);
+    }
+
+    #[test]
+    fn splits_busy_leaves() {
+        let mut tree = SdTree::new(Bounds3::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Point {
+                x: 4.0,
+                y: 2.0,
+                z: 2.0,
+            },
+        ));
+        let left = Point {
+            x: 0.5,
+            y: 1.0,
+            z: 1.0,
+        };
+        let right = Point {
+            x: 3.5,
+            y: 1.0,
+            z: 1.0,
+        };
+        for k in 0..100 {
+            tree.record(&GuideRecord {
+                position: left,
+                direction: from_square(0.9, (k as f32 + 0.5) / 100.0),
+                weight: 1.0,
+            });
+        }
+        assert!(tree.distribution(left).is_none());
+        tree.refine(50);
+
+        assert_eq!(tree.nodes.len(), 3);
+        let (a, b) = (tree.leaf(left), tree.leaf(right));
+        assert_ne!(a, b);
+        assert!(tree.distribution(left).is_some());
+        let up = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        assert!(tree.distribution(left).unwrap().pdf(up) > 1.0 / (4.0 * PI));
+    }
+
+    #[test]
+    fn guided_paths_match_unguided() {
+        let mean = |pixels: &[Color]| {
+            let n = pixels.len() as f32;
+            pixels.iter().map(|c| c.g / n).sum::<f32>()
+        };
+        let bits = |pixels: &[Color]| pixels.iter().map(|c| c.g.to_bits()).collect::<Vec<_>>();
+        let scene = lamp_scene();
+        let guided = render(
+            &PathIntegrator::new(4, 4).with_guiding(5),
+            &scene,
+            256,
+            3,
+            4,
+        );
+        let unguided = render(&PathIntegrator::new(4, 4), &scene, 256, 3, 4);
+        // the same samples took other directions
+        assert_ne!(bits(&guided), bits(&unguided));
+        let (guided, unguided) = (mean(&guided), mean(&unguided));
+        assert!(unguided > 0.01);
+        assert!(
+            (guided - unguided).abs() < 0.03 * unguided,
+            "{guided} {unguided}"
+        );
+
+        let guiding = PathIntegrator::new(4, 4).with_guiding(3);
+        let single = render(&guiding, &scene, 2, 3, 1);
+        let multi = render(&guiding, &scene, 2, 3, 5);
+        assert_eq!(bits(&single), bits(&multi));
+
+        // the guide does not outlive the render
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: -0.3,
+                z: -1.0,
+            }
+            .normalize(),
+        };
+        let plain = PathIntegrator::new(4, 4);
+        for k in 0..64 {
+            let after = guiding.sample_radiance(&ray, &scene, &mut Sampler::new(0, k));
+            let expected = plain.sample_radiance(&ray, &scene, &mut Sampler::new(0, k));
+            assert_eq!(after.g.to_bits(), expected.g.to_bits());
+        }
+    }
+}
@@ -1,90 +1,1041 @@
+use crate::aov::{Aov, AovRecord};
+use crate::bdpt::BidirectionalPathIntegrator;
+use crate::debug::*;
+use crate::guiding::{GuideRecord, SdTree};
+use crate::lightsampler::LightSampler;
 use crate::material::*;
 use crate::math::*;
+use crate::mlt::MetropolisIntegrator;
+use crate::photon::{PhotonMapIntegrator, ProgressivePhotonMapIntegrator};
+use crate::restir::*;
+use crate::sampler::Sampler;
 use crate::scene::*;
-use crate::sensor::Color;
+use crate::sensor::{Camera, Color};
+use crate::spectral::SpectralPathIntegrator;
+use crate::volpath::VolumetricPathIntegrator;
+use crate::whitted::WhittedIntegrator;
 
 use rand::Rng;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Radiance that a sample deposits on a pixel other than its own, e.g. by light tracing.
+pub struct Splat {
+    /// Index of the pixel in `Camera::get_pixels`.
+    pub pixel: usize,
+    pub radiance: Color,
+}
 
 pub trait Integrator: Send + Sync {
-    fn sample_radiance(&self, ray: &Ray, scene: &Scene) -> Color;
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color;
+
+    /// Like `sample_radiance`, but also fills `aovs` for the camera sample.
+    ///
+    /// Integrators that do not produce AOVs leave the record untouched.
+    fn sample_radiance_aovs(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        sampler: &mut Sampler,
+        _aovs: &mut AovRecord,
+    ) -> Color {
+        self.sample_radiance(ray, scene, sampler)
+    }
+
+    /// Radiance of a camera sample, with AOVs if `aovs` is given.
+    ///
+    /// Integrators that reach other pixels than the sampled one push their
+    /// contributions to `splats`, which are weighted like a sample of that pixel.
+    fn sample_camera_ray(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        sampler: &mut Sampler,
+        _camera: &dyn Camera,
+        aovs: Option<&mut AovRecord>,
+        _splats: &mut Vec<Splat>,
+    ) -> Color {
+        match aovs {
+            Some(aovs) => self.sample_radiance_aovs(ray, scene, sampler, aovs),
+            None => self.sample_radiance(ray, scene, sampler),
+        }
+    }
+
+    /// Renders `spp` samples per pixel into the sensor of `camera`.
+    ///
+    /// Every sample draws from its own stream derived from `seed`, the pixel
+    /// index and the sample index, so the result is bit-identical for a given
+    /// seed regardless of `num_threads`. Splats are summed per tile of pixels
+    /// and the tiles are added up in order after all pixels, for the same reason. Pixels
+    /// are converted from the working space of the scene into the color space
+    /// of the sensor.
+    fn render(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        spp: usize,
+        seed: u64,
+        num_threads: usize,
+    ) {
+        render_pixels(
+            camera,
+            scene,
+            spp,
+            seed,
+            num_threads,
+            |ray, sampler, aovs, splats| {
+                self.sample_camera_ray(ray, scene, sampler, camera, aovs, splats)
+            },
+        );
+    }
+}
+
+/// Pixels rendered by a worker at a time, in the order of `Camera::get_pixels`.
+const TILE_PIXELS: usize = 4096;
+
+/// Splats of finished tiles, added to `film` strictly in tile order.
+struct SplatFilm {
+    film: Vec<Color>,
+    next_tile: usize,
+    pending: BTreeMap<usize, Vec<Color>>,
+}
+
+impl SplatFilm {
+    fn finish_tile(&mut self, tile: usize, splats: Option<Vec<Color>>) {
+        self.pending.insert(tile, splats.unwrap_or_default());
+        while let Some(splats) = self.pending.remove(&self.next_tile) {
+            for (accum, splat) in self.film.iter_mut().zip(splats) {
+                *accum = *accum + splat;
+            }
+            self.next_tile += 1;
+        }
+    }
+}
+
+/// Body of `Integrator::render`, for integrators that do more work around it.
+///
+/// `trace` takes the place of `Integrator::sample_camera_ray`, so that
+/// state computed once per render can be handed to every sample.
+pub(crate) fn render_pixels(
+    camera: &dyn Camera,
+    scene: &Scene,
+    spp: usize,
+    seed: u64,
+    num_threads: usize,
+    trace: impl Fn(&Ray, &mut Sampler, Option<&mut AovRecord>, &mut Vec<Splat>) -> Color + Sync,
+) {
+    let sensor = camera.get_sensor();
+    let pixels = camera.get_pixels();
+    let with_aovs = !sensor.aovs().is_empty();
+    let to_sensor = scene.color_space.matrix_to(sensor.color_space());
+    let film = Mutex::new(SplatFilm {
+        film: Vec::new(),
+        next_tile: 0,
+        pending: BTreeMap::new(),
+    });
+
+    let tiles = pixels.len().div_ceil(TILE_PIXELS);
+    parallel_for(tiles, num_threads, |tile| {
+        let first = tile * TILE_PIXELS;
+
+        // only allocated once the tile splats at all
+        let mut tile_splats: Option<Vec<Color>> = None;
+        let mut pixel_splats = Vec::new();
+        for index in first..usize::min(first + TILE_PIXELS, pixels.len()) {
+            let pixel = &pixels[index];
+            let (i, j) = pixel.position;
+
+            let mut aovs = AovRecord::new();
+            let radiance = (0..spp)
+                .filter_map(|sample| {
+                    let mut sampler = Sampler::for_sample(seed, index, sample);
+                    let ray = camera.sample_ray(i, j, &mut sampler)?;
+                    if !with_aovs {
+                        return Some(trace(&ray, &mut sampler, None, &mut pixel_splats));
+                    }
+                    let mut record = AovRecord::new();
+                    let radiance = trace(&ray, &mut sampler, Some(&mut record), &mut pixel_splats);
+                    aovs.accumulate(&record, sample == 0);
+                    Some(radiance)
+                })
+                .reduce(|accum, radiance| accum + radiance);
+
+            if let Some(radiance) = radiance {
+                let f = 1.0 / spp as f32;
+                *pixel.color.write().unwrap() = to_sensor.apply(f * radiance);
+                if with_aovs {
+                    aovs.scale_filtered(f);
+                    aovs.convert_colors(&to_sensor);
+                    sensor.write_aovs(i, j, &aovs);
+                }
+            }
+            if !pixel_splats.is_empty() {
+                let buffer = tile_splats
+                    .get_or_insert_with(|| vec![Color::new(0.0, 0.0, 0.0); pixels.len()]);
+                for splat in pixel_splats.drain(..) {
+                    if let Some(accum) = buffer.get_mut(splat.pixel) {
+                        *accum = *accum + splat.radiance;
+                    }
+                }
+            }
+        }
+
+        let mut film = film.lock().unwrap();
+        if tile_splats.is_some() && film.film.is_empty() {
+            film.film = vec![Color::new(0.0, 0.0, 0.0); pixels.len()];
+        }
+        film.finish_tile(tile, tile_splats);
+    });
+
+    let film = film.into_inner().unwrap().film;
+    let f = 1.0 / spp as f32;
+    for (pixel, splat) in pixels.iter().zip(film) {
+        let mut color = pixel.color.write().unwrap();
+        *color = *color + to_sensor.apply(f * splat);
+    }
+}
+
+/// Calls `f` for every index of `0..count` on `num_threads` threads.
+///
+/// Indices are handed out one at a time in increasing order, so threads that
+/// finish early pick up the remaining work.
+pub(crate) fn parallel_for(count: usize, num_threads: usize, f: impl Fn(usize) + Sync) {
+    let next_index = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..num_threads.max(1) {
+            scope.spawn(|| loop {
+                let index = next_index.fetch_add(1, Ordering::Relaxed);
+                if index >= count {
+                    break;
+                }
+                f(index);
+            });
+        }
+    });
+}
+
+/// Results of `f` for `0..count`, computed on `num_threads` threads, in order.
+pub(crate) fn parallel_map<T: Send>(
+    count: usize,
+    num_threads: usize,
+    f: impl Fn(usize) -> T + Sync,
+) -> Vec<T> {
+    let results: Vec<Mutex<Option<T>>> = (0..count).map(|_| Mutex::new(None)).collect();
+    parallel_for(count, num_threads, |index| {
+        *results[index].lock().unwrap() = Some(f(index));
+    });
+    results
+        .into_iter()
+        .map(|result| result.into_inner().unwrap().unwrap())
+        .collect()
 }
 
 pub struct PathIntegrator {
     max_bounce: usize,
     russian_roulette: usize,
+    light_sampler: Option<Box<dyn LightSampler>>,
+    training_passes: usize,
+    reservoirs: Option<ReservoirSampling>,
 }
 
+/// Share of guided bounces where a learned distribution is available.
+const GUIDED_FRACTION: f32 = 0.5;
+/// Records per spatial leaf of the guide before it is split, scaled by the
+/// square root of the samples per pixel of the pass.
+const GUIDE_LEAF_RECORDS: usize = 12_000;
+/// Pixels traced at once in a training pass, bounds the memory for records.
+const TRAINING_BATCH: usize = 4096;
+/// Mixed into the seed of training passes so that they do not repeat the samples of the image.
+const TRAINING_SEED: u64 = 0x6a09_e667_f3bc_c909;
+/// Mixed into the seed of the choices made by spatial reuse of reservoirs.
+const REUSE_SEED: u64 = 0xbb67_ae85_84ca_a73b;
+
 impl PathIntegrator {
     pub fn new(max_bounce: usize, russian_roulette: usize) -> PathIntegrator {
         PathIntegrator {
             max_bounce,
             russian_roulette,
+            light_sampler: None,
+            training_passes: 0,
+            reservoirs: None,
+        }
+    }
+
+    /// Samples a single light per bounce picked by `light_sampler` instead of every light.
+    ///
+    /// Scenes with another number of lights than the one `light_sampler` was
+    /// built for are rendered with every light instead.
+    pub fn with_light_sampler(mut self, light_sampler: Box<dyn LightSampler>) -> PathIntegrator {
+        self.light_sampler = Some(light_sampler);
+        self
+    }
+
+    /// The light sampler if it was built for the lights of `scene`.
+    fn light_sampler(&self, scene: &Scene) -> Option<&dyn LightSampler> {
+        self.light_sampler
+            .as_deref()
+            .filter(|light_sampler| light_sampler.num_lights() == scene.lights.len())
+    }
+
+    /// Guides bounces by incident radiance learned in `training_passes` passes before rendering.
+    ///
+    /// Pass `k` traces `2^k` samples per pixel and records the radiance
+    /// arriving at every vertex in an `SdTree`, which the next pass samples
+    /// from. At non-delta vertices the learned distribution is mixed with
+    /// `bsdf_sample` by one-sample MIS, picking either with equal
+    /// probability and dividing by the combined density. The guide only lives
+    /// for one `render`, `sample_radiance` alone is not guided.
+    pub fn with_guiding(mut self, training_passes: usize) -> PathIntegrator {
+        self.training_passes = training_passes;
+        self
+    }
+
+    /// Lights the first hit of camera paths by resampled reservoirs instead of light samples.
+    ///
+    /// Rendering then goes through the image once per sample index, so that
+    /// pixels can reuse the reservoirs of their neighbors. Later bounces
+    /// sample lights as before. `sample_radiance` alone cannot reuse and
+    /// keeps to light samples.
+    pub fn with_reservoirs(mut self, reservoirs: ReservoirSampling) -> PathIntegrator {
+        self.reservoirs = Some(reservoirs);
+        self
+    }
+
+    /// Runs the training passes of path guiding on the pixels of `camera`.
+    fn train_guide(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        seed: u64,
+        num_threads: usize,
+    ) -> SdTree {
+        let pixels = camera.get_pixels();
+        let mut guide = SdTree::new(scene.bounds());
+        let mut first_sample = 0;
+
+        for pass in 0..self.training_passes {
+            let spp = 1 << pass;
+            for start in (0..pixels.len()).step_by(TRAINING_BATCH) {
+                let end = usize::min(start + TRAINING_BATCH, pixels.len());
+                let records = parallel_map(end - start, num_threads, |k| {
+                    let index = start + k;
+                    let (i, j) = pixels[index].position;
+                    let mut records = Vec::new();
+                    for sample in first_sample..first_sample + spp {
+                        let mut sampler = Sampler::for_sample(seed ^ TRAINING_SEED, index, sample);
+                        let Some(ray) = camera.sample_ray(i, j, &mut sampler) else {
+                            continue;
+                        };
+                        self.trace(
+                            &ray,
+                            scene,
+                            &mut sampler,
+                            None,
+                            Some(&guide),
+                            Some(&mut records),
+                            None,
+                        );
+                    }
+                    records
+                });
+                for record in records.iter().flatten() {
+                    guide.record(record);
+                }
+            }
+            let max_records = GUIDE_LEAF_RECORDS as f32 * f32::sqrt(spp as f32);
+            guide.refine(max_records as usize);
+            first_sample += spp;
+        }
+        guide
+    }
+
+    /// Renders one sample index at a time over the whole image, so that the
+    /// reservoirs of all first hits exist before pixels reuse their neighbors.
+    #[allow(clippy::too_many_arguments)]
+    fn render_reservoirs(
+        &self,
+        settings: &ReservoirSampling,
+        camera: &dyn Camera,
+        scene: &Scene,
+        spp: usize,
+        seed: u64,
+        num_threads: usize,
+        guide: Option<&SdTree>,
+    ) {
+        let sensor = camera.get_sensor();
+        let size = (sensor.width(), sensor.height());
+        let pixels = camera.get_pixels();
+        let with_aovs = !sensor.aovs().is_empty();
+        let to_sensor = scene.color_space.matrix_to(sensor.color_space());
+        let bounds = scene.bounds();
+
+        let mut radiance = vec![None; pixels.len()];
+        let mut aovs: Vec<_> = pixels.iter().map(|_| AovRecord::new()).collect();
+        for sample in 0..spp {
+            // first hits and their own reservoirs
+            let hits = parallel_map(pixels.len(), num_threads, |index| {
+                let (i, j) = pixels[index].position;
+                let mut sampler = Sampler::for_sample(seed, index, sample);
+                let ray = camera.sample_ray(i, j, &mut sampler)?;
+                let reservoir = scene
+                    .closest_hit(&ray)
+                    .filter(|si| !si.material.is_delta_reflector())
+                    .map(|si| {
+                        let reservoir = Reservoir::generate(
+                            scene,
+                            &si,
+                            settings.candidates,
+                            self.light_sampler(scene),
+                            &bounds,
+                            &mut sampler,
+                        );
+                        (si, reservoir)
+                    });
+                Some((ray, sampler, reservoir))
+            });
+
+            let reused = match settings.neighbors {
+                0 => None,
+                _ => Some(parallel_map(pixels.len(), num_threads, |index| {
+                    let Some((_, _, Some((si, own)))) = &hits[index] else {
+                        return None;
+                    };
+                    let mut sampler = Sampler::for_sample(seed ^ REUSE_SEED, index, sample);
+                    let neighbors: Vec<_> = settings
+                        .neighbors(pixels[index].position, size, &mut sampler)
+                        .into_iter()
+                        .filter_map(|(i, j)| match &hits[j * size.0 + i] {
+                            Some((_, _, Some((si, reservoir)))) => Some((si, reservoir)),
+                            _ => None,
+                        })
+                        .collect();
+                    Some(spatial_reuse(
+                        scene,
+                        si,
+                        own,
+                        &neighbors,
+                        settings.unbiased,
+                        &mut sampler,
+                    ))
+                })),
+            };
+
+            let samples = parallel_map(pixels.len(), num_threads, |index| {
+                let (ray, sampler, hit) = hits[index].as_ref()?;
+                let reservoir = match &reused {
+                    Some(reused) => reused[index].as_ref(),
+                    None => hit.as_ref().map(|(_, reservoir)| reservoir),
+                };
+                let mut sampler = sampler.clone();
+                let mut record = AovRecord::new();
+                let color = self.trace(
+                    ray,
+                    scene,
+                    &mut sampler,
+                    with_aovs.then_some(&mut record),
+                    guide,
+                    None,
+                    reservoir,
+                );
+                Some((color, record))
+            });
+
+            for (index, (color, record)) in samples
+                .into_iter()
+                .enumerate()
+                .filter_map(|(index, sample)| Some((index, sample?)))
+            {
+                let first = radiance[index].is_none();
+                radiance[index] =
+                    Some(radiance[index].unwrap_or(Color::new(0.0, 0.0, 0.0)) + color);
+                if with_aovs {
+                    aovs[index].accumulate(&record, first);
+                }
+            }
+        }
+
+        let f = 1.0 / spp as f32;
+        for (index, pixel) in pixels.iter().enumerate() {
+            let Some(radiance) = radiance[index] else {
+                continue;
+            };
+            *pixel.color.write().unwrap() = to_sensor.apply(f * radiance);
+            if with_aovs {
+                let (i, j) = pixel.position;
+                aovs[index].scale_filtered(f);
+                aovs[index].convert_colors(&to_sensor);
+                sensor.write_aovs(i, j, &aovs[index]);
+            }
         }
     }
 }
 
-impl Integrator for PathIntegrator {
-    fn sample_radiance(&self, ray: &Ray, scene: &Scene) -> Color {
+impl PathIntegrator {
+    #[allow(clippy::too_many_arguments)]
+    fn trace(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        sampler: &mut Sampler,
+        mut aovs: Option<&mut AovRecord>,
+        guide: Option<&SdTree>,
+        records: Option<&mut Vec<GuideRecord>>,
+        reservoir: Option<&Reservoir>,
+    ) -> Color {
         let mut throughput = Color::new(1.0, 1.0, 1.0);
         let mut color = Color::new(0.0, 0.0, 0.0);
+        // vertices to record for guiding: position, direction, throughput and radiance so far
+        let mut vertices = Vec::new();
 
         let mut ray = Ray {
             origin: ray.origin,
             direction: ray.direction,
         };
 
-        let mut rng = rand::thread_rng();
+        // share of the path throughput that was scattered by diffuse lobes at the first hit
+        let mut diffuse_fraction = None;
+        // emission is only gathered by hitting lights after camera rays and delta bounces
+        let mut specular = true;
 
         for bounce in 0..self.max_bounce {
             let Some(si) = scene.closest_hit(&ray) else {
-                color = color + throughput * scene.background_color;
+                let contribution = throughput * scene.background(ray.direction);
+                color = color + contribution;
+                if let Some(aovs) = aovs.as_deref_mut() {
+                    split_contribution(aovs, contribution, bounce == 0, diffuse_fraction);
+                }
                 break;
             };
 
+            if bounce == 0 {
+                if let Some(aovs) = aovs.as_deref_mut() {
+                    record_first_hit(aovs, &si);
+                }
+            }
+
             if let Some(light) = si.emitter {
-                if bounce == 0 {
-                    color = color + throughput * light.sample().radiance;
+                if specular {
+                    let contribution = throughput * light.emitted(&si);
+                    color = color + contribution;
+                    if let Some(aovs) = aovs.as_deref_mut() {
+                        split_contribution(aovs, contribution, bounce == 0, diffuse_fraction);
+                    }
                 }
             }
 
-            let mut le = Color::new(0.0, 0.0, 0.0);
-            for light in scene.lights.iter() {
-                let light_sample = light.sample();
-                let wo = (light_sample.position - si.position).normalize();
-                let dist = norm(light_sample.position - si.position);
-                let shadow_si = scene.closest_hit(&Ray {
-                    origin: si.position + 1e-3 * wo,
-                    direction: wo,
-                });
-                if let Some(si) = shadow_si {
-                    if si.t < dist {
-                        continue;
+            // delta lobes cannot be hit by light samples, their emission is found by the next bounce
+            // light entering a translucent shape is shaded where it leaves
+            let (si, reservoir) = match si.material.subsurface() {
+                Some(subsurface) => match subsurface.random_walk(scene, &si, sampler) {
+                    Some((exit, weight)) => {
+                        throughput = throughput * weight;
+                        (exit, None)
+                    }
+                    None => break,
+                },
+                None => (si, reservoir.filter(|_| bounce == 0)),
+            };
+            if let (false, Some(reservoir)) = (si.material.is_delta_reflector(), reservoir) {
+                if let Some((contribution, wo)) = reservoir.shade(scene, &si) {
+                    color = color + throughput * contribution;
+                    if let Some(aovs) = aovs.as_deref_mut() {
+                        let fraction = Some(si.material.diffuse_fraction(&si, wo));
+                        split_contribution(aovs, throughput * contribution, true, fraction);
                     }
                 }
+            } else if !si.material.is_delta_reflector() {
+                let mut le = Color::new(0.0, 0.0, 0.0);
+                // either the one picked light or every light, without collecting them
+                let (picked, all) = match self.light_sampler(scene) {
+                    Some(light_sampler) => (
+                        light_sampler
+                            .sample(si.position, si.normal, sampler.gen())
+                            .map(|(index, pmf)| (scene.lights[index].as_ref(), 1.0 / pmf)),
+                        None,
+                    ),
+                    None => (
+                        None,
+                        Some(scene.lights.iter().map(|light| (light.as_ref(), 1.0))),
+                    ),
+                };
+                for (light, weight) in picked.into_iter().chain(all.into_iter().flatten()) {
+                    let light_sample = light.sample(si.position, sampler);
+                    let wo = (light_sample.position - si.position).normalize();
+                    let dist = norm(light_sample.position - si.position);
+                    let shadow_si = scene.closest_hit(&Ray {
+                        origin: si.position + 1e-3 * wo,
+                        direction: wo,
+                    });
+                    if let Some(si) = shadow_si {
+                        // the light itself is hit at about dist - 1e-3 from the offset origin
+                        if si.t < dist - 2e-3 {
+                            continue;
+                        }
+                    }
 
-                le = le + si.material.bsdf_eval(&si, wo).radiance * light_sample.radiance;
-            }
+                    let contribution =
+                        weight * si.material.bsdf_eval(&si, wo).radiance * light_sample.radiance;
+                    le = le + contribution;
 
-            color = color + throughput * le;
+                    if let Some(aovs) = aovs.as_deref_mut() {
+                        let fraction = match bounce {
+                            0 => Some(si.material.diffuse_fraction(&si, wo)),
+                            _ => diffuse_fraction,
+                        };
+                        split_contribution(aovs, throughput * contribution, bounce == 0, fraction);
+                    }
+                }
+
+                color = color + throughput * le;
+            }
 
             // compute new ray direction
-            let wo = si.material.bsdf_sample(&si);
-            let BsdfSample { radiance, pdf } = si.material.bsdf_eval(&si, wo);
+            let guided = match si.material.is_delta_reflector() {
+                true => None,
+                false => guide.and_then(|guide| guide.distribution(si.position)),
+            };
+            let sample = match guided {
+                Some(distribution) if sampler.gen::<f32>() < GUIDED_FRACTION => {
+                    let wo = distribution.sample(sampler);
+                    let BsdfSample { radiance, pdf } = si.material.bsdf_eval(&si, wo);
+                    ScatterSample {
+                        wo,
+                        radiance,
+                        pdf,
+                        delta: false,
+                    }
+                }
+                _ => si.material.bsdf_scatter(&si, sampler),
+            };
+            let ScatterSample {
+                wo,
+                radiance,
+                mut pdf,
+                delta,
+            } = sample;
+            if let Some(distribution) = guided {
+                // the guide never samples delta lobes
+                pdf = match delta {
+                    true => (1.0 - GUIDED_FRACTION) * pdf,
+                    false => {
+                        GUIDED_FRACTION * distribution.pdf(wo)
+                            + (1.0 - GUIDED_FRACTION) * f32::max(pdf, 0.0)
+                    }
+                };
+            }
+
+            if bounce == 0 && aovs.is_some() {
+                diffuse_fraction = Some(match delta {
+                    true => 0.0,
+                    false => si.material.diffuse_fraction(&si, wo),
+                });
+            }
 
             throughput = (1.0 / pdf) * throughput * radiance;
+            specular = delta;
+            if records.is_some() && !specular {
+                vertices.push((si.position, wo, throughput, color, pdf));
+            }
 
             ray.origin = si.position + 1e-3 * wo;
             ray.direction = wo;
 
             if bounce > self.russian_roulette {
                 let p = f32::max(throughput.r, f32::max(throughput.g, throughput.b));
-                if rng.gen::<f32>() > p {
+                if sampler.gen::<f32>() > p {
                     break;
                 }
                 throughput = (1.0 / p) * throughput;
             }
         }
 
+        if let Some(records) = records {
+            // radiance arriving along each recorded direction is what the path gathered after it
+            for (position, direction, throughput, before, pdf) in vertices {
+                let incident = |gathered: f32, throughput: f32| match throughput > 0.0 {
+                    true => gathered / throughput,
+                    false => 0.0,
+                };
+                let later = color - before;
+                let radiance = (incident(later.r, throughput.r)
+                    + incident(later.g, throughput.g)
+                    + incident(later.b, throughput.b))
+                    / 3.0;
+                let weight = radiance / pdf;
+                if weight.is_finite() && pdf > 0.0 {
+                    records.push(GuideRecord {
+                        position,
+                        direction,
+                        weight,
+                    });
+                }
+            }
+        }
         color
     }
 }
+
+impl Integrator for PathIntegrator {
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        self.trace(ray, scene, sampler, None, None, None, None)
+    }
+
+    fn sample_radiance_aovs(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        sampler: &mut Sampler,
+        aovs: &mut AovRecord,
+    ) -> Color {
+        self.trace(ray, scene, sampler, Some(aovs), None, None, None)
+    }
+
+    /// Trains the guide first if guiding is enabled, and resamples reservoirs if enabled.
+    fn render(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        spp: usize,
+        seed: u64,
+        num_threads: usize,
+    ) {
+        let guide = match self.training_passes {
+            0 => None,
+            _ => Some(self.train_guide(camera, scene, seed, num_threads)),
+        };
+        match &self.reservoirs {
+            Some(settings) => self.render_reservoirs(
+                settings,
+                camera,
+                scene,
+                spp,
+                seed,
+                num_threads,
+                guide.as_ref(),
+            ),
+            None => render_pixels(
+                camera,
+                scene,
+                spp,
+                seed,
+                num_threads,
+                |ray, sampler, aovs, _| {
+                    self.trace(ray, scene, sampler, aovs, guide.as_ref(), None, None)
+                },
+            ),
+        }
+    }
+}
+
+/// Stores the geometric AOVs of the first surface seen by the camera.
+pub(crate) fn record_first_hit(aovs: &mut AovRecord, si: &SurfaceInteraction) {
+    let n = si.normal;
+    let p = si.position;
+    aovs.set(Aov::Albedo, si.material.albedo(si));
+    aovs.set(Aov::Normal, Color::new(n.x, n.y, n.z));
+    aovs.set(Aov::Depth, Color::new(si.t, si.t, si.t));
+    aovs.set(Aov::Position, Color::new(p.x, p.y, p.z));
+    let shape_id = si.shape_id as f32;
+    aovs.set(Aov::ShapeId, Color::new(shape_id, shape_id, shape_id));
+    let material_id = si.material_id as f32;
+    aovs.set(
+        Aov::MaterialId,
+        Color::new(material_id, material_id, material_id),
+    );
+}
+
+/// Adds a beauty contribution to the light-path AOVs.
+///
+/// `diffuse_fraction` is the share of the contribution that was reflected by
+/// diffuse lobes at the first hit, `None` if it was not reflected there at all.
+pub(crate) fn split_contribution(
+    aovs: &mut AovRecord,
+    contribution: Color,
+    direct: bool,
+    diffuse_fraction: Option<f32>,
+) {
+    match direct {
+        true => aovs.add(Aov::Direct, contribution),
+        false => aovs.add(Aov::Indirect, contribution),
+    }
+    if let Some(fraction) = diffuse_fraction {
+        aovs.add(Aov::Diffuse, fraction * contribution);
+        aovs.add(Aov::Specular, (1.0 - fraction) * contribution);
+    }
+}
+
+/// Names accepted by `integrator_by_name`, besides the `DebugView` names.
+pub const INTEGRATOR_NAMES: [&str; 12] = [
+    "path", "guided", "restir", "volpath", "spectral", "bdpt", "photon", "sppm", "mlt", "whitted",
+    "ao", "direct",
+];
+
+/// Integrator called `name` with settings for a preview of `scene`, `None` for unknown names.
+///
+/// `ao` takes its radius after a colon as in `ao:0.5`. Without one, and for
+/// the photon gather radii, a fraction of the size of the scene is used.
+pub fn integrator_by_name(name: &str, scene: &Scene) -> Option<Box<dyn Integrator>> {
+    let bounds = scene.bounds();
+    let size = match bounds.is_empty() {
+        true => 1.0,
+        false => norm(bounds.diagonal()),
+    };
+    let (name, argument) = match name.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (name, None),
+    };
+    if argument.is_some() && name != "ao" {
+        return None;
+    }
+
+    let integrator: Box<dyn Integrator> = match name {
+        "path" => Box::new(PathIntegrator::new(4, 2)),
+        "guided" => Box::new(PathIntegrator::new(4, 2).with_guiding(5)),
+        "restir" => Box::new(
+            PathIntegrator::new(4, 2)
+                .with_reservoirs(ReservoirSampling::new(8).with_spatial_reuse(4, 10.0)),
+        ),
+        "volpath" => Box::new(VolumetricPathIntegrator::new(4, 2)),
+        "spectral" => Box::new(SpectralPathIntegrator::new(4, 2)),
+        "bdpt" => Box::new(BidirectionalPathIntegrator::new(5)),
+        "photon" => Box::new(PhotonMapIntegrator::new(scene, 200_000, 0.01 * size, 5)),
+        "sppm" => Box::new(ProgressivePhotonMapIntegrator::new(100_000, 0.02 * size, 5)),
+        "mlt" => Box::new(MetropolisIntegrator::new(Box::new(PathIntegrator::new(
+            4, 2,
+        )))),
+        "whitted" => Box::new(WhittedIntegrator::new(8)),
+        "ao" => {
+            let radius = match argument {
+                Some(radius) => radius.parse().ok()?,
+                None => 0.1 * size,
+            };
+            Box::new(AmbientOcclusionIntegrator::new(radius))
+        }
+        "direct" => Box::new(DirectLightingIntegrator::new(5)),
+        _ => Box::new(DebugIntegrator::new(DebugView::from_name(name)?)),
+    };
+    Some(integrator)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::emitter::PointLight;
+    use crate::primitives::Parallelogram;
+    use crate::sensor::{PinholeCamera, Sensor};
+    use std::sync::Arc;
+
+    pub(crate) fn diffuse(albedo: f32) -> Box<dyn Material> {
+        Box::new(DiffuseMaterial {
+            albedo: Box::new(Color::new(albedo, albedo, albedo)),
+        })
+    }
+
+    /// A unit sphere of `material` straight in front of the camera.
+    pub(crate) fn ball(material: Box<dyn Material>) -> Scene {
+        let mut scene = Scene::new();
+        scene.add_shape(Box::new(Sphere::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: -3.0,
+            },
+            1.0,
+            material,
+        )));
+        scene
+    }
+
+    /// A sphere on a floor, lit by a small lamp facing down.
+    pub(crate) fn lamp_scene() -> Scene {
+        let mut scene = ball(diffuse(0.7));
+        scene.background_color = Color::new(0.0, 0.0, 0.0);
+        scene.add_shape(Box::new(Parallelogram::rectangle(
+            Point {
+                x: 0.0,
+                y: -1.0,
+                z: -3.0,
+            },
+            Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 6.0,
+            },
+            Vector {
+                x: 6.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            diffuse(0.5),
+        )));
+        let lamp = Parallelogram::rectangle(
+            Point {
+                x: 0.5,
+                y: 2.0,
+                z: -2.5,
+            },
+            Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            diffuse(0.0),
+        );
+        scene.add_area_light(Arc::new(lamp), Color::new(5.0, 5.0, 5.0));
+        scene
+    }
+
+    /// Pixels of a small render of `scene`.
+    pub(crate) fn render(
+        integrator: &dyn Integrator,
+        scene: &Scene,
+        spp: usize,
+        seed: u64,
+        num_threads: usize,
+    ) -> Vec<Color> {
+        let camera = PinholeCamera::new(Sensor::zero(16, 12), 60.0);
+        integrator.render(&camera, scene, spp, seed, num_threads);
+        camera
+            .get_pixels()
+            .iter()
+            .map(|pixel| *pixel.color.read().unwrap())
+            .collect()
+    }
+
+    fn test_scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.add_shape(Box::new(Sphere::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: -3.0,
+            },
+            1.0,
+            Box::new(DiffuseMaterial {
+                albedo: Box::new(Color::new(0.8, 0.5, 0.2)),
+            }),
+        )));
+        scene.add_light(Box::new(PointLight::new(
+            Point {
+                x: 1.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            1.0,
+        )));
+        scene
+    }
+
+    fn render_with_threads(num_threads: usize, seed: u64) -> Vec<(f32, f32, f32)> {
+        let camera = PinholeCamera::new(Sensor::zero(16, 12), 60.0);
+        let integrator = PathIntegrator::new(4, 2);
+        integrator.render(&camera, &test_scene(), 4, seed, num_threads);
+        camera
+            .get_pixels()
+            .iter()
+            .map(|pixel| {
+                let c = *pixel.color.read().unwrap();
+                (c.r, c.g, c.b)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn renders_deterministically() {
+        let single = render_with_threads(1, 7);
+        let multi = render_with_threads(5, 7);
+        assert_eq!(single, multi);
+
+        let other_seed = render_with_threads(5, 8);
+        assert_ne!(single, other_seed);
+    }
+
+    #[test]
+    fn point_lights_agree() {
+        let mut scene = test_scene();
+        scene.background_color = Color::new(0.0, 0.0, 0.0);
+        let mean = |integrator: &dyn Integrator, spp: usize| {
+            let camera = PinholeCamera::new(Sensor::zero(16, 12), 60.0);
+            integrator.render(&camera, &scene, spp, 0, 4);
+            let pixels = camera.get_pixels();
+            pixels
+                .iter()
+                .map(|pixel| pixel.color.read().unwrap().g)
+                .sum::<f32>()
+                / pixels.len() as f32
+        };
+
+        let expected = mean(&PathIntegrator::new(4, 2), 64);
+        assert!(expected > 0.001, "{expected}");
+        let integrators: [(&str, Box<dyn Integrator>); 6] = [
+            ("whitted", Box::new(WhittedIntegrator::new(4))),
+            ("direct", Box::new(DirectLightingIntegrator::new(4))),
+            ("volpath", Box::new(VolumetricPathIntegrator::new(4, 2))),
+            ("bdpt", Box::new(BidirectionalPathIntegrator::new(4))),
+            (
+                "sppm",
+                Box::new(ProgressivePhotonMapIntegrator::new(1000, 0.1, 4)),
+            ),
+            (
+                "restir",
+                Box::new(PathIntegrator::new(4, 2).with_reservoirs(ReservoirSampling::new(4))),
+            ),
+        ];
+        for (name, integrator) in integrators {
+            let estimate = mean(integrator.as_ref(), 64);
+            assert!(
+                (estimate - expected).abs() < 0.02 * expected,
+                "{name} {estimate} {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn writes_aovs() {
+        let sensor = Sensor::zero(16, 12).with_aovs(&Aov::ALL);
+        let camera = PinholeCamera::new(sensor, 60.0);
+        let integrator = PathIntegrator::new(4, 2);
+        integrator.render(&camera, &test_scene(), 4, 0, 2);
+
+        let sensor = camera.get_sensor();
+        let at = |aov: Aov, i: usize, j: usize| {
+            *sensor.aov(aov).unwrap().pixels[j * sensor.width() + i]
+                .read()
+                .unwrap()
+        };
+
+        let depth = at(Aov::Depth, 8, 6);
+        assert!((depth.r - 2.0).abs() < 0.05);
+        assert_eq!(at(Aov::ShapeId, 8, 6).r, 0.0);
+        assert_eq!(at(Aov::ShapeId, 0, 0).r, -1.0);
+        assert_eq!(at(Aov::Albedo, 8, 6).r, 0.8);
+
+        for (index, pixel) in camera.get_pixels().iter().enumerate() {
+            let (i, j) = (index % sensor.width(), index / sensor.width());
+            let beauty = *pixel.color.read().unwrap();
+            let split = at(Aov::Direct, i, j) + at(Aov::Indirect, i, j);
+            assert!((beauty.g - split.g).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn finds_integrators_by_name() {
+        let scene = test_scene();
+        for name in INTEGRATOR_NAMES.into_iter().chain(["ao:0.5"]) {
+            assert!(integrator_by_name(name, &scene).is_some(), "{name}");
+        }
+        for view in DebugView::ALL {
+            assert!(integrator_by_name(view.name(), &scene).is_some());
+        }
+        for name in ["", "pathtracer", "ao:far", "path:4"] {
+            assert!(integrator_by_name(name, &scene).is_none(), "{name}");
+        }
+    }
+}
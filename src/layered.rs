@@ -0,0 +1,530 @@
+use crate::material::*;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::Color;
+use crate::texture::Texture;
+
+use rand::Rng;
+
+/// Blend of two materials, e.g. rust on metal masked by a texture.
+///
+/// Directions are sampled from `b` with probability `weight` and from `a`
+/// otherwise, and evaluated with the pdf of both. Mixing a delta reflector
+/// with a smooth material keeps the delta lobe sharp.
+pub struct MixMaterial {
+    pub a: Box<dyn Material>,
+    pub b: Box<dyn Material>,
+    /// Share of `b`, averaged over the color channels.
+    pub weight: Box<dyn Texture>,
+}
+
+/// Smooth dielectric coat over a base material, like clear varnish over paint.
+///
+/// The coat reflects by the Fresnel equations and refracts light onto the
+/// base and back out. Light reflected back down by the coat from inside is
+/// not followed, its share is put back by a factor on the base, so a white
+/// diffuse base stays white. The reflection off the coat is a delta lobe,
+/// which `bsdf_eval` leaves out and only `bsdf_scatter` samples.
+pub struct CoatedMaterial {
+    pub base: Box<dyn Material>,
+    /// Index of refraction of the coat relative to outside.
+    pub ior: f32,
+    /// Optical depth of the coat at normal incidence per color channel, zero for a clear coat.
+    pub absorption: Color,
+}
+
+impl MixMaterial {
+    pub fn new(
+        a: Box<dyn Material>,
+        b: Box<dyn Material>,
+        weight: Box<dyn Texture>,
+    ) -> MixMaterial {
+        MixMaterial { a, b, weight }
+    }
+
+    /// Both materials with their share at `si`.
+    fn parts(&self, si: &SurfaceInteraction) -> [(&dyn Material, f32); 2] {
+        let w = f32::clamp(self.weight.eval(si).average(), 0.0, 1.0);
+        [(self.a.as_ref(), 1.0 - w), (self.b.as_ref(), w)]
+    }
+}
+
+impl Material for MixMaterial {
+    /// Smooth lobes of both materials, delta reflectors only add delta lobes.
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        self.parts(si)
+            .iter()
+            .filter(|&&(material, w)| w > 0.0 && !material.is_delta_reflector())
+            .fold(
+                BsdfSample {
+                    radiance: Color::new(0.0, 0.0, 0.0),
+                    pdf: 0.0,
+                },
+                |sum, &(material, w)| {
+                    let sample = material.bsdf_eval(si, wo);
+                    BsdfSample {
+                        radiance: sum.radiance + w * sample.radiance,
+                        pdf: sum.pdf + w * sample.pdf,
+                    }
+                },
+            )
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        let [(a, _), (b, w)] = self.parts(si);
+        match sampler.gen::<f32>() < w {
+            true => b.bsdf_sample(si, sampler),
+            false => a.bsdf_sample(si, sampler),
+        }
+    }
+
+    fn bsdf_scatter(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> ScatterSample {
+        let [(a, wa), (b, wb)] = self.parts(si);
+        let (material, w) = match sampler.gen::<f32>() < wb {
+            true => (b, wb),
+            false => (a, wa),
+        };
+        let sample = material.bsdf_scatter(si, sampler);
+        if sample.delta {
+            // the pdf of a delta lobe is a probability, it cannot be added to densities
+            return ScatterSample {
+                radiance: w * sample.radiance,
+                pdf: w * sample.pdf,
+                ..sample
+            };
+        }
+        let BsdfSample { radiance, pdf } = self.bsdf_eval(si, sample.wo);
+        ScatterSample {
+            wo: sample.wo,
+            radiance,
+            pdf,
+            delta: false,
+        }
+    }
+
+    fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        self.bsdf_eval(si, wo).pdf
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        self.a.is_delta_reflector() && self.b.is_delta_reflector()
+    }
+
+    fn albedo(&self, si: &SurfaceInteraction) -> Color {
+        let [(a, wa), (b, wb)] = self.parts(si);
+        wa * a.albedo(si) + wb * b.albedo(si)
+    }
+
+    fn diffuse_fraction(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        let (diffuse, total) =
+            self.parts(si)
+                .iter()
+                .fold((0.0, 0.0), |(diffuse, total), &(material, w)| {
+                    let f = w * material.bsdf_eval(si, wo).radiance.luminance();
+                    (diffuse + f * material.diffuse_fraction(si, wo), total + f)
+                });
+        match total > 0.0 {
+            true => diffuse / total,
+            false => 0.0,
+        }
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.a.is_dispersive() || self.b.is_dispersive()
+    }
+
+    fn delta_lobes(&self, si: &SurfaceInteraction) -> Vec<(Vector, Color)> {
+        self.parts(si)
+            .iter()
+            .filter(|&&(_, w)| w > 0.0)
+            .flat_map(|&(material, w)| {
+                material
+                    .delta_lobes(si)
+                    .into_iter()
+                    .map(move |(wo, weight)| (wo, w * weight))
+            })
+            .collect()
+    }
+}
+
+/// Hemispherical average of the Fresnel reflectance for light arriving from
+/// inside a dielectric of relative index `eta`.
+fn internal_diffuse_reflectance(eta: f32) -> f32 {
+    // fit by Egan and Hilgeman
+    -1.440 / (eta * eta) + 0.710 / eta + 0.668 + 0.0636 * eta
+}
+
+impl CoatedMaterial {
+    pub fn new(base: Box<dyn Material>, ior: f32) -> CoatedMaterial {
+        CoatedMaterial {
+            base,
+            ior,
+            absorption: Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Tints light crossing the coat, `absorption` being the optical depth at normal incidence.
+    pub fn with_absorption(mut self, absorption: Color) -> CoatedMaterial {
+        self.absorption = absorption;
+        self
+    }
+
+    /// Share of light kept after crossing the coat at angles with cosines `cos_i` and `cos_o`.
+    fn transmittance(&self, cos_i: f32, cos_o: f32) -> Color {
+        let length = 1.0 / f32::max(cos_i, 1e-4) + 1.0 / f32::max(cos_o, 1e-4);
+        Color::new(
+            f32::exp(-self.absorption.r * length),
+            f32::exp(-self.absorption.g * length),
+            f32::exp(-self.absorption.b * length),
+        )
+    }
+
+    /// Interaction seen by the base, lit from the direction `si.wi` refracts into, and
+    /// the Fresnel reflectance of the coat. `None` when `si.wi` is below the surface.
+    fn below<'a>(&self, si: &SurfaceInteraction<'a>) -> Option<(SurfaceInteraction<'a>, f32)> {
+        let cos = dot(si.wi, si.normal);
+        if cos <= 0.0 {
+            return None;
+        }
+        let mut inner = si.clone();
+        inner.wi = -refract(si.wi, si.normal, self.ior)?;
+        Some((inner, fresnel_dielectric(cos, self.ior)))
+    }
+
+    /// Outside direction of light leaving the base along `inner`, its Fresnel
+    /// transmittance and the cosine of `inner`.
+    ///
+    /// Light going down through the base passes unchanged. `None` if the coat
+    /// reflects it back inside.
+    fn exit(&self, si: &SurfaceInteraction, inner: Vector) -> Option<(Vector, f32, f32)> {
+        let cos = dot(inner, si.normal);
+        if cos <= 0.0 {
+            return Some((inner, 1.0, -cos));
+        }
+        let wo = refract(-inner, -si.normal, 1.0 / self.ior)?;
+        Some((wo, 1.0 - fresnel_dielectric(cos, 1.0 / self.ior), cos))
+    }
+}
+
+impl Material for CoatedMaterial {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let Some((inner, f)) = self.below(si) else {
+            return self.base.bsdf_eval(si, wo);
+        };
+        let wo = wo.normalize();
+        let cos_o = dot(wo, si.normal);
+        // direction inside the coat that leaves along wo
+        let inner_wo = match cos_o > 0.0 {
+            true => refract(wo, si.normal, self.ior).map(|wt| -wt),
+            false => Some(wo),
+        };
+        let Some(inner_wo) = inner_wo else {
+            return BsdfSample {
+                radiance: Color::new(0.0, 0.0, 0.0),
+                pdf: 0.0,
+            };
+        };
+        let cos_i = dot(inner.wi, si.normal);
+        let (t_o, cos_inner) = match cos_o > 0.0 {
+            true => {
+                let cos = dot(inner_wo, si.normal);
+                (1.0 - fresnel_dielectric(cos, 1.0 / self.ior), cos)
+            }
+            false => (1.0, f32::abs(cos_o)),
+        };
+        let absorbed = self.transmittance(cos_i, cos_inner);
+        let base = self.base.bsdf_eval(&inner, inner_wo);
+        if self.base.is_delta_reflector() {
+            return BsdfSample {
+                radiance: ((1.0 - f) * t_o) * absorbed * base.radiance,
+                pdf: (1.0 - f) * base.pdf,
+            };
+        }
+
+        // solid angles shrink by cos_o / (eta² cos_inner) leaving the coat
+        let jacobian = match cos_o > 0.0 {
+            true => cos_o / (self.ior * self.ior * f32::max(cos_inner, 1e-4)),
+            false => 1.0,
+        };
+        // light reflected back down by the coat reaches the base again
+        let reflected =
+            internal_diffuse_reflectance(self.ior) * absorbed * self.base.albedo(&inner);
+        let bounces = Color::new(
+            1.0 / (1.0 - reflected.r),
+            1.0 / (1.0 - reflected.g),
+            1.0 / (1.0 - reflected.b),
+        );
+        // base.radiance holds f_base·cos_inner, the coated lobe f_base·cos_o / eta²
+        let scale = (1.0 - f) * t_o * jacobian;
+        BsdfSample {
+            radiance: scale * bounces * absorbed * base.radiance,
+            pdf: (1.0 - f) * base.pdf * jacobian,
+        }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        let Some((inner, f)) = self.below(si) else {
+            return self.base.bsdf_sample(si, sampler);
+        };
+        if sampler.gen::<f32>() < f {
+            return -reflect(si.wi, si.normal);
+        }
+        let inner_wo = self.base.bsdf_sample(&inner, sampler);
+        match self.exit(si, inner_wo) {
+            Some((wo, _, _)) => wo,
+            // reflected back inside, which opaque bases give no weight
+            None => -si.normal,
+        }
+    }
+
+    fn bsdf_scatter(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> ScatterSample {
+        let Some((inner, f)) = self.below(si) else {
+            return self.base.bsdf_scatter(si, sampler);
+        };
+        if sampler.gen::<f32>() < f {
+            return ScatterSample {
+                wo: -reflect(si.wi, si.normal),
+                radiance: Color::new(f, f, f),
+                pdf: f,
+                delta: true,
+            };
+        }
+        let sample = self.base.bsdf_scatter(&inner, sampler);
+        match (sample.delta, self.exit(si, sample.wo)) {
+            (true, Some((wo, t_o, cos))) => {
+                let absorbed = self.transmittance(dot(inner.wi, si.normal), cos);
+                ScatterSample {
+                    wo,
+                    radiance: ((1.0 - f) * t_o) * absorbed * sample.radiance,
+                    pdf: (1.0 - f) * sample.pdf,
+                    delta: true,
+                }
+            }
+            (false, Some((wo, _, _))) => {
+                let BsdfSample { radiance, pdf } = self.bsdf_eval(si, wo);
+                ScatterSample {
+                    wo,
+                    radiance,
+                    pdf,
+                    delta: false,
+                }
+            }
+            // reflected back inside, which opaque bases give no weight
+            (_, None) => ScatterSample {
+                wo: -si.normal,
+                radiance: Color::new(0.0, 0.0, 0.0),
+                pdf: 1.0,
+                delta: sample.delta,
+            },
+        }
+    }
+
+    fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        self.bsdf_eval(si, wo).pdf
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        self.base.is_delta_reflector()
+    }
+
+    fn albedo(&self, si: &SurfaceInteraction) -> Color {
+        match self.below(si) {
+            Some((inner, _)) => self.base.albedo(&inner),
+            None => self.base.albedo(si),
+        }
+    }
+
+    fn diffuse_fraction(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        match self.below(si) {
+            Some((inner, _)) => match refract(wo, si.normal, self.ior) {
+                Some(wt) => self.base.diffuse_fraction(&inner, -wt),
+                None => 0.0,
+            },
+            None => self.base.diffuse_fraction(si, wo),
+        }
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.base.is_dispersive()
+    }
+
+    fn delta_lobes(&self, si: &SurfaceInteraction) -> Vec<(Vector, Color)> {
+        let Some((inner, f)) = self.below(si) else {
+            return self.base.delta_lobes(si);
+        };
+        let cos_i = dot(inner.wi, si.normal);
+        let mut lobes = vec![(-reflect(si.wi, si.normal), Color::new(f, f, f))];
+        for (inner_wo, weight) in self.base.delta_lobes(&inner) {
+            if let Some((wo, t_o, cos)) = self.exit(si, inner_wo) {
+                let absorbed = self.transmittance(cos_i, cos);
+                lobes.push((wo, ((1.0 - f) * t_o) * absorbed * weight));
+            }
+        }
+        lobes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::tests::diffuse;
+    use crate::integrator::{Integrator, PathIntegrator};
+    use crate::primitives::Parallelogram;
+    use std::sync::Arc;
+
+    fn mirror() -> Box<ConductorMaterial> {
+        Box::new(ConductorMaterial::from_reflectance(Color::new(
+            0.9, 0.9, 0.9,
+        )))
+    }
+
+    /// Mean radiance seen along a ray hitting the floor of a scene whose
+    /// lamp is in the mirror direction.
+    fn floor_radiance(floor: Box<dyn Material>, n: u64) -> Color {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(0.0, 0.0, 0.0);
+        scene.add_shape(Box::new(InfinitePlane::new(
+            Point {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            floor,
+        )));
+        let lamp = Parallelogram::rectangle(
+            Point {
+                x: 0.0,
+                y: 1.0,
+                z: -3.0,
+            },
+            Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            diffuse(0.0),
+        );
+        scene.add_area_light(Arc::new(lamp), Color::new(5.0, 5.0, 5.0));
+
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: -1.0,
+                z: -1.0,
+            }
+            .normalize(),
+        };
+        let path = PathIntegrator::new(4, 4);
+        let sum = (0..n)
+            .map(|k| {
+                let mut sampler = Sampler::for_sample(0, 0, k as usize);
+                path.sample_radiance(&ray, &scene, &mut sampler)
+            })
+            .fold(Color::new(0.0, 0.0, 0.0), |sum, c| sum + c);
+        (1.0 / n as f32) * sum
+    }
+
+    /// Mean radiance of a unit sphere of `material` under a uniform white sky.
+    fn furnace(material: Box<dyn Material>, n: u64) -> Color {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 1.0, 1.0);
+        scene.add_shape(Box::new(Sphere::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: -3.0,
+            },
+            1.0,
+            material,
+        )));
+        let path = PathIntegrator::new(8, 8);
+        let sum = (0..n)
+            .map(|k| {
+                // rays spread over the visible half of the sphere
+                let mut sampler = Sampler::new(1, k);
+                let (x, y) = (sampler.gen::<f32>() - 0.5, sampler.gen::<f32>() - 0.5);
+                let ray = Ray {
+                    origin: Point {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 0.0,
+                    },
+                    direction: Vector { x, y, z: -3.0 }.normalize(),
+                };
+                path.sample_radiance(&ray, &scene, &mut sampler)
+            })
+            .fold(Color::new(0.0, 0.0, 0.0), |sum, c| sum + c);
+        (1.0 / n as f32) * sum
+    }
+
+    #[test]
+    fn mixes_linearly() {
+        let n = 20_000;
+        let a = floor_radiance(diffuse(0.8), n);
+        let b = floor_radiance(mirror(), n);
+        assert!(b.g > 1.0 && a.g > 0.0, "{a:?} {b:?}");
+        for w in [0.25, 0.5] {
+            let mix = floor_radiance(
+                Box::new(MixMaterial::new(
+                    diffuse(0.8),
+                    mirror(),
+                    Box::new(Color::new(w, w, w)),
+                )),
+                n,
+            );
+            let expected = (1.0 - w) * a.g + w * b.g;
+            assert!(
+                (mix.g - expected).abs() < 0.05 * expected,
+                "{w} {mix:?} {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn coat_reflects_lamp() {
+        let n = 50_000;
+        // the ray meets the floor at 45 degrees and mirrors onto the lamp
+        let f = fresnel_dielectric(f32::sqrt(0.5), 1.5);
+        let clear = floor_radiance(Box::new(CoatedMaterial::new(diffuse(0.0), 1.5)), n);
+        assert!((clear.g - 5.0 * f).abs() < 0.05 * 5.0 * f, "{clear:?} {f}");
+
+        let bare = floor_radiance(diffuse(0.8), n);
+        let coated = floor_radiance(Box::new(CoatedMaterial::new(diffuse(0.8), 1.5)), n);
+        assert!(
+            coated.g > clear.g + 0.5 * bare.g && coated.g < clear.g + bare.g,
+            "{coated:?} {clear:?} {bare:?}"
+        );
+    }
+
+    #[test]
+    fn coat_keeps_energy() {
+        let n = 20_000;
+        let clear = furnace(Box::new(CoatedMaterial::new(diffuse(1.0), 1.5)), n);
+        assert!((clear.g - 1.0).abs() < 0.05, "{clear:?}");
+
+        let tinted = furnace(
+            Box::new(
+                CoatedMaterial::new(diffuse(1.0), 1.5).with_absorption(Color::new(0.0, 0.2, 0.4)),
+            ),
+            n,
+        );
+        assert!(tinted.r > tinted.g && tinted.g > tinted.b, "{tinted:?}");
+        assert!((tinted.r - clear.r).abs() < 0.05, "{tinted:?} {clear:?}");
+    }
+}
@@ -1,13 +1,79 @@
+mod aov;
+mod bdpt;
+mod bvh;
+mod colorspace;
+mod debug;
+mod denoise;
 mod emitter;
+mod gltf;
+mod grid;
+mod guiding;
 mod integrator;
+mod layered;
+mod lightsampler;
 mod material;
 mod math;
+mod medium;
+mod mesh;
+mod mlt;
+mod obj;
+mod pbrt;
+mod photon;
+mod ply;
+mod primitives;
+mod principled;
+mod restir;
+mod sampler;
 mod scene;
 mod sensor;
+mod sheen;
+mod spectral;
+mod spectrum;
+mod subsurface;
+mod texture;
+mod volpath;
+mod whitted;
 
+pub use aov::*;
+pub use bdpt::*;
+pub use bvh::*;
+pub use colorspace::*;
+pub use debug::*;
+pub use denoise::*;
 pub use emitter::*;
+pub use gltf::*;
+pub use grid::*;
+pub use guiding::*;
 pub use integrator::*;
+pub use layered::*;
+pub use lightsampler::*;
 pub use material::*;
 pub use math::*;
+pub use medium::*;
+pub use mesh::*;
+pub use mlt::*;
+pub use pbrt::*;
+pub use photon::*;
+pub use primitives::*;
+pub use principled::*;
+pub use restir::*;
+pub use sampler::*;
 pub use scene::*;
 pub use sensor::*;
+pub use sheen::*;
+pub use spectral::*;
+pub use spectrum::*;
+pub use subsurface::*;
+pub use texture::*;
+pub use volpath::*;
+pub use whitted::*;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+
+    /// A file name in the temp directory that concurrent test runs do not share.
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("walnut_{}_{name}", std::process::id()))
+    }
+}
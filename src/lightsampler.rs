@@ -0,0 +1,582 @@
+use crate::emitter::LightBounds;
+use crate::math::*;
+use crate::scene::Scene;
+
+use std::f32::consts::PI;
+
+/// Picks one light of `Scene::lights` to sample at a shading point.
+///
+/// Integrators divide the contribution of the picked light by its
+/// probability, which keeps them unbiased as long as every light that can
+/// reach the point has a nonzero probability.
+pub trait LightSampler: Send + Sync {
+    /// Index of a light for shading `reference` with `normal` and its probability,
+    /// `None` if no light can contribute. `u` is uniform in [0, 1).
+    fn sample(&self, reference: Point, normal: Vector, u: f32) -> Option<(usize, f32)>;
+    /// Probability of `sample` picking light `index`.
+    fn pmf(&self, reference: Point, normal: Vector, index: usize) -> f32;
+    /// Number of lights of the scene the sampler was built for.
+    fn num_lights(&self) -> usize;
+}
+
+/// Every light is equally likely.
+pub struct UniformLightSampler {
+    lights: usize,
+}
+
+/// Lights are picked proportionally to their power.
+pub struct PowerLightSampler {
+    table: Option<AliasTable>,
+}
+
+/// Bounding volume hierarchy over the lights, descended by the estimated
+/// contribution of each subtree to the shading point.
+///
+/// The estimate takes the power, distance and emission cone of a subtree and
+/// the angle to the surface normal into account, following the light BVH of
+/// pbrt-v4. Nodes are split at the median centroid along their largest axis.
+/// Lights at infinity are kept out of the tree and picked uniformly, with the
+/// whole tree counting as one more of them.
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+    /// Choices leading from the root to the leaf of each light, bit `k` is set
+    /// when the second child is taken at depth `k`. `None` for lights outside the tree.
+    trails: Vec<Option<u64>>,
+    infinite: Vec<usize>,
+}
+
+struct LightNode {
+    bounds: LightBounds,
+    /// The light of a leaf, or the second child of an interior node whose
+    /// first child directly follows it.
+    index: usize,
+    leaf: bool,
+}
+
+/// Samples indices in constant time with probabilities proportional to fixed weights.
+///
+/// Built with Vose's method, every bin keeps its own index with probability
+/// `q` and gives way to its alias otherwise.
+pub struct AliasTable {
+    bins: Vec<AliasBin>,
+}
+
+struct AliasBin {
+    q: f32,
+    pmf: f32,
+    alias: usize,
+}
+
+impl AliasTable {
+    /// Table over nonnegative `weights`, uniform if they are all zero.
+    pub fn new(weights: &[f32]) -> AliasTable {
+        let total: f64 = weights.iter().map(|&w| f64::from(w.max(0.0))).sum();
+        let n = weights.len();
+        let pmf: Vec<f64> = weights
+            .iter()
+            .map(|&w| match total > 0.0 {
+                true => f64::from(w.max(0.0)) / total,
+                false => 1.0 / n as f64,
+            })
+            .collect();
+
+        let mut q: Vec<f64> = pmf.iter().map(|p| p * n as f64).collect();
+        let mut alias: Vec<usize> = (0..n).collect();
+        let (mut under, mut over): (Vec<usize>, Vec<usize>) = (0..n).partition(|&k| q[k] < 1.0);
+        while let (Some(&small), Some(&large)) = (under.last(), over.last()) {
+            under.pop();
+            alias[small] = large;
+            q[large] -= 1.0 - q[small];
+            if q[large] < 1.0 {
+                over.pop();
+                under.push(large);
+            }
+        }
+        // leftovers are off from one by rounding only
+        for k in under.into_iter().chain(over) {
+            q[k] = 1.0;
+        }
+
+        AliasTable {
+            bins: (0..n)
+                .map(|k| AliasBin {
+                    q: q[k] as f32,
+                    pmf: pmf[k] as f32,
+                    alias: alias[k],
+                })
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bins.is_empty()
+    }
+
+    /// Index picked by `u` in [0, 1) and its probability.
+    pub fn sample(&self, u: f32) -> (usize, f32) {
+        let scaled = u * self.bins.len() as f32;
+        let offset = usize::min(scaled as usize, self.bins.len() - 1);
+        let up = f32::min(scaled - offset as f32, 1.0 - f32::EPSILON);
+        let index = match up < self.bins[offset].q {
+            true => offset,
+            false => self.bins[offset].alias,
+        };
+        (index, self.bins[index].pmf)
+    }
+
+    pub fn pmf(&self, index: usize) -> f32 {
+        self.bins[index].pmf
+    }
+}
+
+impl UniformLightSampler {
+    pub fn new(scene: &Scene) -> UniformLightSampler {
+        UniformLightSampler {
+            lights: scene.lights.len(),
+        }
+    }
+}
+
+impl LightSampler for UniformLightSampler {
+    fn sample(&self, _reference: Point, _normal: Vector, u: f32) -> Option<(usize, f32)> {
+        match self.lights {
+            0 => None,
+            n => Some((usize::min((u * n as f32) as usize, n - 1), 1.0 / n as f32)),
+        }
+    }
+
+    fn pmf(&self, _reference: Point, _normal: Vector, index: usize) -> f32 {
+        match index < self.lights {
+            true => 1.0 / self.lights as f32,
+            false => 0.0,
+        }
+    }
+
+    fn num_lights(&self) -> usize {
+        self.lights
+    }
+}
+
+impl PowerLightSampler {
+    pub fn new(scene: &Scene) -> PowerLightSampler {
+        // lights at infinity cover the lights too when no finite shape is around
+        let bounds = scene.lights.iter().fold(scene.bounds(), |b, light| {
+            light
+                .light_bounds()
+                .map_or(b, |light| b.union(&light.bounds))
+        });
+        let powers: Vec<f32> = scene
+            .lights
+            .iter()
+            .map(|light| light.power(&bounds).average())
+            .collect();
+        PowerLightSampler {
+            table: match powers.is_empty() {
+                true => None,
+                false => Some(AliasTable::new(&powers)),
+            },
+        }
+    }
+}
+
+impl LightSampler for PowerLightSampler {
+    fn sample(&self, _reference: Point, _normal: Vector, u: f32) -> Option<(usize, f32)> {
+        let (index, pmf) = self.table.as_ref()?.sample(u);
+        match pmf > 0.0 {
+            true => Some((index, pmf)),
+            false => None,
+        }
+    }
+
+    fn pmf(&self, _reference: Point, _normal: Vector, index: usize) -> f32 {
+        self.table
+            .as_ref()
+            .map_or(0.0, |table| match index < table.len() {
+                true => table.pmf(index),
+                false => 0.0,
+            })
+    }
+
+    fn num_lights(&self) -> usize {
+        self.table.as_ref().map_or(0, AliasTable::len)
+    }
+}
+
+/// Smallest cone containing the cones around `a` and `b` with half angles `theta_a` and `theta_b`.
+fn cone_union(a: Vector, theta_a: f32, b: Vector, theta_b: f32) -> (Vector, f32) {
+    let ((a, theta_a), (b, theta_b)) = match theta_a >= theta_b {
+        true => ((a, theta_a), (b, theta_b)),
+        false => ((b, theta_b), (a, theta_a)),
+    };
+    let theta_d = f32::acos(f32::clamp(dot(a, b), -1.0, 1.0));
+    if f32::min(theta_d + theta_b, PI) <= theta_a {
+        return (a, theta_a);
+    }
+
+    let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+    let axis = cross(a, b);
+    if theta_o >= PI || norm2(axis) == 0.0 {
+        return (a, PI);
+    }
+    let rotation = Transform::rotate((theta_o - theta_a).to_degrees(), axis);
+    (rotation.vector(a).normalize(), theta_o)
+}
+
+fn union(a: &LightBounds, b: &LightBounds) -> LightBounds {
+    let (direction, theta_o) = cone_union(a.direction, a.theta_o, b.direction, b.theta_o);
+    LightBounds {
+        bounds: a.bounds.union(&b.bounds),
+        power: a.power + b.power,
+        direction,
+        theta_o,
+        theta_e: f32::max(a.theta_e, b.theta_e),
+    }
+}
+
+/// Estimated contribution of the lights within `b` to `reference` on a surface with `normal`.
+fn importance(b: &LightBounds, reference: Point, normal: Vector) -> f32 {
+    let center = b.bounds.centroid();
+    let radius = 0.5 * norm(b.bounds.diagonal());
+    let to_reference = reference - center;
+    let d2 = f32::max(norm2(to_reference), f32::max(radius, 1e-6));
+
+    // half angle of the bounds as seen from the reference point
+    let theta_b = match b.bounds.contains(reference) || norm2(to_reference) <= radius * radius {
+        true => PI,
+        false => f32::asin(f32::min(radius / norm(to_reference), 1.0)),
+    };
+    let wi = to_reference.normalize();
+
+    let theta_w = f32::acos(f32::clamp(dot(b.direction, wi), -1.0, 1.0));
+    let theta_p = f32::max(0.0, theta_w - b.theta_o - theta_b);
+    if theta_p >= b.theta_e {
+        return 0.0;
+    }
+    // kept positive so that wide spot lights are never ruled out where they shine
+    let mut importance = b.power * f32::max(f32::cos(theta_p), 1e-3) / d2;
+
+    if norm2(normal) > 0.0 {
+        let theta_i = f32::acos(f32::min(f32::abs(dot(wi, normal.normalize())), 1.0));
+        importance *= f32::cos(f32::max(0.0, theta_i - theta_b));
+    }
+    f32::max(importance, 0.0)
+}
+
+impl LightTree {
+    pub fn new(scene: &Scene) -> LightTree {
+        let mut tree = LightTree {
+            nodes: Vec::new(),
+            trails: vec![None; scene.lights.len()],
+            infinite: Vec::new(),
+        };
+        let mut bounded = Vec::new();
+        for (index, light) in scene.lights.iter().enumerate() {
+            match light.light_bounds() {
+                Some(bounds) if bounds.power > 0.0 => bounded.push((index, bounds)),
+                Some(_) => {}
+                None => tree.infinite.push(index),
+            }
+        }
+        if !bounded.is_empty() {
+            tree.build(&mut bounded, 0, 0);
+        }
+        tree
+    }
+
+    /// Builds the subtree over `lights` reached by `trail` at `depth` and returns its node index.
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+        let node = self.nodes.len();
+        if let [(index, bounds)] = lights {
+            self.nodes.push(LightNode {
+                bounds: *bounds,
+                index: *index,
+                leaf: true,
+            });
+            self.trails[*index] = Some(trail);
+            return node;
+        }
+
+        let bounds = lights[1..]
+            .iter()
+            .fold(lights[0].1, |b, (_, other)| union(&b, other));
+        self.nodes.push(LightNode {
+            bounds,
+            index: 0,
+            leaf: false,
+        });
+
+        let centroids = lights.iter().fold(Bounds3::empty(), |b, (_, light)| {
+            b.union_point(light.bounds.centroid())
+        });
+        let extent = centroids.diagonal();
+        let key = |p: Point| match (
+            extent.x >= extent.y && extent.x >= extent.z,
+            extent.y >= extent.z,
+        ) {
+            (true, _) => p.x,
+            (false, true) => p.y,
+            (false, false) => p.z,
+        };
+        lights.sort_by(|(_, a), (_, b)| {
+            key(a.bounds.centroid()).total_cmp(&key(b.bounds.centroid()))
+        });
+
+        let (first, second) = lights.split_at_mut(lights.len() / 2);
+        self.build(first, trail, depth + 1);
+        let second = self.build(second, trail | 1 << depth, depth + 1);
+        self.nodes[node].index = second;
+        node
+    }
+
+    /// Probability of picking the tree rather than one of the lights at infinity.
+    fn tree_probability(&self) -> f32 {
+        let tree = match self.nodes.is_empty() {
+            true => 0.0,
+            false => 1.0,
+        };
+        tree / (tree + self.infinite.len() as f32)
+    }
+
+    /// Probabilities of descending into the first and second child of `node`.
+    fn child_probabilities(&self, node: usize, reference: Point, normal: Vector) -> Option<f32> {
+        let first = importance(&self.nodes[node + 1].bounds, reference, normal);
+        let second = importance(
+            &self.nodes[self.nodes[node].index].bounds,
+            reference,
+            normal,
+        );
+        match first + second > 0.0 {
+            true => Some(first / (first + second)),
+            false => None,
+        }
+    }
+}
+
+impl LightSampler for LightTree {
+    fn sample(&self, reference: Point, normal: Vector, mut u: f32) -> Option<(usize, f32)> {
+        let p_tree = self.tree_probability();
+        if u >= p_tree {
+            let n = self.infinite.len();
+            u = (u - p_tree) / (1.0 - p_tree);
+            let k = usize::min((u * n as f32) as usize, n - 1);
+            return Some((self.infinite[k], (1.0 - p_tree) / n as f32));
+        }
+        u /= p_tree;
+
+        let mut node = 0;
+        let mut pmf = p_tree;
+        while !self.nodes[node].leaf {
+            let p_first = self.child_probabilities(node, reference, normal)?;
+            (node, pmf, u) = match u < p_first {
+                true => (node + 1, pmf * p_first, u / p_first),
+                false => (
+                    self.nodes[node].index,
+                    pmf * (1.0 - p_first),
+                    (u - p_first) / (1.0 - p_first),
+                ),
+            };
+            u = f32::min(u, 1.0 - f32::EPSILON);
+        }
+        // a single light in the tree is only taken if it can contribute at all
+        if node == 0 && importance(&self.nodes[0].bounds, reference, normal) <= 0.0 {
+            return None;
+        }
+        Some((self.nodes[node].index, pmf))
+    }
+
+    fn pmf(&self, reference: Point, normal: Vector, index: usize) -> f32 {
+        let p_tree = self.tree_probability();
+        if self.infinite.contains(&index) {
+            return (1.0 - p_tree) / self.infinite.len() as f32;
+        }
+        let Some(Some(trail)) = self.trails.get(index) else {
+            return 0.0;
+        };
+
+        let mut node = 0;
+        let mut pmf = p_tree;
+        let mut depth = 0;
+        while !self.nodes[node].leaf {
+            let Some(p_first) = self.child_probabilities(node, reference, normal) else {
+                return 0.0;
+            };
+            (node, pmf) = match trail >> depth & 1 {
+                0 => (node + 1, pmf * p_first),
+                _ => (self.nodes[node].index, pmf * (1.0 - p_first)),
+            };
+            depth += 1;
+        }
+        if node == 0 && importance(&self.nodes[0].bounds, reference, normal) <= 0.0 {
+            return 0.0;
+        }
+        pmf
+    }
+
+    fn num_lights(&self) -> usize {
+        self.trails.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emitter::*;
+    use crate::integrator::{Integrator, PathIntegrator};
+    use crate::material::DiffuseMaterial;
+    use crate::sampler::Sampler;
+    use crate::scene::InfinitePlane;
+    use crate::sensor::Color;
+    use rand::Rng;
+
+    fn many_lights() -> Scene {
+        let mut scene = Scene::new();
+        scene.add_shape(Box::new(InfinitePlane::new(
+            Point {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            Box::new(DiffuseMaterial {
+                albedo: Box::new(Color::new(0.5, 0.5, 0.5)),
+            }),
+        )));
+        let mut sampler = Sampler::new(3, 0);
+        for k in 0..40 {
+            let position = Point {
+                x: 8.0 * sampler.gen::<f32>() - 4.0,
+                y: 2.0 * sampler.gen::<f32>() - 0.5,
+                z: 8.0 * sampler.gen::<f32>() - 4.0,
+            };
+            let intensity = 0.05 + 0.2 * sampler.gen::<f32>();
+            match k % 8 {
+                0 => scene.add_light(Box::new(SpotLight::new(
+                    position,
+                    Vector {
+                        x: 0.0,
+                        y: -1.0,
+                        z: 0.2,
+                    },
+                    Color::new(intensity, intensity, intensity),
+                    20.0,
+                    40.0,
+                ))),
+                _ => scene.add_light(Box::new(PointLight::new(position, intensity))),
+            }
+        }
+        scene.add_light(Box::new(DirectionalLight::new(
+            Vector {
+                x: 0.3,
+                y: -1.0,
+                z: 0.0,
+            },
+            Color::new(0.2, 0.2, 0.2),
+        )));
+        scene
+    }
+
+    #[test]
+    fn alias_table_matches_weights() {
+        let table = AliasTable::new(&[1.0, 0.0, 3.0, 6.0]);
+        let mut counts = [0; 4];
+        let mut sampler = Sampler::new(0, 0);
+        let n = 100_000;
+        for _ in 0..n {
+            let (index, pmf) = table.sample(sampler.gen());
+            assert_eq!(pmf, table.pmf(index));
+            counts[index] += 1;
+        }
+        assert_eq!(counts[1], 0);
+        for (index, count) in counts.into_iter().enumerate() {
+            assert!((count as f32 / n as f32 - table.pmf(index)).abs() < 0.01);
+        }
+        assert_eq!(AliasTable::new(&[0.0, 0.0]).pmf(1), 0.5);
+    }
+
+    #[test]
+    fn tree_pmf_matches_sampling() {
+        let scene = many_lights();
+        let tree = LightTree::new(&scene);
+        let reference = Point {
+            x: 0.5,
+            y: -1.0,
+            z: 0.2,
+        };
+        let normal = Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+
+        let total: f32 = (0..scene.lights.len())
+            .map(|index| tree.pmf(reference, normal, index))
+            .sum();
+        assert!((total - 1.0).abs() < 1e-4, "{total}");
+
+        let mut counts = vec![0; scene.lights.len()];
+        let mut sampler = Sampler::new(1, 0);
+        let n = 200_000;
+        for _ in 0..n {
+            let (index, pmf) = tree.sample(reference, normal, sampler.gen()).unwrap();
+            assert!((pmf - tree.pmf(reference, normal, index)).abs() < 1e-5);
+            counts[index] += 1;
+        }
+        for (index, count) in counts.into_iter().enumerate() {
+            let pmf = tree.pmf(reference, normal, index);
+            assert!((count as f32 / n as f32 - pmf).abs() < 0.005 + 0.05 * pmf);
+        }
+    }
+
+    #[test]
+    fn strategies_agree() {
+        let scene = many_lights();
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.1,
+                y: -1.0,
+                z: 0.0,
+            }
+            .normalize(),
+        };
+        let mean = |integrator: &PathIntegrator, n: u64| {
+            let sum = (0..n)
+                .map(|k| integrator.sample_radiance(&ray, &scene, &mut Sampler::new(0, k)))
+                .fold(0.0, |sum, c| sum + c.g);
+            sum / n as f32
+        };
+
+        let expected = mean(&PathIntegrator::new(1, 1), 1);
+        assert!(expected > 0.0);
+        let strategies: [Box<dyn LightSampler>; 3] = [
+            Box::new(UniformLightSampler::new(&scene)),
+            Box::new(PowerLightSampler::new(&scene)),
+            Box::new(LightTree::new(&scene)),
+        ];
+        for strategy in strategies {
+            let integrator = PathIntegrator::new(1, 1).with_light_sampler(strategy);
+            let estimate = mean(&integrator, 20_000);
+            assert!(
+                (estimate - expected).abs() < 0.03 * expected,
+                "{estimate} {expected}"
+            );
+        }
+
+        // a sampler built for another scene falls back to every light
+        let other =
+            PathIntegrator::new(1, 1).with_light_sampler(Box::new(LightTree::new(&Scene::new())));
+        assert_eq!(mean(&other, 1), expected);
+    }
+}
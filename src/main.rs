@@ -1,14 +1,18 @@
 use walnut::*;
 
+use std::env;
+use std::process;
 use std::sync::Arc;
 use std::thread;
 use std::time::Instant;
 
 fn main() {
     let spp = 256;
-    let sensor = Sensor::zero(800, 800);
-    let camera = Arc::new(PinholeCamera::new(sensor, 75.0));
-    let integrator = Arc::new(PathIntegrator::new(4, 2));
+    let seed = 0;
+    let sensor = Sensor::zero(800, 800).with_aovs(&Aov::ALL);
+    let camera = PinholeCamera::new(sensor, 75.0);
+    // integrator to render with, e.g. `path`, `bdpt`, `ao:0.5` or `normal`
+    let name = env::args().nth(1).unwrap_or_else(|| "path".to_string());
 
     let mut scene = Scene::new();
 
@@ -40,83 +44,130 @@ fn main() {
         }),
     );
 
-    let ground = InfinitePlane::new(
+    // closed box with an open front, the walls face inwards
+    let ground = Parallelogram::rectangle(
         Point {
             x: 0.0,
             y: -1.0,
-            z: 0.0,
+            z: -1.0,
         },
         Vector {
             x: 0.0,
-            y: 1.0,
+            y: 0.0,
+            z: 6.0,
+        },
+        Vector {
+            x: 8.0,
+            y: 0.0,
             z: 0.0,
         },
         Box::new(DiffuseMaterial {
-            albedo: Color::new(0.5, 0.5, 0.5),
+            albedo: Box::new(Color::new(0.5, 0.5, 0.5)),
         }),
     );
 
-    let back = InfinitePlane::new(
+    let back = Parallelogram::rectangle(
         Point {
             x: 0.0,
-            y: 0.0,
+            y: 1.5,
             z: -4.0,
         },
         Vector {
-            x: 0.0,
+            x: 8.0,
             y: 0.0,
-            z: 1.0,
+            z: 0.0,
+        },
+        Vector {
+            x: 0.0,
+            y: 5.0,
+            z: 0.0,
         },
         Box::new(DiffuseMaterial {
-            albedo: Color::new(0.5, 0.5, 0.5),
+            albedo: Box::new(Color::new(0.5, 0.5, 0.5)),
         }),
     );
 
-    let top = InfinitePlane::new(
+    let top = Parallelogram::rectangle(
         Point {
             x: 0.0,
             y: 4.0,
+            z: -1.0,
+        },
+        Vector {
+            x: 8.0,
+            y: 0.0,
             z: 0.0,
         },
         Vector {
             x: 0.0,
-            y: -1.0,
-            z: 0.0,
+            y: 0.0,
+            z: 6.0,
         },
         Box::new(DiffuseMaterial {
-            albedo: Color::new(0.5, 0.5, 0.5),
+            albedo: Box::new(Color::new(0.5, 0.5, 0.5)),
         }),
     );
 
-    let left = InfinitePlane::new(
+    let left = Parallelogram::rectangle(
         Point {
             x: -4.0,
-            y: 0.0,
+            y: 1.5,
+            z: -1.0,
+        },
+        Vector {
+            x: 0.0,
+            y: 5.0,
             z: 0.0,
         },
         Vector {
-            x: 1.0,
+            x: 0.0,
             y: 0.0,
-            z: 0.0,
+            z: 6.0,
         },
         Box::new(DiffuseMaterial {
-            albedo: Color::new(0.5, 0.5, 0.5),
+            albedo: Box::new(Color::new(0.5, 0.5, 0.5)),
         }),
     );
 
-    let right = InfinitePlane::new(
+    let right = Parallelogram::rectangle(
         Point {
             x: 4.0,
+            y: 1.5,
+            z: -1.0,
+        },
+        Vector {
+            x: 0.0,
             y: 0.0,
+            z: 6.0,
+        },
+        Vector {
+            x: 0.0,
+            y: 5.0,
             z: 0.0,
         },
+        Box::new(DiffuseMaterial {
+            albedo: Box::new(Color::new(0.5, 0.5, 0.5)),
+        }),
+    );
+
+    let lamp = Parallelogram::rectangle(
+        Point {
+            x: 0.0,
+            y: 3.99,
+            z: -2.5,
+        },
         Vector {
-            x: -1.0,
+            x: 1.0,
             y: 0.0,
             z: 0.0,
         },
+        Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        },
         Box::new(DiffuseMaterial {
-            albedo: Color::new(0.5, 0.5, 0.5),
+            albedo: Box::new(Color::new(0.0, 0.0, 0.0)),
         }),
     );
 
@@ -127,6 +178,7 @@ fn main() {
     scene.add_shape(Box::new(top));
     scene.add_shape(Box::new(left));
     scene.add_shape(Box::new(right));
+    scene.add_area_light(Arc::new(lamp), Color::new(4.0, 4.0, 4.0));
 
     let light = PointLight::new(
         Point {
@@ -134,12 +186,19 @@ fn main() {
             y: 1.0,
             z: 1.0,
         },
-        0.8,
+        8.0,
     );
 
     scene.add_light(Box::new(light));
 
-    let scene = Arc::new(scene);
+    let Some(integrator) = integrator_by_name(&name, &scene) else {
+        let views = DebugView::ALL.map(|view| view.name());
+        eprintln!(
+            "Unknown integrator {name}, expected one of {}",
+            [&INTEGRATOR_NAMES[..], &views[..]].concat().join(", ")
+        );
+        process::exit(1);
+    };
 
     let num_cores = match thread::available_parallelism() {
         Ok(num_cores) => num_cores.get(),
@@ -148,36 +207,22 @@ fn main() {
 
     println!("Running {num_cores} tasks");
 
-    let chunks = camera.get_pixels().chunks(num_cores);
-
     let timer = Instant::now();
-    thread::scope(|scope| {
-        for chunk in chunks {
-            let camera = camera.clone();
-            let scene = scene.clone();
-            let integrator = integrator.clone();
-            scope.spawn(move || {
-                for pixel in chunk {
-                    let (i, j) = pixel.position;
-
-                    let radiance = (0..spp)
-                        .into_iter()
-                        .filter_map(|_| camera.sample_ray(i, j))
-                        .map(|ray| integrator.sample_radiance(&ray, &scene))
-                        .reduce(|accum, radiance| accum + radiance);
-
-                    if let Some(radiance) = radiance {
-                        let f = 1.0 / spp as f32;
-                        *pixel.color.write().unwrap() = f * radiance;
-                    }
-                }
-            });
-        }
-    });
+    integrator.render(&camera, &scene, spp, seed, num_cores);
     println!("Finished in {:.3}s", timer.elapsed().as_secs_f32());
 
     camera
         .get_sensor()
         .save("image.png")
         .expect("Error writing file");
+    camera
+        .get_sensor()
+        .save_aovs("image")
+        .expect("Error writing AOVs");
+
+    Denoiser::new(5).denoise(camera.get_sensor());
+    camera
+        .get_sensor()
+        .save("image_denoised.png")
+        .expect("Error writing file");
 }
@@ -1,6 +1,9 @@
 use crate::math::*;
+use crate::sampler::Sampler;
 use crate::scene::*;
 use crate::sensor::Color;
+use crate::subsurface::SubsurfaceMaterial;
+use crate::texture::Texture;
 use rand::Rng;
 
 pub struct BsdfSample {
@@ -8,12 +11,22 @@ pub struct BsdfSample {
     pub pdf: f32,
 }
 
-fn uniform_hemisphere_sample(si: &SurfaceInteraction) -> Vector {
+/// Direction drawn by `Material::bsdf_scatter` together with its value.
+pub struct ScatterSample {
+    pub wo: Vector,
+    /// Value of the sampled lobe, the weight of the lobe for delta lobes.
+    pub radiance: Color,
+    /// Density of `wo`, the probability of picking the lobe for delta lobes.
+    pub pdf: f32,
+    /// Whether `wo` was scattered by a delta lobe.
+    pub delta: bool,
+}
+
+fn uniform_hemisphere_sample(si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
     let (u, v, w) = si.local_frame();
 
-    let mut rng = rand::thread_rng();
-    let e1: f32 = rng.gen();
-    let e2: f32 = rng.gen();
+    let e1: f32 = sampler.gen();
+    let e2: f32 = sampler.gen();
 
     let r = f32::sqrt(1.0 - e1 * e1);
     let phi = 2.0 * std::f32::consts::PI * e2;
@@ -21,12 +34,14 @@ fn uniform_hemisphere_sample(si: &SurfaceInteraction) -> Vector {
     f32::cos(phi) * r * u + f32::sin(phi) * r * v + e1 * w
 }
 
-fn cosine_weighted_hemisphere_sample(si: &SurfaceInteraction) -> Vector {
+pub(crate) fn cosine_weighted_hemisphere_sample(
+    si: &SurfaceInteraction,
+    sampler: &mut Sampler,
+) -> Vector {
     let (u, v, w) = si.local_frame();
 
-    let mut rng = rand::thread_rng();
-    let e1: f32 = rng.gen();
-    let e2: f32 = rng.gen();
+    let e1: f32 = sampler.gen();
+    let e2: f32 = sampler.gen();
 
     let r = f32::sqrt(e1);
     let phi = 2.0 * std::f32::consts::PI * e2;
@@ -36,9 +51,60 @@ fn cosine_weighted_hemisphere_sample(si: &SurfaceInteraction) -> Vector {
 
 pub trait Material: Send + Sync {
     fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample;
-    fn bsdf_sample(&self, si: &SurfaceInteraction) -> Vector;
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector;
     fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32;
     fn is_delta_reflector(&self) -> bool;
+    fn albedo(&self, si: &SurfaceInteraction) -> Color;
+
+    /// Whether the surface is invisible and only delimits a medium.
+    fn is_null(&self) -> bool {
+        false
+    }
+
+    /// Fraction of `bsdf_eval(si, wo)` that stems from diffuse lobes.
+    fn diffuse_fraction(&self, _si: &SurfaceInteraction, _wo: Vector) -> f32 {
+        match self.is_delta_reflector() {
+            true => 0.0,
+            false => 1.0,
+        }
+    }
+
+    /// Whether scattered directions depend on `si.wavelength`.
+    ///
+    /// Spectral integrators can then only follow the hero wavelength.
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+
+    /// Every direction the delta lobes scatter `si.wi` into, with its weight.
+    ///
+    /// Following all of them gives what `bsdf_scatter` finds on average along
+    /// delta lobes. Empty for materials without delta lobes.
+    fn delta_lobes(&self, _si: &SurfaceInteraction) -> Vec<(Vector, Color)> {
+        Vec::new()
+    }
+
+    /// Samples a direction like `bsdf_sample` and evaluates the lobe it was drawn from.
+    ///
+    /// Materials with both delta and smooth lobes override this, as their
+    /// `bsdf_eval` only holds the smooth lobes. Light arriving along delta
+    /// lobes is only found by following them.
+    fn bsdf_scatter(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> ScatterSample {
+        let wo = self.bsdf_sample(si, sampler);
+        let BsdfSample { radiance, pdf } = self.bsdf_eval(si, wo);
+        ScatterSample {
+            wo,
+            radiance,
+            pdf,
+            delta: self.is_delta_reflector(),
+        }
+    }
+
+    /// Scattering inside the shape, for materials that integrators following
+    /// random walks should let light into.
+    fn subsurface(&self) -> Option<&SubsurfaceMaterial> {
+        None
+    }
 }
 
 pub struct BlackBody {}
@@ -50,19 +116,129 @@ pub struct PhongMaterial {
 }
 
 pub struct DiffuseMaterial {
-    pub albedo: Color,
+    pub albedo: Box<dyn Texture>,
+}
+
+/// Rough diffuse surface like clay or concrete, Fujii's improved Oren-Nayar model.
+///
+/// Brightens towards the light and flattens the falloff of Lambertian
+/// shading. `sigma` 0 is Lambertian, values around 1 are very rough.
+pub struct OrenNayarMaterial {
+    pub albedo: Box<dyn Texture>,
+    pub sigma: f32,
+}
+
+/// Invisible boundary of a medium, lets light pass straight through.
+pub struct NullMaterial {}
+
+/// Smooth metal with a complex index of refraction per color channel.
+pub struct ConductorMaterial {
+    pub eta: Color,
+    pub k: Color,
+}
+
+/// Smooth glass-like interface, reflecting and refracting by the Fresnel equations.
+pub struct DielectricMaterial {
+    /// Index of refraction inside relative to outside.
+    pub ior: f32,
+    /// Wavelength dependence of the index, used instead of `ior` on spectral paths.
+    pub dispersion: Option<Dispersion>,
+}
+
+/// Index of refraction as a function of wavelength.
+#[derive(Clone, Copy, Debug)]
+pub enum Dispersion {
+    /// `n = a + b / λ²` with λ in micrometers.
+    Cauchy { a: f32, b: f32 },
+    /// `n² = 1 + Σ b λ² / (λ² - c)` with λ in micrometers.
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    /// Schott N-BK7 crown glass.
+    pub const BK7: Dispersion = Dispersion::Sellmeier {
+        b: [1.039_612, 0.231_792_3, 1.010_469],
+        c: [0.006_000_699, 0.020_017_914, 103.560_65],
+    };
+
+    /// Fused silica.
+    pub const FUSED_SILICA: Dispersion = Dispersion::Sellmeier {
+        b: [0.696_166_3, 0.407_942_6, 0.897_479_4],
+        c: [0.004_679_148, 0.013_512_063, 97.934_0],
+    };
+
+    /// Index of refraction at `wavelength` in nanometers.
+    pub fn ior(&self, wavelength: f32) -> f32 {
+        let l = wavelength * 1e-3;
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / (l * l),
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = (0..3).fold(1.0, |n2, i| n2 + b[i] * l * l / (l * l - c[i]));
+                f32::sqrt(n2)
+            }
+        }
+    }
+}
+
+/// Tolerance for matching a direction with the single one a delta lobe scatters into.
+pub(crate) const DELTA_TOLERANCE: f32 = 1e-4;
+
+/// Fresnel reflectance of unpolarized light at a dielectric interface.
+///
+/// `eta` is the index of refraction on the far side relative to the near one.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = f32::sqrt(1.0 - sin2_t);
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+/// Fresnel reflectance of unpolarized light at a conductor with index `eta + i k`.
+pub fn fresnel_conductor(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let (eta2, k2) = (eta * eta, k * k);
+
+    let t0 = eta2 - k2 - sin2;
+    let a2b2 = f32::sqrt(t0 * t0 + 4.0 * eta2 * k2);
+    let t1 = a2b2 + cos2;
+    let a = f32::sqrt(f32::max(0.0, 0.5 * (a2b2 + t0)));
+    let t2 = 2.0 * cos_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+/// Direction `wi` refracts into through a surface with normal `n` on the side of `wi`.
+///
+/// Returns `None` on total internal reflection.
+pub fn refract(wi: Vector, n: Vector, eta: f32) -> Option<Vector> {
+    let cos_i = dot(wi, n);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = f32::sqrt(1.0 - sin2_t);
+    Some((-1.0 / eta) * wi + (cos_i / eta - cos_t) * n)
 }
 
 impl Material for BlackBody {
     fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
         BsdfSample {
             radiance: Color::new(0.0, 0.0, 0.0),
-            pdf: self.bsdf_pdf(&si, wo),
+            pdf: self.bsdf_pdf(si, wo),
         }
     }
 
-    fn bsdf_sample(&self, si: &SurfaceInteraction) -> Vector {
-        cosine_weighted_hemisphere_sample(&si)
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        cosine_weighted_hemisphere_sample(si, sampler)
     }
 
     fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
@@ -72,27 +248,36 @@ impl Material for BlackBody {
     fn is_delta_reflector(&self) -> bool {
         false
     }
+
+    fn albedo(&self, _si: &SurfaceInteraction) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+}
+
+impl PhongMaterial {
+    fn bsdf_eval_diffuse(&self) -> Color {
+        let kd = self.albedo / (self.albedo + self.specular);
+        let diffuse_norm = 1.0 / std::f32::consts::PI;
+        diffuse_norm * kd
+    }
 }
 
 impl Material for PhongMaterial {
     fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
         let n = si.normal;
-        let kd = self.albedo / (self.albedo + self.specular);
         let ks = self.specular / (self.albedo + self.specular);
         let r_v = -reflect(si.wi, n);
-        let diffuse_norm = 1.0 / std::f32::consts::PI;
-        let diffuse = kd;
         let specular_norm = (self.exponent + 2.0) / (2.0 * std::f32::consts::PI);
         let specular = f32::powf(f32::max(dot(r_v, wo), 0.0), self.exponent) * ks;
 
         BsdfSample {
-            radiance: diffuse_norm * diffuse + specular_norm * specular,
-            pdf: self.bsdf_pdf(&si, wo),
+            radiance: self.bsdf_eval_diffuse() + specular_norm * specular,
+            pdf: self.bsdf_pdf(si, wo),
         }
     }
 
-    fn bsdf_sample(&self, si: &SurfaceInteraction) -> Vector {
-        uniform_hemisphere_sample(si)
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        uniform_hemisphere_sample(si, sampler)
     }
 
     fn bsdf_pdf(&self, _si: &SurfaceInteraction, _wo: Vector) -> f32 {
@@ -102,21 +287,35 @@ impl Material for PhongMaterial {
     fn is_delta_reflector(&self) -> bool {
         false
     }
+
+    fn albedo(&self, _si: &SurfaceInteraction) -> Color {
+        self.albedo
+    }
+
+    fn diffuse_fraction(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        let diffuse = self.bsdf_eval_diffuse().luminance();
+        let total = self.bsdf_eval(si, wo).radiance.luminance();
+        match total > 0.0 {
+            true => diffuse / total,
+            false => 1.0,
+        }
+    }
 }
 
 impl Material for DiffuseMaterial {
     fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
         let n = si.normal;
-        let diffuse = (1.0 / std::f32::consts::PI) * f32::max(dot(n, wo), 0.0) * self.albedo;
+        let diffuse =
+            (1.0 / std::f32::consts::PI) * f32::max(dot(n, wo), 0.0) * self.albedo.eval(si);
 
         BsdfSample {
             radiance: diffuse,
-            pdf: self.bsdf_pdf(&si, wo),
+            pdf: self.bsdf_pdf(si, wo),
         }
     }
 
-    fn bsdf_sample(&self, si: &SurfaceInteraction) -> Vector {
-        cosine_weighted_hemisphere_sample(&si)
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        cosine_weighted_hemisphere_sample(si, sampler)
     }
 
     fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
@@ -126,5 +325,378 @@ impl Material for DiffuseMaterial {
     fn is_delta_reflector(&self) -> bool {
         false
     }
+
+    fn albedo(&self, si: &SurfaceInteraction) -> Color {
+        self.albedo.eval(si)
+    }
+}
+
+impl OrenNayarMaterial {
+    pub fn new(albedo: Box<dyn Texture>, sigma: f32) -> OrenNayarMaterial {
+        OrenNayarMaterial { albedo, sigma }
+    }
+}
+
+impl Material for OrenNayarMaterial {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let n = si.normal;
+        let wo = wo.normalize();
+        let (cos_i, cos_o) = (dot(n, si.wi), dot(n, wo));
+        let radiance = match cos_i > 0.0 && cos_o > 0.0 {
+            true => {
+                let s = dot(si.wi, wo) - cos_i * cos_o;
+                let t = match s > 0.0 {
+                    true => f32::max(cos_i, cos_o),
+                    false => 1.0,
+                };
+                let norm =
+                    std::f32::consts::PI + (std::f32::consts::FRAC_PI_2 - 2.0 / 3.0) * self.sigma;
+                let f = (1.0 + self.sigma * s / t) / norm;
+                (f * cos_o) * self.albedo.eval(si)
+            }
+            false => Color::new(0.0, 0.0, 0.0),
+        };
+        BsdfSample {
+            radiance,
+            pdf: self.bsdf_pdf(si, wo),
+        }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        cosine_weighted_hemisphere_sample(si, sampler)
+    }
+
+    fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        f32::max(dot(si.normal, wo.normalize()), 0.0) / std::f32::consts::PI
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self, si: &SurfaceInteraction) -> Color {
+        self.albedo.eval(si)
+    }
 }
 
+impl Material for NullMaterial {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let passes = dot(wo.normalize(), -si.wi) > 1.0 - 1e-4;
+        BsdfSample {
+            radiance: match passes {
+                true => Color::new(1.0, 1.0, 1.0),
+                false => Color::new(0.0, 0.0, 0.0),
+            },
+            pdf: self.bsdf_pdf(si, wo),
+        }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, _sampler: &mut Sampler) -> Vector {
+        -si.wi
+    }
+
+    fn bsdf_pdf(&self, _si: &SurfaceInteraction, _wo: Vector) -> f32 {
+        1.0
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        true
+    }
+
+    fn albedo(&self, _si: &SurfaceInteraction) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    fn is_null(&self) -> bool {
+        true
+    }
+
+    fn delta_lobes(&self, si: &SurfaceInteraction) -> Vec<(Vector, Color)> {
+        vec![(-si.wi, Color::new(1.0, 1.0, 1.0))]
+    }
+}
+
+impl ConductorMaterial {
+    pub fn new(eta: Color, k: Color) -> ConductorMaterial {
+        ConductorMaterial { eta, k }
+    }
+
+    /// Conductor reflecting `reflectance` at normal incidence.
+    pub fn from_reflectance(reflectance: Color) -> ConductorMaterial {
+        let k = |r: f32| {
+            let r = f32::clamp(r, 0.0, 0.9999);
+            2.0 * f32::sqrt(r) / f32::sqrt(1.0 - r)
+        };
+        ConductorMaterial {
+            eta: Color::new(1.0, 1.0, 1.0),
+            k: Color::new(k(reflectance.r), k(reflectance.g), k(reflectance.b)),
+        }
+    }
+
+    fn reflectance(&self, cos_i: f32) -> Color {
+        Color::new(
+            fresnel_conductor(cos_i, self.eta.r, self.k.r),
+            fresnel_conductor(cos_i, self.eta.g, self.k.g),
+            fresnel_conductor(cos_i, self.eta.b, self.k.b),
+        )
+    }
+}
+
+impl Material for ConductorMaterial {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let mirrored = -reflect(si.wi, si.normal);
+        let radiance = match dot(wo.normalize(), mirrored) > 1.0 - DELTA_TOLERANCE {
+            true => self.reflectance(f32::abs(dot(si.wi, si.normal))),
+            false => Color::new(0.0, 0.0, 0.0),
+        };
+        BsdfSample {
+            radiance,
+            pdf: self.bsdf_pdf(si, wo),
+        }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, _sampler: &mut Sampler) -> Vector {
+        -reflect(si.wi, si.normal)
+    }
+
+    fn bsdf_pdf(&self, _si: &SurfaceInteraction, _wo: Vector) -> f32 {
+        1.0
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        true
+    }
+
+    fn albedo(&self, _si: &SurfaceInteraction) -> Color {
+        self.reflectance(1.0)
+    }
+
+    fn delta_lobes(&self, si: &SurfaceInteraction) -> Vec<(Vector, Color)> {
+        let reflectance = self.reflectance(f32::abs(dot(si.wi, si.normal)));
+        vec![(-reflect(si.wi, si.normal), reflectance)]
+    }
+}
+
+impl DielectricMaterial {
+    pub fn new(ior: f32) -> DielectricMaterial {
+        DielectricMaterial {
+            ior,
+            dispersion: None,
+        }
+    }
+
+    /// Dispersive glass, `ior` becomes the index at the sodium D line.
+    pub fn with_dispersion(dispersion: Dispersion) -> DielectricMaterial {
+        DielectricMaterial {
+            ior: dispersion.ior(589.3),
+            dispersion: Some(dispersion),
+        }
+    }
+
+    /// Index of refraction seen by a path of the given wavelength.
+    fn ior_at(&self, wavelength: Option<f32>) -> f32 {
+        match (self.dispersion, wavelength) {
+            (Some(dispersion), Some(wavelength)) => dispersion.ior(wavelength),
+            _ => self.ior,
+        }
+    }
+
+    /// Normal facing `wi`, relative index of refraction and Fresnel reflectance.
+    fn interface(&self, si: &SurfaceInteraction) -> (Vector, f32, f32) {
+        let cos = dot(si.wi, si.normal);
+        let ior = self.ior_at(si.wavelength);
+        let (n, eta) = match cos >= 0.0 {
+            true => (si.normal, ior),
+            false => (-si.normal, 1.0 / ior),
+        };
+        (n, eta, fresnel_dielectric(f32::abs(cos), eta))
+    }
+}
+
+impl Material for DielectricMaterial {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let (n, eta, f) = self.interface(si);
+        let wo = wo.normalize();
+
+        // the probability of picking a lobe cancels its Fresnel weight
+        let weight = match refract(si.wi, n, eta) {
+            Some(wt) if dot(wo, wt) > 1.0 - DELTA_TOLERANCE => 1.0 - f,
+            _ if dot(wo, -reflect(si.wi, n)) > 1.0 - DELTA_TOLERANCE => f,
+            _ => 0.0,
+        };
+        BsdfSample {
+            radiance: Color::new(weight, weight, weight),
+            pdf: match weight > 0.0 {
+                true => weight,
+                false => 1.0,
+            },
+        }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        let (n, eta, f) = self.interface(si);
+        match refract(si.wi, n, eta) {
+            Some(wt) if sampler.gen::<f32>() >= f => wt,
+            _ => -reflect(si.wi, n),
+        }
+    }
+
+    fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        self.bsdf_eval(si, wo).pdf
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        true
+    }
+
+    fn albedo(&self, _si: &SurfaceInteraction) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.dispersion.is_some()
+    }
+
+    fn delta_lobes(&self, si: &SurfaceInteraction) -> Vec<(Vector, Color)> {
+        let (n, eta, f) = self.interface(si);
+        let reflected = -reflect(si.wi, n);
+        match refract(si.wi, n, eta) {
+            Some(wt) => vec![
+                (reflected, Color::new(f, f, f)),
+                (wt, Color::new(1.0 - f, 1.0 - f, 1.0 - f)),
+            ],
+            None => vec![(reflected, Color::new(1.0, 1.0, 1.0))],
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    pub(crate) fn up() -> Vector {
+        Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        }
+    }
+
+    /// Reflectance towards `wi` estimated by importance sampling and by uniform
+    /// sampling of the sphere, and the integral of the pdf.
+    pub(crate) fn estimates(material: &dyn Material, wi: Vector, n: u64) -> (f32, f32, f32) {
+        let origin = Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let si = SurfaceInteraction::new(origin, up(), 1.0, wi.normalize(), (0.5, 0.5), material);
+        let mut sampler = Sampler::new(0, 0);
+        let (mut sampled, mut uniform, mut pdf) = (0.0, 0.0, 0.0);
+        for _ in 0..n {
+            let wo = material.bsdf_sample(&si, &mut sampler);
+            let sample = material.bsdf_eval(&si, wo);
+            if sample.pdf > 0.0 {
+                sampled += sample.radiance.luminance() / sample.pdf;
+            }
+
+            let z = 1.0 - 2.0 * sampler.gen::<f32>();
+            let r = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+            let phi = 2.0 * PI * sampler.gen::<f32>();
+            let wo = Vector {
+                x: r * f32::cos(phi),
+                y: r * f32::sin(phi),
+                z,
+            };
+            let sample = material.bsdf_eval(&si, wo);
+            uniform += 4.0 * PI * sample.radiance.luminance();
+            pdf += 4.0 * PI * sample.pdf;
+        }
+        let n = n as f32;
+        (sampled / n, uniform / n, pdf / n)
+    }
+
+    #[test]
+    fn oren_nayar_samples_and_flattens() {
+        let gray = || Box::new(Color::new(0.8, 0.8, 0.8));
+        let oblique = Vector {
+            x: 0.6,
+            y: 0.0,
+            z: 0.8,
+        };
+        for sigma in [0.0, 0.5, 1.0] {
+            let rough = OrenNayarMaterial::new(gray(), sigma);
+            let (sampled, uniform, pdf) = estimates(&rough, oblique, 100_000);
+            assert!(
+                (sampled - uniform).abs() < 0.02 * uniform,
+                "{sampled} {uniform}"
+            );
+            assert!((pdf - 1.0).abs() < 0.02, "{pdf}");
+        }
+
+        // looking back along the light, rough surfaces are brighter than Lambertian
+        let lambert = DiffuseMaterial { albedo: gray() };
+        let smooth = OrenNayarMaterial::new(gray(), 0.0);
+        let rough = OrenNayarMaterial::new(gray(), 1.0);
+        let f = |material: &dyn Material| {
+            let origin = Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            };
+            let si = SurfaceInteraction::new(origin, up(), 1.0, oblique, (0.5, 0.5), material);
+            material.bsdf_eval(&si, oblique).radiance.g
+        };
+        assert!((f(&smooth) - f(&lambert)).abs() < 1e-6);
+        assert!(f(&rough) > 1.1 * f(&lambert));
+    }
+
+    #[test]
+    fn fresnel_limits() {
+        // normal incidence reduces to ((n - 1) / (n + 1))^2 for both interfaces
+        let f = fresnel_dielectric(1.0, 1.5);
+        assert!((f - 0.04).abs() < 1e-6);
+        assert!((fresnel_conductor(1.0, 1.5, 0.0) - f).abs() < 1e-6);
+        assert!((fresnel_dielectric(0.0, 1.5) - 1.0).abs() < 1e-6);
+        // leaving the denser medium beyond the critical angle
+        assert_eq!(fresnel_dielectric(0.5, 1.0 / 1.5), 1.0);
+
+        let r = Color::new(0.9, 0.5, 0.1);
+        let metal = ConductorMaterial::from_reflectance(r);
+        let f = fresnel_conductor(1.0, metal.eta.g, metal.k.g);
+        assert!((f - r.g).abs() < 1e-5);
+    }
+
+    #[test]
+    fn refraction_follows_snell() {
+        let n = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let wi = Vector {
+            x: 0.6,
+            y: 0.0,
+            z: 0.8,
+        };
+        let wt = refract(wi, n, 1.5).unwrap();
+        assert!((norm(wt) - 1.0).abs() < 1e-5);
+        assert!(wt.z < 0.0 && wt.x < 0.0);
+        assert!((-wt.x * 1.5 - 0.6).abs() < 1e-5);
+        assert!(refract(wi, n, 0.5).is_none());
+    }
+
+    #[test]
+    fn glass_disperses() {
+        let glass = DielectricMaterial::with_dispersion(Dispersion::BK7);
+        assert!((glass.ior - 1.5168).abs() < 1e-3);
+        assert!((Dispersion::FUSED_SILICA.ior(587.6) - 1.4585).abs() < 1e-3);
+        // blue light bends more than red
+        assert!(glass.ior_at(Some(450.0)) > glass.ior_at(Some(650.0)));
+        assert_eq!(glass.ior_at(None), glass.ior);
+
+        let cauchy = Dispersion::Cauchy { a: 1.5, b: 0.004 };
+        assert!((cauchy.ior(500.0) - 1.516).abs() < 1e-5);
+    }
+}
@@ -20,6 +20,22 @@ pub struct Ray {
     pub direction: Vector,
 }
 
+/// Axis-aligned bounding box.
+///
+/// Unbounded shapes report `Bounds3::unbounded()`, which spans all of space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds3 {
+    pub min: Point,
+    pub max: Point,
+}
+
+/// Affine transformation stored as a 4x4 matrix together with its inverse.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    m: [[f32; 4]; 4],
+    inv: [[f32; 4]; 4],
+}
+
 impl Vector {
     pub fn normalize(&self) -> Vector {
         let length = f32::sqrt(self.x * self.x + self.y * self.y + self.z * self.z);
@@ -154,6 +170,379 @@ pub fn cross(a: Vector, b: Vector) -> Vector {
     }
 }
 
+const IDENTITY: [[f32; 4]; 4] = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+fn mat_mul(a: &[[f32; 4]; 4], b: &[[f32; 4]; 4]) -> [[f32; 4]; 4] {
+    let mut r = [[0.0; 4]; 4];
+    for (i, row) in r.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    r
+}
+
+fn transpose(m: &[[f32; 4]; 4]) -> [[f32; 4]; 4] {
+    let mut r = [[0.0; 4]; 4];
+    for (i, row) in m.iter().enumerate() {
+        for (j, value) in row.iter().enumerate() {
+            r[j][i] = *value;
+        }
+    }
+    r
+}
+
+/// Inverse by Gauss-Jordan elimination with partial pivoting, `None` if singular.
+fn invert(m: &[[f32; 4]; 4]) -> Option<[[f32; 4]; 4]> {
+    let mut a = *m;
+    let mut inv = IDENTITY;
+
+    for col in 0..4 {
+        let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        inv.swap(col, pivot);
+
+        let scale = 1.0 / a[col][col];
+        for k in 0..4 {
+            a[col][k] *= scale;
+            inv[col][k] *= scale;
+        }
+
+        for row in 0..4 {
+            if row == col {
+                continue;
+            }
+            let factor = a[row][col];
+            for k in 0..4 {
+                a[row][k] -= factor * a[col][k];
+                inv[row][k] -= factor * inv[col][k];
+            }
+        }
+    }
+
+    Some(inv)
+}
+
+impl Bounds3 {
+    /// Smallest box containing both corners.
+    pub fn new(a: Point, b: Point) -> Bounds3 {
+        Bounds3 {
+            min: Point {
+                x: f32::min(a.x, b.x),
+                y: f32::min(a.y, b.y),
+                z: f32::min(a.z, b.z),
+            },
+            max: Point {
+                x: f32::max(a.x, b.x),
+                y: f32::max(a.y, b.y),
+                z: f32::max(a.z, b.z),
+            },
+        }
+    }
+
+    /// Box containing nothing, the neutral element of `union`.
+    pub fn empty() -> Bounds3 {
+        Bounds3 {
+            min: Point {
+                x: f32::INFINITY,
+                y: f32::INFINITY,
+                z: f32::INFINITY,
+            },
+            max: Point {
+                x: f32::NEG_INFINITY,
+                y: f32::NEG_INFINITY,
+                z: f32::NEG_INFINITY,
+            },
+        }
+    }
+
+    pub fn unbounded() -> Bounds3 {
+        Bounds3 {
+            min: Point {
+                x: f32::NEG_INFINITY,
+                y: f32::NEG_INFINITY,
+                z: f32::NEG_INFINITY,
+            },
+            max: Point {
+                x: f32::INFINITY,
+                y: f32::INFINITY,
+                z: f32::INFINITY,
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        let d = self.diagonal();
+        let finite = d.x.is_finite() && d.y.is_finite() && d.z.is_finite();
+        !self.is_empty() && !finite
+    }
+
+    pub fn union(&self, other: &Bounds3) -> Bounds3 {
+        Bounds3 {
+            min: Point {
+                x: f32::min(self.min.x, other.min.x),
+                y: f32::min(self.min.y, other.min.y),
+                z: f32::min(self.min.z, other.min.z),
+            },
+            max: Point {
+                x: f32::max(self.max.x, other.max.x),
+                y: f32::max(self.max.y, other.max.y),
+                z: f32::max(self.max.z, other.max.z),
+            },
+        }
+    }
+
+    pub fn union_point(&self, p: Point) -> Bounds3 {
+        self.union(&Bounds3 { min: p, max: p })
+    }
+
+    pub fn diagonal(&self) -> Vector {
+        self.max - self.min
+    }
+
+    pub fn centroid(&self) -> Point {
+        self.min + 0.5 * self.diagonal()
+    }
+
+    pub fn contains(&self, p: Point) -> bool {
+        (self.min.x..=self.max.x).contains(&p.x)
+            && (self.min.y..=self.max.y).contains(&p.y)
+            && (self.min.z..=self.max.z).contains(&p.z)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.diagonal();
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Parametric range `[t0, t1]` within `[0, t_max]` in which `ray` is inside the box.
+    ///
+    /// `t` is measured in units of `ray.direction`, which need not be normalized.
+    pub fn intersect(&self, ray: &Ray, t_max: f32) -> Option<(f32, f32)> {
+        let o = [ray.origin.x, ray.origin.y, ray.origin.z];
+        let d = [ray.direction.x, ray.direction.y, ray.direction.z];
+        let min = [self.min.x, self.min.y, self.min.z];
+        let max = [self.max.x, self.max.y, self.max.z];
+
+        let (mut t0, mut t1) = (0.0f32, t_max);
+        for axis in 0..3 {
+            let inv = 1.0 / d[axis];
+            let near = (min[axis] - o[axis]) * inv;
+            let far = (max[axis] - o[axis]) * inv;
+            let (near, far) = (f32::min(near, far), f32::max(near, far));
+            // NaN from rays in the plane of a slab leave the range untouched
+            t0 = if near > t0 { near } else { t0 };
+            t1 = if far < t1 { far } else { t1 };
+            if t0 > t1 {
+                return None;
+            }
+        }
+
+        Some((t0, t1))
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            m: IDENTITY,
+            inv: IDENTITY,
+        }
+    }
+
+    /// Transform from a row-major matrix, `None` if it is not invertible.
+    pub fn from_matrix(m: [[f32; 4]; 4]) -> Option<Transform> {
+        Some(Transform {
+            m,
+            inv: invert(&m)?,
+        })
+    }
+
+    pub fn translate(delta: Vector) -> Transform {
+        let mut m = IDENTITY;
+        let mut inv = IDENTITY;
+        (m[0][3], m[1][3], m[2][3]) = (delta.x, delta.y, delta.z);
+        (inv[0][3], inv[1][3], inv[2][3]) = (-delta.x, -delta.y, -delta.z);
+        Transform { m, inv }
+    }
+
+    pub fn scale(x: f32, y: f32, z: f32) -> Transform {
+        let mut m = IDENTITY;
+        let mut inv = IDENTITY;
+        (m[0][0], m[1][1], m[2][2]) = (x, y, z);
+        (inv[0][0], inv[1][1], inv[2][2]) = (1.0 / x, 1.0 / y, 1.0 / z);
+        Transform { m, inv }
+    }
+
+    /// Rotation by `degrees` counter-clockwise around `axis`.
+    pub fn rotate(degrees: f32, axis: Vector) -> Transform {
+        let a = axis.normalize();
+        let (sin, cos) = f32::sin_cos(degrees.to_radians());
+        let mut m = IDENTITY;
+
+        m[0][0] = a.x * a.x + (1.0 - a.x * a.x) * cos;
+        m[0][1] = a.x * a.y * (1.0 - cos) - a.z * sin;
+        m[0][2] = a.x * a.z * (1.0 - cos) + a.y * sin;
+        m[1][0] = a.x * a.y * (1.0 - cos) + a.z * sin;
+        m[1][1] = a.y * a.y + (1.0 - a.y * a.y) * cos;
+        m[1][2] = a.y * a.z * (1.0 - cos) - a.x * sin;
+        m[2][0] = a.x * a.z * (1.0 - cos) - a.y * sin;
+        m[2][1] = a.y * a.z * (1.0 - cos) + a.x * sin;
+        m[2][2] = a.z * a.z + (1.0 - a.z * a.z) * cos;
+
+        // rotations are orthogonal
+        Transform {
+            m,
+            inv: transpose(&m),
+        }
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            m: self.inv,
+            inv: self.m,
+        }
+    }
+
+    pub fn matrix(&self) -> [[f32; 4]; 4] {
+        self.m
+    }
+
+    /// Determinant of the linear part, i.e. the volume scale factor.
+    pub fn determinant(&self) -> f32 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    /// Scale factor of the linear part if it scales all directions alike.
+    ///
+    /// `None` for non-uniform scales and shears, which distort areas unevenly.
+    pub fn uniform_scale(&self) -> Option<f32> {
+        let m = &self.m;
+        let column = |k: usize| [m[0][k], m[1][k], m[2][k]];
+        let dot = |a: [f32; 3], b: [f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+        let (x, y, z) = (column(0), column(1), column(2));
+        let scale2 = dot(x, x);
+        let tolerance = 1e-4 * scale2;
+        let uniform = f32::abs(dot(y, y) - scale2) <= tolerance
+            && f32::abs(dot(z, z) - scale2) <= tolerance
+            && f32::abs(dot(x, y)) <= tolerance
+            && f32::abs(dot(y, z)) <= tolerance
+            && f32::abs(dot(z, x)) <= tolerance;
+        uniform.then(|| f32::sqrt(scale2))
+    }
+
+    pub fn point(&self, p: Point) -> Point {
+        let m = &self.m;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        match w == 1.0 {
+            true => Point { x, y, z },
+            false => Point {
+                x: x / w,
+                y: y / w,
+                z: z / w,
+            },
+        }
+    }
+
+    pub fn vector(&self, v: Vector) -> Vector {
+        let m = &self.m;
+        Vector {
+            x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        }
+    }
+
+    /// Transforms a surface normal with the inverse transpose, the result is not normalized.
+    pub fn normal(&self, n: Vector) -> Vector {
+        let inv = &self.inv;
+        Vector {
+            x: inv[0][0] * n.x + inv[1][0] * n.y + inv[2][0] * n.z,
+            y: inv[0][1] * n.x + inv[1][1] * n.y + inv[2][1] * n.z,
+            z: inv[0][2] * n.x + inv[1][2] * n.y + inv[2][2] * n.z,
+        }
+    }
+
+    /// Bounds of the eight transformed corners of `b`.
+    pub fn bounds(&self, b: &Bounds3) -> Bounds3 {
+        if b.is_empty() || b.is_unbounded() {
+            return *b;
+        }
+        (0..8)
+            .map(|k| Point {
+                x: if k & 1 == 0 { b.min.x } else { b.max.x },
+                y: if k & 2 == 0 { b.min.y } else { b.max.y },
+                z: if k & 4 == 0 { b.min.z } else { b.max.z },
+            })
+            .fold(Bounds3::empty(), |acc, p| acc.union_point(self.point(p)))
+    }
+
+    pub fn ray(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: self.point(ray.origin),
+            direction: self.vector(ray.direction),
+        }
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::identity()
+    }
+}
+
+/// Composition, `a * b` applies `b` first.
+impl Mul<Transform> for Transform {
+    type Output = Transform;
+    fn mul(self, rhs: Transform) -> Self::Output {
+        Transform {
+            m: mat_mul(&self.m, &rhs.m),
+            inv: mat_mul(&rhs.inv, &self.inv),
+        }
+    }
+}
+
+/// Orthonormal basis with `w` as third axis.
+pub fn coordinate_system(w: Vector) -> (Vector, Vector, Vector) {
+    let axis = match f32::abs(w.x) > 0.1 {
+        true => Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        false => Vector {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        },
+    };
+    let u = cross(axis, w).normalize();
+    let v = cross(w, u);
+
+    (u, v, w)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -228,4 +617,159 @@ mod tests {
             }
         );
     }
+
+    fn assert_close(a: Point, b: Point) {
+        assert!(norm(a - b) < 1e-5, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn composes_and_inverts() {
+        let t = Transform::translate(Vector {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        }) * Transform::rotate(
+            30.0,
+            Vector {
+                x: 1.0,
+                y: 1.0,
+                z: 0.0,
+            },
+        ) * Transform::scale(2.0, 0.5, 3.0);
+        let p = Point {
+            x: 0.3,
+            y: -1.2,
+            z: 4.0,
+        };
+
+        assert_close(t.inverse().point(t.point(p)), p);
+        assert!(t.uniform_scale().is_none());
+
+        let general = Transform::from_matrix(t.matrix()).unwrap();
+        assert_close(general.inverse().point(t.point(p)), p);
+
+        let rotation = Transform::rotate(
+            90.0,
+            Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+        );
+        assert_close(
+            rotation.point(Point {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            }),
+            Point {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+        );
+        let scale = (rotation * Transform::scale(2.0, 2.0, 2.0)).uniform_scale();
+        assert!((scale.unwrap() - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn keeps_normals_perpendicular() {
+        let t = Transform::scale(4.0, 1.0, 1.0)
+            * Transform::rotate(
+                45.0,
+                Vector {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+            );
+        let tangent = Vector {
+            x: 1.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let normal = Vector {
+            x: 1.0,
+            y: -1.0,
+            z: 0.0,
+        };
+        assert!(dot(t.vector(tangent), t.normal(normal)).abs() < 1e-5);
+        assert!(Transform::from_matrix([[0.0; 4]; 4]).is_none());
+    }
+
+    #[test]
+    fn bounds_union_and_area() {
+        let a = Bounds3::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Point {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        );
+        let b = Bounds3::new(
+            Point {
+                x: 2.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Point {
+                x: 3.0,
+                y: 2.0,
+                z: 1.0,
+            },
+        );
+        let u = a.union(&b);
+        assert_eq!(u.min, a.min);
+        assert_eq!(u.max, b.max);
+        assert_eq!(a.surface_area(), 6.0);
+        assert_eq!(Bounds3::empty().union(&a), a);
+        assert!(Bounds3::unbounded().union(&a).is_unbounded());
+        assert!(!u.is_unbounded());
+    }
+
+    #[test]
+    fn bounds_slab_test() {
+        let b = Bounds3::new(
+            Point {
+                x: -1.0,
+                y: -1.0,
+                z: -1.0,
+            },
+            Point {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        );
+        let ray = Ray {
+            origin: Point {
+                x: -3.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+        assert_eq!(b.intersect(&ray, f32::INFINITY), Some((2.0, 4.0)));
+        assert_eq!(b.intersect(&ray, 3.0), Some((2.0, 3.0)));
+        assert_eq!(b.intersect(&ray, 1.0), None);
+
+        let miss = Ray {
+            origin: Point {
+                x: -3.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            ..ray
+        };
+        assert_eq!(b.intersect(&miss, f32::INFINITY), None);
+    }
 }
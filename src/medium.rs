@@ -0,0 +1,397 @@
+use crate::grid::{MajorantGrid, VoxelGrid};
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::sensor::Color;
+use rand::Rng;
+
+/// Outcome of sampling a free-flight distance inside a medium.
+pub struct MediumSample {
+    /// Distance of the sampled scattering event, `None` if the ray passed through.
+    pub t: Option<f32>,
+    /// Throughput weight of the sample, i.e. transmittance (times albedo) over pdf.
+    pub weight: Color,
+}
+
+pub trait Medium: Send + Sync {
+    /// Samples a free-flight distance along `ray` up to `t_max`.
+    fn sample_distance(&self, ray: &Ray, t_max: f32, sampler: &mut Sampler) -> MediumSample;
+    /// Estimates the transmittance along `ray` between 0 and `t_max`.
+    fn transmittance(&self, ray: &Ray, t_max: f32, sampler: &mut Sampler) -> Color;
+    fn phase(&self) -> &HenyeyGreenstein;
+}
+
+/// Henyey-Greenstein phase function with asymmetry parameter `g` in (-1, 1).
+pub struct HenyeyGreenstein {
+    pub g: f32,
+}
+
+/// Medium with constant absorption and scattering coefficients.
+pub struct HomogeneousMedium {
+    pub sigma_a: Color,
+    pub sigma_s: Color,
+    pub phase: HenyeyGreenstein,
+}
+
+/// Medium whose density is given by a voxel grid stretched over an axis-aligned box.
+///
+/// Extinction is `sigma_t * density`, a share `albedo` of it scatters. Distances are
+/// sampled with delta tracking and transmittance is estimated with ratio tracking,
+/// both against the per-cell bounds of a majorant grid to skip empty space.
+pub struct HeterogeneousMedium {
+    density: VoxelGrid,
+    majorants: MajorantGrid,
+    bounds: Bounds3,
+    sigma_t: f32,
+    albedo: Color,
+    phase: HenyeyGreenstein,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein { g }
+    }
+
+    /// Phase function value for light travelling along `wi` being scattered into `wo`.
+    ///
+    /// Both directions point away from the scattering point, like `SurfaceInteraction::wi`.
+    pub fn eval(&self, wi: Vector, wo: Vector) -> f32 {
+        let cos_theta = dot(-wi.normalize(), wo.normalize());
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * std::f32::consts::PI * denom * f32::sqrt(denom))
+    }
+
+    /// Samples `wo` proportional to `eval(wi, wo)`, so the sample weight is one.
+    pub fn sample(&self, wi: Vector, sampler: &mut Sampler) -> Vector {
+        let g = self.g;
+        let e1: f32 = sampler.gen();
+        let e2: f32 = sampler.gen();
+
+        let cos_theta = match f32::abs(g) < 1e-3 {
+            true => 1.0 - 2.0 * e1,
+            false => {
+                let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * e1);
+                (1.0 + g * g - s * s) / (2.0 * g)
+            }
+        };
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * std::f32::consts::PI * e2;
+
+        // cos_theta is measured against the propagation direction -wi
+        let (u, v, w) = coordinate_system(-wi.normalize());
+        f32::cos(phi) * sin_theta * u + f32::sin(phi) * sin_theta * v + cos_theta * w
+    }
+}
+
+impl HomogeneousMedium {
+    pub fn new(sigma_a: Color, sigma_s: Color, g: f32) -> HomogeneousMedium {
+        HomogeneousMedium {
+            sigma_a,
+            sigma_s,
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    fn sigma_t(&self) -> Color {
+        self.sigma_a + self.sigma_s
+    }
+
+    fn transmittance_at(&self, t: f32) -> Color {
+        let sigma_t = self.sigma_t();
+        Color::new(
+            f32::exp(-sigma_t.r * t),
+            f32::exp(-sigma_t.g * t),
+            f32::exp(-sigma_t.b * t),
+        )
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    fn sample_distance(&self, _ray: &Ray, t_max: f32, sampler: &mut Sampler) -> MediumSample {
+        let sigma_t = self.sigma_t();
+
+        // pick a channel uniformly and sample its exponential distribution
+        let channel = f32::min(sampler.gen::<f32>() * 3.0, 2.0) as usize;
+        let sigma = [sigma_t.r, sigma_t.g, sigma_t.b][channel];
+        let t = match sigma > 0.0 {
+            true => -f32::ln(1.0 - sampler.gen::<f32>()) / sigma,
+            false => f32::INFINITY,
+        };
+
+        if t < t_max {
+            let tr = self.transmittance_at(t);
+            let pdf = (sigma_t * tr).average();
+            MediumSample {
+                t: Some(t),
+                weight: (1.0 / pdf) * (tr * self.sigma_s),
+            }
+        } else {
+            let tr = self.transmittance_at(t_max);
+            let pdf = tr.average();
+            MediumSample {
+                t: None,
+                weight: (1.0 / pdf) * tr,
+            }
+        }
+    }
+
+    fn transmittance(&self, _ray: &Ray, t_max: f32, _sampler: &mut Sampler) -> Color {
+        self.transmittance_at(t_max)
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+impl HeterogeneousMedium {
+    /// Stretches `density` over `bounds`.
+    pub fn new(
+        density: VoxelGrid,
+        bounds: Bounds3,
+        sigma_t: f32,
+        albedo: Color,
+        g: f32,
+    ) -> HeterogeneousMedium {
+        let resolution = density.resolution().map(|n| usize::max(n / 8, 1));
+        let majorants = MajorantGrid::new(&density, resolution);
+        HeterogeneousMedium {
+            density,
+            majorants,
+            bounds,
+            sigma_t,
+            albedo,
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    /// Ray in the unit cube of the grid, with the parameter range it spends inside the cube.
+    ///
+    /// The parameter `t` is shared with the world space ray.
+    fn to_grid(&self, ray: &Ray, t_max: f32) -> Option<([f32; 3], [f32; 3], f32, f32)> {
+        let ray = Ray {
+            origin: ray.origin,
+            direction: ray.direction.normalize(),
+        };
+        let (t_min, t_max) = self.bounds.intersect(&ray, t_max)?;
+
+        let extent = self.bounds.diagonal();
+        let o = ray.origin - self.bounds.min;
+        let d = ray.direction;
+        let origin = [o.x / extent.x, o.y / extent.y, o.z / extent.z];
+        let direction = [d.x / extent.x, d.y / extent.y, d.z / extent.z];
+
+        Some((origin, direction, t_min, t_max))
+    }
+
+    fn density_at(&self, origin: [f32; 3], direction: [f32; 3], t: f32) -> f32 {
+        self.density.lookup([
+            origin[0] + t * direction[0],
+            origin[1] + t * direction[1],
+            origin[2] + t * direction[2],
+        ])
+    }
+}
+
+impl Medium for HeterogeneousMedium {
+    fn sample_distance(&self, ray: &Ray, t_max: f32, sampler: &mut Sampler) -> MediumSample {
+        let passed = MediumSample {
+            t: None,
+            weight: Color::new(1.0, 1.0, 1.0),
+        };
+        let Some((origin, direction, t_min, t_max)) = self.to_grid(ray, t_max) else {
+            return passed;
+        };
+
+        let mut collision = None;
+        self.majorants
+            .traverse(origin, direction, t_min, t_max, |majorant, t0, t1| {
+                let sigma_bar = majorant * self.sigma_t;
+                if sigma_bar <= 0.0 {
+                    return true;
+                }
+                let mut t = t0;
+                loop {
+                    t -= f32::ln(1.0 - sampler.gen::<f32>()) / sigma_bar;
+                    if t >= t1 {
+                        return true;
+                    }
+                    let sigma = self.density_at(origin, direction, t) * self.sigma_t;
+                    if sampler.gen::<f32>() < sigma / sigma_bar {
+                        collision = Some(t);
+                        return false;
+                    }
+                }
+            });
+
+        match collision {
+            Some(t) => MediumSample {
+                t: Some(t),
+                weight: self.albedo,
+            },
+            None => passed,
+        }
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: f32, sampler: &mut Sampler) -> Color {
+        let Some((origin, direction, t_min, t_max)) = self.to_grid(ray, t_max) else {
+            return Color::new(1.0, 1.0, 1.0);
+        };
+
+        let mut tr = 1.0;
+        self.majorants
+            .traverse(origin, direction, t_min, t_max, |majorant, t0, t1| {
+                let sigma_bar = majorant * self.sigma_t;
+                if sigma_bar <= 0.0 {
+                    return true;
+                }
+                let mut t = t0;
+                loop {
+                    t -= f32::ln(1.0 - sampler.gen::<f32>()) / sigma_bar;
+                    if t >= t1 {
+                        return true;
+                    }
+                    let sigma = self.density_at(origin, direction, t) * self.sigma_t;
+                    tr *= 1.0 - sigma / sigma_bar;
+                    // ratio tracking never terminates on its own in dense media
+                    if tr < 1e-3 {
+                        if sampler.gen::<f32>() < 0.5 {
+                            tr = 0.0;
+                            return false;
+                        }
+                        tr *= 2.0;
+                    }
+                }
+            });
+
+        Color::new(tr, tr, tr)
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phase_function_is_normalized() {
+        let mut sampler = Sampler::new(3, 0);
+        let wi = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        for g in [-0.7, 0.0, 0.3, 0.9] {
+            let phase = HenyeyGreenstein::new(g);
+            // uniform sphere sampling has pdf 1 / (4 pi)
+            let n = 20000;
+            let integral: f32 = (0..n)
+                .map(|_| {
+                    let z = 1.0 - 2.0 * sampler.gen::<f32>();
+                    let r = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+                    let phi = 2.0 * std::f32::consts::PI * sampler.gen::<f32>();
+                    let wo = Vector {
+                        x: r * f32::cos(phi),
+                        y: r * f32::sin(phi),
+                        z,
+                    };
+                    phase.eval(wi, wo) * 4.0 * std::f32::consts::PI
+                })
+                .sum::<f32>()
+                / n as f32;
+            assert!((integral - 1.0).abs() < 0.05, "g = {g}: {integral}");
+        }
+    }
+
+    #[test]
+    fn samples_forward_scattering() {
+        let mut sampler = Sampler::new(5, 0);
+        let phase = HenyeyGreenstein::new(0.8);
+        let wi = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let mean_z: f32 = (0..10000)
+            .map(|_| phase.sample(wi, &mut sampler).z)
+            .sum::<f32>()
+            / 10000.0;
+        // the mean cosine against the propagation direction equals g
+        assert!((mean_z + 0.8).abs() < 0.02);
+    }
+
+    fn constant_medium(density: f32) -> HeterogeneousMedium {
+        let grid = VoxelGrid::new([8, 8, 8], vec![density; 512]);
+        HeterogeneousMedium::new(
+            grid,
+            Bounds3::new(
+                Point {
+                    x: -1.0,
+                    y: -1.0,
+                    z: -1.0,
+                },
+                Point {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                },
+            ),
+            1.0,
+            Color::new(1.0, 1.0, 1.0),
+            0.0,
+        )
+    }
+
+    fn ray_through_box() -> Ray {
+        Ray {
+            origin: Point {
+                x: -3.0,
+                y: 0.1,
+                z: 0.2,
+            },
+            direction: Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        }
+    }
+
+    #[test]
+    fn tracks_constant_density() {
+        let medium = constant_medium(0.7);
+        let ray = ray_through_box();
+        let expected = f32::exp(-0.7 * 2.0);
+
+        let mut sampler = Sampler::new(9, 0);
+        let n = 20000;
+        let ratio = (0..n)
+            .map(|_| medium.transmittance(&ray, 10.0, &mut sampler).r)
+            .sum::<f32>()
+            / n as f32;
+        assert!((ratio - expected).abs() < 0.01);
+
+        let passed = (0..n)
+            .filter(|_| medium.sample_distance(&ray, 10.0, &mut sampler).t.is_none())
+            .count() as f32
+            / n as f32;
+        assert!((passed - expected).abs() < 0.02);
+    }
+
+    #[test]
+    fn collides_inside_the_box() {
+        let medium = constant_medium(5.0);
+        let ray = ray_through_box();
+        let mut sampler = Sampler::new(2, 0);
+        for _ in 0..100 {
+            if let Some(t) = medium.sample_distance(&ray, 10.0, &mut sampler).t {
+                assert!((2.0..=4.0).contains(&t));
+            }
+        }
+        // the medium ends before the surface at t_max
+        assert!(medium.sample_distance(&ray, 1.5, &mut sampler).t.is_none());
+    }
+}
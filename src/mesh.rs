@@ -0,0 +1,278 @@
+use crate::bvh::Bvh;
+use crate::material::Material;
+use crate::math::*;
+use crate::primitives::intersect_triangle;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::Color;
+
+use rand::Rng;
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+/// Indexed triangle mesh with optional per-vertex normals, UVs and colors.
+///
+/// Attribute vectors are either empty or hold one entry per position.
+pub struct TriangleMesh {
+    pub positions: Vec<Point>,
+    pub normals: Vec<Vector>,
+    pub uvs: Vec<(f32, f32)>,
+    pub colors: Vec<Color>,
+    pub indices: Vec<[usize; 3]>,
+    pub material: Box<dyn Material>,
+    bvh: Bvh,
+    /// Running sum of triangle areas, for sampling triangles by area.
+    cdf: Vec<f32>,
+}
+
+/// Checks that an attribute has no entries or one per position.
+fn check_attribute(name: &str, len: usize, positions: usize) -> std::io::Result<()> {
+    match len == 0 || len == positions {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("mesh has {len} {name} for {positions} positions"),
+        )),
+    }
+}
+
+impl TriangleMesh {
+    /// Mesh of `indices` into `positions`, an error if any index is out of range.
+    pub fn new(
+        positions: Vec<Point>,
+        indices: Vec<[usize; 3]>,
+        material: Box<dyn Material>,
+    ) -> std::io::Result<TriangleMesh> {
+        if let Some(&index) = indices.iter().flatten().find(|&&i| i >= positions.len()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "mesh index {index} is out of range for {} positions",
+                    positions.len()
+                ),
+            ));
+        }
+        let corners = |&[a, b, c]: &[usize; 3]| (positions[a], positions[b], positions[c]);
+
+        let bounds: Vec<Bounds3> = indices
+            .iter()
+            .map(|triangle| {
+                let (p0, p1, p2) = corners(triangle);
+                Bounds3::new(p0, p1).union_point(p2)
+            })
+            .collect();
+
+        let cdf = indices
+            .iter()
+            .scan(0.0, |sum, triangle| {
+                let (p0, p1, p2) = corners(triangle);
+                *sum += 0.5 * norm(cross(p1 - p0, p2 - p0));
+                Some(*sum)
+            })
+            .collect();
+
+        Ok(TriangleMesh {
+            bvh: Bvh::new(&bounds),
+            cdf,
+            positions,
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices,
+            material,
+        })
+    }
+
+    pub fn with_normals(mut self, normals: Vec<Vector>) -> std::io::Result<TriangleMesh> {
+        check_attribute("normals", normals.len(), self.positions.len())?;
+        self.normals = normals;
+        Ok(self)
+    }
+
+    pub fn with_uvs(mut self, uvs: Vec<(f32, f32)>) -> std::io::Result<TriangleMesh> {
+        check_attribute("uvs", uvs.len(), self.positions.len())?;
+        self.uvs = uvs;
+        Ok(self)
+    }
+
+    pub fn with_colors(mut self, colors: Vec<Color>) -> std::io::Result<TriangleMesh> {
+        check_attribute("colors", colors.len(), self.positions.len())?;
+        self.colors = colors;
+        Ok(self)
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Loads a binary STL file. Facets do not share vertices.
+    pub fn from_stl(
+        path: impl AsRef<Path>,
+        material: Box<dyn Material>,
+    ) -> std::io::Result<TriangleMesh> {
+        let bytes = fs::read(path)?;
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+        // 80 byte header, facet count, then 50 bytes per facet
+        let count = bytes
+            .get(80..84)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(|| invalid("STL file is too short"))?;
+        if bytes.len() < 84 + 50 * count {
+            return Err(invalid("STL file is truncated, ASCII STL is not supported"));
+        }
+
+        let float = |offset: usize| {
+            f32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        let positions = (0..3 * count)
+            .map(|k| {
+                // skip the facet normal, it is recomputed from the winding
+                let offset = 84 + 50 * (k / 3) + 12 + 12 * (k % 3);
+                Point {
+                    x: float(offset),
+                    y: float(offset + 4),
+                    z: float(offset + 8),
+                }
+            })
+            .collect();
+        let indices = (0..count).map(|k| [3 * k, 3 * k + 1, 3 * k + 2]).collect();
+
+        TriangleMesh::new(positions, indices, material)
+    }
+
+    fn interpolate<T: Copy>(
+        attribute: &[T],
+        [a, b, c]: [usize; 3],
+        (b1, b2): (f32, f32),
+        mix: impl Fn(T, f32) -> T,
+        add: impl Fn(T, T) -> T,
+    ) -> Option<T> {
+        match attribute.is_empty() {
+            true => None,
+            false => Some(add(
+                add(mix(attribute[a], 1.0 - b1 - b2), mix(attribute[b], b1)),
+                mix(attribute[c], b2),
+            )),
+        }
+    }
+}
+
+impl Shape for TriangleMesh {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let u = ray.direction.normalize();
+        let normalized = Ray {
+            origin: ray.origin,
+            direction: u,
+        };
+
+        let mut closest: Option<(usize, f32, f32, f32)> = None;
+        self.bvh.traverse(&normalized, f32::INFINITY, |k| {
+            let [a, b, c] = self.indices[k];
+            let p = &self.positions;
+            let (t, b1, b2) = intersect_triangle(ray.origin, u, p[a], p[b], p[c])?;
+            if closest.is_none_or(|(_, closest_t, _, _)| t < closest_t) {
+                closest = Some((k, t, b1, b2));
+            }
+            Some(t)
+        });
+
+        let (k, t, b1, b2) = closest?;
+        let triangle = self.indices[k];
+        let [a, b, c] = triangle;
+        let p = &self.positions;
+        let geometric = cross(p[b] - p[a], p[c] - p[a]).normalize();
+
+        // shading normals are flipped to the side of the geometric normal
+        let normal = TriangleMesh::interpolate(
+            &self.normals,
+            triangle,
+            (b1, b2),
+            |n, w| w * n,
+            |x, y| x + y,
+        )
+        .map(|n| n.normalize())
+        .map(|n| match dot(n, geometric) < 0.0 {
+            true => -n,
+            false => n,
+        })
+        .unwrap_or(geometric);
+        let uv = TriangleMesh::interpolate(
+            &self.uvs,
+            triangle,
+            (b1, b2),
+            |(s, t), w| (w * s, w * t),
+            |x, y| (x.0 + y.0, x.1 + y.1),
+        )
+        .unwrap_or((b1, b2));
+
+        let mut si = SurfaceInteraction::new(
+            ray.origin + t * u,
+            normal,
+            t,
+            -u,
+            uv,
+            self.material.as_ref(),
+        );
+        si.color =
+            TriangleMesh::interpolate(&self.colors, triangle, (b1, b2), |c, w| w * c, |x, y| x + y);
+        Some(si)
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        self.bvh.bounds()
+    }
+
+    fn area(&self) -> f32 {
+        self.cdf.last().copied().unwrap_or(0.0)
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        if self.area() <= 0.0 {
+            return None;
+        }
+        let target = sampler.gen::<f32>() * self.area();
+        let k = usize::min(
+            self.cdf.partition_point(|&sum| sum <= target),
+            self.indices.len() - 1,
+        );
+
+        let [a, b, c] = self.indices[k];
+        let p = &self.positions;
+        let su = f32::sqrt(sampler.gen::<f32>());
+        let b1 = su * (1.0 - sampler.gen::<f32>());
+        let b2 = su - b1;
+        let e1 = p[b] - p[a];
+        let e2 = p[c] - p[a];
+
+        Some(ShapeSample {
+            position: p[a] + (b1 * e1 + b2 * e2),
+            normal: cross(e1, e2).normalize(),
+            pdf: 1.0 / self.area(),
+        })
+    }
+
+    fn traversal_cost(&self, ray: &Ray) -> usize {
+        let u = ray.direction.normalize();
+        let normalized = Ray {
+            origin: ray.origin,
+            direction: u,
+        };
+        self.bvh.traverse(&normalized, f32::INFINITY, |k| {
+            let [a, b, c] = self.indices[k];
+            let p = &self.positions;
+            intersect_triangle(ray.origin, u, p[a], p[b], p[c]).map(|(t, _, _)| t)
+        })
+    }
+}
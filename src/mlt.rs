@@ -0,0 +1,295 @@
+use crate::integrator::{parallel_map, Integrator};
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::{Camera, Color};
+
+use rand::Rng;
+
+/// Primary sample space Metropolis light transport.
+///
+/// Runs an inner integrator, e.g. `PathIntegrator`, on random numbers that
+/// come from points in primary sample space instead of a plain stream. Markov
+/// chains over those points mutate them by small steps, which explore the
+/// neighborhood of paths that carry light, and large steps, which start
+/// afresh. A chain that found light squeezing through a gap keeps finding it.
+///
+/// A bootstrap pass estimates the brightness of the image to normalize the
+/// chains and picks their starting points. Every mutation splats its
+/// contribution onto the sensor at whichever pixel the path passes through.
+/// `render` runs `spp` mutations per pixel on average. `sample_radiance`
+/// cannot form chains and just calls the inner integrator.
+pub struct MetropolisIntegrator {
+    inner: Box<dyn Integrator>,
+    bootstrap_samples: usize,
+    chains: usize,
+    sigma: f32,
+    large_step_probability: f32,
+}
+
+/// Pixel and radiance of a sample taken with the coordinates of a primary sample point.
+struct PathSample {
+    pixel: usize,
+    radiance: Color,
+    /// Scalar importance the chain is distributed by, the luminance.
+    weight: f32,
+}
+
+impl MetropolisIntegrator {
+    pub fn new(inner: Box<dyn Integrator>) -> MetropolisIntegrator {
+        MetropolisIntegrator {
+            inner,
+            bootstrap_samples: 100_000,
+            chains: 1000,
+            sigma: 0.01,
+            large_step_probability: 0.3,
+        }
+    }
+
+    pub fn with_bootstrap_samples(mut self, bootstrap_samples: usize) -> MetropolisIntegrator {
+        self.bootstrap_samples = bootstrap_samples;
+        self
+    }
+
+    pub fn with_chains(mut self, chains: usize) -> MetropolisIntegrator {
+        self.chains = chains;
+        self
+    }
+
+    /// Standard deviation of the small steps in primary sample space.
+    pub fn with_sigma(mut self, sigma: f32) -> MetropolisIntegrator {
+        self.sigma = sigma;
+        self
+    }
+
+    pub fn with_large_step_probability(mut self, probability: f32) -> MetropolisIntegrator {
+        self.large_step_probability = probability;
+        self
+    }
+
+    /// Picks a pixel and traces a path through it with the current primary sample point.
+    fn sample(&self, camera: &dyn Camera, scene: &Scene, sampler: &mut Sampler) -> PathSample {
+        let sensor = camera.get_sensor();
+        let (width, height) = (sensor.width(), sensor.height());
+        let i = usize::min((sampler.gen::<f32>() * width as f32) as usize, width - 1);
+        let j = usize::min((sampler.gen::<f32>() * height as f32) as usize, height - 1);
+        let pixel = j * width + i;
+
+        let radiance = match camera.sample_ray(i, j, sampler) {
+            Some(ray) => self.inner.sample_radiance(&ray, scene, sampler),
+            None => Color::new(0.0, 0.0, 0.0),
+        };
+        let weight = scene.color_space.matrix_to_xyz().apply(radiance).g;
+        match weight.is_finite() && weight > 0.0 {
+            true => PathSample {
+                pixel,
+                radiance,
+                weight,
+            },
+            false => PathSample {
+                pixel,
+                radiance: Color::new(0.0, 0.0, 0.0),
+                weight: 0.0,
+            },
+        }
+    }
+
+    fn bootstrap_sampler(&self, seed: u64, index: usize) -> Sampler {
+        Sampler::primary(seed, index as u64, self.sigma, self.large_step_probability)
+    }
+
+    /// Weights of the bootstrap samples in order, computed on `num_threads` threads.
+    fn bootstrap(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        seed: u64,
+        num_threads: usize,
+    ) -> Vec<f32> {
+        parallel_map(self.bootstrap_samples, num_threads, |index| {
+            let mut sampler = self.bootstrap_sampler(seed, index);
+            self.sample(camera, scene, &mut sampler).weight
+        })
+    }
+
+    /// Runs one Markov chain for `mutations` steps, splatting into `image`.
+    ///
+    /// Every step splats both the current and the proposed path, weighted by
+    /// the probability of keeping either, which wastes no rejected samples.
+    #[allow(clippy::too_many_arguments)]
+    fn run_chain(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        cdf: &[f32],
+        chain: usize,
+        mutations: usize,
+        seed: u64,
+        image: &mut [Color],
+    ) {
+        // the chain starts at a bootstrap sample picked proportionally to its weight
+        let mut picker = Sampler::new(seed, (self.bootstrap_samples + chain) as u64);
+        let total = cdf[cdf.len() - 1];
+        let u = picker.gen::<f32>() * total;
+        let start = usize::min(cdf.partition_point(|&c| c <= u), cdf.len() - 1);
+
+        // replaying the bootstrap sample restores its point, the mutations
+        // follow a stream of the chain so that chains from the same start part ways
+        let mut sampler = self.bootstrap_sampler(seed, start);
+        let mut current = self.sample(camera, scene, &mut sampler);
+        let chains = self.chains.max(1);
+        sampler.reseed(seed, (self.bootstrap_samples + chains + chain) as u64);
+        for _ in 0..mutations {
+            sampler.start_iteration();
+            let proposed = self.sample(camera, scene, &mut sampler);
+            let accept = match current.weight > 0.0 {
+                true => f32::min(1.0, proposed.weight / current.weight),
+                false => 1.0,
+            };
+
+            if proposed.weight > 0.0 {
+                let splat = (accept / proposed.weight) * proposed.radiance;
+                image[proposed.pixel] = image[proposed.pixel] + splat;
+            }
+            if current.weight > 0.0 {
+                let splat = ((1.0 - accept) / current.weight) * current.radiance;
+                image[current.pixel] = image[current.pixel] + splat;
+            }
+
+            match picker.gen::<f32>() < accept {
+                true => {
+                    sampler.accept();
+                    current = proposed;
+                }
+                false => sampler.reject(),
+            }
+        }
+    }
+}
+
+impl Integrator for MetropolisIntegrator {
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        self.inner.sample_radiance(ray, scene, sampler)
+    }
+
+    /// Renders with `spp` mutations per pixel on average.
+    ///
+    /// Chains run in batches of `num_threads` whose images are added in chain
+    /// order, so the result only depends on `seed`.
+    fn render(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        spp: usize,
+        seed: u64,
+        num_threads: usize,
+    ) {
+        let sensor = camera.get_sensor();
+        let to_sensor = scene.color_space.matrix_to(sensor.color_space());
+        let pixels = camera.get_pixels();
+        let black = Color::new(0.0, 0.0, 0.0);
+
+        let weights = self.bootstrap(camera, scene, seed, num_threads);
+        let cdf: Vec<f32> = weights
+            .iter()
+            .scan(0.0, |sum, &w| {
+                *sum += w;
+                Some(*sum)
+            })
+            .collect();
+        let Some(&total) = cdf.last().filter(|&&total| total > 0.0) else {
+            for pixel in pixels {
+                *pixel.color.write().unwrap() = black;
+            }
+            return;
+        };
+        // mean weight of a sample, the integral of luminance over the image
+        let b = total / weights.len() as f32;
+
+        let chains = self.chains.max(1);
+        let mutations = spp * pixels.len();
+        let mut image = vec![black; pixels.len()];
+        let batch = num_threads.max(1);
+        for first in (0..chains).step_by(batch) {
+            let images = parallel_map(usize::min(batch, chains - first), batch, |k| {
+                let chain = first + k;
+                // spread the remainder over the first chains
+                let count = mutations / chains + usize::from(chain < mutations % chains);
+                let mut image = vec![black; pixels.len()];
+                self.run_chain(camera, scene, &cdf, chain, count, seed, &mut image);
+                image
+            });
+            for chain_image in images {
+                for (sum, color) in image.iter_mut().zip(chain_image) {
+                    *sum = *sum + color;
+                }
+            }
+        }
+
+        let scale = b * pixels.len() as f32 / mutations as f32;
+        for (pixel, color) in pixels.iter().zip(image) {
+            *pixel.color.write().unwrap() = to_sensor.apply(scale * color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::tests::{lamp_scene, render};
+    use crate::integrator::PathIntegrator;
+    use crate::sensor::{PinholeCamera, Sensor};
+
+    fn metropolis() -> MetropolisIntegrator {
+        MetropolisIntegrator::new(Box::new(PathIntegrator::new(4, 4)))
+            .with_bootstrap_samples(20_000)
+            .with_chains(64)
+    }
+
+    #[test]
+    fn matches_path_tracer() {
+        // the normalization fixes the mean, so compare 4x4 blocks to check where light lands
+        let blocks = |pixels: Vec<Color>| -> Vec<f32> {
+            let mut blocks = vec![0.0; 12];
+            for (index, color) in pixels.iter().enumerate() {
+                let (i, j) = (index % 16, index / 16);
+                blocks[(j / 4) * 4 + i / 4] += color.g / 16.0;
+            }
+            blocks
+        };
+        let scene = lamp_scene();
+        let reference = blocks(render(&PathIntegrator::new(4, 4), &scene, 256, 5, 4));
+        let estimate = blocks(render(&metropolis(), &scene, 256, 5, 4));
+        for (a, b) in reference.iter().zip(estimate.iter()) {
+            assert!((a - b).abs() < 0.1 * a + 1e-3, "{reference:?} {estimate:?}");
+        }
+    }
+
+    #[test]
+    fn renders_deterministically() {
+        let bits = |pixels: Vec<Color>| -> Vec<(f32, f32, f32)> {
+            pixels.iter().map(|c| (c.r, c.g, c.b)).collect()
+        };
+        let integrator = metropolis().with_chains(7);
+        let scene = lamp_scene();
+        assert_eq!(
+            bits(render(&integrator, &scene, 2, 5, 1)),
+            bits(render(&integrator, &scene, 2, 5, 3))
+        );
+    }
+
+    #[test]
+    fn chains_from_one_start_part_ways() {
+        // a single bootstrap sample makes every chain start at the same point
+        let integrator = metropolis().with_bootstrap_samples(1);
+        let camera = PinholeCamera::new(Sensor::zero(16, 12), 60.0);
+        let scene = lamp_scene();
+        let cdf = [1.0];
+        let chain = |index: usize| {
+            let mut image = vec![Color::new(0.0, 0.0, 0.0); 16 * 12];
+            integrator.run_chain(&camera, &scene, &cdf, index, 64, 5, &mut image);
+            image.iter().map(|c| c.g.to_bits()).collect::<Vec<_>>()
+        };
+        assert_ne!(chain(0), chain(1));
+    }
+}
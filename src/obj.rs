@@ -0,0 +1,125 @@
+use crate::material::Material;
+use crate::math::*;
+use crate::mesh::TriangleMesh;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// Resolves a one-based, possibly negative OBJ index into `count` elements.
+fn resolve(index: &str, count: usize) -> std::io::Result<Option<usize>> {
+    if index.is_empty() {
+        return Ok(None);
+    }
+    let index: i64 = index.parse().map_err(|_| invalid("malformed OBJ index"))?;
+    let resolved = match index < 0 {
+        true => count as i64 + index,
+        false => index - 1,
+    };
+    match (0..count as i64).contains(&resolved) {
+        true => Ok(Some(resolved as usize)),
+        false => Err(invalid("OBJ index out of range")),
+    }
+}
+
+impl TriangleMesh {
+    /// Loads the geometry of a Wavefront OBJ file as a single mesh.
+    ///
+    /// Groups and material assignments are ignored. Texture coordinates are
+    /// flipped vertically to put the image origin at the top left.
+    pub fn from_obj(
+        path: impl AsRef<Path>,
+        material: Box<dyn Material>,
+    ) -> std::io::Result<TriangleMesh> {
+        let text = fs::read_to_string(path)?;
+
+        let mut positions = Vec::new();
+        let mut uvs = Vec::new();
+        let mut normals = Vec::new();
+
+        // OBJ indexes attributes separately, each distinct corner becomes a vertex
+        let mut corners: HashMap<(usize, Option<usize>, Option<usize>), usize> = HashMap::new();
+        let mut vertices: Vec<(usize, Option<usize>, Option<usize>)> = Vec::new();
+        let mut indices = Vec::new();
+
+        for line in text.lines() {
+            let mut words = line.split_ascii_whitespace();
+            let keyword = words.next();
+            let numbers = |words: std::str::SplitAsciiWhitespace| {
+                words
+                    .map(|w| {
+                        w.parse::<f32>()
+                            .map_err(|_| invalid("malformed OBJ number"))
+                    })
+                    .collect::<std::io::Result<Vec<f32>>>()
+            };
+
+            match keyword {
+                Some("v") => match numbers(words)?.as_slice() {
+                    [x, y, z, ..] => positions.push(Point {
+                        x: *x,
+                        y: *y,
+                        z: *z,
+                    }),
+                    _ => return Err(invalid("OBJ vertex needs three coordinates")),
+                },
+                Some("vt") => match numbers(words)?.as_slice() {
+                    [u, v, ..] => uvs.push((*u, 1.0 - *v)),
+                    [u] => uvs.push((*u, 1.0)),
+                    _ => return Err(invalid("OBJ texture coordinate is empty")),
+                },
+                Some("vn") => match numbers(words)?.as_slice() {
+                    [x, y, z, ..] => normals.push(Vector {
+                        x: *x,
+                        y: *y,
+                        z: *z,
+                    }),
+                    _ => return Err(invalid("OBJ normal needs three coordinates")),
+                },
+                Some("f") => {
+                    let mut face = Vec::new();
+                    for corner in words {
+                        let mut parts = corner.split('/');
+                        let position = resolve(parts.next().unwrap_or(""), positions.len())?
+                            .ok_or_else(|| invalid("OBJ face corner without a position"))?;
+                        let uv = resolve(parts.next().unwrap_or(""), uvs.len())?;
+                        let normal = resolve(parts.next().unwrap_or(""), normals.len())?;
+
+                        let key = (position, uv, normal);
+                        let vertex = *corners.entry(key).or_insert_with(|| {
+                            vertices.push(key);
+                            vertices.len() - 1
+                        });
+                        face.push(vertex);
+                    }
+                    for k in 1..face.len().saturating_sub(1) {
+                        indices.push([face[0], face[k], face[k + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // attributes only come through when every corner has them
+        let all_uvs = vertices.iter().all(|v| v.1.is_some());
+        let all_normals = vertices.iter().all(|v| v.2.is_some());
+        let mesh_uvs = match all_uvs {
+            true => vertices.iter().map(|v| uvs[v.1.unwrap()]).collect(),
+            false => Vec::new(),
+        };
+        let mesh_normals = match all_normals {
+            true => vertices.iter().map(|v| normals[v.2.unwrap()]).collect(),
+            false => Vec::new(),
+        };
+        let mesh_positions = vertices.iter().map(|v| positions[v.0]).collect();
+
+        TriangleMesh::new(mesh_positions, indices, material)?
+            .with_uvs(mesh_uvs)?
+            .with_normals(mesh_normals)
+    }
+}
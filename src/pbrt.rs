@@ -0,0 +1,825 @@
+use crate::colorspace::ColorSpace;
+use crate::emitter::*;
+use crate::integrator::PathIntegrator;
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::TriangleMesh;
+use crate::primitives::*;
+use crate::scene::*;
+use crate::sensor::*;
+use crate::texture::*;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Scene, camera and render settings read from a PBRT file.
+pub struct PbrtScene {
+    pub scene: Scene,
+    pub camera: PinholeCamera,
+    pub integrator: PathIntegrator,
+    pub spp: usize,
+    /// Output image requested by the film.
+    pub filename: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Open,
+    Close,
+}
+
+/// A `"type name" value` pair following a directive.
+struct Param {
+    ty: String,
+    name: String,
+    values: Vec<Token>,
+}
+
+struct Params(Vec<Param>);
+
+#[derive(Clone)]
+enum MaterialDesc {
+    Diffuse(Color),
+    Conductor(Color, Color),
+    Mirror(Color),
+    Dielectric(f32),
+    Dispersive(Dispersion),
+    Interface,
+}
+
+/// Attributes saved and restored by `AttributeBegin` and `AttributeEnd`.
+#[derive(Clone)]
+struct State {
+    ctm: Transform,
+    material: MaterialDesc,
+    /// Material id in the scene shared by the shapes of `material`.
+    material_id: usize,
+    area_light: Option<Color>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    base: PathBuf,
+    /// Files being parsed with the position just past their tokens, outermost first.
+    files: Vec<(PathBuf, usize)>,
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn gray(v: f32) -> Color {
+    Color::new(v, v, v)
+}
+
+fn point(v: &[f32]) -> Point {
+    Point {
+        x: v[0],
+        y: v[1],
+        z: v[2],
+    }
+}
+
+fn vector(v: &[f32]) -> Vector {
+    Vector {
+        x: v[0],
+        y: v[1],
+        z: v[2],
+    }
+}
+
+fn tokenize(text: &str) -> std::io::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '[' | ']' => {
+                chars.next();
+                tokens.push(if c == '[' { Token::Open } else { Token::Close });
+            }
+            '"' => {
+                chars.next();
+                let text: String = std::iter::from_fn(|| chars.next_if(|&c| c != '"')).collect();
+                if chars.next().is_none() {
+                    return Err(invalid("unterminated string"));
+                }
+                tokens.push(Token::Str(text));
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let word = std::iter::from_fn(|| {
+                    chars.next_if(|&c| !c.is_whitespace() && !matches!(c, '[' | ']' | '"' | '#'))
+                })
+                .collect();
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Known metals as RGB fits of their complex index of refraction.
+fn metal(name: &str) -> Option<(Color, Color)> {
+    match name {
+        "Cu" => Some((
+            Color::new(0.200, 0.924, 1.102),
+            Color::new(3.913, 2.453, 2.142),
+        )),
+        "Au" => Some((
+            Color::new(0.143, 0.375, 1.442),
+            Color::new(3.983, 2.386, 1.603),
+        )),
+        "Ag" => Some((
+            Color::new(0.155, 0.117, 0.138),
+            Color::new(4.828, 3.122, 2.147),
+        )),
+        "Al" => Some((
+            Color::new(1.657, 0.880, 0.521),
+            Color::new(9.224, 6.270, 4.837),
+        )),
+        _ => None,
+    }
+}
+
+impl Params {
+    fn find(&self, names: &[&str]) -> Option<&Param> {
+        self.0.iter().find(|p| names.contains(&p.name.as_str()))
+    }
+
+    fn floats(&self, names: &[&str]) -> std::io::Result<Option<Vec<f32>>> {
+        let Some(param) = self.find(names) else {
+            return Ok(None);
+        };
+        param
+            .values
+            .iter()
+            .map(|value| match value {
+                Token::Word(word) => word
+                    .parse::<f32>()
+                    .map_err(|_| invalid(format!("malformed number in {}", param.name))),
+                _ => Err(invalid(format!("{} expects numbers", param.name))),
+            })
+            .collect::<std::io::Result<Vec<f32>>>()
+            .map(Some)
+    }
+
+    /// Numbers of a parameter that lists `n` of them per element.
+    fn elements(&self, names: &[&str], n: usize) -> std::io::Result<Option<Vec<f32>>> {
+        match self.floats(names)? {
+            Some(v) if !v.chunks_exact(n).remainder().is_empty() => Err(invalid(format!(
+                "{} expects a multiple of {n} numbers",
+                names[0]
+            ))),
+            v => Ok(v),
+        }
+    }
+
+    fn point(&self, names: &[&str], default: Point) -> std::io::Result<Point> {
+        match self.floats(names)? {
+            Some(v) if v.len() == 3 => Ok(point(&v)),
+            Some(_) => Err(invalid(format!("{} expects three numbers", names[0]))),
+            None => Ok(default),
+        }
+    }
+
+    fn float(&self, names: &[&str], default: f32) -> std::io::Result<f32> {
+        Ok(self
+            .floats(names)?
+            .and_then(|v| v.first().copied())
+            .unwrap_or(default))
+    }
+
+    fn string(&self, names: &[&str]) -> Option<String> {
+        match self.find(names)?.values.first() {
+            Some(Token::Str(text)) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    /// Color given as `rgb`, a constant `spectrum` or a single `float`.
+    fn color(&self, names: &[&str], default: Color) -> std::io::Result<Color> {
+        let Some(param) = self.find(names) else {
+            return Ok(default);
+        };
+        match (param.ty.as_str(), self.floats(names)) {
+            ("rgb" | "color", Ok(Some(v))) if v.len() == 3 => Ok(Color::new(v[0], v[1], v[2])),
+            ("spectrum" | "float", Ok(Some(v))) if v.len() == 1 => Ok(gray(v[0])),
+            _ => Err(invalid(format!(
+                "unsupported {} value for {}",
+                param.ty, param.name
+            ))),
+        }
+    }
+
+    /// Named metal spectrum such as `metal-Cu-eta`, split into eta and k.
+    fn metal(&self) -> Option<(Color, Color)> {
+        let name = self.string(&["eta"])?;
+        metal(name.strip_prefix("metal-")?.strip_suffix("-eta")?)
+    }
+}
+
+impl MaterialDesc {
+    /// Reads pbrt-v3 and pbrt-v4 material parameters.
+    ///
+    /// Unsupported material types fall back to a diffuse surface with their base color.
+    fn parse(ty: &str, params: &Params) -> std::io::Result<MaterialDesc> {
+        Ok(match ty {
+            "conductor" | "metal" => {
+                if params.find(&["reflectance"]).is_some() {
+                    MaterialDesc::Mirror(params.color(&["reflectance"], gray(1.0))?)
+                } else {
+                    let (eta, k) = params.metal().unwrap_or(metal("Cu").unwrap());
+                    let eta = match params.string(&["eta"]) {
+                        Some(_) => eta,
+                        None => params.color(&["eta"], eta)?,
+                    };
+                    let k = match params.string(&["k"]) {
+                        Some(_) => k,
+                        None => params.color(&["k"], k)?,
+                    };
+                    MaterialDesc::Conductor(eta, k)
+                }
+            }
+            "mirror" => MaterialDesc::Mirror(params.color(&["Kr"], gray(0.9))?),
+            "dielectric" | "glass" | "thindielectric" => {
+                match params.string(&["eta"]).as_deref() {
+                    Some("glass-BK7") => MaterialDesc::Dispersive(Dispersion::BK7),
+                    // other named glass spectra are close enough to this
+                    Some(_) => MaterialDesc::Dielectric(1.5),
+                    None => MaterialDesc::Dielectric(params.float(&["eta", "index"], 1.5)?),
+                }
+            }
+            "interface" | "" | "none" => MaterialDesc::Interface,
+            _ => MaterialDesc::Diffuse(params.color(&["reflectance", "Kd"], gray(0.5))?),
+        })
+    }
+
+    fn build(&self) -> Box<dyn Material> {
+        match self {
+            MaterialDesc::Diffuse(albedo) => Box::new(DiffuseMaterial {
+                albedo: Box::new(*albedo),
+            }),
+            MaterialDesc::Conductor(eta, k) => Box::new(ConductorMaterial::new(*eta, *k)),
+            MaterialDesc::Mirror(reflectance) => {
+                Box::new(ConductorMaterial::from_reflectance(*reflectance))
+            }
+            MaterialDesc::Dielectric(ior) => Box::new(DielectricMaterial::new(*ior)),
+            MaterialDesc::Dispersive(dispersion) => {
+                Box::new(DielectricMaterial::with_dispersion(*dispersion))
+            }
+            MaterialDesc::Interface => Box::new(NullMaterial {}),
+        }
+    }
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn string(&mut self) -> std::io::Result<String> {
+        match self.next() {
+            Some(Token::Str(text)) => Ok(text),
+            other => Err(invalid(format!("expected a string, found {other:?}"))),
+        }
+    }
+
+    /// `n` numbers, optionally enclosed in brackets.
+    fn numbers(&mut self, n: usize) -> std::io::Result<Vec<f32>> {
+        let bracketed = self.peek() == Some(&Token::Open);
+        if bracketed {
+            self.next();
+        }
+        let values = (0..n)
+            .map(|_| match self.next() {
+                Some(Token::Word(word)) => word.parse().map_err(|_| invalid("malformed number")),
+                other => Err(invalid(format!("expected a number, found {other:?}"))),
+            })
+            .collect::<std::io::Result<Vec<f32>>>()?;
+        if bracketed && self.next() != Some(Token::Close) {
+            return Err(invalid("expected ]"));
+        }
+        Ok(values)
+    }
+
+    fn params(&mut self) -> std::io::Result<Params> {
+        let mut params = Vec::new();
+        while let Some(Token::Str(declaration)) = self.peek().cloned() {
+            let mut words = declaration.split_ascii_whitespace();
+            let (Some(ty), Some(name)) = (words.next(), words.next()) else {
+                break;
+            };
+            let (ty, name) = (ty.to_string(), name.to_string());
+            self.next();
+
+            let values = match self.next() {
+                Some(Token::Open) => {
+                    let mut values = Vec::new();
+                    loop {
+                        match self.next() {
+                            Some(Token::Close) => break,
+                            Some(token) => values.push(token),
+                            None => return Err(invalid("unterminated parameter list")),
+                        }
+                    }
+                    values
+                }
+                Some(token) => vec![token],
+                None => return Err(invalid(format!("missing value for {name}"))),
+            };
+            params.push(Param { ty, name, values });
+        }
+        Ok(Params(params))
+    }
+
+    /// Skips the arguments of a directive without effect on rendering.
+    fn skip(&mut self) {
+        while let Some(token) = self.peek() {
+            if let Token::Word(word) = token {
+                if word.starts_with(|c: char| c.is_ascii_uppercase()) {
+                    break;
+                }
+            }
+            self.next();
+        }
+    }
+
+    /// Splices the tokens of `file` in at the current position.
+    ///
+    /// A file that is still being parsed cannot be included again, which
+    /// would otherwise splice in its tokens without end.
+    fn include(&mut self, file: &str) -> std::io::Result<()> {
+        let path = fs::canonicalize(self.base.join(file))?;
+        self.files.retain(|&(_, end)| end >= self.pos);
+        if self.files.iter().any(|(open, _)| *open == path) {
+            return Err(invalid(format!("{file} includes itself")));
+        }
+        let tokens = tokenize(&fs::read_to_string(&path)?)?;
+        for (_, end) in &mut self.files {
+            *end += tokens.len();
+        }
+        self.files.push((path, self.pos + tokens.len()));
+        self.tokens.splice(self.pos..self.pos, tokens);
+        Ok(())
+    }
+}
+
+/// World-to-camera transform of a camera at `eye` looking at `look`, as PBRT defines it.
+fn look_at(eye: Point, look: Point, up: Vector) -> std::io::Result<Transform> {
+    let dir = (look - eye).normalize();
+    let right = cross(up.normalize(), dir).normalize();
+    let new_up = cross(dir, right);
+    let m = [
+        [right.x, new_up.x, dir.x, eye.x],
+        [right.y, new_up.y, dir.y, eye.y],
+        [right.z, new_up.z, dir.z, eye.z],
+        [0.0, 0.0, 0.0, 1.0],
+    ];
+    Transform::from_matrix(m)
+        .map(|t| t.inverse())
+        .ok_or_else(|| invalid("degenerate LookAt"))
+}
+
+fn matrix(values: &[f32]) -> std::io::Result<Transform> {
+    // matrices are listed column by column
+    let m = [0, 1, 2, 3].map(|row| [0, 1, 2, 3].map(|col| values[4 * col + row]));
+    Transform::from_matrix(m).ok_or_else(|| invalid("singular transform"))
+}
+
+/// Builds a shape in object space, `None` for unsupported shape types.
+fn shape(
+    ty: &str,
+    params: &Params,
+    material: Box<dyn Material>,
+    base: &Path,
+) -> std::io::Result<Option<Box<dyn Shape>>> {
+    let origin = Point {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+    let z = Vector {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    };
+    Ok(Some(match ty {
+        "sphere" => Box::new(Sphere::new(
+            origin,
+            params.float(&["radius"], 1.0)?,
+            material,
+        )),
+        "disk" => Box::new(Disk::new(
+            origin + params.float(&["height"], 0.0)? * z,
+            z,
+            params.float(&["radius"], 1.0)?,
+            material,
+        )),
+        "cylinder" => {
+            let (z_min, z_max) = (
+                params.float(&["zmin"], -1.0)?,
+                params.float(&["zmax"], 1.0)?,
+            );
+            Box::new(Cylinder::new(
+                origin + z_min * z,
+                (z_max - z_min) * z,
+                params.float(&["radius"], 1.0)?,
+                false,
+                material,
+            ))
+        }
+        "trianglemesh" => {
+            let positions: Vec<Point> = params
+                .elements(&["P"], 3)?
+                .ok_or_else(|| invalid("trianglemesh without P"))?
+                .chunks_exact(3)
+                .map(point)
+                .collect();
+            let indices: Vec<usize> = match params.floats(&["indices"])? {
+                Some(indices) => indices.iter().map(|&i| i as usize).collect(),
+                None if positions.len() == 3 => vec![0, 1, 2],
+                None => return Err(invalid("trianglemesh without indices")),
+            };
+            let normals = params
+                .elements(&["N"], 3)?
+                .map_or(Vec::new(), |n| n.chunks_exact(3).map(vector).collect());
+            // texture space has its origin at the bottom left
+            let uvs = params.elements(&["uv", "st"], 2)?.map_or(Vec::new(), |uv| {
+                uv.chunks_exact(2).map(|uv| (uv[0], 1.0 - uv[1])).collect()
+            });
+            let indices = indices
+                .chunks_exact(3)
+                .map(|t| [t[0], t[1], t[2]])
+                .collect();
+            Box::new(
+                TriangleMesh::new(positions, indices, material)?
+                    .with_normals(normals)?
+                    .with_uvs(uvs)?,
+            )
+        }
+        "plymesh" | "objmesh" | "obj" => {
+            let file = params
+                .string(&["filename"])
+                .ok_or_else(|| invalid(format!("{ty} without filename")))?;
+            match ty {
+                "plymesh" => Box::new(TriangleMesh::from_ply(base.join(file), material)?),
+                _ => Box::new(TriangleMesh::from_obj(base.join(file), material)?),
+            }
+        }
+        _ => return Ok(None),
+    }))
+}
+
+/// Loads the subset of the PBRT v3 and v4 scene format that walnut can render.
+///
+/// Supported are perspective cameras, the sphere, disk, cylinder, triangle
+/// and PLY or OBJ mesh shapes, diffuse, conductor, dielectric and interface
+/// materials, as well as point, spot, distant, infinite and diffuse area
+/// lights. Any integrator turns into a path tracer with the requested
+/// maximum depth. Textures, media and object instancing are not supported.
+pub fn load_pbrt(path: impl AsRef<Path>) -> std::io::Result<PbrtScene> {
+    let path = path.as_ref();
+    let tokens = tokenize(&fs::read_to_string(path)?)?;
+    let mut parser = Parser {
+        files: vec![(fs::canonicalize(path)?, tokens.len())],
+        tokens,
+        pos: 0,
+        base: path.parent().map_or(PathBuf::new(), Path::to_path_buf),
+    };
+
+    let mut scene = Scene::new();
+    scene.background_color = gray(0.0);
+
+    let mut state = State {
+        ctm: Transform::identity(),
+        material: MaterialDesc::Diffuse(gray(0.5)),
+        material_id: scene.new_material_id(),
+        area_light: None,
+    };
+    let mut stack: Vec<State> = Vec::new();
+    let mut named_materials: HashMap<String, (MaterialDesc, usize)> = HashMap::new();
+    let mut coordinate_systems: HashMap<String, Transform> = HashMap::new();
+
+    let mut camera: Option<(f32, Transform)> = None;
+    let (mut width, mut height) = (640, 480);
+    let mut filename = String::from("pbrt.exr");
+    let mut spp = 16;
+    let mut max_depth = 5;
+
+    while let Some(token) = parser.next() {
+        let Token::Word(directive) = token else {
+            return Err(invalid(format!("expected a directive, found {token:?}")));
+        };
+        match directive.as_str() {
+            "Identity" => state.ctm = Transform::identity(),
+            "Translate" => {
+                let v = parser.numbers(3)?;
+                state.ctm = state.ctm * Transform::translate(vector(&v));
+            }
+            "Scale" => {
+                let v = parser.numbers(3)?;
+                state.ctm = state.ctm * Transform::scale(v[0], v[1], v[2]);
+            }
+            "Rotate" => {
+                let v = parser.numbers(4)?;
+                state.ctm = state.ctm * Transform::rotate(v[0], vector(&v[1..]));
+            }
+            "LookAt" => {
+                let v = parser.numbers(9)?;
+                let look = look_at(point(&v[0..3]), point(&v[3..6]), vector(&v[6..9]))?;
+                state.ctm = state.ctm * look;
+            }
+            "Transform" => state.ctm = matrix(&parser.numbers(16)?)?,
+            "ConcatTransform" => state.ctm = state.ctm * matrix(&parser.numbers(16)?)?,
+            "CoordinateSystem" => {
+                coordinate_systems.insert(parser.string()?, state.ctm);
+            }
+            "CoordSysTransform" => {
+                let name = parser.string()?;
+                state.ctm = *coordinate_systems
+                    .get(&name)
+                    .ok_or_else(|| invalid(format!("unknown coordinate system {name}")))?;
+            }
+            "Camera" => {
+                let ty = parser.string()?;
+                let params = parser.params()?;
+                if ty != "perspective" {
+                    return Err(invalid(format!("unsupported camera {ty}")));
+                }
+                let camera_to_world = state.ctm.inverse();
+                coordinate_systems.insert("camera".to_string(), camera_to_world);
+                camera = Some((params.float(&["fov"], 90.0)?, camera_to_world));
+            }
+            "Film" => {
+                parser.string()?;
+                let params = parser.params()?;
+                width = params.float(&["xresolution"], width as f32)? as usize;
+                height = params.float(&["yresolution"], height as f32)? as usize;
+                filename = params.string(&["filename"]).unwrap_or(filename);
+            }
+            "Sampler" => {
+                parser.string()?;
+                spp = parser.params()?.float(&["pixelsamples"], spp as f32)? as usize;
+            }
+            "Integrator" => {
+                parser.string()?;
+                max_depth = parser.params()?.float(&["maxdepth"], max_depth as f32)? as usize;
+            }
+            "WorldBegin" => {
+                state.ctm = Transform::identity();
+                coordinate_systems.insert("world".to_string(), state.ctm);
+            }
+            "AttributeBegin" | "TransformBegin" => stack.push(state.clone()),
+            "AttributeEnd" => {
+                state = stack
+                    .pop()
+                    .ok_or_else(|| invalid("unmatched AttributeEnd"))?;
+            }
+            "TransformEnd" => {
+                state.ctm = stack
+                    .pop()
+                    .ok_or_else(|| invalid("unmatched TransformEnd"))?
+                    .ctm;
+            }
+            "Material" => {
+                let ty = parser.string()?;
+                state.material = MaterialDesc::parse(&ty, &parser.params()?)?;
+                state.material_id = scene.new_material_id();
+            }
+            "MakeNamedMaterial" => {
+                let name = parser.string()?;
+                let params = parser.params()?;
+                let ty = params.string(&["type"]).unwrap_or_default();
+                let material = MaterialDesc::parse(&ty, &params)?;
+                named_materials.insert(name, (material, scene.new_material_id()));
+            }
+            "NamedMaterial" => {
+                let name = parser.string()?;
+                (state.material, state.material_id) = named_materials
+                    .get(&name)
+                    .cloned()
+                    .ok_or_else(|| invalid(format!("unknown material {name}")))?;
+            }
+            "LightSource" => {
+                let ty = parser.string()?;
+                let params = parser.params()?;
+                let scale = params.color(&["scale"], gray(1.0))?;
+                let from = params.point(&["from"], point(&[0.0, 0.0, 0.0]))?;
+                let to = params.point(&["to"], point(&[0.0, 0.0, 1.0]))?;
+                let position = state.ctm.point(from);
+                let direction = state.ctm.vector(to - from);
+
+                match ty.as_str() {
+                    "point" => {
+                        let intensity = scale * params.color(&["I"], gray(1.0))?;
+                        scene.add_light(Box::new(PointLight::new_colored(position, intensity)));
+                    }
+                    "spot" => {
+                        let intensity = scale * params.color(&["I"], gray(1.0))?;
+                        let cone = params.float(&["coneangle"], 30.0)?;
+                        let delta = params.float(&["conedelta"], 5.0)?;
+                        scene.add_light(Box::new(SpotLight::new(
+                            position,
+                            direction,
+                            intensity,
+                            cone - delta,
+                            cone,
+                        )));
+                    }
+                    "distant" => {
+                        let irradiance = scale * params.color(&["L"], gray(1.0))?;
+                        scene.add_light(Box::new(DirectionalLight::new(direction, irradiance)));
+                    }
+                    "infinite" => match params.string(&["filename"]) {
+                        Some(file) => {
+                            let image = ImageTexture::open(parser.base.join(file))
+                                .map_err(|e| invalid(e.to_string()))?
+                                .into_color_space(scene.color_space);
+                            scene.environment = Some(EnvironmentMap::new(image, scale, state.ctm));
+                        }
+                        None => scene.background_color = scale * params.color(&["L"], gray(1.0))?,
+                    },
+                    _ => return Err(invalid(format!("unsupported light {ty}"))),
+                }
+            }
+            "AreaLightSource" => {
+                parser.string()?;
+                let params = parser.params()?;
+                let radiance = params.color(&["L"], gray(1.0))?;
+                state.area_light = Some(params.color(&["scale"], gray(1.0))? * radiance);
+            }
+            "Shape" => {
+                let ty = parser.string()?;
+                let params = parser.params()?;
+                let material = state.material.build();
+                let Some(shape) = shape(&ty, &params, material, &parser.base)? else {
+                    return Err(invalid(format!("unsupported shape {ty}")));
+                };
+                let instance = Instance::new(Arc::from(shape), state.ctm);
+                match state.area_light {
+                    Some(_) if instance.transform.uniform_scale().is_none() => {
+                        return Err(invalid("area lights need a uniform scale"));
+                    }
+                    Some(radiance) => scene.add_area_light(Arc::new(instance), radiance),
+                    None => scene.add_shape_with_material_id(Box::new(instance), state.material_id),
+                }
+            }
+            "Include" | "Import" => {
+                let file = parser.string()?;
+                parser.include(&file)?;
+            }
+            "WorldEnd" | "ReverseOrientation" => {}
+            "ColorSpace" => {
+                let name = parser.string()?;
+                scene.color_space = ColorSpace::from_name(&name)
+                    .ok_or_else(|| invalid(format!("unsupported color space {name}")))?;
+            }
+            "PixelFilter" | "Accelerator" | "Option" | "Attribute" | "Texture"
+            | "MakeNamedMedium" | "MediumInterface" => parser.skip(),
+            _ => return Err(invalid(format!("unsupported directive {directive}"))),
+        }
+    }
+
+    // PBRT cameras look down +z, the field of view spans the shorter image axis
+    let (fov, camera_to_world) = camera.unwrap_or((90.0, Transform::identity()));
+    let aspect = width as f32 / height as f32;
+    let fov = match aspect < 1.0 {
+        true => 2.0 * f32::atan(f32::tan(fov.to_radians() / 2.0) / aspect).to_degrees(),
+        false => fov,
+    };
+    let camera = PinholeCamera::new(Sensor::zero(width, height), fov)
+        .with_transform(camera_to_world * Transform::scale(1.0, 1.0, -1.0));
+
+    Ok(PbrtScene {
+        scene,
+        camera,
+        integrator: PathIntegrator::new(max_depth, usize::min(max_depth, 3)),
+        spp,
+        filename,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_path;
+
+    const SCENE: &str = r#"
+        # a mirror sphere lit by a quad, seen from the front
+        LookAt 0 0 5  0 0 0  0 1 0
+        Camera "perspective" "float fov" [ 40 ]
+        Film "image" "integer xresolution" [ 32 ] "integer yresolution" [ 16 ]
+            "string filename" [ "out.exr" ]
+        Sampler "halton" "integer pixelsamples" 8
+        Integrator "path" "integer maxdepth" [ 7 ]
+        WorldBegin
+        LightSource "point" "rgb I" [ 1 2 3 ] "point3 from" [ 0 4 0 ]
+        LightSource "infinite" "rgb L" [ 0.1 0.1 0.1 ]
+        AttributeBegin
+            AreaLightSource "diffuse" "rgb L" [ 4 4 4 ]
+            Translate 0 3 0
+            Shape "trianglemesh" "integer indices" [ 0 1 2 0 2 3 ]
+                "point3 P" [ -1 0 -1  1 0 -1  1 0 1  -1 0 1 ]
+        AttributeEnd
+        MakeNamedMaterial "gold" "string type" "conductor"
+            "spectrum eta" "metal-Au-eta" "spectrum k" "metal-Au-k"
+        NamedMaterial "gold"
+        Shape "sphere" "float radius" 1
+        Material "dielectric" "float eta" 1.33
+        Translate 3 0 0
+        Shape "sphere" "float radius" 0.5
+    "#;
+
+    #[test]
+    fn parses_scene() {
+        let path = temp_path("scene.pbrt");
+        fs::write(&path, SCENE).unwrap();
+        let pbrt = load_pbrt(&path);
+        fs::remove_file(path).unwrap();
+        let pbrt = pbrt.unwrap();
+
+        assert_eq!((pbrt.spp, pbrt.filename.as_str()), (8, "out.exr"));
+        assert_eq!(pbrt.scene.shapes().len(), 3);
+        assert_eq!(pbrt.scene.lights.len(), 2);
+        assert!((pbrt.scene.background_color.r - 0.1).abs() < 1e-6);
+
+        let position = pbrt.camera.position();
+        assert!(norm(position - point(&[0.0, 0.0, 5.0])) < 1e-4);
+
+        // the center ray hits the gold sphere straight on
+        let mut sampler = crate::sampler::Sampler::new(0, 0);
+        let ray = pbrt.camera.sample_ray(16, 8, &mut sampler).unwrap();
+        assert!(ray.direction.z < -0.99);
+        let si = pbrt.scene.closest_hit(&ray).unwrap();
+        assert!((si.t - 4.0).abs() < 0.05);
+        assert!(si.material.is_delta_reflector());
+
+        // the area light faces down onto the sphere
+        let up = Ray {
+            origin: point(&[0.0, 1.5, 0.0]),
+            direction: vector(&[0.0, 1.0, 0.0]),
+        };
+        let light = pbrt.scene.closest_hit(&up).unwrap();
+        assert!(light.emitter.is_some() && (light.t - 1.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn keeps_handedness() {
+        // PBRT renders +x to the right when looking down -z from +z
+        let path = temp_path("handedness.pbrt");
+        fs::write(
+            &path,
+            r#"Scale -1 1 1
+            LookAt 0 0 5  0 0 0  0 1 0
+            Camera "perspective"
+            Film "image" "integer xresolution" 8 "integer yresolution" 8
+            WorldBegin"#,
+        )
+        .unwrap();
+        let pbrt = load_pbrt(&path);
+        fs::remove_file(path).unwrap();
+        let pbrt = pbrt.unwrap();
+        let mut sampler = crate::sampler::Sampler::new(0, 0);
+        let right = pbrt.camera.sample_ray(7, 4, &mut sampler).unwrap();
+        assert!(right.direction.x > 0.0);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let path = temp_path("malformed.pbrt");
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let broken = [
+            r#"LightSource "point" "point3 from" [ 0 4 ]"#,
+            r#"Shape "trianglemesh" "point3 P" [ 0 0 0  1 0 0  0 1 0  1 ]"#,
+            r#"Shape "trianglemesh" "point3 P" [ 0 0 0  1 0 0  0 1 0 ] "normal N" [ 0 0 1 ]"#,
+            r#"Shape "trianglemesh" "point3 P" [ 0 0 0  1 0 0  0 1 0 ] "point2 uv" [ 0 0  1 0 ]"#,
+            &format!(r#"Include "{name}""#),
+        ];
+        let loaded: Vec<_> = broken
+            .iter()
+            .map(|text| {
+                fs::write(&path, text).unwrap();
+                load_pbrt(&path).map(|_| ())
+            })
+            .collect();
+        fs::remove_file(path).unwrap();
+        for result in loaded {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+}
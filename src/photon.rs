@@ -0,0 +1,640 @@
+use crate::integrator::{parallel_map, render_pixels, Integrator};
+use crate::material::*;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::{Camera, Color};
+
+use rand::Rng;
+use std::f32::consts::PI;
+use std::ops::Range;
+use std::thread;
+
+/// Light flux arriving at a surface.
+#[derive(Clone, Copy, Debug)]
+pub struct Photon {
+    pub position: Point,
+    /// Direction back towards where the photon came from.
+    pub wi: Vector,
+    pub power: Color,
+}
+
+/// Balanced kd-tree over photons for fixed radius queries.
+///
+/// The photons are stored in build order, every subrange has its splitting
+/// photon in the middle, so the tree needs no pointers.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+}
+
+fn axis_value(p: Point, axis: u8) -> f32 {
+    match axis {
+        0 => p.x,
+        1 => p.y,
+        _ => p.z,
+    }
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        Self::build(&mut photons, &mut axes);
+        PhotonMap { photons, axes }
+    }
+
+    fn build(photons: &mut [Photon], axes: &mut [u8]) {
+        if photons.is_empty() {
+            return;
+        }
+        let bounds = photons
+            .iter()
+            .fold(Bounds3::empty(), |b, photon| b.union_point(photon.position));
+        let d = bounds.diagonal();
+        let axis = match d.x >= d.y && d.x >= d.z {
+            true => 0,
+            false => match d.y >= d.z {
+                true => 1,
+                false => 2,
+            },
+        };
+        let mid = photons.len() / 2;
+        photons.select_nth_unstable_by(mid, |a, b| {
+            let a = axis_value(a.position, axis);
+            let b = axis_value(b.position, axis);
+            a.total_cmp(&b)
+        });
+        axes[mid] = axis;
+        let (left, right) = photons.split_at_mut(mid);
+        let (left_axes, right_axes) = axes.split_at_mut(mid);
+        Self::build(left, left_axes);
+        Self::build(&mut right[1..], &mut right_axes[1..]);
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Calls `f` for every photon closer than `radius` to `point`.
+    pub fn for_each_within(&self, point: Point, radius: f32, mut f: impl FnMut(&Photon)) {
+        self.query(0, self.photons.len(), point, radius, &mut f);
+    }
+
+    fn query(&self, lo: usize, hi: usize, point: Point, radius: f32, f: &mut impl FnMut(&Photon)) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        if norm2(photon.position - point) < radius * radius {
+            f(photon);
+        }
+        let axis = self.axes[mid];
+        let delta = axis_value(point, axis) - axis_value(photon.position, axis);
+        if delta - radius < 0.0 {
+            self.query(lo, mid, point, radius, f);
+        }
+        if delta + radius >= 0.0 {
+            self.query(mid + 1, hi, point, radius, f);
+        }
+    }
+}
+
+/// BSDF without the cosine that `bsdf_eval` includes.
+fn bsdf_f(si: &SurfaceInteraction, wi: Vector) -> Color {
+    let cos = dot(si.normal, wi).abs();
+    match cos > 1e-6 {
+        true => (1.0 / cos) * si.material.bsdf_eval(si, wi).radiance,
+        false => Color::new(0.0, 0.0, 0.0),
+    }
+}
+
+/// Emits the photons numbered `range` from lights chosen uniformly.
+///
+/// Photons are stored at every diffuse hit after the first bounce, direct
+/// light is left to light sampling. Their power is the flux of one photon out
+/// of `emitted`, each photon draws from its own stream, so the result does not
+/// depend on `num_threads`.
+fn trace_photons(
+    scene: &Scene,
+    range: Range<usize>,
+    emitted: usize,
+    max_depth: usize,
+    seed: u64,
+    num_threads: usize,
+) -> Vec<Photon> {
+    const CHUNK: usize = 1024;
+    if scene.lights.is_empty() {
+        return Vec::new();
+    }
+    let bounds = scene.bounds();
+    let chunks = range.len().div_ceil(CHUNK);
+    parallel_map(chunks, num_threads, |chunk| {
+        let start = range.start + chunk * CHUNK;
+        let mut photons = Vec::new();
+        for k in start..usize::min(start + CHUNK, range.end) {
+            let mut sampler = Sampler::new(seed, k as u64);
+            trace_photon(
+                scene,
+                &bounds,
+                emitted,
+                max_depth,
+                &mut sampler,
+                &mut photons,
+            );
+        }
+        photons
+    })
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+fn trace_photon(
+    scene: &Scene,
+    bounds: &Bounds3,
+    emitted: usize,
+    max_depth: usize,
+    sampler: &mut Sampler,
+    photons: &mut Vec<Photon>,
+) {
+    let lights = &scene.lights;
+    let index = usize::min(
+        (sampler.gen::<f32>() * lights.len() as f32) as usize,
+        lights.len() - 1,
+    );
+    let Some(sample) = lights[index].sample_emission(bounds, sampler) else {
+        return;
+    };
+    if sample.pdf_position <= 0.0 || sample.pdf_direction <= 0.0 {
+        return;
+    }
+    let cos = sample
+        .normal
+        .map_or(1.0, |n| dot(n, sample.direction).abs());
+    let light_pdf = 1.0 / lights.len() as f32;
+    let pdf = light_pdf * sample.pdf_position * sample.pdf_direction * emitted as f32;
+    let mut power = (cos / pdf) * sample.emitted;
+    let mut ray = Ray {
+        origin: sample.position + 1e-3 * sample.direction,
+        direction: sample.direction,
+    };
+
+    for depth in 0..max_depth {
+        let Some(si) = scene.closest_surface(&ray) else {
+            break;
+        };
+        if depth > 0 && !si.material.is_delta_reflector() {
+            photons.push(Photon {
+                position: si.position,
+                wi: si.wi,
+                power,
+            });
+        }
+
+        let ScatterSample {
+            wo, radiance, pdf, ..
+        } = si.material.bsdf_scatter(&si, sampler);
+        if pdf <= 0.0 {
+            break;
+        }
+        let next = (1.0 / pdf) * power * radiance;
+
+        // keep the power of surviving photons about constant
+        let p = f32::min(
+            1.0,
+            f32::max(next.r, f32::max(next.g, next.b))
+                / f32::max(power.r, f32::max(power.g, power.b)),
+        );
+        if sampler.gen::<f32>() >= p {
+            break;
+        }
+        power = (1.0 / p) * next;
+        ray = Ray {
+            origin: si.position + 1e-3 * wo,
+            direction: wo,
+        };
+    }
+}
+
+/// First surface seen through delta bounces from a camera ray.
+struct VisiblePoint<'a> {
+    si: SurfaceInteraction<'a>,
+    /// Throughput of the delta bounces leading to the point.
+    beta: Color,
+}
+
+/// Follows `ray` through delta bounces to the first other surface.
+///
+/// Returns the emitted and directly lit radiance along the way together
+/// with that surface. Surfaces with both kinds of lobes continue along a
+/// delta lobe with the probability of its weight. `bounds` are those of the
+/// scene, for sampling infinite lights.
+fn trace_visible_point<'a>(
+    ray: &Ray,
+    scene: &'a Scene,
+    bounds: &Bounds3,
+    sampler: &mut Sampler,
+    max_depth: usize,
+) -> (Color, Option<VisiblePoint<'a>>) {
+    let mut radiance = Color::new(0.0, 0.0, 0.0);
+    let mut beta = Color::new(1.0, 1.0, 1.0);
+    let mut ray = Ray {
+        origin: ray.origin,
+        direction: ray.direction,
+    };
+
+    for _ in 0..max_depth {
+        let Some(si) = scene.closest_surface(&ray) else {
+            radiance = radiance + beta * scene.background(ray.direction);
+            break;
+        };
+        if let Some(light) = si.emitter {
+            radiance = radiance + beta * light.emitted(&si);
+        }
+
+        if !si.material.is_delta_reflector() {
+            for light in scene.lights.iter() {
+                let Some(sample) = light.sample_position(si.position, bounds, sampler) else {
+                    continue;
+                };
+                let w = sample.position - si.position;
+                let dist = norm(w);
+                let wo = w.normalize();
+                let weight = match light.is_infinite() {
+                    true => 1.0,
+                    false => {
+                        let cos = sample.normal.map_or(1.0, |n| dot(n, wo).abs());
+                        cos / (sample.pdf_position * dist * dist)
+                    }
+                };
+                let shadow_si = scene.closest_hit(&Ray {
+                    origin: si.position + 1e-3 * wo,
+                    direction: wo,
+                });
+                if let Some(si) = shadow_si {
+                    if si.t < dist - 2e-3 {
+                        continue;
+                    }
+                }
+                let f = si.material.bsdf_eval(&si, wo).radiance;
+                radiance = radiance + weight * beta * f * sample.emitted;
+            }
+
+            // delta lobes of the surface are followed instead of it with their share of the light
+            let lobes = si.material.delta_lobes(&si);
+            let total: f32 = lobes.iter().map(|(_, weight)| weight.luminance()).sum();
+            let follow = f32::min(total, 0.99);
+            if lobes.is_empty() || sampler.gen::<f32>() >= follow {
+                let beta = (1.0 / (1.0 - follow)) * beta;
+                return (radiance, Some(VisiblePoint { si, beta }));
+            }
+            let mut u = sampler.gen::<f32>() * total;
+            let (wo, weight) = lobes
+                .iter()
+                .find(|(_, weight)| {
+                    u -= weight.luminance();
+                    u < 0.0
+                })
+                .unwrap_or(&lobes[lobes.len() - 1]);
+            let pick = follow * weight.luminance() / total;
+            if pick <= 0.0 {
+                break;
+            }
+            beta = (1.0 / pick) * beta * *weight;
+            ray = Ray {
+                origin: si.position + 1e-3 * *wo,
+                direction: *wo,
+            };
+            continue;
+        }
+
+        let ScatterSample {
+            wo,
+            radiance: f,
+            pdf,
+            ..
+        } = si.material.bsdf_scatter(&si, sampler);
+        if pdf <= 0.0 {
+            break;
+        }
+        beta = (1.0 / pdf) * beta * f;
+        ray = Ray {
+            origin: si.position + 1e-3 * wo,
+            direction: wo,
+        };
+    }
+    (radiance, None)
+}
+
+/// Flux reflected towards the camera by the photons around a visible point.
+///
+/// Returns the flux and the number of photons found.
+fn gather(map: &PhotonMap, vp: &VisiblePoint, radius: f32) -> (Color, usize) {
+    let mut flux = Color::new(0.0, 0.0, 0.0);
+    let mut count = 0;
+    map.for_each_within(vp.si.position, radius, |photon| {
+        // photons on the back of thin surfaces do not count
+        if dot(photon.wi, vp.si.normal) * dot(vp.si.wi, vp.si.normal) <= 0.0 {
+            return;
+        }
+        let mut si = vp.si.clone();
+        si.wi = photon.wi;
+        flux = flux + vp.beta * bsdf_f(&si, vp.si.wi) * photon.power;
+        count += 1;
+    });
+    (flux, count)
+}
+
+fn available_threads() -> usize {
+    thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+/// Photon mapping for caustics and other light that paths from the camera rarely find.
+///
+/// Photons are shot from the lights once when the integrator is created.
+/// Camera rays follow delta bounces to the first other surface, which is lit
+/// directly by light sampling and indirectly by the photons within a fixed
+/// radius. The fixed radius blurs the indirect light a little and keeps it
+/// biased, `ProgressivePhotonMapIntegrator` converges instead.
+///
+/// Media are ignored.
+pub struct PhotonMapIntegrator {
+    map: PhotonMap,
+    radius: f32,
+    max_depth: usize,
+}
+
+impl PhotonMapIntegrator {
+    /// Shoots `photons` photons into `scene` with paths of up to `max_depth` bounces.
+    pub fn new(
+        scene: &Scene,
+        photons: usize,
+        radius: f32,
+        max_depth: usize,
+    ) -> PhotonMapIntegrator {
+        let stored = trace_photons(
+            scene,
+            0..photons,
+            photons,
+            max_depth,
+            0,
+            available_threads(),
+        );
+        PhotonMapIntegrator {
+            map: PhotonMap::new(stored),
+            radius,
+            max_depth,
+        }
+    }
+
+    pub fn photon_map(&self) -> &PhotonMap {
+        &self.map
+    }
+
+    fn radiance(&self, ray: &Ray, scene: &Scene, bounds: &Bounds3, sampler: &mut Sampler) -> Color {
+        let (direct, vp) = trace_visible_point(ray, scene, bounds, sampler, self.max_depth);
+        let Some(vp) = vp else {
+            return direct;
+        };
+        let (flux, _) = gather(&self.map, &vp, self.radius);
+        direct + (1.0 / (PI * self.radius * self.radius)) * flux
+    }
+}
+
+impl Integrator for PhotonMapIntegrator {
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        self.radiance(ray, scene, &scene.bounds(), sampler)
+    }
+
+    /// Shares the bounds of the scene between all camera rays.
+    fn render(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        spp: usize,
+        seed: u64,
+        num_threads: usize,
+    ) {
+        let bounds = scene.bounds();
+        render_pixels(
+            camera,
+            scene,
+            spp,
+            seed,
+            num_threads,
+            |ray, sampler, _, _| self.radiance(ray, scene, &bounds, sampler),
+        );
+    }
+}
+
+/// Stochastic progressive photon mapping.
+///
+/// Every pass traces one camera ray per pixel to a visible point and shoots a
+/// fresh batch of photons, whose flux is gathered around the visible point.
+/// The gather radius of each pixel shrinks with the photons it has seen, so
+/// the image converges to the right answer as passes go on. `render` takes
+/// the number of passes for `spp`. AOVs are not written.
+pub struct ProgressivePhotonMapIntegrator {
+    photons_per_pass: usize,
+    initial_radius: f32,
+    max_depth: usize,
+    /// Share of new photons kept per pass, 2/3 in the original method.
+    alpha: f32,
+}
+
+/// Per pixel state carried from pass to pass.
+#[derive(Clone, Copy)]
+struct PixelStatistics {
+    radius: f32,
+    photons: f32,
+    flux: Color,
+    direct: Color,
+}
+
+impl ProgressivePhotonMapIntegrator {
+    pub fn new(
+        photons_per_pass: usize,
+        initial_radius: f32,
+        max_depth: usize,
+    ) -> ProgressivePhotonMapIntegrator {
+        ProgressivePhotonMapIntegrator {
+            photons_per_pass,
+            initial_radius,
+            max_depth,
+            alpha: 2.0 / 3.0,
+        }
+    }
+
+    pub fn with_alpha(mut self, alpha: f32) -> ProgressivePhotonMapIntegrator {
+        self.alpha = alpha;
+        self
+    }
+
+    /// Statistics of a pixel after gathering the photons of `map` at a new visible point.
+    fn update(
+        &self,
+        stat: PixelStatistics,
+        ray: &Ray,
+        scene: &Scene,
+        bounds: &Bounds3,
+        map: &PhotonMap,
+        sampler: &mut Sampler,
+    ) -> PixelStatistics {
+        let (direct, vp) = trace_visible_point(ray, scene, bounds, sampler, self.max_depth);
+        let mut stat = PixelStatistics {
+            direct: stat.direct + direct,
+            ..stat
+        };
+        let Some(vp) = vp else {
+            return stat;
+        };
+        let (flux, found) = gather(map, &vp, stat.radius);
+        if found == 0 {
+            return stat;
+        }
+        // keep a share alpha of the new photons and shrink the radius to match
+        let photons = stat.photons + self.alpha * found as f32;
+        let radius = stat.radius * f32::sqrt(photons / (stat.photons + found as f32));
+        let shrink = (radius * radius) / (stat.radius * stat.radius);
+        stat.flux = shrink * (stat.flux + flux);
+        stat.photons = photons;
+        stat.radius = radius;
+        stat
+    }
+}
+
+impl Integrator for ProgressivePhotonMapIntegrator {
+    /// Photons only exist during `render`, a single ray just gets emitted and direct light.
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        trace_visible_point(ray, scene, &scene.bounds(), sampler, self.max_depth).0
+    }
+
+    /// Runs `spp` passes, the result only depends on `seed`.
+    fn render(
+        &self,
+        camera: &dyn Camera,
+        scene: &Scene,
+        spp: usize,
+        seed: u64,
+        num_threads: usize,
+    ) {
+        let sensor = camera.get_sensor();
+        let to_sensor = scene.color_space.matrix_to(sensor.color_space());
+        let bounds = scene.bounds();
+        let pixels = camera.get_pixels();
+        let mut stats = vec![
+            PixelStatistics {
+                radius: self.initial_radius,
+                photons: 0.0,
+                flux: Color::new(0.0, 0.0, 0.0),
+                direct: Color::new(0.0, 0.0, 0.0),
+            };
+            pixels.len()
+        ];
+
+        let total = self.photons_per_pass * spp;
+        for pass in 0..spp {
+            let photons = trace_photons(
+                scene,
+                pass * self.photons_per_pass..(pass + 1) * self.photons_per_pass,
+                total,
+                self.max_depth,
+                seed,
+                num_threads,
+            );
+            let map = PhotonMap::new(photons);
+
+            let updates = parallel_map(pixels.len(), num_threads, |index| {
+                let (i, j) = pixels[index].position;
+                let mut sampler = Sampler::for_sample(seed, index, pass);
+                let ray = camera.sample_ray(i, j, &mut sampler)?;
+                Some(self.update(stats[index], &ray, scene, &bounds, &map, &mut sampler))
+            });
+            for (stat, update) in stats.iter_mut().zip(updates) {
+                if let Some(update) = update {
+                    *stat = update;
+                }
+            }
+        }
+
+        for (pixel, stat) in pixels.iter().zip(stats.iter()) {
+            let area = PI * stat.radius * stat.radius;
+            let radiance = (1.0 / spp as f32) * stat.direct + (1.0 / area) * stat.flux;
+            *pixel.color.write().unwrap() = to_sensor.apply(radiance);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::tests::{lamp_scene, render};
+    use crate::integrator::PathIntegrator;
+
+    fn mean_green(integrator: &dyn Integrator, spp: usize, num_threads: usize) -> f32 {
+        let pixels = render(integrator, &lamp_scene(), spp, 1, num_threads);
+        pixels.iter().map(|color| color.g).sum::<f32>() / pixels.len() as f32
+    }
+
+    #[test]
+    fn finds_photons_in_radius() {
+        let mut sampler = Sampler::new(0, 0);
+        let photons: Vec<_> = (0..500)
+            .map(|_| Photon {
+                position: Point {
+                    x: sampler.gen(),
+                    y: sampler.gen(),
+                    z: 0.5 * sampler.gen::<f32>(),
+                },
+                wi: Vector {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                power: Color::new(1.0, 1.0, 1.0),
+            })
+            .collect();
+        let map = PhotonMap::new(photons.clone());
+        assert_eq!(map.len(), 500);
+
+        for _ in 0..20 {
+            let point = Point {
+                x: sampler.gen(),
+                y: sampler.gen(),
+                z: sampler.gen(),
+            };
+            let expected = photons
+                .iter()
+                .filter(|photon| norm(photon.position - point) < 0.2)
+                .count();
+            let mut found = 0;
+            map.for_each_within(point, 0.2, |_| found += 1);
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn converges_to_path_tracer() {
+        let reference = mean_green(&PathIntegrator::new(4, 4), 256, 4);
+
+        let photon_map = PhotonMapIntegrator::new(&lamp_scene(), 200_000, 0.1, 4);
+        let estimate = mean_green(&photon_map, 16, 4);
+        assert!(
+            (estimate - reference).abs() < 0.1 * reference,
+            "{estimate} {reference}"
+        );
+
+        let sppm = ProgressivePhotonMapIntegrator::new(20_000, 0.2, 4);
+        let estimate = mean_green(&sppm, 64, 4);
+        assert!(
+            (estimate - reference).abs() < 0.05 * reference,
+            "{estimate} {reference}"
+        );
+        assert_eq!(estimate, mean_green(&sppm, 64, 1));
+    }
+}
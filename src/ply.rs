@@ -0,0 +1,442 @@
+use crate::material::Material;
+use crate::math::*;
+use crate::mesh::TriangleMesh;
+use crate::sensor::Color;
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scalar {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    /// Count and item type, list names do not matter as only face indices are read.
+    List(Scalar, Scalar),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Values in the body of a PLY file, in the order the header declares them.
+enum Values<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary {
+        bytes: &'a [u8],
+        offset: usize,
+        big_endian: bool,
+    },
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+impl Scalar {
+    fn parse(name: &str) -> std::io::Result<Scalar> {
+        match name {
+            "char" | "int8" => Ok(Scalar::Int8),
+            "uchar" | "uint8" => Ok(Scalar::UInt8),
+            "short" | "int16" => Ok(Scalar::Int16),
+            "ushort" | "uint16" => Ok(Scalar::UInt16),
+            "int" | "int32" => Ok(Scalar::Int32),
+            "uint" | "uint32" => Ok(Scalar::UInt32),
+            "float" | "float32" => Ok(Scalar::Float32),
+            "double" | "float64" => Ok(Scalar::Float64),
+            _ => Err(invalid(format!("unknown PLY type {name}"))),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Scalar::Int8 | Scalar::UInt8 => 1,
+            Scalar::Int16 | Scalar::UInt16 => 2,
+            Scalar::Int32 | Scalar::UInt32 | Scalar::Float32 => 4,
+            Scalar::Float64 => 8,
+        }
+    }
+
+    /// Scale mapping stored color values to [0, 1].
+    fn color_scale(&self) -> f64 {
+        match self {
+            Scalar::UInt8 => 1.0 / 255.0,
+            Scalar::UInt16 => 1.0 / 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+impl Values<'_> {
+    fn read(&mut self, ty: Scalar) -> std::io::Result<f64> {
+        match self {
+            Values::Ascii(tokens) => tokens
+                .next()
+                .ok_or_else(|| invalid("PLY file ends early"))?
+                .parse::<f64>()
+                .map_err(|_| invalid("malformed PLY value")),
+            Values::Binary {
+                bytes,
+                offset,
+                big_endian,
+            } => {
+                let raw = bytes
+                    .get(*offset..*offset + ty.size())
+                    .ok_or_else(|| invalid("PLY file ends early"))?;
+                *offset += ty.size();
+
+                let mut b = [0u8; 8];
+                b[..raw.len()].copy_from_slice(raw);
+                if *big_endian {
+                    b[..raw.len()].reverse();
+                }
+                Ok(match ty {
+                    Scalar::Int8 => b[0] as i8 as f64,
+                    Scalar::UInt8 => b[0] as f64,
+                    Scalar::Int16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                    Scalar::UInt16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+                    Scalar::Int32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    Scalar::UInt32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    Scalar::Float32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    Scalar::Float64 => f64::from_le_bytes(b),
+                })
+            }
+        }
+    }
+}
+
+/// Parses the header, returns the elements and the byte offset of the body.
+fn parse_header(bytes: &[u8]) -> std::io::Result<(String, Vec<Element>, usize)> {
+    let marker = b"end_header";
+    let end = bytes
+        .windows(marker.len())
+        .position(|w| w == marker)
+        .ok_or_else(|| invalid("PLY header is not terminated"))?;
+    let body = end
+        + marker.len()
+        + bytes[end + marker.len()..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(0, |p| p + 1);
+
+    let header =
+        std::str::from_utf8(&bytes[..end]).map_err(|_| invalid("PLY header is not text"))?;
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        return Err(invalid("not a PLY file"));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        match words.as_slice() {
+            ["format", name, _] => format = Some(name.to_string()),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| invalid("malformed PLY element"))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, _] => elements
+                .last_mut()
+                .ok_or_else(|| invalid("PLY property outside of an element"))?
+                .properties
+                .push(Property::List(Scalar::parse(count)?, Scalar::parse(item)?)),
+            ["property", ty, name] => elements
+                .last_mut()
+                .ok_or_else(|| invalid("PLY property outside of an element"))?
+                .properties
+                .push(Property::Scalar(name.to_string(), Scalar::parse(ty)?)),
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(invalid(format!("unknown PLY header line {line}"))),
+        }
+    }
+
+    let format = format.ok_or_else(|| invalid("PLY format is missing"))?;
+    Ok((format, elements, body))
+}
+
+impl TriangleMesh {
+    /// Loads an ASCII or binary PLY file.
+    ///
+    /// Vertex normals (`nx`, `ny`, `nz`), UVs (`u`, `v` or `s`, `t`) and colors
+    /// (`red`, `green`, `blue`) are kept when present. Polygons are split into
+    /// triangle fans.
+    pub fn from_ply(
+        path: impl AsRef<Path>,
+        material: Box<dyn Material>,
+    ) -> std::io::Result<TriangleMesh> {
+        let bytes = fs::read(path)?;
+        let (format, elements, body) = parse_header(&bytes)?;
+
+        let mut values = match format.as_str() {
+            "ascii" => Values::Ascii(
+                std::str::from_utf8(&bytes[body..])
+                    .map_err(|_| invalid("ASCII PLY body is not text"))?
+                    .split_ascii_whitespace(),
+            ),
+            "binary_little_endian" | "binary_big_endian" => Values::Binary {
+                bytes: &bytes[body..],
+                offset: 0,
+                big_endian: format == "binary_big_endian",
+            },
+            _ => return Err(invalid(format!("unknown PLY format {format}"))),
+        };
+
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut colors = Vec::new();
+        let mut indices = Vec::new();
+
+        for element in elements.iter() {
+            let index_of = |names: &[&str]| {
+                element.properties.iter().position(|p| match p {
+                    Property::Scalar(name, _) => names.contains(&name.as_str()),
+                    Property::List(..) => false,
+                })
+            };
+            let position = [index_of(&["x"]), index_of(&["y"]), index_of(&["z"])];
+            let normal = [index_of(&["nx"]), index_of(&["ny"]), index_of(&["nz"])];
+            let uv = [
+                index_of(&["u", "s", "texture_u", "texture_s"]),
+                index_of(&["v", "t", "texture_v", "texture_t"]),
+            ];
+            let color = [
+                index_of(&["red", "r"]),
+                index_of(&["green", "g"]),
+                index_of(&["blue", "b"]),
+            ];
+
+            for _ in 0..element.count {
+                let mut scalars = vec![0.0; element.properties.len()];
+                let mut list = Vec::new();
+                for (k, property) in element.properties.iter().enumerate() {
+                    match property {
+                        Property::Scalar(_, ty) => scalars[k] = values.read(*ty)?,
+                        Property::List(count, item) => {
+                            let n = values.read(*count)? as usize;
+                            // only the first list of an element is kept, i.e. the face indices
+                            let keep = list.is_empty();
+                            for _ in 0..n {
+                                let value = values.read(*item)?;
+                                if keep {
+                                    list.push(value);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                match element.name.as_str() {
+                    "vertex" => {
+                        let get = |k: Option<usize>| k.map(|k| scalars[k] as f32);
+                        let [Some(x), Some(y), Some(z)] = position.map(get) else {
+                            return Err(invalid("PLY vertex without a position"));
+                        };
+                        positions.push(Point { x, y, z });
+                        if let [Some(x), Some(y), Some(z)] = normal.map(get) {
+                            normals.push(Vector { x, y, z });
+                        }
+                        if let [Some(u), Some(v)] = uv.map(get) {
+                            uvs.push((u, v));
+                        }
+                        let channel = |k: Option<usize>| {
+                            let k = k?;
+                            let scale = match &element.properties[k] {
+                                Property::Scalar(_, ty) => ty.color_scale(),
+                                Property::List(..) => 1.0,
+                            };
+                            Some((scalars[k] * scale) as f32)
+                        };
+                        if let [Some(r), Some(g), Some(b)] = color.map(channel) {
+                            colors.push(Color::new(r, g, b));
+                        }
+                    }
+                    "face" => {
+                        // out of range indices are left to the mesh to reject
+                        if list.iter().any(|&i| i < 0.0 || i.fract() != 0.0) {
+                            return Err(invalid("PLY face index is not a vertex index"));
+                        }
+                        let face: Vec<usize> = list.iter().map(|&i| i as usize).collect();
+                        for k in 1..face.len().saturating_sub(1) {
+                            indices.push([face[0], face[k], face[k + 1]]);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        TriangleMesh::new(positions, indices, material)?
+            .with_normals(normals)?
+            .with_uvs(uvs)?
+            .with_colors(colors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::DiffuseMaterial;
+    use crate::scene::Shape;
+    use crate::tests::temp_path;
+    use crate::texture::VertexColor;
+
+    const ASCII: &str = "ply
+format ascii 1.0
+comment unit quad in the xy plane
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property float u
+property float v
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 0 0 1 0 0 255 0 0
+1 0 0 0 0 1 1 0 255 0 0
+1 1 0 0 0 1 1 1 0 0 255
+0 1 0 0 0 1 0 1 0 0 255
+4 0 1 2 3
+";
+
+    fn material() -> Box<DiffuseMaterial> {
+        Box::new(DiffuseMaterial {
+            albedo: Box::new(VertexColor::new(Color::new(0.5, 0.5, 0.5))),
+        })
+    }
+
+    fn down(x: f32, y: f32) -> Ray {
+        Ray {
+            origin: Point { x, y, z: 1.0 },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        }
+    }
+
+    fn write_binary(path: &Path, big_endian: bool) {
+        let format = match big_endian {
+            true => "binary_big_endian",
+            false => "binary_little_endian",
+        };
+        let mut bytes = format!(
+            "ply\nformat {format} 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\nelement face 1\nproperty list uchar uint vertex_indices\nend_header\n"
+        )
+        .into_bytes();
+        let floats = [0.0f32, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 2.0, 0.0];
+        for f in floats {
+            bytes.extend(match big_endian {
+                true => f.to_be_bytes(),
+                false => f.to_le_bytes(),
+            });
+        }
+        bytes.push(3);
+        for i in [0u32, 1, 2] {
+            bytes.extend(match big_endian {
+                true => i.to_be_bytes(),
+                false => i.to_le_bytes(),
+            });
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn loads_ascii_attributes() {
+        let path = temp_path("ascii.ply");
+        fs::write(&path, ASCII).unwrap();
+        let mesh = TriangleMesh::from_ply(&path, material());
+        fs::remove_file(path).unwrap();
+        let mesh = mesh.unwrap();
+        assert_eq!(mesh.triangle_count(), 2);
+        assert_eq!((mesh.normals.len(), mesh.uvs.len()), (4, 4));
+
+        let si = mesh.intersect(&down(0.9, 0.1)).unwrap();
+        assert!((si.uv.0 - 0.9).abs() < 1e-5 && (si.uv.1 - 0.1).abs() < 1e-5);
+        assert!((si.color.unwrap().r - 0.9).abs() < 1e-5);
+        // vertex colors drive the diffuse albedo
+        let albedo = si.material.albedo(&si);
+        assert!((albedo.r - 0.9).abs() < 1e-5 && (albedo.b - 0.1).abs() < 1e-5);
+
+        let si = mesh.intersect(&down(0.1, 0.9)).unwrap();
+        assert!((si.color.unwrap().b - 0.9).abs() < 1e-5);
+    }
+
+    #[test]
+    fn rejects_bad_faces() {
+        let path = temp_path("missing.ply");
+        let errors: Vec<_> = ["3 0 1 4", "3 0 1 -1"]
+            .into_iter()
+            .map(|face| {
+                fs::write(&path, ASCII.replace("4 0 1 2 3", face)).unwrap();
+                TriangleMesh::from_ply(&path, material()).err()
+            })
+            .collect();
+        fs::remove_file(path).unwrap();
+        for error in errors {
+            assert_eq!(error.unwrap().kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn loads_binary_either_endian() {
+        for big_endian in [false, true] {
+            let path = temp_path(&format!("binary_{big_endian}.ply"));
+            write_binary(&path, big_endian);
+            let mesh = TriangleMesh::from_ply(&path, material());
+            fs::remove_file(path).unwrap();
+            let mesh = mesh.unwrap();
+            assert_eq!(mesh.triangle_count(), 1);
+            assert!((mesh.area() - 2.0).abs() < 1e-5);
+            assert!(mesh.intersect(&down(0.5, 0.5)).is_some());
+            assert!(mesh.intersect(&down(1.5, 1.5)).is_none());
+        }
+    }
+
+    #[test]
+    fn loads_binary_stl() {
+        let mut bytes = vec![0u8; 80];
+        bytes.extend(1u32.to_le_bytes());
+        let floats = [
+            0.0f32, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0,
+        ];
+        for f in floats {
+            bytes.extend(f.to_le_bytes());
+        }
+        bytes.extend(0u16.to_le_bytes());
+        let path = temp_path("triangle.stl");
+        fs::write(&path, bytes).unwrap();
+
+        let mesh = TriangleMesh::from_stl(&path, material());
+        fs::remove_file(path).unwrap();
+        let mesh = mesh.unwrap();
+        assert_eq!(mesh.triangle_count(), 1);
+        let si = mesh.intersect(&down(0.25, 0.25)).unwrap();
+        assert!((si.t - 1.0).abs() < 1e-5);
+        assert!((si.normal.z - 1.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,882 @@
+use crate::material::Material;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+
+use rand::Rng;
+use std::f32::consts::PI;
+
+/// Flat patch spanned by two edges leaving a corner.
+///
+/// Rectangles are parallelograms with perpendicular edges. The front side is
+/// the one `cross(edge1, edge2)` points to, but both sides can be hit.
+pub struct Parallelogram {
+    pub origin: Point,
+    pub edge1: Vector,
+    pub edge2: Vector,
+    pub material: Box<dyn Material>,
+}
+
+/// Flat disk facing `normal`.
+pub struct Disk {
+    pub center: Point,
+    pub normal: Vector,
+    pub radius: f32,
+    pub material: Box<dyn Material>,
+}
+
+/// Cylinder around the segment from `base` to `base + axis`, optionally closed by two disks.
+pub struct Cylinder {
+    pub base: Point,
+    pub axis: Vector,
+    pub radius: f32,
+    pub capped: bool,
+    pub material: Box<dyn Material>,
+}
+
+/// Cone with a base disk of `radius` at `base` and its apex at `base + axis`.
+pub struct Cone {
+    pub base: Point,
+    pub axis: Vector,
+    pub radius: f32,
+    pub capped: bool,
+    pub material: Box<dyn Material>,
+}
+
+/// Box aligned with the coordinate axes.
+pub struct AxisAlignedBox {
+    pub bounds: Bounds3,
+    pub material: Box<dyn Material>,
+}
+
+/// Single triangle, front-facing when its vertices appear counter-clockwise.
+pub struct Triangle {
+    pub p0: Point,
+    pub p1: Point,
+    pub p2: Point,
+    pub material: Box<dyn Material>,
+}
+
+/// Ray parameter of the intersection with the plane through `point`, either side.
+fn plane_intersection(o: Point, u: Vector, point: Point, normal: Vector) -> Option<f32> {
+    let denom = dot(u, normal);
+    if f32::abs(denom) < 1e-9 {
+        return None;
+    }
+    let t = dot(point - o, normal) / denom;
+    match t > 0.0 {
+        true => Some(t),
+        false => None,
+    }
+}
+
+/// Angle of `(x, y)` around the origin mapped to [0, 1).
+fn azimuth(x: f32, y: f32) -> f32 {
+    let phi = f32::atan2(y, x);
+    let phi = if phi < 0.0 { phi + 2.0 * PI } else { phi };
+    phi / (2.0 * PI)
+}
+
+fn disk_bounds(center: Point, normal: Vector, radius: f32) -> Bounds3 {
+    let n = normal.normalize();
+    let extent = Vector {
+        x: radius * f32::sqrt(f32::max(0.0, 1.0 - n.x * n.x)),
+        y: radius * f32::sqrt(f32::max(0.0, 1.0 - n.y * n.y)),
+        z: radius * f32::sqrt(f32::max(0.0, 1.0 - n.z * n.z)),
+    };
+    Bounds3::new(center + -extent, center + extent)
+}
+
+/// Uniform point on a disk of `radius` in the plane spanned by `tu` and `tv`.
+fn sample_disk(sampler: &mut Sampler, radius: f32, tu: Vector, tv: Vector) -> Vector {
+    let r = radius * f32::sqrt(sampler.gen::<f32>());
+    let phi = 2.0 * PI * sampler.gen::<f32>();
+    r * f32::cos(phi) * tu + r * f32::sin(phi) * tv
+}
+
+/// Coordinates of `v` in the frame `(tu, tv, w)`.
+fn to_local(v: Vector, (tu, tv, w): (Vector, Vector, Vector)) -> (f32, f32, f32) {
+    (dot(v, tu), dot(v, tv), dot(v, w))
+}
+
+/// Smallest positive root of `a t^2 + b t + c` for which `accept(t)` holds.
+fn quadratic_root(a: f32, b: f32, c: f32, accept: impl Fn(f32) -> bool) -> Option<f32> {
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    // stable form, also valid when a vanishes and the equation turns linear
+    let q = -0.5 * (b + f32::copysign(f32::sqrt(discriminant), b));
+    if q == 0.0 {
+        return None;
+    }
+    let (t0, t1) = (q / a, c / q);
+    let (t0, t1) = (f32::min(t0, t1), f32::max(t0, t1));
+    [t0, t1]
+        .into_iter()
+        .find(|&t| t.is_finite() && t > 0.0 && accept(t))
+}
+
+impl Parallelogram {
+    pub fn new(
+        origin: Point,
+        edge1: Vector,
+        edge2: Vector,
+        material: Box<dyn Material>,
+    ) -> Parallelogram {
+        Parallelogram {
+            origin,
+            edge1,
+            edge2,
+            material,
+        }
+    }
+
+    /// Rectangle centered at `center` with edges `edge1` and `edge2`.
+    pub fn rectangle(
+        center: Point,
+        edge1: Vector,
+        edge2: Vector,
+        material: Box<dyn Material>,
+    ) -> Parallelogram {
+        let origin = center + -0.5 * (edge1 + edge2);
+        Parallelogram::new(origin, edge1, edge2, material)
+    }
+}
+
+impl Shape for Parallelogram {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let o = ray.origin;
+        let u = ray.direction.normalize();
+        let n = cross(self.edge1, self.edge2);
+
+        let t = plane_intersection(o, u, self.origin, n)?;
+        let position = o + t * u;
+        let d = position - self.origin;
+
+        let n2 = norm2(n);
+        let a = dot(cross(d, self.edge2), n) / n2;
+        let b = dot(cross(self.edge1, d), n) / n2;
+        if !(0.0..=1.0).contains(&a) || !(0.0..=1.0).contains(&b) {
+            return None;
+        }
+
+        Some(SurfaceInteraction::new(
+            position,
+            n.normalize(),
+            t,
+            -u,
+            (a, b),
+            self.material.as_ref(),
+        ))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        Bounds3::new(self.origin, self.origin + self.edge1)
+            .union_point(self.origin + self.edge2)
+            .union_point(self.origin + self.edge1 + self.edge2)
+    }
+
+    fn area(&self) -> f32 {
+        norm(cross(self.edge1, self.edge2))
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        let a: f32 = sampler.gen();
+        let b: f32 = sampler.gen();
+        Some(ShapeSample {
+            position: self.origin + (a * self.edge1 + b * self.edge2),
+            normal: cross(self.edge1, self.edge2).normalize(),
+            pdf: 1.0 / self.area(),
+        })
+    }
+}
+
+impl Disk {
+    pub fn new(center: Point, normal: Vector, radius: f32, material: Box<dyn Material>) -> Disk {
+        Disk {
+            center,
+            normal: normal.normalize(),
+            radius,
+            material,
+        }
+    }
+}
+
+impl Shape for Disk {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let o = ray.origin;
+        let u = ray.direction.normalize();
+
+        let t = plane_intersection(o, u, self.center, self.normal)?;
+        let position = o + t * u;
+        let d = position - self.center;
+        if norm2(d) > self.radius * self.radius {
+            return None;
+        }
+
+        let (x, y, _) = to_local(d, coordinate_system(self.normal));
+        let uv = (azimuth(x, y), norm(d) / self.radius);
+
+        Some(SurfaceInteraction::new(
+            position,
+            self.normal,
+            t,
+            -u,
+            uv,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        disk_bounds(self.center, self.normal, self.radius)
+    }
+
+    fn area(&self) -> f32 {
+        PI * self.radius * self.radius
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        let (tu, tv, _) = coordinate_system(self.normal);
+        Some(ShapeSample {
+            position: self.center + sample_disk(sampler, self.radius, tu, tv),
+            normal: self.normal,
+            pdf: 1.0 / self.area(),
+        })
+    }
+}
+
+impl Cylinder {
+    pub fn new(
+        base: Point,
+        axis: Vector,
+        radius: f32,
+        capped: bool,
+        material: Box<dyn Material>,
+    ) -> Cylinder {
+        Cylinder {
+            base,
+            axis,
+            radius,
+            capped,
+            material,
+        }
+    }
+
+    fn height(&self) -> f32 {
+        norm(self.axis)
+    }
+
+    fn lateral_area(&self) -> f32 {
+        2.0 * PI * self.radius * self.height()
+    }
+
+    fn cap_area(&self) -> f32 {
+        match self.capped {
+            true => PI * self.radius * self.radius,
+            false => 0.0,
+        }
+    }
+}
+
+impl Shape for Cylinder {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let u = ray.direction.normalize();
+        let frame = coordinate_system(self.axis.normalize());
+        let (tu, tv, w) = frame;
+        let h = self.height();
+        let r = self.radius;
+        let (ox, oy, oz) = to_local(ray.origin - self.base, frame);
+        let (dx, dy, dz) = to_local(u, frame);
+
+        let lateral = quadratic_root(
+            dx * dx + dy * dy,
+            2.0 * (ox * dx + oy * dy),
+            ox * ox + oy * oy - r * r,
+            |t| (0.0..=h).contains(&(oz + t * dz)),
+        )
+        .map(|t| {
+            let (x, y, z) = (ox + t * dx, oy + t * dy, oz + t * dz);
+            (t, (1.0 / r) * (x * tu + y * tv), (azimuth(x, y), z / h))
+        });
+
+        let caps = [(0.0, -1.0), (h, 1.0)]
+            .into_iter()
+            .filter(|_| self.capped && f32::abs(dz) > 1e-9)
+            .filter_map(|(z, side)| {
+                let t = (z - oz) / dz;
+                let (x, y) = (ox + t * dx, oy + t * dy);
+                match t > 0.0 && x * x + y * y <= r * r {
+                    true => Some((t, side * w, (azimuth(x, y), f32::sqrt(x * x + y * y) / r))),
+                    false => None,
+                }
+            });
+
+        let (t, normal, uv) = lateral
+            .into_iter()
+            .chain(caps)
+            .min_by(|a, b| a.0.total_cmp(&b.0))?;
+
+        Some(SurfaceInteraction::new(
+            ray.origin + t * u,
+            normal,
+            t,
+            -u,
+            uv,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        disk_bounds(self.base, self.axis, self.radius).union(&disk_bounds(
+            self.base + self.axis,
+            self.axis,
+            self.radius,
+        ))
+    }
+
+    fn area(&self) -> f32 {
+        self.lateral_area() + 2.0 * self.cap_area()
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        let (tu, tv, w) = coordinate_system(self.axis.normalize());
+        let pdf = 1.0 / self.area();
+
+        // pick the lateral surface or a cap proportional to their areas
+        let choice = sampler.gen::<f32>() * self.area();
+        if choice < self.lateral_area() {
+            let phi = 2.0 * PI * sampler.gen::<f32>();
+            let z = sampler.gen::<f32>();
+            let normal = f32::cos(phi) * tu + f32::sin(phi) * tv;
+            return Some(ShapeSample {
+                position: self.base + (z * self.axis + self.radius * normal),
+                normal,
+                pdf,
+            });
+        }
+
+        let (center, normal) = match choice < self.lateral_area() + self.cap_area() {
+            true => (self.base, -w),
+            false => (self.base + self.axis, w),
+        };
+        Some(ShapeSample {
+            position: center + sample_disk(sampler, self.radius, tu, tv),
+            normal,
+            pdf,
+        })
+    }
+}
+
+impl Cone {
+    pub fn new(
+        base: Point,
+        axis: Vector,
+        radius: f32,
+        capped: bool,
+        material: Box<dyn Material>,
+    ) -> Cone {
+        Cone {
+            base,
+            axis,
+            radius,
+            capped,
+            material,
+        }
+    }
+
+    fn height(&self) -> f32 {
+        norm(self.axis)
+    }
+
+    fn lateral_area(&self) -> f32 {
+        let (r, h) = (self.radius, self.height());
+        PI * r * f32::sqrt(r * r + h * h)
+    }
+
+    fn cap_area(&self) -> f32 {
+        match self.capped {
+            true => PI * self.radius * self.radius,
+            false => 0.0,
+        }
+    }
+
+    /// Outward normal of the lateral surface at local coordinates `(x, y, z)`.
+    fn lateral_normal(&self, x: f32, y: f32, z: f32, frame: (Vector, Vector, Vector)) -> Vector {
+        let (tu, tv, w) = frame;
+        let h = self.height();
+        let k = self.radius / h;
+        (x * tu + y * tv + k * k * (h - z) * w).normalize()
+    }
+}
+
+impl Shape for Cone {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let u = ray.direction.normalize();
+        let frame = coordinate_system(self.axis.normalize());
+        let h = self.height();
+        let k2 = (self.radius / h) * (self.radius / h);
+        let (ox, oy, oz) = to_local(ray.origin - self.base, frame);
+        let (dx, dy, dz) = to_local(u, frame);
+
+        // x^2 + y^2 = k^2 (h - z)^2
+        let lateral = quadratic_root(
+            dx * dx + dy * dy - k2 * dz * dz,
+            2.0 * (ox * dx + oy * dy + k2 * (h - oz) * dz),
+            ox * ox + oy * oy - k2 * (h - oz) * (h - oz),
+            |t| (0.0..=h).contains(&(oz + t * dz)),
+        )
+        .map(|t| {
+            let (x, y, z) = (ox + t * dx, oy + t * dy, oz + t * dz);
+            let normal = self.lateral_normal(x, y, z, frame);
+            (t, normal, (azimuth(x, y), z / h))
+        });
+
+        let cap = Some(())
+            .filter(|_| self.capped && f32::abs(dz) > 1e-9)
+            .and_then(|_| {
+                let t = -oz / dz;
+                let (x, y) = (ox + t * dx, oy + t * dy);
+                let r2 = x * x + y * y;
+                match t > 0.0 && r2 <= self.radius * self.radius {
+                    true => Some((t, -frame.2, (azimuth(x, y), f32::sqrt(r2) / self.radius))),
+                    false => None,
+                }
+            });
+
+        let (t, normal, uv) = lateral
+            .into_iter()
+            .chain(cap)
+            .min_by(|a, b| a.0.total_cmp(&b.0))?;
+
+        Some(SurfaceInteraction::new(
+            ray.origin + t * u,
+            normal,
+            t,
+            -u,
+            uv,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        disk_bounds(self.base, self.axis, self.radius).union_point(self.base + self.axis)
+    }
+
+    fn area(&self) -> f32 {
+        self.lateral_area() + self.cap_area()
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        let frame = coordinate_system(self.axis.normalize());
+        let (tu, tv, w) = frame;
+        let pdf = 1.0 / self.area();
+
+        if sampler.gen::<f32>() * self.area() < self.lateral_area() {
+            // the circumference grows linearly with the distance s from the apex
+            let s = f32::sqrt(sampler.gen::<f32>());
+            let phi = 2.0 * PI * sampler.gen::<f32>();
+            let (x, y) = (
+                self.radius * s * f32::cos(phi),
+                self.radius * s * f32::sin(phi),
+            );
+            let z = self.height() * (1.0 - s);
+            return Some(ShapeSample {
+                position: self.base + (x * tu + y * tv + z * w),
+                normal: self.lateral_normal(x, y, z, frame),
+                pdf,
+            });
+        }
+
+        Some(ShapeSample {
+            position: self.base + sample_disk(sampler, self.radius, tu, tv),
+            normal: -w,
+            pdf,
+        })
+    }
+}
+
+impl AxisAlignedBox {
+    pub fn new(min: Point, max: Point, material: Box<dyn Material>) -> AxisAlignedBox {
+        AxisAlignedBox {
+            bounds: Bounds3::new(min, max),
+            material,
+        }
+    }
+
+    fn extent(&self) -> [f32; 3] {
+        let d = self.bounds.diagonal();
+        [d.x, d.y, d.z]
+    }
+
+    fn face_area(&self, axis: usize) -> f32 {
+        let e = self.extent();
+        e[(axis + 1) % 3] * e[(axis + 2) % 3]
+    }
+}
+
+fn axis_vector(axis: usize, sign: f32) -> Vector {
+    let mut v = [0.0; 3];
+    v[axis] = sign;
+    Vector {
+        x: v[0],
+        y: v[1],
+        z: v[2],
+    }
+}
+
+impl Shape for AxisAlignedBox {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let u = ray.direction.normalize();
+        let (t0, t1) = self.bounds.intersect(
+            &Ray {
+                origin: ray.origin,
+                direction: u,
+            },
+            f32::INFINITY,
+        )?;
+        // the far side is hit from inside the box
+        let t = if t0 > 0.0 { t0 } else { t1 };
+        if t <= 0.0 || !t.is_finite() {
+            return None;
+        }
+
+        let position = ray.origin + t * u;
+        let p = [position.x, position.y, position.z];
+        let min = [self.bounds.min.x, self.bounds.min.y, self.bounds.min.z];
+        let max = [self.bounds.max.x, self.bounds.max.y, self.bounds.max.z];
+        let extent = self.extent();
+
+        // the face whose plane is closest to the hit point
+        let (axis, sign) = (0..3)
+            .flat_map(|axis| {
+                [
+                    (axis, -1.0, f32::abs(p[axis] - min[axis])),
+                    (axis, 1.0, f32::abs(p[axis] - max[axis])),
+                ]
+            })
+            .min_by(|a, b| a.2.total_cmp(&b.2))
+            .map(|(axis, sign, _)| (axis, sign))?;
+
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let uv = ((p[a] - min[a]) / extent[a], (p[b] - min[b]) / extent[b]);
+
+        Some(SurfaceInteraction::new(
+            position,
+            axis_vector(axis, sign),
+            t,
+            -u,
+            uv,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        self.bounds
+    }
+
+    fn area(&self) -> f32 {
+        self.bounds.surface_area()
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        let mut choice = sampler.gen::<f32>() * self.area();
+        let mut face = 5;
+        for k in 0..6 {
+            let area = self.face_area(k / 2);
+            if choice < area {
+                face = k;
+                break;
+            }
+            choice -= area;
+        }
+
+        let (axis, sign) = (face / 2, if face % 2 == 0 { -1.0 } else { 1.0 });
+        let min = [self.bounds.min.x, self.bounds.min.y, self.bounds.min.z];
+        let max = [self.bounds.max.x, self.bounds.max.y, self.bounds.max.z];
+        let mut p = [0.0; 3];
+        p[axis] = if sign < 0.0 { min[axis] } else { max[axis] };
+        for other in [(axis + 1) % 3, (axis + 2) % 3] {
+            p[other] = min[other] + sampler.gen::<f32>() * (max[other] - min[other]);
+        }
+
+        Some(ShapeSample {
+            position: Point {
+                x: p[0],
+                y: p[1],
+                z: p[2],
+            },
+            normal: axis_vector(axis, sign),
+            pdf: 1.0 / self.area(),
+        })
+    }
+}
+
+impl Triangle {
+    pub fn new(p0: Point, p1: Point, p2: Point, material: Box<dyn Material>) -> Triangle {
+        Triangle {
+            p0,
+            p1,
+            p2,
+            material,
+        }
+    }
+}
+
+/// Möller-Trumbore test, returns `t` and the barycentrics of `p1` and `p2`.
+pub(crate) fn intersect_triangle(
+    o: Point,
+    u: Vector,
+    p0: Point,
+    p1: Point,
+    p2: Point,
+) -> Option<(f32, f32, f32)> {
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let pvec = cross(u, e2);
+    let det = dot(e1, pvec);
+    if f32::abs(det) < 1e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let tvec = o - p0;
+    let b1 = dot(tvec, pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let qvec = cross(tvec, e1);
+    let b2 = dot(u, qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+
+    let t = dot(e2, qvec) * inv_det;
+    match t > 0.0 {
+        true => Some((t, b1, b2)),
+        false => None,
+    }
+}
+
+impl Shape for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let u = ray.direction.normalize();
+        let (t, b1, b2) = intersect_triangle(ray.origin, u, self.p0, self.p1, self.p2)?;
+        let normal = cross(self.p1 - self.p0, self.p2 - self.p0).normalize();
+
+        Some(SurfaceInteraction::new(
+            ray.origin + t * u,
+            normal,
+            t,
+            -u,
+            (b1, b2),
+            self.material.as_ref(),
+        ))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        Bounds3::new(self.p0, self.p1).union_point(self.p2)
+    }
+
+    fn area(&self) -> f32 {
+        0.5 * norm(cross(self.p1 - self.p0, self.p2 - self.p0))
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        let su = f32::sqrt(sampler.gen::<f32>());
+        let b1 = su * (1.0 - sampler.gen::<f32>());
+        let b2 = su - b1;
+        let e1 = self.p1 - self.p0;
+        let e2 = self.p2 - self.p0;
+        Some(ShapeSample {
+            position: self.p0 + (b1 * e1 + b2 * e2),
+            normal: cross(e1, e2).normalize(),
+            pdf: 1.0 / self.area(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::BlackBody;
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Point { x, y, z }
+    }
+
+    fn vector(x: f32, y: f32, z: f32) -> Vector {
+        Vector { x, y, z }
+    }
+
+    fn ray(origin: Point, direction: Vector) -> Ray {
+        Ray { origin, direction }
+    }
+
+    /// Sampled points lie on the surface, face along the normal and integrate to the area.
+    fn check_sampling(shape: &dyn Shape) {
+        let mut sampler = Sampler::new(11, 0);
+        let n = 20000;
+        let mut area = 0.0;
+        for _ in 0..n {
+            let sample = shape.sample_area(&mut sampler).unwrap();
+            area += 1.0 / sample.pdf;
+
+            let origin = sample.position + 1e-2 * sample.normal;
+            let si = shape.intersect(&ray(origin, -sample.normal)).unwrap();
+            assert!(si.t < 2e-2, "sample is not on the surface");
+            assert!(dot(si.normal, sample.normal) > 0.99);
+        }
+        assert!((area / n as f32 - shape.area()).abs() < 1e-3 * shape.area());
+    }
+
+    #[test]
+    fn intersects_parallelogram() {
+        let quad = Parallelogram::rectangle(
+            point(0.0, 0.0, -2.0),
+            vector(2.0, 0.0, 0.0),
+            vector(0.0, 1.0, 0.0),
+            Box::new(BlackBody {}),
+        );
+        let si = quad
+            .intersect(&ray(point(0.5, 0.25, 0.0), vector(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert!((si.t - 2.0).abs() < 1e-5);
+        assert!((si.uv.0 - 0.75).abs() < 1e-5 && (si.uv.1 - 0.75).abs() < 1e-5);
+        assert!(quad
+            .intersect(&ray(point(1.5, 0.0, 0.0), vector(0.0, 0.0, -1.0)))
+            .is_none());
+        check_sampling(&quad);
+    }
+
+    #[test]
+    fn intersects_disk() {
+        let disk = Disk::new(
+            point(0.0, 1.0, 0.0),
+            vector(0.0, 1.0, 0.0),
+            0.5,
+            Box::new(BlackBody {}),
+        );
+        let down = vector(0.0, -1.0, 0.0);
+        assert!(disk.intersect(&ray(point(0.4, 3.0, 0.0), down)).is_some());
+        assert!(disk.intersect(&ray(point(0.4, 3.0, 0.4), down)).is_none());
+        check_sampling(&disk);
+    }
+
+    #[test]
+    fn intersects_cylinder() {
+        let cylinder = Cylinder::new(
+            point(0.0, 0.0, 0.0),
+            vector(0.0, 2.0, 0.0),
+            1.0,
+            true,
+            Box::new(BlackBody {}),
+        );
+        let side = cylinder
+            .intersect(&ray(point(-3.0, 1.0, 0.0), vector(1.0, 0.0, 0.0)))
+            .unwrap();
+        assert!((side.t - 2.0).abs() < 1e-5);
+        assert!(norm(side.normal - vector(-1.0, 0.0, 0.0)) < 1e-5);
+
+        let top = cylinder
+            .intersect(&ray(point(0.5, 5.0, 0.0), vector(0.0, -1.0, 0.0)))
+            .unwrap();
+        assert!((top.t - 3.0).abs() < 1e-5);
+        assert!(norm(top.normal - vector(0.0, 1.0, 0.0)) < 1e-5);
+        check_sampling(&cylinder);
+
+        let open = Cylinder::new(
+            point(0.0, 0.0, 0.0),
+            vector(0.0, 2.0, 0.0),
+            1.0,
+            false,
+            Box::new(BlackBody {}),
+        );
+        // a ray parallel to the axis passes through the open tube without touching the wall
+        assert!(open
+            .intersect(&ray(point(0.5, 5.0, 0.0), vector(0.0, -1.0, 0.0)))
+            .is_none());
+    }
+
+    #[test]
+    fn intersects_cone() {
+        let cone = Cone::new(
+            point(0.0, 0.0, 0.0),
+            vector(0.0, 1.0, 0.0),
+            1.0,
+            true,
+            Box::new(BlackBody {}),
+        );
+        let si = cone
+            .intersect(&ray(point(-3.0, 0.5, 0.0), vector(1.0, 0.0, 0.0)))
+            .unwrap();
+        assert!((si.t - 2.5).abs() < 1e-5);
+        let expected = vector(-1.0, 1.0, 0.0).normalize();
+        assert!(norm(si.normal - expected) < 1e-5);
+        check_sampling(&cone);
+    }
+
+    #[test]
+    fn intersects_box() {
+        let cube = AxisAlignedBox::new(
+            point(-1.0, -1.0, -1.0),
+            point(1.0, 1.0, 1.0),
+            Box::new(BlackBody {}),
+        );
+        let outside = cube
+            .intersect(&ray(point(0.0, 0.0, 5.0), vector(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert!((outside.t - 4.0).abs() < 1e-5);
+        assert!(norm(outside.normal - vector(0.0, 0.0, 1.0)) < 1e-5);
+
+        let inside = cube
+            .intersect(&ray(point(0.0, 0.0, 0.0), vector(1.0, 0.0, 0.0)))
+            .unwrap();
+        assert!((inside.t - 1.0).abs() < 1e-5);
+        assert!(norm(inside.normal - vector(1.0, 0.0, 0.0)) < 1e-5);
+        check_sampling(&cube);
+    }
+
+    #[test]
+    fn intersects_triangle() {
+        let triangle = Triangle::new(
+            point(0.0, 0.0, 0.0),
+            point(1.0, 0.0, 0.0),
+            point(0.0, 1.0, 0.0),
+            Box::new(BlackBody {}),
+        );
+        let si = triangle
+            .intersect(&ray(point(0.25, 0.5, 1.0), vector(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert!((si.uv.0 - 0.25).abs() < 1e-5 && (si.uv.1 - 0.5).abs() < 1e-5);
+        assert!(norm(si.normal - vector(0.0, 0.0, 1.0)) < 1e-5);
+        assert!(triangle
+            .intersect(&ray(point(0.75, 0.5, 1.0), vector(0.0, 0.0, -1.0)))
+            .is_none());
+        check_sampling(&triangle);
+    }
+}
@@ -0,0 +1,502 @@
+use crate::material::*;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::Color;
+use crate::texture::Texture;
+
+use rand::Rng;
+use std::f32::consts::PI;
+
+/// Principled BSDF after Burley's 2015 Disney model, one material for most surfaces.
+///
+/// A diffuse lobe with retro-reflection, sheen and a flattened subsurface
+/// look, anisotropic GGX reflection, a GGX clearcoat and rough GGX
+/// transmission are blended by parameters in [0, 1]. Metals take their
+/// reflection color from `base_color`. Directions are sampled by picking a
+/// lobe in proportion to its expected reflectance.
+///
+/// Fields not set by `new` can be given with struct update syntax, as in
+/// `PrincipledMaterial { metallic: 1.0, ..PrincipledMaterial::new(color) }`.
+pub struct PrincipledMaterial {
+    pub base_color: Box<dyn Texture>,
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Reflectance of dielectrics at normal incidence, 0.5 giving 4%.
+    pub specular: f32,
+    /// Tints the reflection of dielectrics towards the base color.
+    pub specular_tint: f32,
+    /// Stretches highlights along the tangent.
+    ///
+    /// Surfaces carry no `dpdu`, so the tangent is the first axis of
+    /// `coordinate_system` of the normal. It is the same on both sides and
+    /// constant over flat surfaces, but follows no texture layout.
+    pub anisotropic: f32,
+    /// Grazing reflection of cloth.
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    pub clearcoat: f32,
+    /// Smoothness of the clearcoat, 1 for a polished coat.
+    pub clearcoat_gloss: f32,
+    /// Share of the dielectric part that is transmitted instead of diffuse.
+    pub transmission: f32,
+    /// Index of refraction of the transmitting part, inside relative to outside.
+    pub ior: f32,
+    /// Blends the diffuse lobe towards the flatter look of light scattered under the surface.
+    pub subsurface: f32,
+}
+
+/// Anisotropic GGX microfacet distribution in a local frame with the normal along z.
+struct Ggx {
+    ax: f32,
+    ay: f32,
+}
+
+/// Everything about the surface at one interaction, in the local frame of the side of `wi`.
+struct Shading {
+    frame: (Vector, Vector, Vector),
+    /// Index of refraction on the far side relative to the side of `wi`.
+    eta: f32,
+    base: Color,
+    /// Reflectance at normal incidence of the opaque part.
+    specular: Color,
+    sheen: Color,
+    ggx: Ggx,
+    /// Roughness of the clearcoat distribution.
+    clearcoat_alpha: f32,
+    /// Weights of the diffuse, opaque specular, clearcoat and dielectric parts.
+    weights: [f32; 4],
+    /// Probabilities of sampling the diffuse, specular, clearcoat and dielectric lobes.
+    probabilities: [f32; 4],
+}
+
+fn mix(a: Color, b: Color, t: f32) -> Color {
+    (1.0 - t) * a + t * b
+}
+
+fn schlick_weight(cos: f32) -> f32 {
+    let m = f32::clamp(1.0 - cos, 0.0, 1.0);
+    m * m * m * m * m
+}
+
+fn schlick(f0: Color, cos: f32) -> Color {
+    mix(f0, Color::new(1.0, 1.0, 1.0), schlick_weight(cos))
+}
+
+/// Reflection of `v` about the microfacet normal `h`.
+fn reflect_about(v: Vector, h: Vector) -> Vector {
+    2.0 * dot(v, h) * h - v
+}
+
+impl Ggx {
+    fn new(roughness: f32, anisotropic: f32) -> Ggx {
+        let alpha = roughness * roughness;
+        let aspect = f32::sqrt(1.0 - 0.9 * anisotropic);
+        Ggx {
+            ax: f32::max(1e-3, alpha / aspect),
+            ay: f32::max(1e-3, alpha * aspect),
+        }
+    }
+
+    fn d(&self, h: Vector) -> f32 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let t = (h.x / self.ax) * (h.x / self.ax) + (h.y / self.ay) * (h.y / self.ay) + h.z * h.z;
+        1.0 / (PI * self.ax * self.ay * t * t)
+    }
+
+    fn lambda(&self, w: Vector) -> f32 {
+        let cos2 = w.z * w.z;
+        if cos2 <= 0.0 {
+            return f32::INFINITY;
+        }
+        let tan2 = (self.ax * self.ax * w.x * w.x + self.ay * self.ay * w.y * w.y) / cos2;
+        0.5 * (f32::sqrt(1.0 + tan2) - 1.0)
+    }
+
+    fn g1(&self, w: Vector) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Height correlated masking and shadowing.
+    fn g(&self, v: Vector, l: Vector) -> f32 {
+        1.0 / (1.0 + self.lambda(v) + self.lambda(l))
+    }
+
+    /// Density of `sample` returning `h`, the normals visible from `v`.
+    fn pdf(&self, v: Vector, h: Vector) -> f32 {
+        self.g1(v) * f32::max(dot(v, h), 0.0) * self.d(h) / f32::abs(v.z)
+    }
+
+    /// Samples a microfacet normal visible from `v`, after Heitz 2018.
+    fn sample(&self, v: Vector, sampler: &mut Sampler) -> Vector {
+        let vh = Vector {
+            x: self.ax * v.x,
+            y: self.ay * v.y,
+            z: v.z,
+        }
+        .normalize();
+        let length2 = vh.x * vh.x + vh.y * vh.y;
+        let t1 = match length2 > 0.0 {
+            true => {
+                (1.0 / f32::sqrt(length2))
+                    * Vector {
+                        x: -vh.y,
+                        y: vh.x,
+                        z: 0.0,
+                    }
+            }
+            false => Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+        let t2 = cross(vh, t1);
+
+        let r = f32::sqrt(sampler.gen::<f32>());
+        let phi = 2.0 * PI * sampler.gen::<f32>();
+        let p1 = r * f32::cos(phi);
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * f32::sqrt(f32::max(0.0, 1.0 - p1 * p1)) + s * r * f32::sin(phi);
+        let nh = p1 * t1 + p2 * t2 + f32::sqrt(f32::max(0.0, 1.0 - p1 * p1 - p2 * p2)) * vh;
+        Vector {
+            x: self.ax * nh.x,
+            y: self.ay * nh.y,
+            z: f32::max(1e-6, nh.z),
+        }
+        .normalize()
+    }
+}
+
+/// Generalized Trowbridge-Reitz distribution with exponent 1 of the clearcoat.
+fn gtr1(cos: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    (a2 - 1.0) / (PI * f32::ln(a2) * (1.0 + (a2 - 1.0) * cos * cos))
+}
+
+impl PrincipledMaterial {
+    /// Rough dielectric of `base_color` with the defaults of the Disney model.
+    pub fn new(base_color: Box<dyn Texture>) -> PrincipledMaterial {
+        PrincipledMaterial {
+            base_color,
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.0,
+            anisotropic: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            ior: 1.5,
+            subsurface: 0.0,
+        }
+    }
+
+    /// `specular` for the reflectance at normal incidence of a dielectric of index `ior`.
+    pub fn specular_from_ior(ior: f32) -> f32 {
+        let f0 = ((ior - 1.0) / (ior + 1.0)) * ((ior - 1.0) / (ior + 1.0));
+        f0 / 0.08
+    }
+
+    /// `clearcoat_gloss` for a coat of GGX roughness `roughness`, alpha being its square.
+    ///
+    /// The clearcoat spans alphas from 0.001 to 0.1, rougher coats get the roughest one.
+    pub fn gloss_from_roughness(roughness: f32) -> f32 {
+        let alpha = roughness * roughness;
+        f32::clamp((alpha - 0.1) / (0.001 - 0.1), 0.0, 1.0)
+    }
+
+    fn shading(&self, si: &SurfaceInteraction) -> Shading {
+        let outside = dot(si.wi, si.normal) >= 0.0;
+        let (n, eta) = match outside {
+            true => (si.normal, self.ior),
+            false => (-si.normal, 1.0 / self.ior),
+        };
+        // both sides share the tangent, so anisotropic transmission is reciprocal
+        let (u, _, _) = coordinate_system(si.normal);
+        let frame = (u, cross(n, u), n);
+        let cos = f32::abs(dot(si.wi, n));
+
+        let base = self.base_color.eval(si);
+        let white = Color::new(1.0, 1.0, 1.0);
+        let tint = match base.luminance() > 0.0 {
+            true => base / base.luminance(),
+            false => white,
+        };
+        let dielectric = (0.08 * self.specular) * mix(white, tint, self.specular_tint);
+        let specular = mix(dielectric, base, self.metallic);
+
+        let transmissive = (1.0 - self.metallic) * self.transmission;
+        // the diffuse lobe and the clearcoat lie on the outside only
+        let weights = [
+            match outside {
+                true => (1.0 - self.metallic) * (1.0 - self.transmission),
+                false => 0.0,
+            },
+            1.0 - transmissive,
+            match outside {
+                true => 0.25 * self.clearcoat,
+                false => 0.0,
+            },
+            transmissive,
+        ];
+        let expected = [
+            weights[0] * f32::max(base.luminance(), 0.1),
+            weights[1] * schlick(specular, cos).luminance(),
+            weights[2] * (0.04 + 0.96 * schlick_weight(cos)),
+            weights[3],
+        ];
+        let total: f32 = expected.iter().sum();
+        let probabilities = match total > 0.0 {
+            true => expected.map(|e| e / total),
+            false => [1.0, 0.0, 0.0, 0.0],
+        };
+
+        Shading {
+            frame,
+            eta,
+            base,
+            specular,
+            sheen: self.sheen * mix(white, tint, self.sheen_tint),
+            ggx: Ggx::new(self.roughness, self.anisotropic),
+            clearcoat_alpha: 0.1 + (0.001 - 0.1) * self.clearcoat_gloss,
+            weights,
+            probabilities,
+        }
+    }
+
+    /// `f·cos` of all lobes and of the diffuse ones towards `wo`, and the pdf of sampling `wo`.
+    fn evaluate(&self, si: &SurfaceInteraction, wo: Vector) -> (Color, Color, f32) {
+        let s = self.shading(si);
+        let (fu, fv, fw) = s.frame;
+        let local = |d: Vector| Vector {
+            x: dot(d, fu),
+            y: dot(d, fv),
+            z: dot(d, fw),
+        };
+        let v = local(si.wi);
+        let l = local(wo.normalize());
+        let black = Color::new(0.0, 0.0, 0.0);
+        let [p_diffuse, p_specular, p_clearcoat, p_dielectric] = s.probabilities;
+        let [w_diffuse, w_specular, w_clearcoat, w_dielectric] = s.weights;
+        if v.z <= 0.0 || l.z == 0.0 {
+            return (black, black, 0.0);
+        }
+
+        if l.z > 0.0 {
+            let h = (v + l).normalize();
+            let (cos_l, cos_v, cos_h) = (l.z, v.z, dot(l, h));
+            let mut diffuse = black;
+            let mut pdf = 0.0;
+
+            if w_diffuse > 0.0 {
+                let (fl, fv) = (schlick_weight(cos_l), schlick_weight(cos_v));
+                let r = self.roughness;
+                let fd90 = 0.5 + 2.0 * cos_h * cos_h * r;
+                let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+                let fss90 = cos_h * cos_h * r;
+                let fss = (1.0 + (fss90 - 1.0) * fl) * (1.0 + (fss90 - 1.0) * fv);
+                let ss = 1.25 * (fss * (1.0 / (cos_l + cos_v) - 0.5) + 0.5);
+                let lambert = (1.0 - self.subsurface) * fd + self.subsurface * ss;
+                let sheen = schlick_weight(cos_h) * s.sheen;
+                diffuse = w_diffuse * ((lambert / PI) * s.base + sheen);
+                pdf += p_diffuse * cos_l / PI;
+            }
+
+            // opaque specular with Schlick's Fresnel, dielectric with the exact one
+            let d = s.ggx.d(h);
+            let g = s.ggx.g(v, l);
+            let f_dielectric = fresnel_dielectric(dot(v, h), s.eta);
+            let fresnel = w_specular * schlick(s.specular, cos_h)
+                + (w_dielectric * f_dielectric) * Color::new(1.0, 1.0, 1.0);
+            let mut f = diffuse + (d * g / (4.0 * cos_v * cos_l)) * fresnel;
+            let reflected = s.ggx.pdf(v, h) / (4.0 * dot(v, h));
+            pdf += p_specular * reflected + p_dielectric * f_dielectric * reflected;
+
+            if w_clearcoat > 0.0 {
+                let dc = gtr1(h.z, s.clearcoat_alpha);
+                let coat = Ggx { ax: 0.25, ay: 0.25 };
+                let gc = coat.g1(v) * coat.g1(l);
+                let fc = 0.04 + 0.96 * schlick_weight(cos_h);
+                let clearcoat = w_clearcoat * dc * gc * fc / (4.0 * cos_v * cos_l);
+                f = f + Color::new(clearcoat, clearcoat, clearcoat);
+                pdf += p_clearcoat * dc * h.z / (4.0 * cos_h);
+            }
+            return (cos_l * f, cos_l * diffuse, pdf);
+        }
+
+        if w_dielectric <= 0.0 {
+            return (black, black, 0.0);
+        }
+        // refraction through microfacets after Walter et al. 2007
+        let mut h = (s.eta * l + v).normalize();
+        if h.z < 0.0 {
+            h = -h;
+        }
+        let (lh, vh) = (dot(l, h), dot(v, h));
+        if lh >= 0.0 || vh <= 0.0 {
+            return (black, black, 0.0);
+        }
+        let f_dielectric = fresnel_dielectric(vh, s.eta);
+        let denom = (lh + vh / s.eta) * (lh + vh / s.eta);
+        let d = s.ggx.d(h);
+        let g = s.ggx.g(v, l);
+        // radiance is compressed into the smaller solid angle of the denser side
+        let ft = d * g * (1.0 - f_dielectric) * f32::abs(lh * vh / (denom * l.z * v.z))
+            / (s.eta * s.eta);
+        let pdf = p_dielectric * (1.0 - f_dielectric) * s.ggx.pdf(v, h) * f32::abs(lh) / denom;
+        ((w_dielectric * ft * f32::abs(l.z)) * s.base, black, pdf)
+    }
+}
+
+impl Material for PrincipledMaterial {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let (radiance, _, pdf) = self.evaluate(si, wo);
+        BsdfSample { radiance, pdf }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        let s = self.shading(si);
+        let (fu, fv, fw) = s.frame;
+        let v = Vector {
+            x: dot(si.wi, fu),
+            y: dot(si.wi, fv),
+            z: dot(si.wi, fw),
+        };
+        let [p_diffuse, p_specular, p_clearcoat, _] = s.probabilities;
+
+        let u = sampler.gen::<f32>();
+        let l = if u < p_diffuse {
+            let r = f32::sqrt(sampler.gen::<f32>());
+            let phi = 2.0 * PI * sampler.gen::<f32>();
+            Vector {
+                x: r * f32::cos(phi),
+                y: r * f32::sin(phi),
+                z: f32::sqrt(f32::max(0.0, 1.0 - r * r)),
+            }
+        } else if u < p_diffuse + p_specular {
+            reflect_about(v, s.ggx.sample(v, sampler))
+        } else if u < p_diffuse + p_specular + p_clearcoat {
+            let a2 = s.clearcoat_alpha * s.clearcoat_alpha;
+            let e = sampler.gen::<f32>();
+            let cos = f32::sqrt(f32::max(0.0, (1.0 - f32::powf(a2, 1.0 - e)) / (1.0 - a2)));
+            let sin = f32::sqrt(f32::max(0.0, 1.0 - cos * cos));
+            let phi = 2.0 * PI * sampler.gen::<f32>();
+            let h = Vector {
+                x: sin * f32::cos(phi),
+                y: sin * f32::sin(phi),
+                z: cos,
+            };
+            reflect_about(v, h)
+        } else {
+            // reflect or refract by the Fresnel equations of the sampled microfacet
+            let h = s.ggx.sample(v, sampler);
+            match refract(v, h, s.eta) {
+                Some(wt) if sampler.gen::<f32>() >= fresnel_dielectric(dot(v, h), s.eta) => wt,
+                _ => reflect_about(v, h),
+            }
+        };
+        l.x * fu + l.y * fv + l.z * fw
+    }
+
+    fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        self.evaluate(si, wo).2
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self, si: &SurfaceInteraction) -> Color {
+        self.base_color.eval(si)
+    }
+
+    fn diffuse_fraction(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        let (total, diffuse, _) = self.evaluate(si, wo);
+        match total.luminance() > 0.0 {
+            true => diffuse.luminance() / total.luminance(),
+            false => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::tests::{estimates, up};
+
+    fn gray(value: f32) -> Box<Color> {
+        Box::new(Color::new(value, value, value))
+    }
+
+    #[test]
+    fn sampling_matches_evaluation() {
+        let materials = [
+            PrincipledMaterial {
+                roughness: 0.6,
+                sheen: 1.0,
+                clearcoat: 1.0,
+                clearcoat_gloss: 0.5,
+                subsurface: 0.5,
+                ..PrincipledMaterial::new(Box::new(Color::new(0.8, 0.3, 0.2)))
+            },
+            PrincipledMaterial {
+                metallic: 1.0,
+                roughness: 0.4,
+                anisotropic: 0.8,
+                ..PrincipledMaterial::new(gray(0.9))
+            },
+            PrincipledMaterial {
+                transmission: 1.0,
+                roughness: 0.4,
+                anisotropic: 0.5,
+                ..PrincipledMaterial::new(gray(1.0))
+            },
+        ];
+        let oblique = Vector {
+            x: 0.5,
+            y: 0.2,
+            z: 0.8,
+        };
+        for (k, material) in materials.iter().enumerate() {
+            for wi in [oblique, -oblique] {
+                let (sampled, uniform, pdf) = estimates(material, wi, 400_000);
+                assert!(
+                    (sampled - uniform).abs() < 0.03 * uniform,
+                    "{k} {wi:?} {sampled} {uniform}"
+                );
+                // rough lobes lose some samples below the horizon
+                assert!(pdf > 0.85 && pdf < 1.02, "{k} {wi:?} {pdf}");
+            }
+        }
+    }
+
+    #[test]
+    fn maps_gltf_parameters() {
+        // an ior of 1.5 reflects the default 4%
+        assert!((PrincipledMaterial::specular_from_ior(1.5) - 0.5).abs() < 1e-6);
+        assert_eq!(PrincipledMaterial::gloss_from_roughness(0.0), 1.0);
+        assert_eq!(PrincipledMaterial::gloss_from_roughness(1.0), 0.0);
+        // the roughest clearcoat has alpha 0.1
+        let rough = f32::sqrt(0.1);
+        assert!(PrincipledMaterial::gloss_from_roughness(rough) < 1e-5);
+    }
+
+    #[test]
+    fn metals_keep_energy() {
+        // single scattering GGX loses energy as it gets rougher
+        for (roughness, least) in [(0.1, 0.95), (0.5, 0.85), (1.0, 0.25)] {
+            let metal = PrincipledMaterial {
+                metallic: 1.0,
+                roughness,
+                ..PrincipledMaterial::new(gray(1.0))
+            };
+            let (albedo, _, _) = estimates(&metal, up(), 20_000);
+            assert!(albedo > least && albedo <= 1.01, "{roughness} {albedo}");
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use crate::lightsampler::LightSampler;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::Color;
+
+use rand::Rng;
+
+/// Settings of reservoir based direct lighting at the first hit of camera paths (ReSTIR).
+///
+/// Every pixel draws `candidates` light samples and keeps one by weighted
+/// reservoir sampling, proportionally to its unshadowed contribution. With
+/// spatial reuse, the reservoirs of random neighboring pixels with similar
+/// normal and depth are merged into each pixel's before a single shadow ray
+/// is traced. Without reuse the estimate is unbiased. Plain reuse divides by
+/// all candidates seen and is biased where neighbors cannot reach a light
+/// sample, e.g. at contact shadows of differently oriented surfaces. The
+/// unbiased mode only counts the neighbors whose target is nonzero for the
+/// picked sample.
+#[derive(Clone, Copy, Debug)]
+pub struct ReservoirSampling {
+    pub(crate) candidates: usize,
+    pub(crate) neighbors: usize,
+    /// Distance of neighbors in pixels.
+    radius: f32,
+    pub(crate) unbiased: bool,
+}
+
+/// Sample point on one of `Scene::lights`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LightPoint {
+    light: usize,
+    vertex: LightVertex,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum LightVertex {
+    /// Point on an area light and the normal there.
+    Surface(Point, Vector),
+    Point(Point),
+    /// Direction towards a light at infinity.
+    Direction(Vector),
+}
+
+/// Weighted reservoir holding a single light sample.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Reservoir {
+    sample: Option<LightPoint>,
+    weight_sum: f32,
+    /// Candidates that went into the reservoir.
+    count: usize,
+    /// Unbiased contribution weight, an estimate of one over the density of `sample`.
+    weight: f32,
+}
+
+impl ReservoirSampling {
+    pub fn new(candidates: usize) -> ReservoirSampling {
+        ReservoirSampling {
+            candidates: candidates.max(1),
+            neighbors: 0,
+            radius: 0.0,
+            unbiased: false,
+        }
+    }
+
+    /// Merges the reservoirs of `neighbors` random pixels up to `radius` pixels away.
+    pub fn with_spatial_reuse(mut self, neighbors: usize, radius: f32) -> ReservoirSampling {
+        self.neighbors = neighbors;
+        self.radius = radius;
+        self
+    }
+
+    /// Normalizes merged reservoirs such that spatial reuse stays unbiased.
+    pub fn with_unbiased_reuse(mut self, unbiased: bool) -> ReservoirSampling {
+        self.unbiased = unbiased;
+        self
+    }
+
+    /// Random pixels of a `width` by `height` image near `(i, j)`, without `(i, j)` itself.
+    pub(crate) fn neighbors(
+        &self,
+        (i, j): (usize, usize),
+        (width, height): (usize, usize),
+        sampler: &mut Sampler,
+    ) -> Vec<(usize, usize)> {
+        (0..self.neighbors)
+            .filter_map(|_| {
+                let r = self.radius * f32::sqrt(sampler.gen::<f32>());
+                let phi = 2.0 * std::f32::consts::PI * sampler.gen::<f32>();
+                let x = i as f32 + r * f32::cos(phi);
+                let y = j as f32 + r * f32::sin(phi);
+                let (x, y) = (f32::round(x), f32::round(y));
+                let inside = x >= 0.0 && y >= 0.0 && x < width as f32 && y < height as f32;
+                let pixel = (x as usize, y as usize);
+                match inside && pixel != (i, j) {
+                    true => Some(pixel),
+                    false => None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Unshadowed light from `point` scattered by `si`, with the direction and
+/// distance to the light.
+///
+/// Area lights are measured per unit area, so the value does not depend on
+/// how the point was sampled. Point lights fall off with the squared distance,
+/// like in `PathIntegrator`.
+fn unshadowed(scene: &Scene, si: &SurfaceInteraction, point: &LightPoint) -> (Color, Vector, f32) {
+    let light = &scene.lights[point.light];
+    let (position, normal) = match point.vertex {
+        LightVertex::Surface(position, normal) => (position, Some(normal)),
+        LightVertex::Point(position) => (position, None),
+        LightVertex::Direction(wo) => {
+            let emitted = light.emission(None, -wo);
+            return (
+                si.material.bsdf_eval(si, wo).radiance * emitted,
+                wo,
+                f32::INFINITY,
+            );
+        }
+    };
+    let to_light = position - si.position;
+    let dist = norm(to_light);
+    if dist <= 0.0 {
+        return (Color::new(0.0, 0.0, 0.0), si.normal, 0.0);
+    }
+    let wo = (1.0 / dist) * to_light;
+    let emitted = match normal {
+        Some(normal) => {
+            let cos = f32::max(0.0, -dot(normal, wo));
+            (cos / (dist * dist)) * light.emission(Some(normal), -wo)
+        }
+        None => (1.0 / (dist * dist)) * light.emission(None, -wo),
+    };
+    (si.material.bsdf_eval(si, wo).radiance * emitted, wo, dist)
+}
+
+/// Density that resampling aims for, the brightness of `contribution`.
+fn target(contribution: Color) -> f32 {
+    f32::max(contribution.luminance(), 0.0)
+}
+
+impl Reservoir {
+    /// Streams in `point` with resampling `weight`.
+    fn update(&mut self, point: LightPoint, weight: f32, u: f32) {
+        if weight <= 0.0 || !weight.is_finite() {
+            return;
+        }
+        self.weight_sum += weight;
+        if u * self.weight_sum < weight {
+            self.sample = Some(point);
+        }
+    }
+
+    /// Resamples one of `candidates` light samples for `si`.
+    ///
+    /// Lights are picked by `light_sampler` if given and uniformly otherwise.
+    pub(crate) fn generate(
+        scene: &Scene,
+        si: &SurfaceInteraction,
+        candidates: usize,
+        light_sampler: Option<&dyn LightSampler>,
+        bounds: &Bounds3,
+        sampler: &mut Sampler,
+    ) -> Reservoir {
+        let mut reservoir = Reservoir {
+            count: candidates,
+            ..Reservoir::default()
+        };
+        let lights = scene.lights.len();
+        if lights == 0 {
+            return reservoir;
+        }
+
+        for _ in 0..candidates {
+            let u = sampler.gen::<f32>();
+            let picked = match light_sampler {
+                Some(light_sampler) => light_sampler.sample(si.position, si.normal, u),
+                None => Some((
+                    usize::min((u * lights as f32) as usize, lights - 1),
+                    1.0 / lights as f32,
+                )),
+            };
+            let Some((index, pmf)) = picked else {
+                continue;
+            };
+            let light = &scene.lights[index];
+            let sampled = match (light.is_infinite(), light.is_delta()) {
+                (true, _) => {
+                    let position = light.sample(si.position, sampler).position;
+                    let direction = (position - si.position).normalize();
+                    Some((LightVertex::Direction(direction), 1.0))
+                }
+                (false, true) => {
+                    let position = light.sample(si.position, sampler).position;
+                    Some((LightVertex::Point(position), 1.0))
+                }
+                (false, false) => light
+                    .sample_position(si.position, bounds, sampler)
+                    .and_then(|sample| {
+                        let normal = sample.normal?;
+                        Some((
+                            LightVertex::Surface(sample.position, normal),
+                            sample.pdf_position,
+                        ))
+                    }),
+            };
+            let u = sampler.gen::<f32>();
+            let Some((vertex, pdf)) = sampled else {
+                continue;
+            };
+            let point = LightPoint {
+                light: index,
+                vertex,
+            };
+            let p_hat = target(unshadowed(scene, si, &point).0);
+            reservoir.update(point, p_hat / (pmf * pdf), u);
+        }
+
+        reservoir.normalize(scene, si, candidates as f32);
+        reservoir
+    }
+
+    /// Sets the contribution weight of the sample for `si`, with `count` effective candidates.
+    fn normalize(&mut self, scene: &Scene, si: &SurfaceInteraction, count: f32) {
+        let p_hat = self
+            .sample
+            .map_or(0.0, |point| target(unshadowed(scene, si, &point).0));
+        self.weight = match p_hat > 0.0 && count > 0.0 {
+            true => self.weight_sum / (count * p_hat),
+            false => 0.0,
+        };
+    }
+
+    /// Direct light at `si` through the sample of the reservoir and the direction
+    /// to it, `None` when the reservoir is empty.
+    pub(crate) fn shade(&self, scene: &Scene, si: &SurfaceInteraction) -> Option<(Color, Vector)> {
+        let point = self.sample?;
+        let (contribution, wo, dist) = unshadowed(scene, si, &point);
+        let shadow_si = scene.closest_hit(&Ray {
+            origin: si.position + 1e-3 * wo,
+            direction: wo,
+        });
+        if let Some(si) = shadow_si {
+            if si.t < dist - 2e-3 {
+                return Some((Color::new(0.0, 0.0, 0.0), wo));
+            }
+        }
+        Some((self.weight * contribution, wo))
+    }
+}
+
+/// Whether the reservoir of `other` is a fair stand-in for one at `si`.
+fn similar(si: &SurfaceInteraction, other: &SurfaceInteraction) -> bool {
+    dot(si.normal, other.normal) > 0.9 && f32::abs(other.t - si.t) < 0.1 * si.t
+}
+
+/// Merges the reservoirs of `neighbors` into `own`, the reservoir of `si`.
+pub(crate) fn spatial_reuse(
+    scene: &Scene,
+    si: &SurfaceInteraction,
+    own: &Reservoir,
+    neighbors: &[(&SurfaceInteraction, &Reservoir)],
+    unbiased: bool,
+    sampler: &mut Sampler,
+) -> Reservoir {
+    let sources: Vec<_> = std::iter::once((si, own))
+        .chain(
+            neighbors
+                .iter()
+                .copied()
+                .filter(|(other, _)| similar(si, other)),
+        )
+        .collect();
+
+    let mut merged = Reservoir::default();
+    for (_, reservoir) in sources.iter() {
+        merged.count += reservoir.count;
+        let u = sampler.gen::<f32>();
+        if let Some(point) = reservoir.sample {
+            let p_hat = target(unshadowed(scene, si, &point).0);
+            merged.update(point, p_hat * reservoir.weight * reservoir.count as f32, u);
+        }
+    }
+
+    let count = match (unbiased, merged.sample) {
+        // only pixels that could have picked the sample share in it
+        (true, Some(point)) => sources
+            .iter()
+            .filter(|(other, _)| target(unshadowed(scene, other, &point).0) > 0.0)
+            .map(|(_, reservoir)| reservoir.count)
+            .sum(),
+        _ => merged.count,
+    };
+    merged.normalize(scene, si, count as f32);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emitter::PointLight;
+    use crate::integrator::tests::{lamp_scene, render};
+    use crate::integrator::PathIntegrator;
+    use crate::lightsampler::UniformLightSampler;
+
+    /// The lamp scene with a few bright point lights among many dim ones.
+    fn many_lights() -> Scene {
+        let mut scene = lamp_scene();
+        let mut sampler = Sampler::new(5, 0);
+        for k in 0..32 {
+            let position = Point {
+                x: 6.0 * sampler.gen::<f32>() - 3.0,
+                y: 1.5 * sampler.gen::<f32>() + 1.5,
+                z: -6.0 * sampler.gen::<f32>(),
+            };
+            let intensity = match k % 8 {
+                0 => 3.0,
+                _ => 0.06,
+            };
+            scene.add_light(Box::new(PointLight::new(position, intensity)));
+        }
+        scene
+    }
+
+    fn mean(pixels: &[Color]) -> f32 {
+        pixels.iter().map(|c| c.g).sum::<f32>() / pixels.len() as f32
+    }
+
+    fn rmse(pixels: &[Color], reference: &[Color]) -> f32 {
+        let sum: f32 = pixels
+            .iter()
+            .zip(reference)
+            .map(|(a, b)| (a.g - b.g) * (a.g - b.g))
+            .sum();
+        f32::sqrt(sum / pixels.len() as f32)
+    }
+
+    #[test]
+    fn resampling_matches_all_lights() {
+        let scene = many_lights();
+        let reference = render(&PathIntegrator::new(1, 1), &scene, 1024, 3, 4);
+        let expected = mean(&reference);
+        assert!(expected > 0.01);
+
+        let settings = [
+            ReservoirSampling::new(16),
+            ReservoirSampling::new(4)
+                .with_spatial_reuse(4, 4.0)
+                .with_unbiased_reuse(true),
+        ];
+        for settings in settings {
+            let integrator = PathIntegrator::new(1, 1).with_reservoirs(settings);
+            let estimate = mean(&render(&integrator, &scene, 1024, 3, 4));
+            assert!(
+                (estimate - expected).abs() < 0.02 * expected,
+                "{settings:?} {estimate} {expected}"
+            );
+        }
+        let biased = PathIntegrator::new(1, 1)
+            .with_reservoirs(ReservoirSampling::new(4).with_spatial_reuse(4, 4.0));
+        let estimate = mean(&render(&biased, &scene, 256, 3, 4));
+        assert!((estimate - expected).abs() < 0.05 * expected);
+    }
+
+    #[test]
+    fn resampling_reduces_noise() {
+        let scene = many_lights();
+        let reference = render(&PathIntegrator::new(1, 1), &scene, 256, 3, 4);
+        let uniform = PathIntegrator::new(1, 1)
+            .with_light_sampler(Box::new(UniformLightSampler::new(&scene)));
+        let resampled = PathIntegrator::new(1, 1).with_reservoirs(ReservoirSampling::new(8));
+        let reused = PathIntegrator::new(1, 1)
+            .with_reservoirs(ReservoirSampling::new(8).with_spatial_reuse(4, 3.0));
+
+        let uniform = rmse(&render(&uniform, &scene, 4, 3, 4), &reference);
+        let resampled = rmse(&render(&resampled, &scene, 4, 3, 4), &reference);
+        let reused = rmse(&render(&reused, &scene, 4, 3, 4), &reference);
+        assert!(resampled < 0.7 * uniform, "{resampled} {uniform}");
+        assert!(reused < resampled, "{reused} {resampled}");
+
+        let settings = ReservoirSampling::new(4).with_spatial_reuse(3, 3.0);
+        let integrator = PathIntegrator::new(2, 1).with_reservoirs(settings);
+        let single = render(&integrator, &scene, 2, 3, 1);
+        let multi = render(&integrator, &scene, 2, 3, 5);
+        let bits = |pixels: &[Color]| pixels.iter().map(|c| c.g.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&single), bits(&multi));
+    }
+}
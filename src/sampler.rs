@@ -0,0 +1,293 @@
+use rand::{Error, RngCore};
+
+/// Deterministic random number stream based on PCG32 (XSH-RR variant).
+///
+/// Every camera sample gets its own stream derived from the render seed, the
+/// pixel index and the sample index, so an image only depends on the seed and
+/// not on how pixels are distributed over threads.
+///
+/// A sampler made by `Sampler::primary` instead hands out the coordinates of
+/// a point in primary sample space, which Metropolis light transport mutates
+/// from iteration to iteration.
+#[derive(Clone, Debug)]
+pub struct Sampler {
+    pcg: Pcg,
+    primary: Option<Box<PrimarySamples>>,
+}
+
+#[derive(Clone, Debug)]
+struct Pcg {
+    state: u64,
+    inc: u64,
+}
+
+/// Coordinate of a primary sample space point with its value before the current mutation.
+#[derive(Clone, Debug)]
+struct PrimarySample {
+    value: f32,
+    last_modified: u64,
+    backup: f32,
+    backup_modified: u64,
+}
+
+/// Point in primary sample space, mutated lazily as its coordinates are used.
+///
+/// Small steps move every coordinate by a normal distribution, large steps
+/// draw fresh coordinates, as proposed by Kelemen et al.
+#[derive(Clone, Debug)]
+struct PrimarySamples {
+    samples: Vec<PrimarySample>,
+    /// Index of the next coordinate handed out.
+    index: usize,
+    iteration: u64,
+    last_large_step: u64,
+    large_step: bool,
+    sigma: f32,
+    large_step_probability: f32,
+}
+
+const PCG_MULTIPLIER: u64 = 6364136223846793005;
+
+/// SplitMix64 finalizer, used to decorrelate seeds that only differ in a few bits.
+fn mix64(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl Pcg {
+    fn step(&mut self) {
+        self.state = self
+            .state
+            .wrapping_mul(PCG_MULTIPLIER)
+            .wrapping_add(self.inc);
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.step();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// Uniform float in [0, 1).
+    fn uniform(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+    }
+}
+
+impl PrimarySamples {
+    /// Next coordinate, brought up to date with the mutations it missed.
+    fn next(&mut self, pcg: &mut Pcg) -> f32 {
+        let index = self.index;
+        self.index += 1;
+        if index >= self.samples.len() {
+            self.samples.resize(
+                index + 1,
+                PrimarySample {
+                    value: 0.0,
+                    last_modified: 0,
+                    backup: 0.0,
+                    backup_modified: 0,
+                },
+            );
+        }
+        let sample = &mut self.samples[index];
+
+        // coordinates untouched since the last accepted large step take a fresh value
+        if sample.last_modified < self.last_large_step {
+            sample.value = pcg.uniform();
+            sample.last_modified = self.last_large_step;
+        }
+        sample.backup = sample.value;
+        sample.backup_modified = sample.last_modified;
+
+        match self.large_step {
+            true => sample.value = pcg.uniform(),
+            false => {
+                // all small steps skipped so far at once, their variances add up
+                let steps = (self.iteration - sample.last_modified) as f32;
+                let u1 = 1.0 - pcg.uniform();
+                let u2 = pcg.uniform();
+                let normal =
+                    f32::sqrt(-2.0 * f32::ln(u1)) * f32::cos(2.0 * std::f32::consts::PI * u2);
+                sample.value += normal * self.sigma * f32::sqrt(steps);
+                sample.value -= f32::floor(sample.value);
+                // rounding can land exactly on one
+                if sample.value >= 1.0 {
+                    sample.value = 0.0;
+                }
+            }
+        }
+        sample.last_modified = self.iteration;
+        sample.value
+    }
+}
+
+impl Sampler {
+    pub fn new(seed: u64, stream: u64) -> Sampler {
+        let mut pcg = Pcg {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        pcg.step();
+        pcg.state = pcg.state.wrapping_add(seed);
+        pcg.step();
+        Sampler { pcg, primary: None }
+    }
+
+    /// Stream for the `sample`-th camera sample of pixel `pixel`.
+    pub fn for_sample(seed: u64, pixel: usize, sample: usize) -> Sampler {
+        let key = mix64(seed ^ mix64(pixel as u64 ^ mix64(sample as u64)));
+        Sampler::new(mix64(key), key)
+    }
+
+    /// Sampler over a point in primary sample space for Metropolis light transport.
+    ///
+    /// The point starts out uniformly random and is only drawn as its
+    /// coordinates are used, from a stream picked like in `Sampler::new`.
+    /// Small steps have a standard deviation of `sigma`.
+    pub fn primary(seed: u64, stream: u64, sigma: f32, large_step_probability: f32) -> Sampler {
+        let mut sampler = Sampler::new(seed, stream);
+        sampler.primary = Some(Box::new(PrimarySamples {
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            last_large_step: 0,
+            large_step: true,
+            sigma,
+            large_step_probability,
+        }));
+        sampler
+    }
+
+    /// Draws all further numbers from the stream `Sampler::new(seed, stream)` starts with.
+    ///
+    /// A primary sample point keeps its coordinates, only later mutations change.
+    pub fn reseed(&mut self, seed: u64, stream: u64) {
+        self.pcg = Sampler::new(seed, stream).pcg;
+    }
+
+    /// Mutates the primary sample point, its coordinates are handed out again from the first.
+    pub fn start_iteration(&mut self) {
+        if let Some(primary) = self.primary.as_mut() {
+            primary.iteration += 1;
+            primary.large_step = self.pcg.uniform() < primary.large_step_probability;
+            primary.index = 0;
+        }
+    }
+
+    /// Whether the current mutation of the primary sample point is a large step.
+    pub fn is_large_step(&self) -> bool {
+        self.primary
+            .as_ref()
+            .is_some_and(|primary| primary.large_step)
+    }
+
+    /// Keeps the current mutation.
+    pub fn accept(&mut self) {
+        if let Some(primary) = self.primary.as_mut() {
+            if primary.large_step {
+                primary.last_large_step = primary.iteration;
+            }
+        }
+    }
+
+    /// Goes back to the point before the current mutation.
+    pub fn reject(&mut self) {
+        if let Some(primary) = self.primary.as_mut() {
+            for sample in primary.samples.iter_mut() {
+                if sample.last_modified == primary.iteration {
+                    sample.value = sample.backup;
+                    sample.last_modified = sample.backup_modified;
+                }
+            }
+            primary.iteration -= 1;
+        }
+    }
+}
+
+impl RngCore for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        match self.primary.as_mut() {
+            Some(primary) => (primary.next(&mut self.pcg) as f64 * 4294967296.0) as u32,
+            None => self.pcg.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn is_reproducible() {
+        let mut a = Sampler::for_sample(42, 17, 3);
+        let mut b = Sampler::for_sample(42, 17, 3);
+        for _ in 0..100 {
+            assert_eq!(a.next_u32(), b.next_u32());
+        }
+    }
+
+    #[test]
+    fn streams_differ() {
+        let mut a = Sampler::for_sample(42, 17, 3);
+        let mut b = Sampler::for_sample(42, 17, 4);
+        let mut c = Sampler::for_sample(43, 17, 3);
+        let a: Vec<u32> = (0..8).map(|_| a.next_u32()).collect();
+        let b: Vec<u32> = (0..8).map(|_| b.next_u32()).collect();
+        let c: Vec<u32> = (0..8).map(|_| c.next_u32()).collect();
+        assert_ne!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn mutates_primary_samples() {
+        let values = |sampler: &Sampler| -> Vec<f32> {
+            let primary = sampler.primary.as_ref().unwrap();
+            primary.samples.iter().map(|sample| sample.value).collect()
+        };
+        let mut sampler = Sampler::primary(1, 2, 0.01, 0.0);
+        let first: Vec<f32> = (0..4).map(|_| sampler.gen()).collect();
+        let point = values(&sampler);
+
+        // small steps stay close to the previous point
+        sampler.start_iteration();
+        let second: Vec<f32> = (0..4).map(|_| sampler.gen()).collect();
+        for (a, b) in first.iter().zip(second.iter()) {
+            let distance = f32::min((a - b).abs(), 1.0 - (a - b).abs());
+            assert!(a != b && distance < 0.1);
+        }
+
+        sampler.reject();
+        assert_eq!(values(&sampler), point);
+    }
+
+    #[test]
+    fn floats_in_unit_interval() {
+        let mut sampler = Sampler::new(0, 0);
+        for _ in 0..10000 {
+            let x: f32 = sampler.gen();
+            assert!((0.0..1.0).contains(&x));
+        }
+    }
+}
@@ -1,15 +1,34 @@
-use crate::emitter::Emitter;
+use crate::colorspace::ColorSpace;
+use crate::emitter::{AreaLight, Emitter};
 use crate::material::*;
 use crate::math::*;
+use crate::medium::Medium;
+use crate::sampler::Sampler;
 use crate::sensor::Color;
+use crate::texture::EnvironmentMap;
 
+use rand::Rng;
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+#[derive(Clone)]
 pub struct SurfaceInteraction<'a> {
     pub position: Point,
     pub normal: Vector,
     pub t: f32,
-    pub material: &'a Box<dyn Material>,
+    pub uv: (f32, f32),
+    pub material: &'a dyn Material,
     pub wi: Vector,
-    pub emitter: Option<&'a Box<dyn Emitter>>,
+    pub emitter: Option<&'a dyn Emitter>,
+    pub shape_id: usize,
+    pub material_id: usize,
+    /// Medium enclosed by the hit shape, if any.
+    pub interior: Option<&'a dyn Medium>,
+    /// Color interpolated from mesh vertices, if the shape has any.
+    pub color: Option<Color>,
+    /// Hero wavelength in nanometers of a spectral path, `None` when rendering RGB.
+    pub wavelength: Option<f32>,
 }
 
 pub struct Sphere {
@@ -24,32 +43,116 @@ pub struct InfinitePlane {
     pub material: Box<dyn Material>,
 }
 
+/// A shared shape placed in the scene by a transform.
+///
+/// Many instances can refer to the same shape, e.g. a mesh, without copying it.
+pub struct Instance {
+    pub shape: Arc<dyn Shape>,
+    pub transform: Transform,
+}
+
+/// Point sampled on the surface of a shape.
+pub struct ShapeSample {
+    pub position: Point,
+    pub normal: Vector,
+    /// Density with respect to surface area.
+    pub pdf: f32,
+}
+
 pub trait Shape: Send + Sync {
-    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction>;
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>>;
+    fn material(&self) -> &dyn Material;
+    fn bounds(&self) -> Bounds3;
+    fn area(&self) -> f32;
+    /// Samples a point on the surface, `None` for shapes that cannot be sampled.
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample>;
+
+    /// Acceleration structure nodes visited to intersect `ray`, zero for shapes without one.
+    fn traversal_cost(&self, _ray: &Ray) -> usize {
+        0
+    }
 }
 
 pub struct Scene {
-    pub shapes: Vec<Box<dyn Shape>>,
+    shapes: Vec<SceneShape>,
     pub lights: Vec<Box<dyn Emitter>>,
     pub background_color: Color,
+    /// Surrounding image, replaces `background_color` when present.
+    pub environment: Option<EnvironmentMap>,
+    /// Medium filling the space outside of all shapes, e.g. fog.
+    pub medium: Option<Box<dyn Medium>>,
+    /// Working space of all material, light and texture colors.
+    pub color_space: ColorSpace,
+    next_material_id: usize,
+}
+
+/// A shape of a scene with what the scene knows about it.
+struct SceneShape {
+    shape: Box<dyn Shape>,
+    material_id: usize,
+    /// Medium filling the inside of the shape.
+    interior: Option<Box<dyn Medium>>,
+    /// Index into `Scene::lights` of the area light the shape is the surface of.
+    emitter: Option<usize>,
 }
 
 impl Scene {
-    pub fn closest_hit(&self, ray: &Ray) -> Option<SurfaceInteraction> {
-        let closest = self
+    pub fn closest_hit(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let (shape_id, mut closest) = self
             .shapes
             .iter()
-            .filter_map(|shape| shape.intersect(&ray))
-            .min_by(|a, b| a.t.partial_cmp(&b.t).unwrap())?;
-
-        Some(SurfaceInteraction {
-            position: closest.position,
-            normal: closest.normal,
-            t: closest.t,
-            material: closest.material,
-            wi: closest.wi,
-            emitter: None,
-        })
+            .enumerate()
+            .filter_map(|(k, shape)| Some((k, shape.shape.intersect(ray)?)))
+            .min_by(|(_, a), (_, b)| a.t.partial_cmp(&b.t).unwrap())?;
+
+        let shape = &self.shapes[shape_id];
+        closest.shape_id = shape_id;
+        closest.material_id = shape.material_id;
+        closest.interior = shape.interior.as_deref();
+        closest.emitter = shape.emitter.map(|light| self.lights[light].as_ref());
+
+        Some(closest)
+    }
+
+    /// Closest surface along `ray` that is not a bare medium boundary.
+    pub(crate) fn closest_surface(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let mut ray = Ray {
+            origin: ray.origin,
+            direction: ray.direction,
+        };
+        loop {
+            let si = self.closest_hit(&ray)?;
+            if !si.material.is_null() {
+                return Some(si);
+            }
+            ray.origin = si.position + 1e-3 * ray.direction;
+        }
+    }
+
+    /// Work spent tracing `ray`, one per shape plus the acceleration structure nodes
+    /// visited inside them.
+    pub fn traversal_cost(&self, ray: &Ray) -> usize {
+        self.shapes
+            .iter()
+            .map(|shape| 1 + shape.shape.traversal_cost(ray))
+            .sum()
+    }
+
+    /// Bounds of all shapes with finite extent, planes and the like are left out.
+    pub fn bounds(&self) -> Bounds3 {
+        self.shapes
+            .iter()
+            .map(|shape| shape.shape.bounds())
+            .filter(|bounds| !bounds.is_unbounded())
+            .fold(Bounds3::empty(), |accum, bounds| accum.union(&bounds))
+    }
+
+    /// Radiance reaching a ray travelling along `direction` that leaves the scene.
+    pub fn background(&self, direction: Vector) -> Color {
+        match &self.environment {
+            Some(environment) => environment.lookup(direction),
+            None => self.background_color,
+        }
     }
 
     pub fn new() -> Scene {
@@ -57,16 +160,66 @@ impl Scene {
             shapes: Vec::new(),
             lights: Vec::new(),
             background_color: Color::new(0.2, 0.2, 0.2),
+            environment: None,
+            medium: None,
+            color_space: ColorSpace::Srgb,
+            next_material_id: 0,
         }
     }
 
+    /// The shapes in the order they were added, their index is the shape id of their hits.
+    pub fn shapes(&self) -> impl ExactSizeIterator<Item = &dyn Shape> {
+        self.shapes.iter().map(|shape| shape.shape.as_ref())
+    }
+
+    /// Reserves a material id that no shape has yet, see `add_shape_with_material_id`.
+    pub fn new_material_id(&mut self) -> usize {
+        self.next_material_id += 1;
+        self.next_material_id - 1
+    }
+
+    /// Adds a shape to the scene with a material id of its own.
     pub fn add_shape(&mut self, shape: Box<dyn Shape>) {
-        self.shapes.push(shape);
+        let material_id = self.new_material_id();
+        self.add_shape_with_material_id(shape, material_id);
+    }
+
+    /// Adds a shape whose material is the same as that of the other shapes added
+    /// with `material_id`, e.g. the parts of a mesh loaded from a file.
+    pub fn add_shape_with_material_id(&mut self, shape: Box<dyn Shape>, material_id: usize) {
+        self.next_material_id = usize::max(self.next_material_id, material_id + 1);
+        self.shapes.push(SceneShape {
+            shape,
+            material_id,
+            interior: None,
+            emitter: None,
+        });
+    }
+
+    /// Adds a closed shape whose inside is filled with `medium`.
+    ///
+    /// Give the shape a `NullMaterial` for a bare volume like a puff of smoke.
+    pub fn add_shape_with_interior(&mut self, shape: Box<dyn Shape>, medium: Box<dyn Medium>) {
+        self.add_shape(shape);
+        self.shapes.last_mut().unwrap().interior = Some(medium);
     }
 
     pub fn add_light(&mut self, light: Box<dyn Emitter>) {
         self.lights.push(light);
     }
+
+    /// Adds `shape` as geometry and as a light emitting `radiance` from its front side.
+    pub fn add_area_light(&mut self, shape: Arc<dyn Shape>, radiance: Color) {
+        self.add_light(Box::new(AreaLight::new(shape.clone(), radiance)));
+        self.add_shape(Box::new(shape));
+        self.shapes.last_mut().unwrap().emitter = Some(self.lights.len() - 1);
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Scene::new()
+    }
 }
 
 impl Sphere {
@@ -90,7 +243,7 @@ impl InfinitePlane {
 }
 
 impl Shape for Sphere {
-    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction> {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
         let o = ray.origin;
         let u = ray.direction.normalize();
         let c = self.center;
@@ -102,7 +255,13 @@ impl Shape for Sphere {
             return None;
         }
 
-        let t = -dot(u, o - c) - f32::sqrt(discriminant);
+        // take the far intersection if the ray starts inside the sphere
+        let t_near = -dot(u, o - c) - f32::sqrt(discriminant);
+        let t_far = -dot(u, o - c) + f32::sqrt(discriminant);
+        let t = match t_near < 0.0 {
+            true => t_far,
+            false => t_near,
+        };
         if t < 0.0 {
             return None;
         }
@@ -110,19 +269,50 @@ impl Shape for Sphere {
         let intersection = o + t * u;
         let normal = (intersection - c).normalize();
 
-        Some(SurfaceInteraction {
-            position: intersection,
+        let phi = f32::atan2(normal.y, normal.x);
+        let phi = if phi < 0.0 { phi + 2.0 * PI } else { phi };
+        let theta = f32::acos(f32::clamp(normal.z, -1.0, 1.0));
+        let uv = (phi / (2.0 * PI), theta / PI);
+
+        Some(SurfaceInteraction::new(
+            intersection,
             normal,
             t,
-            wi: -u,
-            material: &self.material,
-            emitter: None,
+            -u,
+            uv,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        let r = Vector {
+            x: self.radius,
+            y: self.radius,
+            z: self.radius,
+        };
+        Bounds3::new(self.center + -r, self.center + r)
+    }
+
+    fn area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        let normal = uniform_sphere_sample(sampler);
+        Some(ShapeSample {
+            position: self.center + self.radius * normal,
+            normal,
+            pdf: 1.0 / self.area(),
         })
     }
 }
 
 impl Shape for InfinitePlane {
-    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction> {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
         let o = ray.origin;
         let u = ray.direction.normalize();
         let n = self.normal;
@@ -140,37 +330,159 @@ impl Shape for InfinitePlane {
         }
 
         let intersection = o + t * u;
+        let (tu, tv, _) = coordinate_system(n);
+        let uv = (dot(intersection - c, tu), dot(intersection - c, tv));
 
-        Some(SurfaceInteraction {
-            position: intersection,
-            normal: n,
+        Some(SurfaceInteraction::new(
+            intersection,
+            n,
             t,
-            wi: -u,
-            material: &self.material,
-            emitter: None,
+            -u,
+            uv,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        Bounds3::unbounded()
+    }
+
+    fn area(&self) -> f32 {
+        f32::INFINITY
+    }
+
+    fn sample_area(&self, _sampler: &mut Sampler) -> Option<ShapeSample> {
+        None
+    }
+}
+
+impl Instance {
+    pub fn new(shape: Arc<dyn Shape>, transform: Transform) -> Instance {
+        Instance { shape, transform }
+    }
+}
+
+impl Shape for Instance {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        let u = ray.direction.normalize();
+        let local_ray = self.transform.inverse().ray(&Ray {
+            origin: ray.origin,
+            direction: u,
+        });
+        let mut si = self.shape.intersect(&local_ray)?;
+
+        si.position = self.transform.point(si.position);
+        si.normal = self.transform.normal(si.normal).normalize();
+        si.t = norm(si.position - ray.origin);
+        si.wi = -u;
+
+        Some(si)
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.shape.material()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        self.transform.bounds(&self.shape.bounds())
+    }
+
+    /// Only exact for uniform scales, loaders refuse emitters under other transforms.
+    fn area(&self) -> f32 {
+        self.shape.area() * f32::powf(self.transform.determinant().abs(), 2.0 / 3.0)
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        let sample = self.shape.sample_area(sampler)?;
+        let normal = self.transform.normal(sample.normal);
+        // local area element scaling of an affine map for a unit normal
+        let jacobian = self.transform.determinant().abs() * norm(normal);
+        Some(ShapeSample {
+            position: self.transform.point(sample.position),
+            normal: normal.normalize(),
+            pdf: sample.pdf / jacobian,
         })
     }
+
+    fn traversal_cost(&self, ray: &Ray) -> usize {
+        let local_ray = self.transform.inverse().ray(&Ray {
+            origin: ray.origin,
+            direction: ray.direction.normalize(),
+        });
+        self.shape.traversal_cost(&local_ray)
+    }
+}
+
+impl<T: Shape + ?Sized> Shape for Arc<T> {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceInteraction<'_>> {
+        self.as_ref().intersect(ray)
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.as_ref().material()
+    }
+
+    fn bounds(&self) -> Bounds3 {
+        self.as_ref().bounds()
+    }
+
+    fn area(&self) -> f32 {
+        self.as_ref().area()
+    }
+
+    fn sample_area(&self, sampler: &mut Sampler) -> Option<ShapeSample> {
+        self.as_ref().sample_area(sampler)
+    }
+
+    fn traversal_cost(&self, ray: &Ray) -> usize {
+        self.as_ref().traversal_cost(ray)
+    }
+}
+
+/// Uniformly distributed direction on the unit sphere.
+pub fn uniform_sphere_sample(sampler: &mut Sampler) -> Vector {
+    let z = 1.0 - 2.0 * sampler.gen::<f32>();
+    let r = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+    let phi = 2.0 * PI * sampler.gen::<f32>();
+    Vector {
+        x: r * f32::cos(phi),
+        y: r * f32::sin(phi),
+        z,
+    }
 }
 
 impl<'a> SurfaceInteraction<'a> {
-    pub fn local_frame(&self) -> (Vector, Vector, Vector) {
-        let w = self.normal;
-        let axis = match f32::abs(w.x) > 0.1 {
-            true => Vector {
-                x: 0.0,
-                y: 1.0,
-                z: 0.0,
-            },
-            false => Vector {
-                x: 1.0,
-                y: 0.0,
-                z: 0.0,
-            },
-        };
-        let u = cross(axis, w).normalize();
-        let v = cross(w, u);
+    /// Interaction as reported by a shape, the scene fills in ids, interior medium and emitter.
+    pub fn new(
+        position: Point,
+        normal: Vector,
+        t: f32,
+        wi: Vector,
+        uv: (f32, f32),
+        material: &'a dyn Material,
+    ) -> SurfaceInteraction<'a> {
+        SurfaceInteraction {
+            position,
+            normal,
+            t,
+            uv,
+            material,
+            wi,
+            emitter: None,
+            shape_id: 0,
+            material_id: 0,
+            interior: None,
+            color: None,
+            wavelength: None,
+        }
+    }
 
-        (u, v, w)
+    pub fn local_frame(&self) -> (Vector, Vector, Vector) {
+        coordinate_system(self.normal)
     }
 }
 
@@ -236,4 +548,77 @@ mod tests {
 
         assert!(si.is_none());
     }
+
+    #[test]
+    fn intersects_instances() {
+        let sphere: Arc<dyn Shape> = Arc::new(Sphere::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            1.0,
+            Box::new(BlackBody {}),
+        ));
+        // unit sphere stretched to an ellipsoid with half axis 2 along y, centered at y = 5
+        let instance = Instance::new(
+            sphere,
+            Transform::translate(Vector {
+                x: 0.0,
+                y: 5.0,
+                z: 0.0,
+            }) * Transform::scale(1.0, 2.0, 1.0),
+        );
+
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 2.0,
+                z: 0.0,
+            },
+        };
+        let si = instance.intersect(&ray).unwrap();
+        assert!((si.t - 4.0).abs() < 1e-4);
+        assert!(
+            norm(
+                si.normal
+                    - Vector {
+                        x: 0.0,
+                        y: -1.0,
+                        z: 0.0
+                    }
+            ) < 1e-4
+        );
+        assert!((si.position.y - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn assigns_material_ids() {
+        let null_sphere = |z: f32| {
+            Box::new(Sphere::new(
+                Point { x: 0.0, y: 0.0, z },
+                0.5,
+                Box::new(NullMaterial {}),
+            ))
+        };
+        let mut scene = Scene::new();
+        // zero-sized materials all live at the same address
+        scene.add_shape(null_sphere(-2.0));
+        scene.add_shape(null_sphere(-4.0));
+        let shared = scene.new_material_id();
+        scene.add_shape_with_material_id(null_sphere(-6.0), shared);
+        scene.add_shape_with_material_id(null_sphere(-8.0), shared);
+        scene.add_shape(null_sphere(-10.0));
+
+        let ids: Vec<usize> = scene.shapes.iter().map(|shape| shape.material_id).collect();
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(ids[2], ids[3]);
+        assert!(!ids[..2].contains(&ids[2]));
+        assert!(!ids[..4].contains(&ids[4]));
+    }
 }
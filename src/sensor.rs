@@ -1,9 +1,12 @@
-use image::ImageResult;
+use image::{ImageBuffer, ImageResult, Rgb};
 use rand::Rng;
-use std::ops::{Add, Div, Mul};
+use std::ops::{Add, Div, Mul, Sub};
 use std::sync::RwLock;
 
+use crate::aov::{Aov, AovRecord};
+use crate::colorspace::{ColorSpace, TransferFunction};
 use crate::math::*;
+use crate::sampler::Sampler;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Color {
@@ -17,16 +20,26 @@ pub struct Pixel {
     pub color: RwLock<Color>,
 }
 
+pub struct AovBuffer {
+    pub aov: Aov,
+    pub pixels: Vec<RwLock<Color>>,
+}
+
 pub struct Sensor {
     pixels: Vec<Pixel>,
     width: usize,
     height: usize,
+    aovs: Vec<AovBuffer>,
+    color_space: ColorSpace,
+    /// Display space and encoding of `readout`, `None` writes the linear pixels.
+    output: Option<(ColorSpace, TransferFunction)>,
 }
 
 pub struct PinholeCamera {
     sensor: Sensor,
     fov: f32,
-    position: Point,
+    /// Places the camera, which looks down -z from the origin in its own space.
+    to_world: Transform,
 }
 
 pub trait Camera: Send + Sync {
@@ -34,7 +47,17 @@ pub trait Camera: Send + Sync {
     fn get_sensor(&self) -> &Sensor;
     fn get_pixels_mut(&mut self) -> &mut Vec<Pixel>;
     fn get_pixels(&self) -> &Vec<Pixel>;
-    fn sample_ray(&self, i: usize, j: usize) -> Option<Ray>;
+    fn sample_ray(&self, i: usize, j: usize, sampler: &mut Sampler) -> Option<Ray>;
+
+    /// Origin of all camera rays.
+    fn position(&self) -> Point;
+    /// Pixel that the world-space `point` projects to, `None` if it is not in view.
+    fn pixel_of(&self, point: Point) -> Option<(usize, usize)>;
+    /// Importance arriving at `point` from the camera, `We cos / d²` of the
+    /// connecting ray normalized over the whole image, zero if not in view.
+    fn importance(&self, point: Point) -> f32;
+    /// Solid angle density of `sample_ray` picking `direction` for a uniformly chosen pixel.
+    fn pdf_direction(&self, direction: Vector) -> f32;
 }
 
 impl PinholeCamera {
@@ -42,16 +65,37 @@ impl PinholeCamera {
         PinholeCamera {
             sensor,
             fov: fov.to_radians(),
-            position: Point {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            to_world: Transform::identity(),
         }
     }
 
-    pub fn position(&self) -> Point {
-        self.position
+    pub fn with_transform(mut self, to_world: Transform) -> PinholeCamera {
+        self.to_world = to_world;
+        self
+    }
+
+    /// Raster position of the camera-space `direction`, `None` if it misses the image.
+    fn raster(&self, direction: Vector) -> Option<(f32, f32)> {
+        if direction.z >= 0.0 {
+            return None;
+        }
+        let tan = f32::tan(self.fov / 2.0);
+        let x = direction.x / -direction.z / (self.sensor.aspect() * tan);
+        let y = direction.y / -direction.z / tan;
+        // inverse of the mapping in sample_ray
+        let i = (x + 1.0) / 2.0 * (self.sensor.width + 1) as f32;
+        let j = (1.0 - y) / 2.0 * (self.sensor.height + 1) as f32;
+        let inside = (0.0..self.sensor.width as f32).contains(&i)
+            && (0.0..self.sensor.height as f32).contains(&j);
+        inside.then_some((i, j))
+    }
+
+    /// Area of the image on the plane at distance one in front of the camera.
+    fn image_area(&self) -> f32 {
+        let tan = f32::tan(self.fov / 2.0);
+        let (width, height) = (self.sensor.width as f32, self.sensor.height as f32);
+        (2.0 * self.sensor.aspect() * tan * width / (width + 1.0))
+            * (2.0 * tan * height / (height + 1.0))
     }
 }
 
@@ -72,16 +116,15 @@ impl Camera for PinholeCamera {
         &self.sensor.pixels
     }
 
-    fn sample_ray(&self, i: usize, j: usize) -> Option<Ray> {
+    fn sample_ray(&self, i: usize, j: usize, sampler: &mut Sampler) -> Option<Ray> {
         if !self.sensor.inside(i, j) {
             return None;
         }
 
         let aspect_ratio = self.sensor.aspect();
 
-        let mut rng = rand::thread_rng();
-        let jitter_u: f32 = rng.gen();
-        let jitter_v: f32 = rng.gen();
+        let jitter_u: f32 = sampler.gen();
+        let jitter_v: f32 = sampler.gen();
 
         // pixel coord to normalized coord in [0, 1]
         let u = (i as f32 + jitter_u) / (self.sensor.width + 1) as f32;
@@ -91,18 +134,61 @@ impl Camera for PinholeCamera {
         let v = (1.0 - 2.0 * v) * f32::tan(self.fov / 2.0);
 
         Some(Ray {
-            origin: Point {
+            origin: self.position(),
+            direction: self
+                .to_world
+                .vector(Vector {
+                    x: u,
+                    y: v,
+                    z: -1.0,
+                })
+                .normalize(),
+        })
+    }
+
+    fn position(&self) -> Point {
+        self.to_world.point(Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        })
+    }
+
+    fn pixel_of(&self, point: Point) -> Option<(usize, usize)> {
+        let local = self.to_world.inverse().point(point);
+        let direction = local
+            - Point {
                 x: 0.0,
                 y: 0.0,
                 z: 0.0,
-            },
-            direction: Vector {
-                x: u,
-                y: v,
-                z: -1.0,
-            }
-            .normalize(),
-        })
+            };
+        let (i, j) = self.raster(direction)?;
+        Some((i as usize, j as usize))
+    }
+
+    fn importance(&self, point: Point) -> f32 {
+        let local = self.to_world.inverse().point(point);
+        let direction = local
+            - Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            };
+        if self.raster(direction).is_none() {
+            return 0.0;
+        }
+        let dist2 = norm2(direction);
+        let cos = -direction.z / f32::sqrt(dist2);
+        1.0 / (self.image_area() * cos * cos * cos * dist2)
+    }
+
+    fn pdf_direction(&self, direction: Vector) -> f32 {
+        let local = self.to_world.inverse().vector(direction).normalize();
+        if self.raster(local).is_none() {
+            return 0.0;
+        }
+        let cos = -local.z;
+        1.0 / (self.image_area() * cos * cos * cos)
     }
 }
 
@@ -117,6 +203,19 @@ impl Color {
         (r, g, b)
     }
 
+    /// Whether no channel is positive.
+    pub fn is_black(&self) -> bool {
+        self.r <= 0.0 && self.g <= 0.0 && self.b <= 0.0
+    }
+
+    pub fn average(&self) -> f32 {
+        (self.r + self.g + self.b) / 3.0
+    }
+
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
     pub fn clamp(&self) -> Color {
         Color {
             r: f32::clamp(self.r, 0.0, 1.0),
@@ -137,6 +236,17 @@ impl Add for Color {
     }
 }
 
+impl Sub for Color {
+    type Output = Color;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Color {
+            r: self.r - rhs.r,
+            g: self.g - rhs.g,
+            b: self.b - rhs.b,
+        }
+    }
+}
+
 impl Mul<Color> for f32 {
     type Output = Color;
     fn mul(self, rhs: Color) -> Self::Output {
@@ -188,7 +298,7 @@ impl Sensor {
             for i in 0..width {
                 let pixel = Pixel {
                     position: (i, j),
-                    color: RwLock::new(color.clone()),
+                    color: RwLock::new(color),
                 };
                 pixels.push(pixel);
             }
@@ -198,9 +308,42 @@ impl Sensor {
             pixels,
             width,
             height,
+            aovs: Vec::new(),
+            color_space: ColorSpace::Srgb,
+            output: None,
         }
     }
 
+    /// Stores pixels in `space`, integrators convert from the working space of the scene.
+    pub fn with_color_space(mut self, space: ColorSpace) -> Sensor {
+        self.color_space = space;
+        self
+    }
+
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    /// Makes `readout` and 8-bit `save` convert into `space` and encode with `transfer`.
+    pub fn with_output(mut self, space: ColorSpace, transfer: TransferFunction) -> Sensor {
+        self.output = Some((space, transfer));
+        self
+    }
+
+    /// Adds a zeroed buffer for each of `aovs` that integrators fill next to the beauty pass.
+    pub fn with_aovs(mut self, aovs: &[Aov]) -> Sensor {
+        for &aov in aovs {
+            if self.aov(aov).is_some() {
+                continue;
+            }
+            let pixels = (0..self.width * self.height)
+                .map(|_| RwLock::new(Color::new(0.0, 0.0, 0.0)))
+                .collect();
+            self.aovs.push(AovBuffer { aov, pixels });
+        }
+        self
+    }
+
     pub fn zero(width: usize, height: usize) -> Sensor {
         Sensor::constant(
             Color {
@@ -221,20 +364,104 @@ impl Sensor {
                 b: 0.0,
             };
         }
+        for buffer in self.aovs.iter() {
+            for color in buffer.pixels.iter() {
+                *color.write().unwrap() = Color::new(0.0, 0.0, 0.0);
+            }
+        }
+    }
+
+    pub fn aovs(&self) -> &[AovBuffer] {
+        &self.aovs
+    }
+
+    pub fn aov(&self, aov: Aov) -> Option<&AovBuffer> {
+        self.aovs.iter().find(|buffer| buffer.aov == aov)
+    }
+
+    /// Stores the enabled AOVs of `record` at pixel (`i`, `j`).
+    pub fn write_aovs(&self, i: usize, j: usize, record: &AovRecord) {
+        if !self.inside(i, j) {
+            return;
+        }
+        for buffer in self.aovs.iter() {
+            *buffer.pixels[j * self.width + i].write().unwrap() = record.get(buffer.aov);
+        }
+    }
+
+    /// Writes every AOV buffer as a float EXR image named `{prefix}_{aov}.exr`.
+    pub fn save_aovs(&self, prefix: &str) -> ImageResult<()> {
+        for buffer in self.aovs.iter() {
+            let data = buffer
+                .pixels
+                .iter()
+                .flat_map(|color| {
+                    let col = color.read().unwrap();
+                    [col.r, col.g, col.b]
+                })
+                .collect();
+            let image: ImageBuffer<Rgb<f32>, Vec<f32>> =
+                ImageBuffer::from_raw(self.width as u32, self.height as u32, data).unwrap();
+            image.save(format!("{prefix}_{}.exr", buffer.aov.name()))?;
+        }
+        Ok(())
     }
 
+    /// 8-bit pixels in the output space of the sensor, linear if none was set.
     pub fn readout(&self) -> Vec<u8> {
+        if let Some((space, transfer)) = self.output {
+            return self.readout_encoded(space, transfer);
+        }
         self.pixels
             .iter()
-            .map(|Pixel { color, .. }| {
+            .flat_map(|Pixel { color, .. }| {
                 let col = color.read().unwrap().to_bytes();
-                vec![col.0, col.1, col.2]
+                [col.0, col.1, col.2]
+            })
+            .collect()
+    }
+
+    /// 8-bit pixels converted into `space` and encoded with `transfer`.
+    pub fn readout_encoded(&self, space: ColorSpace, transfer: TransferFunction) -> Vec<u8> {
+        let matrix = self.color_space.matrix_to(space);
+        let byte = |v: f32| (f32::clamp(transfer.encode(v), 0.0, 1.0) * 255.0).round() as u8;
+        self.pixels
+            .iter()
+            .flat_map(|Pixel { color, .. }| {
+                let col = matrix.apply(*color.read().unwrap());
+                [byte(col.r), byte(col.g), byte(col.b)]
+            })
+            .collect()
+    }
+
+    /// Linear float pixels converted into `space`, row by row.
+    pub fn linear_pixels(&self, space: ColorSpace) -> Vec<f32> {
+        let matrix = self.color_space.matrix_to(space);
+        self.pixels
+            .iter()
+            .flat_map(|Pixel { color, .. }| {
+                let col = matrix.apply(*color.read().unwrap());
+                [col.r, col.g, col.b]
             })
-            .flatten()
             .collect()
     }
 
+    /// Writes linear pixels in `space` as a float EXR image for interchange.
+    pub fn save_exr(&self, path: &str, space: ColorSpace) -> ImageResult<()> {
+        let image: ImageBuffer<Rgb<f32>, Vec<f32>> = ImageBuffer::from_raw(
+            self.width as u32,
+            self.height as u32,
+            self.linear_pixels(space),
+        )
+        .unwrap();
+        image.save(path)
+    }
+
+    /// Saves EXR files linear in the color space of the sensor, other formats with `readout`.
     pub fn save(&self, path: &str) -> ImageResult<()> {
+        if path.to_ascii_lowercase().ends_with(".exr") {
+            return self.save_exr(path, self.color_space);
+        }
         image::save_buffer(
             path,
             self.readout().as_slice(),
@@ -278,6 +505,7 @@ impl Sensor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tests::temp_path;
 
     #[test]
     fn rejects_outside() {
@@ -323,7 +551,7 @@ mod tests {
         let sensor = Sensor::zero(200, 100);
         let camera = PinholeCamera::new(sensor, 45.0);
 
-        let ray = camera.sample_ray(0, 0).unwrap();
+        let ray = camera.sample_ray(0, 0, &mut Sampler::new(0, 0)).unwrap();
 
         assert_eq!(
             ray.origin,
@@ -335,4 +563,67 @@ mod tests {
         );
         assert!(ray.direction.z < 0.0);
     }
+
+    #[test]
+    fn finds_pixels_of_points() {
+        let camera =
+            PinholeCamera::new(Sensor::zero(16, 12), 60.0).with_transform(Transform::rotate(
+                30.0,
+                Vector {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+            ));
+        let mut sampler = Sampler::new(0, 0);
+        for (i, j) in [(0, 0), (15, 0), (7, 5), (15, 11)] {
+            let ray = camera.sample_ray(i, j, &mut sampler).unwrap();
+            assert_eq!(
+                camera.pixel_of(ray.origin + 3.0 * ray.direction),
+                Some((i, j))
+            );
+        }
+        let behind =
+            camera.position() + -1.0 * camera.sample_ray(7, 5, &mut sampler).unwrap().direction;
+        assert_eq!(camera.pixel_of(behind), None);
+
+        // the direction density integrates to one over the sphere
+        let n = 200_000;
+        let integral = (0..n)
+            .map(|_| camera.pdf_direction(crate::scene::uniform_sphere_sample(&mut sampler)))
+            .sum::<f32>()
+            * 4.0
+            * std::f32::consts::PI
+            / n as f32;
+        assert!((integral - 1.0).abs() < 0.05, "{integral}");
+    }
+
+    #[test]
+    fn round_trips_acescg_exr() {
+        let sensor = Sensor::zero(3, 2).with_color_space(ColorSpace::AcesCg);
+        for (k, pixel) in sensor.pixels.iter().enumerate() {
+            *pixel.color.write().unwrap() = Color::new(0.1 * k as f32, 2.5, 0.02);
+        }
+        let path = temp_path("acescg.exr");
+        let path = path.to_str().unwrap();
+        sensor.save(path).unwrap();
+
+        let image = image::open(path);
+        std::fs::remove_file(path).unwrap();
+        let image = image.unwrap().to_rgb32f();
+        let written: Vec<f32> = image.into_raw();
+        assert_eq!(written, sensor.linear_pixels(ColorSpace::AcesCg));
+
+        // without an output space the linear pixels are written as they are
+        let white = Sensor::constant(Color::new(0.18, 0.18, 0.18), 1, 1)
+            .with_color_space(ColorSpace::AcesCg);
+        assert_eq!(white.readout(), vec![45, 45, 45]);
+        // display output goes through sRGB primaries and encoding
+        let white = white.with_output(ColorSpace::Srgb, TransferFunction::Srgb);
+        assert_eq!(white.readout(), vec![118, 118, 118]);
+        assert_eq!(
+            white.readout_encoded(ColorSpace::Srgb, TransferFunction::Linear),
+            vec![46, 46, 46]
+        );
+    }
 }
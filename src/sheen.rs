@@ -0,0 +1,266 @@
+use crate::material::*;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::Color;
+use crate::texture::Texture;
+
+use rand::Rng;
+use std::f32::consts::PI;
+
+/// Cloth like velvet or satin, a diffuse base under the grazing sheen of microfibers.
+///
+/// The sheen follows the "Charlie" distribution of Estevez and Kulla with
+/// Neubelt's visibility term. The base only receives the light the sheen does
+/// not reflect, as in Filament, so both together never exceed the albedo of
+/// the base. Half of the directions are sampled from the fiber distribution,
+/// the other half from the cosine of the base.
+pub struct SheenMaterial {
+    pub albedo: Box<dyn Texture>,
+    /// Color of the sheen, black for a plain diffuse surface.
+    pub sheen: Color,
+    /// Roughness of the fibers in (0, 1], low values keep the sheen close to grazing angles.
+    roughness: f32,
+    /// Directional albedo of a white sheen at evenly spaced incident cosines.
+    sheen_albedo: Vec<f32>,
+}
+
+/// Share of directions sampled from the fiber distribution.
+const FIBER_FRACTION: f32 = 0.5;
+
+/// Incident cosines at which the directional albedo of the sheen is tabulated.
+const ALBEDO_TABLE_SIZE: usize = 32;
+
+impl SheenMaterial {
+    pub fn new(albedo: Box<dyn Texture>, sheen: Color, roughness: f32) -> SheenMaterial {
+        let mut material = SheenMaterial {
+            albedo,
+            sheen,
+            roughness: f32::clamp(roughness, 1e-3, 1.0),
+            sheen_albedo: Vec::new(),
+        };
+        material.sheen_albedo = (0..ALBEDO_TABLE_SIZE)
+            .map(|k| material.integrate_sheen((k as f32 + 0.5) / ALBEDO_TABLE_SIZE as f32))
+            .collect();
+        material
+    }
+
+    pub fn roughness(&self) -> f32 {
+        self.roughness
+    }
+
+    /// Charlie distribution of fiber normals at angle `cos` from the surface normal.
+    ///
+    /// Normalized such that it integrates to one against the cosine.
+    fn d(&self, cos: f32) -> f32 {
+        let r = 1.0 / self.roughness;
+        let sin = f32::sqrt(f32::max(0.0, 1.0 - cos * cos));
+        (2.0 + r) * f32::powf(sin, r) / (2.0 * PI)
+    }
+
+    /// `f·cos` of a white sheen between the normalized directions `wi` and `wo`.
+    fn fibers(&self, n: Vector, wi: Vector, wo: Vector) -> f32 {
+        let (cos_i, cos_o) = (dot(n, wi), dot(n, wo));
+        if cos_i <= 0.0 || cos_o <= 0.0 {
+            return 0.0;
+        }
+        let h = (wi + wo).normalize();
+        let visibility = 1.0 / (4.0 * (cos_i + cos_o - cos_i * cos_o));
+        self.d(dot(n, h)) * visibility * cos_o
+    }
+
+    /// Light reflected by a white sheen for incident cosine `cos`, by midpoint quadrature.
+    fn integrate_sheen(&self, cos: f32) -> f32 {
+        const STEPS: usize = 64;
+        let n = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let wi = Vector {
+            x: f32::sqrt(1.0 - cos * cos),
+            y: 0.0,
+            z: cos,
+        };
+        let mut sum = 0.0;
+        for j in 0..STEPS {
+            // uniform in cos θ and φ, so every cell covers the same solid angle
+            let cos_o = (j as f32 + 0.5) / STEPS as f32;
+            let sin_o = f32::sqrt(1.0 - cos_o * cos_o);
+            for k in 0..STEPS {
+                let phi = 2.0 * PI * (k as f32 + 0.5) / STEPS as f32;
+                let wo = Vector {
+                    x: sin_o * f32::cos(phi),
+                    y: sin_o * f32::sin(phi),
+                    z: cos_o,
+                };
+                sum += self.fibers(n, wi, wo);
+            }
+        }
+        sum * 2.0 * PI / (STEPS * STEPS) as f32
+    }
+
+    /// Tabulated directional albedo of a white sheen, linearly interpolated.
+    fn sheen_albedo(&self, cos: f32) -> f32 {
+        let x = f32::clamp(
+            cos * ALBEDO_TABLE_SIZE as f32 - 0.5,
+            0.0,
+            (ALBEDO_TABLE_SIZE - 1) as f32,
+        );
+        let k = usize::min(x as usize, ALBEDO_TABLE_SIZE - 2);
+        let t = x - k as f32;
+        (1.0 - t) * self.sheen_albedo[k] + t * self.sheen_albedo[k + 1]
+    }
+
+    /// `f·cos` of the sheen and the diffuse base towards `wo`.
+    fn lobes(&self, si: &SurfaceInteraction, wo: Vector) -> (Color, Color) {
+        let n = si.normal;
+        let wo = wo.normalize();
+        let (cos_i, cos_o) = (dot(n, si.wi), dot(n, wo));
+        if cos_i <= 0.0 || cos_o <= 0.0 {
+            return (Color::new(0.0, 0.0, 0.0), Color::new(0.0, 0.0, 0.0));
+        }
+        let sheen = self.fibers(n, si.wi, wo) * self.sheen;
+        // the base is lit by what the brightest channel of the sheen lets through
+        let strongest = f32::max(self.sheen.r, f32::max(self.sheen.g, self.sheen.b));
+        let base = f32::max(0.0, 1.0 - strongest * self.sheen_albedo(cos_i));
+        let diffuse = (base * cos_o / PI) * self.albedo.eval(si);
+        (sheen, diffuse)
+    }
+}
+
+impl Material for SheenMaterial {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let (sheen, diffuse) = self.lobes(si, wo);
+        BsdfSample {
+            radiance: sheen + diffuse,
+            pdf: self.bsdf_pdf(si, wo),
+        }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        if sampler.gen::<f32>() >= FIBER_FRACTION {
+            return cosine_weighted_hemisphere_sample(si, sampler);
+        }
+        // fiber normals by the cosine weighted distribution, sin θ = u^(1 / (2 + 1 / roughness))
+        let (u, v, w) = si.local_frame();
+        let sin = f32::powf(sampler.gen::<f32>(), 1.0 / (2.0 + 1.0 / self.roughness));
+        let cos = f32::sqrt(f32::max(0.0, 1.0 - sin * sin));
+        let phi = 2.0 * PI * sampler.gen::<f32>();
+        let h = sin * f32::cos(phi) * u + sin * f32::sin(phi) * v + cos * w;
+        2.0 * dot(si.wi, h) * h - si.wi
+    }
+
+    fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        let n = si.normal;
+        let wo = wo.normalize();
+        if dot(n, wo) <= 0.0 {
+            return 0.0;
+        }
+        let h = (si.wi + wo).normalize();
+        let fibers = self.d(dot(n, h)) * dot(n, h) / (4.0 * f32::abs(dot(si.wi, h)));
+        FIBER_FRACTION * fibers + (1.0 - FIBER_FRACTION) * dot(n, wo) / PI
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self, si: &SurfaceInteraction) -> Color {
+        self.albedo.eval(si)
+    }
+
+    fn diffuse_fraction(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        let (sheen, diffuse) = self.lobes(si, wo);
+        let total = (sheen + diffuse).luminance();
+        match total > 0.0 {
+            true => diffuse.luminance() / total,
+            false => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::tests::{estimates, up};
+
+    #[test]
+    fn sampling_matches_evaluation() {
+        for roughness in [0.1, 0.5, 1.0] {
+            let cloth = SheenMaterial::new(
+                Box::new(Color::new(0.3, 0.1, 0.1)),
+                Color::new(1.0, 1.0, 1.0),
+                roughness,
+            );
+            for wi in [
+                up(),
+                Vector {
+                    x: 0.9,
+                    y: 0.0,
+                    z: 0.3,
+                },
+            ] {
+                let (sampled, uniform, pdf) = estimates(&cloth, wi, 200_000);
+                assert!(
+                    (sampled - uniform).abs() < 0.03 * uniform,
+                    "{roughness} {sampled} {uniform}"
+                );
+                // fibers lying flat reflect many samples below the surface
+                assert!(pdf > 0.45 && pdf < 1.01, "{roughness} {pdf}");
+            }
+        }
+    }
+
+    #[test]
+    fn reflects_at_most_everything() {
+        for roughness in [0.1, 0.5, 1.0] {
+            let cloth = SheenMaterial::new(
+                Box::new(Color::new(1.0, 1.0, 1.0)),
+                Color::new(1.0, 1.0, 1.0),
+                roughness,
+            );
+            for z in [1.0, 0.5, 0.1] {
+                let wi = Vector {
+                    x: f32::sqrt(1.0 - z * z),
+                    y: 0.0,
+                    z,
+                };
+                let (sampled, _, _) = estimates(&cloth, wi, 100_000);
+                assert!(sampled <= 1.01, "{roughness} {z} {sampled}");
+            }
+        }
+    }
+
+    #[test]
+    fn shines_at_grazing_angles() {
+        let cloth = SheenMaterial::new(
+            Box::new(Color::new(0.0, 0.0, 0.0)),
+            Color::new(1.0, 1.0, 1.0),
+            0.3,
+        );
+        let material: &dyn Material = &cloth;
+        let origin = Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let grazing = Vector {
+            x: 0.95,
+            y: 0.0,
+            z: 0.3,
+        }
+        .normalize();
+        let view = |wi: Vector| {
+            let si = SurfaceInteraction::new(origin, up(), 1.0, wi, (0.5, 0.5), material);
+            // light from straight above, divided by its cosine
+            material.bsdf_eval(&si, up()).radiance.g
+        };
+        assert!(
+            view(grazing) > 3.0 * view(up()),
+            "{} {}",
+            view(grazing),
+            view(up())
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use crate::colorspace::ColorSpace;
+use crate::integrator::Integrator;
+use crate::material::*;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::Color;
+use crate::spectrum::*;
+
+use rand::Rng;
+
+/// Path tracer carrying four hero wavelengths per path instead of RGB.
+///
+/// Colors of materials and lights are upsampled to spectra, dispersive
+/// materials bend each path by its hero wavelength. Radiance is returned
+/// through CIE XYZ in the working space of the scene, so the integrator
+/// renders into any `Sensor`.
+pub struct SpectralPathIntegrator {
+    max_bounce: usize,
+    russian_roulette: usize,
+}
+
+impl SpectralPathIntegrator {
+    pub fn new(max_bounce: usize, russian_roulette: usize) -> SpectralPathIntegrator {
+        SpectralPathIntegrator {
+            max_bounce,
+            russian_roulette,
+        }
+    }
+
+    /// Radiance along `ray` at the given wavelengths, which dispersion may terminate.
+    pub fn trace(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        wavelengths: &mut Wavelengths,
+        sampler: &mut Sampler,
+    ) -> SampledSpectrum {
+        // upsampling expects sRGB primaries
+        let to_srgb = scene.color_space.matrix_to(ColorSpace::Srgb);
+        let spectrum = |color: Color, wavelengths: &Wavelengths| {
+            SampledSpectrum::from_rgb(to_srgb.apply(color), wavelengths)
+        };
+        let mut throughput = SampledSpectrum::constant(1.0);
+        let mut radiance = SampledSpectrum::constant(0.0);
+
+        let mut ray = Ray {
+            origin: ray.origin,
+            direction: ray.direction,
+        };
+        let mut specular = true;
+
+        for bounce in 0..self.max_bounce {
+            let Some(mut si) = scene.closest_hit(&ray) else {
+                let background = spectrum(scene.background(ray.direction), wavelengths);
+                radiance = radiance + throughput * background;
+                break;
+            };
+            si.wavelength = Some(wavelengths.hero());
+
+            if let Some(light) = si.emitter {
+                if specular {
+                    let emitted = spectrum(light.emitted(&si), wavelengths);
+                    radiance = radiance + throughput * emitted;
+                }
+            }
+
+            if !si.material.is_delta_reflector() {
+                for light in scene.lights.iter() {
+                    let light_sample = light.sample(si.position, sampler);
+                    let wo = (light_sample.position - si.position).normalize();
+                    let dist = norm(light_sample.position - si.position);
+                    let shadow_si = scene.closest_hit(&Ray {
+                        origin: si.position + 1e-3 * wo,
+                        direction: wo,
+                    });
+                    if let Some(si) = shadow_si {
+                        if si.t < dist - 2e-3 {
+                            continue;
+                        }
+                    }
+
+                    let f = si.material.bsdf_eval(&si, wo).radiance;
+                    let contribution = spectrum(f * light_sample.radiance, wavelengths);
+                    radiance = radiance + throughput * contribution;
+                }
+            }
+
+            // the sampled direction is only right for the hero from here on
+            if si.material.is_dispersive() {
+                wavelengths.terminate_secondary();
+            }
+
+            let ScatterSample {
+                wo,
+                radiance: f,
+                pdf,
+                delta,
+            } = si.material.bsdf_scatter(&si, sampler);
+
+            throughput = (1.0 / pdf) * throughput * spectrum(f, wavelengths);
+            specular = delta;
+
+            ray.origin = si.position + 1e-3 * wo;
+            ray.direction = wo;
+
+            if bounce > self.russian_roulette {
+                let p = throughput.max();
+                if sampler.gen::<f32>() > p {
+                    break;
+                }
+                throughput = (1.0 / p) * throughput;
+            }
+        }
+
+        radiance
+    }
+}
+
+impl Integrator for SpectralPathIntegrator {
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        let mut wavelengths = Wavelengths::sample(sampler.gen());
+        let radiance = self.trace(ray, scene, &mut wavelengths, sampler);
+        radiance.to_rgb(&wavelengths, scene.color_space)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emitter::PointLight;
+    use crate::integrator::PathIntegrator;
+
+    #[test]
+    fn matches_rgb_on_gray_scene() {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(0.5, 0.5, 0.5);
+        scene.add_shape(Box::new(Sphere::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: -3.0,
+            },
+            1.0,
+            Box::new(DiffuseMaterial {
+                albedo: Box::new(Color::new(0.6, 0.6, 0.6)),
+            }),
+        )));
+        scene.add_light(Box::new(PointLight::new(
+            Point {
+                x: 1.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            1.0,
+        )));
+
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.1,
+                y: 0.1,
+                z: -1.0,
+            }
+            .normalize(),
+        };
+        let n = 4000;
+        let mean = |integrator: &dyn Integrator| {
+            (0..n).fold(Color::new(0.0, 0.0, 0.0), |sum, k| {
+                let mut sampler = Sampler::new(3, k);
+                sum + (1.0 / n as f32) * integrator.sample_radiance(&ray, &scene, &mut sampler)
+            })
+        };
+        let rgb = mean(&PathIntegrator::new(4, 2));
+        let spectral = mean(&SpectralPathIntegrator::new(4, 2));
+        for (a, b) in [
+            (rgb.r, spectral.r),
+            (rgb.g, spectral.g),
+            (rgb.b, spectral.b),
+        ] {
+            assert!((a - b).abs() < 0.05 * a, "{rgb:?} {spectral:?}");
+        }
+    }
+
+    #[test]
+    fn glass_disperses_hero() {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 1.0, 1.0);
+        let glass = Dispersion::Cauchy { a: 1.5, b: 0.05 };
+        scene.add_shape(Box::new(Sphere::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: -3.0,
+            },
+            1.0,
+            Box::new(DielectricMaterial::with_dispersion(glass)),
+        )));
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.2,
+                y: 0.0,
+                z: -1.0,
+            }
+            .normalize(),
+        };
+
+        // blue light bends further towards the normal than red
+        let refracted = |lambda: f32| {
+            let mut si = scene.closest_hit(&ray).unwrap();
+            si.wavelength = Some(lambda);
+            let mut sampler = Sampler::new(0, 0);
+            loop {
+                let wo = si.material.bsdf_sample(&si, &mut sampler);
+                if dot(wo, si.normal) < 0.0 {
+                    return dot(wo, -si.normal);
+                }
+            }
+        };
+        assert!(refracted(450.0) > refracted(650.0) + 1e-4);
+
+        let integrator = SpectralPathIntegrator::new(8, 8);
+        let mut wavelengths = Wavelengths::sample(0.5);
+        let mut sampler = Sampler::new(0, 0);
+        let radiance = integrator.trace(&ray, &scene, &mut wavelengths, &mut sampler);
+        assert_eq!(wavelengths.pdf[1..], [0.0; N_WAVELENGTHS - 1]);
+        assert!(radiance.0[0] > 0.0);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::colorspace::ColorSpace;
+use crate::sensor::Color;
+
+use std::ops::{Add, Mul};
+
+/// Number of wavelengths carried by a spectral path.
+pub const N_WAVELENGTHS: usize = 4;
+
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+
+/// Wavelengths in nanometers traced together by one path, the first one is the hero.
+#[derive(Clone, Copy, Debug)]
+pub struct Wavelengths {
+    pub lambda: [f32; N_WAVELENGTHS],
+    pub pdf: [f32; N_WAVELENGTHS],
+}
+
+/// Values of a spectrum at the wavelengths of a path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampledSpectrum(pub [f32; N_WAVELENGTHS]);
+
+/// Density that favors wavelengths the eye is sensitive to.
+fn visible_pdf(lambda: f32) -> f32 {
+    match (LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        true => 0.003_939_804 / f32::powi(f32::cosh(0.0072 * (lambda - 538.0)), 2),
+        false => 0.0,
+    }
+}
+
+fn sample_visible(u: f32) -> f32 {
+    538.0 - 138.888_89 * f32::atanh(0.856_910_6 - 1.827_502 * u)
+}
+
+impl Wavelengths {
+    /// Hero wavelength from `u`, the others at equally rotated offsets.
+    pub fn sample(u: f32) -> Wavelengths {
+        let mut lambda = [0.0; N_WAVELENGTHS];
+        let mut pdf = [0.0; N_WAVELENGTHS];
+        for i in 0..N_WAVELENGTHS {
+            let u = (u + i as f32 / N_WAVELENGTHS as f32).fract();
+            lambda[i] = f32::clamp(sample_visible(u), LAMBDA_MIN, LAMBDA_MAX);
+            pdf[i] = visible_pdf(lambda[i]);
+        }
+        Wavelengths { lambda, pdf }
+    }
+
+    pub fn hero(&self) -> f32 {
+        self.lambda[0]
+    }
+
+    /// Keeps only the hero, e.g. after refraction sent each wavelength elsewhere.
+    pub fn terminate_secondary(&mut self) {
+        if self.pdf[1..].iter().all(|&pdf| pdf == 0.0) {
+            return;
+        }
+        self.pdf[1..].fill(0.0);
+        self.pdf[0] /= N_WAVELENGTHS as f32;
+    }
+}
+
+impl SampledSpectrum {
+    pub fn constant(v: f32) -> SampledSpectrum {
+        SampledSpectrum([v; N_WAVELENGTHS])
+    }
+
+    /// Reflectance spectrum of a linear RGB color at the given wavelengths.
+    pub fn from_rgb(color: Color, wavelengths: &Wavelengths) -> SampledSpectrum {
+        SampledSpectrum(
+            wavelengths
+                .lambda
+                .map(|lambda| rgb_to_spectrum(color, lambda)),
+        )
+    }
+
+    pub fn max(&self) -> f32 {
+        self.0.iter().copied().fold(0.0, f32::max)
+    }
+
+    /// Monte Carlo estimate of the CIE XYZ color of the spectrum.
+    pub fn to_xyz(&self, wavelengths: &Wavelengths) -> [f32; 3] {
+        let mut xyz = [0.0; 3];
+        for i in 0..N_WAVELENGTHS {
+            if wavelengths.pdf[i] == 0.0 {
+                continue;
+            }
+            let w = self.0[i] / (wavelengths.pdf[i] * N_WAVELENGTHS as f32);
+            let (x, y, z) = cie_xyz(wavelengths.lambda[i]);
+            xyz[0] += w * x;
+            xyz[1] += w * y;
+            xyz[2] += w * z;
+        }
+        xyz
+    }
+
+    /// Linear color of the spectrum in `space`, an equal-energy spectrum is white.
+    pub fn to_rgb(&self, wavelengths: &Wavelengths, space: ColorSpace) -> Color {
+        let [x, y, z] = self.to_xyz(wavelengths);
+        space.matrix_from_spectral_xyz().apply(Color::new(x, y, z))
+    }
+}
+
+impl Add for SampledSpectrum {
+    type Output = SampledSpectrum;
+    fn add(self, rhs: SampledSpectrum) -> SampledSpectrum {
+        SampledSpectrum(std::array::from_fn(|i| self.0[i] + rhs.0[i]))
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = SampledSpectrum;
+    fn mul(self, rhs: SampledSpectrum) -> SampledSpectrum {
+        SampledSpectrum(std::array::from_fn(|i| self.0[i] * rhs.0[i]))
+    }
+}
+
+impl Mul<SampledSpectrum> for f32 {
+    type Output = SampledSpectrum;
+    fn mul(self, rhs: SampledSpectrum) -> SampledSpectrum {
+        SampledSpectrum(rhs.0.map(|v| self * v))
+    }
+}
+
+/// Piecewise Gaussian used by the CIE matching function fits.
+fn lobe(lambda: f32, mu: f32, sigma_below: f32, sigma_above: f32) -> f32 {
+    let sigma = match lambda < mu {
+        true => sigma_below,
+        false => sigma_above,
+    };
+    f32::exp(-0.5 * f32::powi((lambda - mu) / sigma, 2))
+}
+
+/// CIE 1931 matching functions, each normalized to integrate to one.
+///
+/// Uses the multi-lobe fit of Wyman, Sloan and Shirley, so an equal-energy
+/// spectrum of one maps to XYZ = (1, 1, 1).
+pub fn cie_xyz(lambda: f32) -> (f32, f32, f32) {
+    let x = 1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * lobe(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8);
+    (x / 106.765_82, y / 106.922_07, z / 106.875)
+}
+
+/// Basis spectra of Smits' RGB upsampling, sampled at ten equally spaced
+/// wavelengths from 380 to 720 nm.
+const SMITS_WHITE: [f32; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f32; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f32; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f32; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f32; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f32; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f32; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+fn smits(basis: &[f32; 10], lambda: f32) -> f32 {
+    let x = f32::clamp((lambda - 380.0) / (720.0 - 380.0) * 9.0, 0.0, 9.0);
+    let i = usize::min(x as usize, 8);
+    let t = x - i as f32;
+    (1.0 - t) * basis[i] + t * basis[i + 1]
+}
+
+/// Smits' smooth spectrum for an RGB color, evaluated at `lambda` in nanometers.
+///
+/// White, the secondary and the primary colors are mixed so that the
+/// smallest channel goes to white, the middle one to a secondary color and
+/// the rest to a primary.
+pub fn rgb_to_spectrum(color: Color, lambda: f32) -> f32 {
+    let Color { r, g, b } = color;
+    let s = |basis: &[f32; 10]| smits(basis, lambda);
+    if r <= g && r <= b {
+        r * s(&SMITS_WHITE)
+            + match g <= b {
+                true => (g - r) * s(&SMITS_CYAN) + (b - g) * s(&SMITS_BLUE),
+                false => (b - r) * s(&SMITS_CYAN) + (g - b) * s(&SMITS_GREEN),
+            }
+    } else if g <= r && g <= b {
+        g * s(&SMITS_WHITE)
+            + match r <= b {
+                true => (r - g) * s(&SMITS_MAGENTA) + (b - r) * s(&SMITS_BLUE),
+                false => (b - g) * s(&SMITS_MAGENTA) + (r - b) * s(&SMITS_RED),
+            }
+    } else {
+        b * s(&SMITS_WHITE)
+            + match r <= g {
+                true => (r - b) * s(&SMITS_YELLOW) + (g - r) * s(&SMITS_GREEN),
+                false => (g - b) * s(&SMITS_YELLOW) + (r - g) * s(&SMITS_RED),
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Color of the upsampled spectrum of `color`, integrated on a fine grid.
+    fn round_trip(color: Color) -> Color {
+        let n = 4700;
+        let step = (LAMBDA_MAX - LAMBDA_MIN) / n as f32;
+        let mut xyz = [0.0; 3];
+        for i in 0..n {
+            let lambda = LAMBDA_MIN + (i as f32 + 0.5) * step;
+            let (x, y, z) = cie_xyz(lambda);
+            let v = step * rgb_to_spectrum(color, lambda);
+            xyz = [xyz[0] + v * x, xyz[1] + v * y, xyz[2] + v * z];
+        }
+        let [x, y, z] = xyz;
+        ColorSpace::Srgb
+            .matrix_from_spectral_xyz()
+            .apply(Color::new(x, y, z))
+    }
+
+    #[test]
+    fn upsampling_round_trips() {
+        let white = round_trip(Color::new(0.5, 0.5, 0.5));
+        for c in [white.r, white.g, white.b] {
+            assert!((c - 0.5).abs() < 0.01, "{white:?}");
+        }
+
+        // Smits' basis is not fit to sRGB primaries, hues survive but saturation drops a little
+        let red = round_trip(Color::new(0.8, 0.1, 0.1));
+        assert!(red.r > 0.6 && red.g < 0.2 && red.b < 0.2, "{red:?}");
+        let teal = round_trip(Color::new(0.1, 0.5, 0.5));
+        assert!(teal.r < 0.2 && (teal.g - teal.b).abs() < 0.15, "{teal:?}");
+    }
+
+    #[test]
+    fn samples_visible_wavelengths() {
+        // the density integrates to one over the sampled range
+        let n = 10000;
+        let step = (LAMBDA_MAX - LAMBDA_MIN) / n as f32;
+        let total: f32 = (0..n)
+            .map(|i| step * visible_pdf(LAMBDA_MIN + (i as f32 + 0.5) * step))
+            .sum();
+        assert!((total - 1.0).abs() < 1e-2);
+
+        let mut wavelengths = Wavelengths::sample(0.3);
+        assert!(wavelengths
+            .lambda
+            .iter()
+            .all(|l| (LAMBDA_MIN..=LAMBDA_MAX).contains(l)));
+        assert!(wavelengths.pdf.iter().all(|&pdf| pdf > 0.0));
+
+        // a white spectrum keeps its expected color when only the hero survives
+        let white = SampledSpectrum::constant(1.0);
+        let mean = |terminate: bool| {
+            let n = 20000;
+            (0..n).fold([0.0; 3], |sum, i| {
+                let mut wavelengths = Wavelengths::sample((i as f32 + 0.5) / n as f32);
+                if terminate {
+                    wavelengths.terminate_secondary();
+                }
+                let xyz = white.to_xyz(&wavelengths);
+                [0, 1, 2].map(|k| sum[k] + xyz[k] / n as f32)
+            })
+        };
+        for xyz in [mean(false), mean(true)] {
+            assert!(xyz.iter().all(|v| (v - 1.0).abs() < 0.02), "{xyz:?}");
+        }
+
+        wavelengths.terminate_secondary();
+        assert_eq!(wavelengths.pdf[1..], [0.0; N_WAVELENGTHS - 1]);
+    }
+}
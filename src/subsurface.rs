@@ -0,0 +1,399 @@
+use crate::material::*;
+use crate::math::*;
+use crate::medium::HenyeyGreenstein;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::Color;
+use crate::texture::Texture;
+
+use rand::Rng;
+
+/// Translucent material like skin, wax or marble, scattering light inside the shape it covers.
+///
+/// `PathIntegrator` reflects light off the surface by the Fresnel equations
+/// for `ior`, lets the rest enter through a diffuse interface and follows a
+/// random walk through an isotropic medium until light leaves again, then
+/// continues from the exit point as if from a white diffuse surface. Shapes
+/// must be closed. `albedo` is the color of the surface seen from afar and
+/// `mfp` how far light travels inside before scattering, per channel, so
+/// red light bleeds further in skin. Leaving the shape, light is reflected
+/// back inside by the Fresnel equations as well.
+///
+/// Other integrators see a diffuse surface of color `albedo`.
+pub struct SubsurfaceMaterial {
+    pub albedo: Box<dyn Texture>,
+    /// Mean free path in scene units per color channel.
+    pub mfp: Color,
+    /// Index of refraction inside relative to outside.
+    pub ior: f32,
+}
+
+/// Diffuse lobe through which a random walk leaves the shape.
+struct SubsurfaceExit;
+
+static EXIT: SubsurfaceExit = SubsurfaceExit;
+
+/// Mirror reflecting light off the surface instead of letting it in.
+struct SubsurfaceEntry;
+
+static ENTRY: SubsurfaceEntry = SubsurfaceEntry;
+
+/// Other shapes touched by a walk before it finds its way out again.
+const MAX_CROSSINGS: usize = 16;
+
+/// Scattering events after which a walk counts as absorbed.
+const MAX_STEPS: usize = 256;
+/// Scattering events after which walks are ended by Russian roulette.
+const ROULETTE_STEPS: usize = 8;
+
+impl SubsurfaceMaterial {
+    pub fn new(albedo: Box<dyn Texture>, mfp: Color, ior: f32) -> SubsurfaceMaterial {
+        SubsurfaceMaterial { albedo, mfp, ior }
+    }
+
+    /// Extinction coefficient and single scattering albedo of the medium inside
+    /// that gives the surface `albedo`.
+    ///
+    /// Uses the fit of Cycles' random walk for a semi-infinite slab, which also
+    /// stretches the free path such that `mfp` matches the visible blur.
+    fn coefficients(&self, albedo: Color) -> (Color, Color) {
+        let channel = |a: f32, mfp: f32| {
+            let a = f32::clamp(a, 0.0, 1.0);
+            let single = 1.0 - f32::exp(a * (-5.09406 + a * (2.61188 - a * 4.31805)));
+            let stretch = 1.9 - a + 3.5 * (a - 0.8) * (a - 0.8);
+            (1.0 / f32::max(mfp * stretch, 1e-6), single)
+        };
+        let (r, g, b) = (
+            channel(albedo.r, self.mfp.r),
+            channel(albedo.g, self.mfp.g),
+            channel(albedo.b, self.mfp.b),
+        );
+        (Color::new(r.0, g.0, b.0), Color::new(r.1, g.1, b.1))
+    }
+
+    /// Follows light from the surface at `si` through the inside of its shape.
+    ///
+    /// Returns where the light leaves, shaded by a white diffuse lobe facing
+    /// outwards, and the throughput weight of the walk. Light reflected at the
+    /// surface leaves right at `si`, shaded by a white mirror. `None` if the
+    /// light is absorbed.
+    pub fn random_walk<'a>(
+        &self,
+        scene: &'a Scene,
+        si: &SurfaceInteraction<'a>,
+        sampler: &mut Sampler,
+    ) -> Option<(SurfaceInteraction<'a>, Color)> {
+        let (sigma_t, single) = self.coefficients(self.albedo.eval(si));
+        let phase = HenyeyGreenstein::new(0.0);
+        let mut weight = Color::new(1.0, 1.0, 1.0);
+
+        // enter through a diffuse interface on the side light arrives from
+        let inward = match dot(si.normal, si.wi) > 0.0 {
+            true => -si.normal,
+            false => si.normal,
+        };
+        let cos = -dot(inward, si.wi);
+        if sampler.gen::<f32>() < fresnel_dielectric(cos, self.ior) {
+            let mut reflected = si.clone();
+            reflected.normal = -inward;
+            reflected.material = &ENTRY;
+            reflected.emitter = None;
+            reflected.interior = None;
+            return Some((reflected, weight));
+        }
+        let (u, v, w) = coordinate_system(inward);
+        let r = f32::sqrt(sampler.gen::<f32>());
+        let phi = 2.0 * std::f32::consts::PI * sampler.gen::<f32>();
+        let cos = f32::sqrt(f32::max(0.0, 1.0 - r * r));
+        let mut ray = Ray {
+            origin: si.position + 1e-3 * inward,
+            direction: r * f32::cos(phi) * u + r * f32::sin(phi) * v + cos * w,
+        };
+
+        for step in 0..MAX_STEPS {
+            // distances are sampled in one channel picked uniformly, weighted by
+            // the average density over all channels
+            let channel = [sigma_t.r, sigma_t.g, sigma_t.b][sampler.gen_range(0..3)];
+            let t = -f32::ln(1.0 - sampler.gen::<f32>()) / channel;
+            let boundary = closest_boundary(scene, &ray, si.shape_id);
+            let transmittance = |t: f32| {
+                Color::new(
+                    f32::exp(-sigma_t.r * t),
+                    f32::exp(-sigma_t.g * t),
+                    f32::exp(-sigma_t.b * t),
+                )
+            };
+
+            match boundary {
+                Some(hit) if hit.t <= t => {
+                    let tr = transmittance(hit.t);
+                    weight = (1.0 / tr.average()) * weight * tr;
+                    let outward = match dot(hit.normal, ray.direction) > 0.0 {
+                        true => hit.normal,
+                        false => -hit.normal,
+                    };
+                    let cos = dot(outward, ray.direction);
+                    if sampler.gen::<f32>() >= fresnel_dielectric(cos, 1.0 / self.ior) {
+                        let mut exit = hit;
+                        exit.normal = outward;
+                        exit.wi = outward;
+                        exit.material = &EXIT;
+                        exit.emitter = None;
+                        exit.interior = None;
+                        return Some((exit, weight));
+                    }
+                    ray = Ray {
+                        origin: hit.position + (-1e-3) * outward,
+                        direction: reflect(ray.direction, outward),
+                    };
+                }
+                // a walk that escapes through a hole in the shape is lost
+                None => return None,
+                Some(_) => {
+                    let tr = transmittance(t);
+                    let pdf = (sigma_t * tr).average();
+                    weight = (1.0 / pdf) * weight * single * sigma_t * tr;
+                    let position = ray.origin + t * ray.direction;
+                    ray = Ray {
+                        origin: position,
+                        direction: phase.sample(-ray.direction, sampler),
+                    };
+                }
+            }
+
+            if step >= ROULETTE_STEPS {
+                let p = f32::min(1.0, f32::max(weight.r, f32::max(weight.g, weight.b)));
+                if sampler.gen::<f32>() >= p {
+                    return None;
+                }
+                weight = (1.0 / p) * weight;
+            }
+        }
+        None
+    }
+}
+
+/// Closest point where `ray` leaves the shape `shape_id`, passing through other shapes.
+///
+/// Shapes inside or touching a translucent one do not end its walks.
+fn closest_boundary<'a>(
+    scene: &'a Scene,
+    ray: &Ray,
+    shape_id: usize,
+) -> Option<SurfaceInteraction<'a>> {
+    let mut origin = ray.origin;
+    let mut travelled = 0.0;
+    for _ in 0..MAX_CROSSINGS {
+        let mut hit = scene.closest_hit(&Ray {
+            origin,
+            direction: ray.direction,
+        })?;
+        if hit.shape_id == shape_id {
+            hit.t += travelled;
+            return Some(hit);
+        }
+        travelled += hit.t + 1e-3;
+        origin = hit.position + 1e-3 * ray.direction;
+    }
+    None
+}
+
+impl Material for SubsurfaceMaterial {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let n = si.normal;
+        let diffuse =
+            (1.0 / std::f32::consts::PI) * f32::max(dot(n, wo), 0.0) * self.albedo.eval(si);
+
+        BsdfSample {
+            radiance: diffuse,
+            pdf: self.bsdf_pdf(si, wo),
+        }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        cosine_weighted_hemisphere_sample(si, sampler)
+    }
+
+    fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        dot(si.normal, wo) / std::f32::consts::PI
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self, si: &SurfaceInteraction) -> Color {
+        self.albedo.eval(si)
+    }
+
+    fn subsurface(&self) -> Option<&SubsurfaceMaterial> {
+        Some(self)
+    }
+}
+
+impl Material for SubsurfaceExit {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let cos = f32::max(dot(si.normal, wo), 0.0);
+        BsdfSample {
+            radiance: Color::new(cos, cos, cos) / std::f32::consts::PI,
+            pdf: self.bsdf_pdf(si, wo),
+        }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, sampler: &mut Sampler) -> Vector {
+        cosine_weighted_hemisphere_sample(si, sampler)
+    }
+
+    fn bsdf_pdf(&self, si: &SurfaceInteraction, wo: Vector) -> f32 {
+        dot(si.normal, wo) / std::f32::consts::PI
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self, _si: &SurfaceInteraction) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+}
+
+impl Material for SubsurfaceEntry {
+    fn bsdf_eval(&self, si: &SurfaceInteraction, wo: Vector) -> BsdfSample {
+        let mirrored = -reflect(si.wi, si.normal);
+        let radiance = match dot(wo.normalize(), mirrored) > 1.0 - DELTA_TOLERANCE {
+            true => Color::new(1.0, 1.0, 1.0),
+            false => Color::new(0.0, 0.0, 0.0),
+        };
+        BsdfSample {
+            radiance,
+            pdf: self.bsdf_pdf(si, wo),
+        }
+    }
+
+    fn bsdf_sample(&self, si: &SurfaceInteraction, _sampler: &mut Sampler) -> Vector {
+        -reflect(si.wi, si.normal)
+    }
+
+    fn bsdf_pdf(&self, _si: &SurfaceInteraction, _wo: Vector) -> f32 {
+        1.0
+    }
+
+    fn is_delta_reflector(&self) -> bool {
+        true
+    }
+
+    fn albedo(&self, _si: &SurfaceInteraction) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+
+    fn delta_lobes(&self, si: &SurfaceInteraction) -> Vec<(Vector, Color)> {
+        vec![(-reflect(si.wi, si.normal), Color::new(1.0, 1.0, 1.0))]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emitter::PointLight;
+    use crate::integrator::tests::ball;
+    use crate::integrator::{Integrator, PathIntegrator};
+
+    fn translucent(albedo: f32, mfp: f32, ior: f32) -> Box<SubsurfaceMaterial> {
+        Box::new(SubsurfaceMaterial::new(
+            Box::new(Color::new(albedo, albedo, albedo)),
+            Color::new(mfp, mfp, mfp),
+            ior,
+        ))
+    }
+
+    fn mean_radiance(scene: &Scene, n: u64) -> f32 {
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        };
+        let path = PathIntegrator::new(64, 64);
+        let sum: f32 = (0..n)
+            .map(|k| path.sample_radiance(&ray, scene, &mut Sampler::new(0, k)).g)
+            .sum();
+        sum / n as f32
+    }
+
+    #[test]
+    fn reaches_albedo_in_furnace() {
+        for (albedo, ior, expected) in [(0.3, 1.0, 0.3), (0.8, 1.0, 0.8), (1.0, 1.4, 1.0)] {
+            let mut scene = ball(translucent(albedo, 0.05, ior));
+            scene.background_color = Color::new(1.0, 1.0, 1.0);
+            let mean = mean_radiance(&scene, 10_000);
+            assert!(
+                (mean - expected).abs() < 0.2 * expected,
+                "{albedo} {ior} {mean}"
+            );
+        }
+    }
+
+    #[test]
+    fn reflects_at_entry() {
+        // a black inside only shows what the surface reflects, 4% head on for ior 1.5
+        for (ior, expected) in [(1.0, 0.0), (1.5, 0.04)] {
+            let mut scene = ball(translucent(0.0, 0.001, ior));
+            scene.background_color = Color::new(1.0, 1.0, 1.0);
+            let mean = mean_radiance(&scene, 10_000);
+            assert!((mean - expected).abs() < 0.01, "{ior} {mean}");
+        }
+    }
+
+    #[test]
+    fn walks_past_shapes_inside() {
+        let furnace = |inner: bool| {
+            let mut scene = ball(translucent(0.8, 0.5, 1.0));
+            if inner {
+                scene.add_shape(Box::new(Sphere::new(
+                    Point {
+                        x: 0.0,
+                        y: 0.0,
+                        z: -3.0,
+                    },
+                    0.9,
+                    Box::new(BlackBody {}),
+                )));
+            }
+            scene.background_color = Color::new(1.0, 1.0, 1.0);
+            mean_radiance(&scene, 10_000)
+        };
+        // walks leave through the surface of the ball, not the sphere inside it
+        let (plain, inner) = (furnace(false), furnace(true));
+        assert!((plain - inner).abs() < 0.02, "{plain} {inner}");
+    }
+
+    #[test]
+    fn bleeds_light_through() {
+        let lit_from_behind = |material: Box<dyn Material>| {
+            let mut scene = ball(material);
+            scene.background_color = Color::new(0.0, 0.0, 0.0);
+            scene.add_light(Box::new(PointLight::new(
+                Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -5.0,
+                },
+                1.0,
+            )));
+            mean_radiance(&scene, 4_000)
+        };
+        let diffuse = lit_from_behind(Box::new(DiffuseMaterial {
+            albedo: Box::new(Color::new(0.8, 0.8, 0.8)),
+        }));
+        assert_eq!(diffuse, 0.0);
+        let thick = lit_from_behind(translucent(0.8, 0.05, 1.4));
+        let thin = lit_from_behind(translucent(0.8, 0.5, 1.4));
+        assert!(thin > thick && thin > 0.01, "{thin} {thick}");
+    }
+}
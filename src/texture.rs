@@ -0,0 +1,183 @@
+use image::{DynamicImage, ImageResult};
+
+use crate::colorspace::*;
+use crate::math::*;
+use crate::scene::SurfaceInteraction;
+use crate::sensor::Color;
+
+use std::f32::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Spatially varying color looked up at a surface point.
+pub trait Texture: Send + Sync {
+    fn eval(&self, si: &SurfaceInteraction) -> Color;
+}
+
+/// A plain color is a constant texture.
+impl Texture for Color {
+    fn eval(&self, _si: &SurfaceInteraction) -> Color {
+        *self
+    }
+}
+
+/// Color interpolated from the vertices of a mesh.
+///
+/// Surfaces without vertex colors get `fallback`.
+pub struct VertexColor {
+    pub fallback: Color,
+}
+
+impl VertexColor {
+    pub fn new(fallback: Color) -> VertexColor {
+        VertexColor { fallback }
+    }
+}
+
+impl Texture for VertexColor {
+    fn eval(&self, si: &SurfaceInteraction) -> Color {
+        si.color.unwrap_or(self.fallback)
+    }
+}
+
+/// Bilinearly filtered image, repeated outside of [0, 1]^2.
+///
+/// `uv = (0, 0)` is the top-left corner of the image, as in glTF.
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    texels: Vec<Color>,
+    /// Space of the linear texels, lookups return them unconverted.
+    color_space: ColorSpace,
+}
+
+impl ImageTexture {
+    /// Texture from linear texels in row-major order, top row first.
+    pub fn new(width: usize, height: usize, texels: Vec<Color>) -> ImageTexture {
+        ImageTexture {
+            width,
+            height,
+            texels,
+            color_space: ColorSpace::Srgb,
+        }
+    }
+
+    /// Tags the texels as being in `space` without changing them.
+    pub fn with_color_space(mut self, space: ColorSpace) -> ImageTexture {
+        self.color_space = space;
+        self
+    }
+
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    /// Converts the texels into `space`, loaders pass the working space of the scene.
+    pub fn into_color_space(mut self, space: ColorSpace) -> ImageTexture {
+        let matrix = self.color_space.matrix_to(space);
+        for texel in self.texels.iter_mut() {
+            *texel = matrix.apply(*texel);
+        }
+        self.color_space = space;
+        self
+    }
+
+    /// Integer images are taken to be sRGB encoded, float images to be linear.
+    ///
+    /// Both are tagged with sRGB primaries, use `with_color_space` for others.
+    pub fn from_image(image: DynamicImage) -> ImageTexture {
+        let transfer = match image {
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                TransferFunction::Linear
+            }
+            _ => TransferFunction::Srgb,
+        };
+        ImageTexture::from_encoded(image, transfer)
+    }
+
+    /// Image whose values are encoded with `transfer`.
+    pub fn from_encoded(image: DynamicImage, transfer: TransferFunction) -> ImageTexture {
+        let rgb = image.to_rgb32f();
+        let decode = |v: f32| transfer.decode(v);
+        let texels = rgb
+            .pixels()
+            .map(|p| Color::new(decode(p[0]), decode(p[1]), decode(p[2])))
+            .collect();
+        ImageTexture::new(rgb.width() as usize, rgb.height() as usize, texels)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> ImageResult<ImageTexture> {
+        Ok(ImageTexture::from_image(image::open(path)?))
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Color {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        self.texels[y * self.width + x]
+    }
+
+    pub fn lookup(&self, (u, v): (f32, f32)) -> Color {
+        if self.texels.is_empty() {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let x = u * self.width as f32 - 0.5;
+        let y = v * self.height as f32 - 0.5;
+        let (x0, y0) = (f32::floor(x), f32::floor(y));
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        (1.0 - fy) * ((1.0 - fx) * self.texel(x0, y0) + fx * self.texel(x0 + 1, y0))
+            + fy * ((1.0 - fx) * self.texel(x0, y0 + 1) + fx * self.texel(x0 + 1, y0 + 1))
+    }
+}
+
+impl Texture for ImageTexture {
+    fn eval(&self, si: &SurfaceInteraction) -> Color {
+        self.lookup(si.uv)
+    }
+}
+
+/// Image wrapped around the scene in latitude-longitude layout, +z pointing up.
+pub struct EnvironmentMap {
+    pub image: ImageTexture,
+    pub scale: Color,
+    to_local: Transform,
+}
+
+impl EnvironmentMap {
+    pub fn new(image: ImageTexture, scale: Color, to_world: Transform) -> EnvironmentMap {
+        EnvironmentMap {
+            image,
+            scale,
+            to_local: to_world.inverse(),
+        }
+    }
+
+    /// Radiance arriving from `direction`.
+    pub fn lookup(&self, direction: Vector) -> Color {
+        let d = self.to_local.vector(direction).normalize();
+        let phi = f32::atan2(d.y, d.x);
+        let phi = if phi < 0.0 { phi + 2.0 * PI } else { phi };
+        let theta = f32::acos(f32::clamp(d.z, -1.0, 1.0));
+        self.scale * self.image.lookup((phi / (2.0 * PI), theta / PI))
+    }
+}
+
+/// Product of two textures, e.g. a base color factor times an image.
+pub struct ScaledTexture {
+    pub scale: Color,
+    pub texture: Box<dyn Texture>,
+}
+
+impl Texture for ScaledTexture {
+    fn eval(&self, si: &SurfaceInteraction) -> Color {
+        self.scale * self.texture.eval(si)
+    }
+}
+
+/// Shared textures, e.g. an image used by several materials.
+impl<T: Texture + ?Sized> Texture for Arc<T> {
+    fn eval(&self, si: &SurfaceInteraction) -> Color {
+        self.as_ref().eval(si)
+    }
+}
@@ -0,0 +1,288 @@
+use crate::integrator::Integrator;
+use crate::material::*;
+use crate::math::*;
+use crate::medium::Medium;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::Color;
+
+use rand::Rng;
+
+/// Path tracer that also scatters inside participating media.
+///
+/// Distances in media are sampled by free-flight sampling, direct light is
+/// gathered at surfaces and medium scattering events alike, with shadow rays
+/// attenuated by the transmittance of every medium they cross.
+pub struct VolumetricPathIntegrator {
+    max_bounce: usize,
+    russian_roulette: usize,
+}
+
+impl VolumetricPathIntegrator {
+    pub fn new(max_bounce: usize, russian_roulette: usize) -> VolumetricPathIntegrator {
+        VolumetricPathIntegrator {
+            max_bounce,
+            russian_roulette,
+        }
+    }
+
+    /// Light arriving at `position` from all lights, weighted by `eval` of the light direction.
+    ///
+    /// Shadow rays start `offset` away from `position` in the medium returned by `medium`.
+    fn direct_light<'a>(
+        &self,
+        scene: &'a Scene,
+        position: Point,
+        offset: f32,
+        medium: impl Fn(Vector) -> Option<&'a dyn Medium>,
+        eval: impl Fn(Vector) -> Color,
+        sampler: &mut Sampler,
+    ) -> Color {
+        let mut le = Color::new(0.0, 0.0, 0.0);
+        for light in scene.lights.iter() {
+            let light_sample = light.sample(position, sampler);
+            let wo = (light_sample.position - position).normalize();
+            let f = eval(wo);
+            if f.is_black() {
+                continue;
+            }
+            let origin = position + offset * wo;
+            let tr = transmittance(scene, origin, light_sample.position, medium(wo), sampler);
+            le = le + f * tr * light_sample.radiance;
+        }
+        le
+    }
+}
+
+/// Medium a ray travelling along `direction` is in after crossing the surface of `si`.
+///
+/// Media do not nest: leaving a shape always returns to the scene medium.
+pub fn medium_after<'a>(
+    scene: &'a Scene,
+    si: &SurfaceInteraction<'a>,
+    direction: Vector,
+) -> Option<&'a dyn Medium> {
+    match dot(direction, si.normal) < 0.0 {
+        true => si.interior,
+        false => scene.medium.as_deref(),
+    }
+}
+
+/// Transmittance between `origin` and `target` when starting out in `medium`.
+///
+/// Null surfaces are crossed, any other surface blocks the segment.
+pub fn transmittance(
+    scene: &Scene,
+    origin: Point,
+    target: Point,
+    medium: Option<&dyn Medium>,
+    sampler: &mut Sampler,
+) -> Color {
+    let mut tr = Color::new(1.0, 1.0, 1.0);
+    let mut origin = origin;
+    let mut medium = medium;
+
+    loop {
+        let dist = norm(target - origin);
+        let ray = Ray {
+            origin,
+            direction: (target - origin).normalize(),
+        };
+        // the light itself may be hit at the end of the segment
+        let hit = scene.closest_hit(&ray).filter(|si| si.t < dist - 1e-3);
+        let segment = hit.as_ref().map_or(dist, |si| si.t);
+
+        if let Some(medium) = medium {
+            tr = tr * medium.transmittance(&ray, segment, sampler);
+        }
+
+        let Some(si) = hit else {
+            return tr;
+        };
+        if !si.material.is_null() {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        medium = medium_after(scene, &si, ray.direction);
+        origin = si.position + 1e-3 * ray.direction;
+    }
+}
+
+impl Integrator for VolumetricPathIntegrator {
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut color = Color::new(0.0, 0.0, 0.0);
+
+        let mut ray = Ray {
+            origin: ray.origin,
+            direction: ray.direction.normalize(),
+        };
+        // the camera is assumed to sit in the scene medium
+        let mut medium = scene.medium.as_deref();
+        let mut bounce = 0;
+        // emission is only gathered by hitting lights after camera rays and delta bounces
+        let mut specular = true;
+
+        while bounce < self.max_bounce {
+            let hit = scene.closest_hit(&ray);
+            let t_max = hit.as_ref().map_or(f32::INFINITY, |si| si.t);
+
+            let mut scattered = false;
+            if let Some(current) = medium {
+                let sample = current.sample_distance(&ray, t_max, sampler);
+                throughput = throughput * sample.weight;
+
+                if let Some(t) = sample.t {
+                    let position = ray.origin + t * ray.direction;
+                    let wi = -ray.direction;
+                    let phase = current.phase();
+
+                    let eval = |wo| {
+                        let p = phase.eval(wi, wo);
+                        Color::new(p, p, p)
+                    };
+                    let le = self.direct_light(scene, position, 0.0, |_| medium, eval, sampler);
+                    color = color + throughput * le;
+
+                    // phase function sampling is perfect, the weight stays unchanged
+                    ray = Ray {
+                        origin: position,
+                        direction: phase.sample(wi, sampler),
+                    };
+                    scattered = true;
+                    specular = false;
+                }
+            }
+
+            if !scattered {
+                let Some(si) = hit else {
+                    color = color + throughput * scene.background(ray.direction);
+                    break;
+                };
+
+                if si.material.is_null() {
+                    medium = medium_after(scene, &si, ray.direction);
+                    ray.origin = si.position + 1e-3 * ray.direction;
+                    continue;
+                }
+
+                if let Some(light) = si.emitter {
+                    if specular {
+                        color = color + throughput * light.emitted(&si);
+                    }
+                }
+
+                if !si.material.is_delta_reflector() {
+                    let le = self.direct_light(
+                        scene,
+                        si.position,
+                        1e-3,
+                        |wo| medium_after(scene, &si, wo),
+                        |wo| si.material.bsdf_eval(&si, wo).radiance,
+                        sampler,
+                    );
+                    color = color + throughput * le;
+                }
+
+                // compute new ray direction
+                let ScatterSample {
+                    wo,
+                    radiance,
+                    pdf,
+                    delta,
+                } = si.material.bsdf_scatter(&si, sampler);
+
+                throughput = (1.0 / pdf) * throughput * radiance;
+                specular = delta;
+                medium = medium_after(scene, &si, wo);
+
+                ray = Ray {
+                    origin: si.position + 1e-3 * wo,
+                    direction: wo.normalize(),
+                };
+            }
+
+            if bounce > self.russian_roulette {
+                let p = f32::max(throughput.r, f32::max(throughput.g, throughput.b));
+                if sampler.gen::<f32>() > p {
+                    break;
+                }
+                throughput = (1.0 / p) * throughput;
+            }
+            bounce += 1;
+        }
+
+        color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::medium::HomogeneousMedium;
+
+    fn absorbing_sphere_scene(sigma_a: f32) -> Scene {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 1.0, 1.0);
+        scene.add_shape_with_interior(
+            Box::new(Sphere::new(
+                Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -3.0,
+                },
+                1.0,
+                Box::new(NullMaterial {}),
+            )),
+            Box::new(HomogeneousMedium::new(
+                Color::new(sigma_a, sigma_a, sigma_a),
+                Color::new(0.0, 0.0, 0.0),
+                0.0,
+            )),
+        );
+        scene
+    }
+
+    #[test]
+    fn attenuates_shadow_rays() {
+        let scene = absorbing_sphere_scene(0.5);
+        let mut sampler = Sampler::new(0, 0);
+        let origin = Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let target = Point {
+            x: 0.0,
+            y: 0.0,
+            z: -6.0,
+        };
+        let tr = transmittance(&scene, origin, target, None, &mut sampler);
+        assert!((tr.r - f32::exp(-0.5 * 2.0)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn attenuates_camera_rays() {
+        let scene = absorbing_sphere_scene(0.5);
+        let integrator = VolumetricPathIntegrator::new(8, 4);
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        };
+        // free-flight sampling without scattering makes this a binary estimator
+        let n = 4000;
+        let mean = (0..n)
+            .map(|k| integrator.sample_radiance(&ray, &scene, &mut Sampler::new(1, k)))
+            .fold(0.0, |sum, c| sum + c.g)
+            / n as f32;
+        assert!((mean - f32::exp(-0.5 * 2.0)).abs() < 0.03);
+    }
+}
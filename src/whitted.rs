@@ -0,0 +1,177 @@
+use crate::integrator::Integrator;
+use crate::math::*;
+use crate::sampler::Sampler;
+use crate::scene::*;
+use crate::sensor::Color;
+
+/// Classic Whitted ray tracer.
+///
+/// Every delta lobe spawns a ray, smooth lobes are shaded by `bsdf_eval`
+/// towards every point, spot and directional light that a shadow ray
+/// reaches. Nothing is sampled at random, so a single sample per pixel gives
+/// the final image. Area lights are only seen directly or in mirrors, and
+/// indirect diffuse light is ignored. Point and spot lights fall off like in
+/// `PathIntegrator`.
+pub struct WhittedIntegrator {
+    max_depth: usize,
+}
+
+impl WhittedIntegrator {
+    /// Traces paths of at most `max_depth` surfaces.
+    pub fn new(max_depth: usize) -> WhittedIntegrator {
+        WhittedIntegrator { max_depth }
+    }
+
+    fn trace(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler, depth: usize) -> Color {
+        let Some(si) = scene.closest_hit(ray) else {
+            return scene.background(ray.direction);
+        };
+        let mut color = match si.emitter {
+            Some(light) => light.emitted(&si),
+            None => Color::new(0.0, 0.0, 0.0),
+        };
+
+        if depth + 1 < self.max_depth {
+            for (wo, weight) in si.material.delta_lobes(&si) {
+                let reflected = Ray {
+                    origin: si.position + 1e-3 * wo,
+                    direction: wo,
+                };
+                color = color + weight * self.trace(&reflected, scene, sampler, depth + 1);
+            }
+        }
+        if si.material.is_delta_reflector() {
+            return color;
+        }
+
+        for light in scene.lights.iter().filter(|light| light.is_delta()) {
+            let light_sample = light.sample(si.position, sampler);
+            let wo = (light_sample.position - si.position).normalize();
+            let dist = norm(light_sample.position - si.position);
+            let shadow_si = scene.closest_hit(&Ray {
+                origin: si.position + 1e-3 * wo,
+                direction: wo,
+            });
+            if let Some(si) = shadow_si {
+                if si.t < dist - 2e-3 {
+                    continue;
+                }
+            }
+            color = color + si.material.bsdf_eval(&si, wo).radiance * light_sample.radiance;
+        }
+        color
+    }
+}
+
+impl Integrator for WhittedIntegrator {
+    fn sample_radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Color {
+        self.trace(ray, scene, sampler, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::DirectLightingIntegrator;
+    use crate::emitter::PointLight;
+    use crate::material::*;
+    use crate::sensor::{Camera, PinholeCamera, Sensor};
+
+    fn glass_scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.add_shape(Box::new(Sphere::new(
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: -3.0,
+            },
+            1.0,
+            Box::new(DielectricMaterial::new(1.5)),
+        )));
+        scene.add_shape(Box::new(Sphere::new(
+            Point {
+                x: 1.5,
+                y: 0.0,
+                z: -4.0,
+            },
+            0.7,
+            Box::new(ConductorMaterial::from_reflectance(Color::new(
+                0.9, 0.6, 0.3,
+            ))),
+        )));
+        scene.add_shape(Box::new(InfinitePlane::new(
+            Point {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            Box::new(DiffuseMaterial {
+                albedo: Box::new(Color::new(0.5, 0.5, 0.5)),
+            }),
+        )));
+        scene.add_light(Box::new(PointLight::new(
+            Point {
+                x: -1.0,
+                y: 3.0,
+                z: -1.0,
+            },
+            3.0,
+        )));
+        scene
+    }
+
+    #[test]
+    fn follows_every_lobe() {
+        let scene = glass_scene();
+        let whitted = WhittedIntegrator::new(8);
+        let direct = DirectLightingIntegrator::new(8);
+        // through the glass sphere onto the lit floor
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.1,
+                y: -0.3,
+                z: -1.0,
+            }
+            .normalize(),
+        };
+
+        let expected = whitted.sample_radiance(&ray, &scene, &mut Sampler::new(0, 0));
+        let n = 20_000;
+        let mean = (0..n)
+            .map(|k| direct.sample_radiance(&ray, &scene, &mut Sampler::new(1, k)))
+            .fold(Color::new(0.0, 0.0, 0.0), |sum, c| sum + c);
+        let mean = (1.0 / n as f32) * mean;
+        assert!(expected.g > 0.0);
+        assert!(
+            (mean.g - expected.g).abs() < 0.03 * expected.g,
+            "{mean:?} {expected:?}"
+        );
+    }
+
+    #[test]
+    fn renders_without_noise() {
+        let scene = glass_scene();
+        let camera = PinholeCamera::new(Sensor::zero(16, 12), 60.0);
+        let whitted = WhittedIntegrator::new(6);
+        let mut lit = 0;
+        for (index, pixel) in camera.get_pixels().iter().enumerate() {
+            let (i, j) = pixel.position;
+            let ray = camera.sample_ray(i, j, &mut Sampler::new(0, 0)).unwrap();
+            let a = whitted.sample_radiance(&ray, &scene, &mut Sampler::new(1, index as u64));
+            let b = whitted.sample_radiance(&ray, &scene, &mut Sampler::new(2, index as u64));
+            assert_eq!((a.r, a.g, a.b), (b.r, b.g, b.b));
+            lit += (a.g > 0.0) as usize;
+        }
+        assert!(lit > 0);
+    }
+}